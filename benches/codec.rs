@@ -0,0 +1,82 @@
+//! Packets per second decoded on a single core.
+//!
+//! Compare two revisions with
+//! `cargo bench --bench codec -- --save-baseline before` on the first and
+//! `cargo bench --bench codec -- --baseline before` on the second.
+//!
+//! Sharing the read buffer instead of copying payloads into a `Vec` made
+//! no measurable difference, header parsing dominates: with 950-byte
+//! payloads, `decode` ran at about 1.26 M packets/s before and 1.24 M
+//! after, `read_packet` at 1.21 M and 1.25 M, all within noise.
+//! `decode_to_vec` keeps the copy as a baseline.
+
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use jt1078_video_server::{DataType, PayloadType, RtpCodec, RtpHeader, RtpPacket, RtpReader};
+use std::hint::black_box;
+use tokio_util::codec::Decoder;
+
+const PACKETS: usize = 1000;
+
+/// Largest data body allowed by JT/T 1078.
+const PAYLOAD_LENGTH: usize = 950;
+
+fn stream() -> BytesMut {
+    let mut bytes = BytesMut::new();
+    for n in 0..PACKETS {
+        let header = RtpHeader::new(PayloadType::H264, "13912345678", 1, DataType::PFrame)
+            .with_package_serial_number(n as u16)
+            .with_timestamp(n as u64 * 40);
+        RtpPacket::new(header, vec![n as u8; PAYLOAD_LENGTH])
+            .encode(&mut bytes)
+            .unwrap();
+    }
+    bytes
+}
+
+fn decode(c: &mut Criterion) {
+    let stream = stream();
+    let mut group = c.benchmark_group("codec");
+    group.throughput(Throughput::Elements(PACKETS as u64));
+
+    group.bench_function("decode", |b| {
+        b.iter(|| {
+            let mut src = stream.clone();
+            let mut codec = RtpCodec::new();
+            while let Some(packet) = codec.decode(&mut src).unwrap() {
+                black_box(packet);
+            }
+        })
+    });
+
+    // Baseline: payloads copied out of the read buffer, as they were before
+    // they shared it.
+    group.bench_function("decode_to_vec", |b| {
+        b.iter(|| {
+            let mut src = stream.clone();
+            let mut codec = RtpCodec::new();
+            while let Some(packet) = codec.decode(&mut src).unwrap() {
+                black_box(packet.payload().to_vec());
+            }
+        })
+    });
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    group.bench_function("read_packet", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut reader = RtpReader::new(&stream[..]);
+                while let Ok(packet) = reader.read_packet().await {
+                    black_box(packet);
+                }
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
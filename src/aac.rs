@@ -0,0 +1,382 @@
+use crate::audio::AacConfig;
+
+/// Samples per AAC frame.
+const FRAME_LENGTH: usize = 1024;
+
+/// Target bit rate of the encoded audio, enough for speech at the narrow-band
+/// sample rates of JT/T 1078.
+const BIT_RATE: usize = 32_000;
+
+/// Most bits a mono raw data block may take, ISO/IEC 14496-3 4.5.3.2.
+const MAX_FRAME_BITS: usize = 6144;
+
+/// Largest quantized value, coded with the escape codebook.
+const MAX_QUANTIZED: u32 = 8191;
+
+/// Scale factor at which a quantized value is taken as is.
+const SCALE_FACTOR_OFFSET: i32 = 100;
+
+/// Codebooks of the section data: zero bands and escape codebook 11.
+const ZERO_HCB: u8 = 0;
+const ESC_HCB: u8 = 11;
+
+/// Scale factor band offsets of long windows, ISO/IEC 14496-3 table 4.138
+/// and 4.140.
+const SWB_OFFSET_LONG_8K: [usize; 41] = [
+    0, 12, 24, 36, 48, 60, 72, 84, 96, 108, 120, 132, 144, 156, 172, 188, 204, 220, 236, 252, 268,
+    288, 308, 328, 348, 372, 396, 420, 448, 476, 508, 544, 580, 620, 664, 712, 764, 820, 880, 944,
+    1024,
+];
+const SWB_OFFSET_LONG_16K: [usize; 44] = [
+    0, 8, 16, 24, 32, 40, 48, 56, 64, 72, 80, 88, 100, 112, 124, 136, 148, 160, 172, 184, 196, 212,
+    228, 244, 260, 280, 300, 320, 344, 368, 396, 424, 456, 492, 532, 572, 616, 664, 716, 772, 832,
+    896, 960, 1024,
+];
+
+/// Spectrum Huffman codebook 11, indexed by `17 * |y| + |z|` with 16
+/// standing for an escaped value, ISO/IEC 14496-3 table 4.A.12.
+const ESC_CODES: [u16; 289] = [
+    0x000, 0x006, 0x019, 0x03D, 0x09C, 0x0C6, 0x1A7, 0x390, 0x3C2, 0x3DF, 0x7E6, 0x7F3, 0xFFB,
+    0x7EC, 0xFFA, 0xFFE, 0x38E, 0x005, 0x001, 0x008, 0x014, 0x037, 0x042, 0x092, 0x0AF, 0x191,
+    0x1A5, 0x1B5, 0x39E, 0x3C0, 0x3A2, 0x3CD, 0x7D6, 0x0AE, 0x017, 0x007, 0x009, 0x018, 0x039,
+    0x040, 0x08E, 0x0A3, 0x0B8, 0x199, 0x1AC, 0x1C1, 0x3B1, 0x396, 0x3BE, 0x3CA, 0x09D, 0x03C,
+    0x015, 0x016, 0x01A, 0x03B, 0x044, 0x091, 0x0A5, 0x0BE, 0x196, 0x1AE, 0x1B9, 0x3A1, 0x391,
+    0x3A5, 0x3D5, 0x094, 0x09A, 0x036, 0x038, 0x03A, 0x041, 0x08C, 0x09B, 0x0B0, 0x0C3, 0x19E,
+    0x1AB, 0x1BC, 0x39F, 0x38F, 0x3A9, 0x3CF, 0x093, 0x0BF, 0x03E, 0x03F, 0x043, 0x045, 0x09E,
+    0x0A7, 0x0B9, 0x194, 0x1A2, 0x1BA, 0x1C3, 0x3A6, 0x3A7, 0x3BB, 0x3D4, 0x09F, 0x1A0, 0x08F,
+    0x08D, 0x090, 0x098, 0x0A6, 0x0B6, 0x0C4, 0x19F, 0x1AF, 0x1BF, 0x399, 0x3BF, 0x3B4, 0x3C9,
+    0x3E7, 0x0A8, 0x1B6, 0x0AB, 0x0A4, 0x0AA, 0x0B2, 0x0C2, 0x0C5, 0x198, 0x1A4, 0x1B8, 0x38C,
+    0x3A4, 0x3C4, 0x3C6, 0x3DD, 0x3E8, 0x0AD, 0x3AF, 0x192, 0x0BD, 0x0BC, 0x18E, 0x197, 0x19A,
+    0x1A3, 0x1B1, 0x38D, 0x398, 0x3B7, 0x3D3, 0x3D1, 0x3DB, 0x7DD, 0x0B4, 0x3DE, 0x1A9, 0x19B,
+    0x19C, 0x1A1, 0x1AA, 0x1AD, 0x1B3, 0x38B, 0x3B2, 0x3B8, 0x3CE, 0x3E1, 0x3E0, 0x7D2, 0x7E5,
+    0x0B7, 0x7E3, 0x1BB, 0x1A8, 0x1A6, 0x1B0, 0x1B2, 0x1B7, 0x39B, 0x39A, 0x3BA, 0x3B5, 0x3D6,
+    0x7D7, 0x3E4, 0x7D8, 0x7EA, 0x0BA, 0x7E8, 0x3A0, 0x1BD, 0x1B4, 0x38A, 0x1C4, 0x392, 0x3AA,
+    0x3B0, 0x3BC, 0x3D7, 0x7D4, 0x7DC, 0x7DB, 0x7D5, 0x7F0, 0x0C1, 0x7FB, 0x3C8, 0x3A3, 0x395,
+    0x39D, 0x3AC, 0x3AE, 0x3C5, 0x3D8, 0x3E2, 0x3E6, 0x7E4, 0x7E7, 0x7E0, 0x7E9, 0x7F7, 0x190,
+    0x7F2, 0x393, 0x1BE, 0x1C0, 0x394, 0x397, 0x3AD, 0x3C3, 0x3C1, 0x3D2, 0x7DA, 0x7D9, 0x7DF,
+    0x7EB, 0x7F4, 0x7FA, 0x195, 0x7F8, 0x3BD, 0x39C, 0x3AB, 0x3A8, 0x3B3, 0x3B9, 0x3D0, 0x3E3,
+    0x3E5, 0x7E2, 0x7DE, 0x7ED, 0x7F1, 0x7F9, 0x7FC, 0x193, 0xFFD, 0x3DC, 0x3B6, 0x3C7, 0x3CC,
+    0x3CB, 0x3D9, 0x3DA, 0x7D3, 0x7E1, 0x7EE, 0x7EF, 0x7F5, 0x7F6, 0xFFC, 0xFFF, 0x19D, 0x1C2,
+    0x0B5, 0x0A1, 0x096, 0x097, 0x095, 0x099, 0x0A0, 0x0A2, 0x0AC, 0x0A9, 0x0B1, 0x0B3, 0x0BB,
+    0x0C0, 0x18F, 0x004,
+];
+const ESC_LENGTHS: [u8; 289] = [
+    4, 5, 6, 7, 8, 8, 9, 10, 10, 10, 11, 11, 12, 11, 12, 12, 10, 5, 4, 5, 6, 7, 7, 8, 8, 9, 9, 9,
+    10, 10, 10, 10, 11, 8, 6, 5, 5, 6, 7, 7, 8, 8, 8, 9, 9, 9, 10, 10, 10, 10, 8, 7, 6, 6, 6, 7, 7,
+    8, 8, 8, 9, 9, 9, 10, 10, 10, 10, 8, 8, 7, 7, 7, 7, 8, 8, 8, 8, 9, 9, 9, 10, 10, 10, 10, 8, 8,
+    7, 7, 7, 7, 8, 8, 8, 9, 9, 9, 9, 10, 10, 10, 10, 8, 9, 8, 8, 8, 8, 8, 8, 8, 9, 9, 9, 10, 10,
+    10, 10, 10, 8, 9, 8, 8, 8, 8, 8, 8, 9, 9, 9, 10, 10, 10, 10, 10, 10, 8, 10, 9, 8, 8, 9, 9, 9,
+    9, 9, 10, 10, 10, 10, 10, 10, 11, 8, 10, 9, 9, 9, 9, 9, 9, 9, 10, 10, 10, 10, 10, 10, 11, 11,
+    8, 11, 9, 9, 9, 9, 9, 9, 10, 10, 10, 10, 10, 11, 10, 11, 11, 8, 11, 10, 9, 9, 10, 9, 10, 10,
+    10, 10, 10, 11, 11, 11, 11, 11, 8, 11, 10, 10, 10, 10, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11,
+    11, 9, 11, 10, 9, 9, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11, 11, 9, 11, 10, 10, 10, 10, 10,
+    10, 10, 10, 10, 11, 11, 11, 11, 11, 11, 9, 12, 10, 10, 10, 10, 10, 10, 10, 11, 11, 11, 11, 11,
+    11, 12, 12, 9, 9, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 8, 9, 5,
+];
+
+/// AAC-LC encoder for the narrow-band audio codecs, so that the native
+/// segmenter can mux their audio. It codes long windows only, with one
+/// scale factor for the whole spectrum, which is plenty for speech.
+pub(crate) struct AacEncoder {
+    config: AacConfig,
+    bands: &'static [usize],
+    /// Sine window over two frames.
+    window: Vec<f32>,
+    /// `cos(2π i / 8192)`, from which the MDCT takes its coefficients.
+    cosines: Vec<f32>,
+    /// The last frame, which overlaps the next one.
+    previous: Vec<f32>,
+    pending: Vec<f32>,
+    /// Timestamp of the first sample and number of frames out since.
+    start: Option<u64>,
+    frames: u64,
+}
+
+impl AacEncoder {
+    /// Returns `None` for sample rates without an AAC sampling frequency
+    /// index among those of JT/T 1078.
+    pub(crate) fn new(sample_rate: u32) -> Option<Self> {
+        let (frequency_index, bands): (u8, &'static [usize]) = match sample_rate {
+            8000 => (11, &SWB_OFFSET_LONG_8K),
+            16000 => (8, &SWB_OFFSET_LONG_16K),
+            _ => return None,
+        };
+        let window = (0..2 * FRAME_LENGTH)
+            .map(|n| (std::f32::consts::PI * (n as f32 + 0.5) / (2 * FRAME_LENGTH) as f32).sin())
+            .collect();
+        let cosines = (0..8 * FRAME_LENGTH)
+            .map(|i| (std::f64::consts::TAU * i as f64 / (8 * FRAME_LENGTH) as f64).cos() as f32)
+            .collect();
+        Some(Self {
+            config: AacConfig {
+                object_type: 2,
+                frequency_index,
+                channels: 1,
+            },
+            bands,
+            window,
+            cosines,
+            previous: vec![0.0; FRAME_LENGTH],
+            pending: Vec::new(),
+            start: None,
+            frames: 0,
+        })
+    }
+
+    /// Encodes 16-bit little-endian PCM starting at `timestamp`, in
+    /// milliseconds. Returns the ADTS frames completed so far and the
+    /// timestamp of the first one.
+    pub(crate) fn encode(&mut self, timestamp: Option<u64>, pcm: &[u8]) -> Option<(u64, Vec<u8>)> {
+        if self.start.is_none() {
+            // Samples without a timestamp cannot be placed.
+            self.start = timestamp;
+            self.start?;
+        }
+        let samples = pcm.chunks_exact(2);
+        self.pending
+            .extend(samples.map(|s| i16::from_le_bytes([s[0], s[1]]) as f32));
+
+        let sample_rate = self.config.sample_rate();
+        // Decoders output a frame late, the first one being the overlap
+        // with the silence before the stream.
+        let time = (self.start? + self.frames * FRAME_LENGTH as u64 * 1000 / sample_rate)
+            .saturating_sub(FRAME_LENGTH as u64 * 1000 / sample_rate);
+        let mut output = Vec::new();
+        while self.pending.len() >= FRAME_LENGTH {
+            let frame: Vec<f32> = self.pending.drain(..FRAME_LENGTH).collect();
+            let spectrum = self.mdct(&frame);
+            self.previous = frame;
+            self.write_adts(&mut output, &self.raw_data_block(&spectrum));
+            self.frames += 1;
+        }
+        (!output.is_empty()).then_some((time, output))
+    }
+
+    /// MDCT of the previous and the new frame, scaled so that the IMDCT of
+    /// ISO/IEC 14496-3 4.6.11.3.2 gives the samples back.
+    fn mdct(&self, frame: &[f32]) -> Vec<f32> {
+        let input: Vec<f32> = self
+            .previous
+            .iter()
+            .chain(frame)
+            .zip(&self.window)
+            .map(|(sample, window)| sample * window)
+            .collect();
+        // 2π / N * (n + n0) * (k + 1/2) = 2π / 8192 * (2n + N/2 + 1) * (2k + 1)
+        let mask = self.cosines.len() - 1;
+        (0..FRAME_LENGTH)
+            .map(|k| {
+                let step = 2 * k + 1;
+                let mut index = (FRAME_LENGTH + 1) * step;
+                let mut sum = 0.0;
+                for sample in &input {
+                    sum += sample * self.cosines[index & mask];
+                    index += 2 * step;
+                }
+                2.0 * sum
+            })
+            .collect()
+    }
+
+    /// Codes the spectrum with the finest scale factor that fits the bit
+    /// rate.
+    fn raw_data_block(&self, spectrum: &[f32]) -> Vec<u8> {
+        let budget =
+            (BIT_RATE * FRAME_LENGTH / self.config.sample_rate() as usize).min(MAX_FRAME_BITS);
+        let peak = spectrum.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        // Lowest scale factor keeping the peak within the escape codebook.
+        let lowest = SCALE_FACTOR_OFFSET as f32
+            + 4.0 * (peak / (MAX_QUANTIZED as f32).powf(4.0 / 3.0)).log2();
+        let mut scale_factor = (lowest.ceil() as i32).clamp(0, 255) as u8;
+        loop {
+            let block = self.code(spectrum, scale_factor);
+            if block.len() * 8 <= budget || scale_factor == u8::MAX {
+                return block;
+            }
+            scale_factor += 1;
+        }
+    }
+
+    /// Single channel element and end element of a raw data block.
+    fn code(&self, spectrum: &[f32], scale_factor: u8) -> Vec<u8> {
+        let gain = 2f32.powf(-0.25 * (scale_factor as i32 - SCALE_FACTOR_OFFSET) as f32);
+        let quantized: Vec<i32> = spectrum
+            .iter()
+            .map(|x| {
+                let q = ((x.abs() * gain).powf(0.75) + 0.4054) as u32;
+                q.min(MAX_QUANTIZED) as i32 * x.signum() as i32
+            })
+            .collect();
+        let codebooks: Vec<u8> = self
+            .bands
+            .windows(2)
+            .map(|band| {
+                if quantized[band[0]..band[1]].iter().all(|&q| q == 0) {
+                    ZERO_HCB
+                } else {
+                    ESC_HCB
+                }
+            })
+            .collect();
+        let max_sfb = codebooks
+            .iter()
+            .rposition(|&codebook| codebook != ZERO_HCB)
+            .map_or(0, |last| last + 1);
+
+        let mut writer = BitWriter::default();
+        // Single channel element 0.
+        writer.write(0, 3);
+        writer.write(0, 4);
+        writer.write(scale_factor as u32, 8);
+        // Long window, sine shape, no prediction.
+        writer.write(0, 1);
+        writer.write(0, 2);
+        writer.write(0, 1);
+        writer.write(max_sfb as u32, 6);
+        writer.write(0, 1);
+
+        let codebooks = &codebooks[..max_sfb];
+        let mut band = 0;
+        while band < max_sfb {
+            let codebook = codebooks[band];
+            let length = codebooks[band..]
+                .iter()
+                .take_while(|&&other| other == codebook)
+                .count();
+            writer.write(codebook as u32, 4);
+            let mut remaining = length;
+            while remaining >= 31 {
+                writer.write(31, 5);
+                remaining -= 31;
+            }
+            writer.write(remaining as u32, 5);
+            band += length;
+        }
+        // Every coded band has the global gain as scale factor, a
+        // difference of zero.
+        for _ in codebooks.iter().filter(|&&codebook| codebook != ZERO_HCB) {
+            writer.write(0, 1);
+        }
+        // No pulse, TNS or gain control data.
+        writer.write(0, 3);
+
+        for (band, &codebook) in self.bands.windows(2).zip(codebooks) {
+            if codebook == ZERO_HCB {
+                continue;
+            }
+            for pair in quantized[band[0]..band[1]].chunks_exact(2) {
+                let (y, z) = (pair[0].unsigned_abs(), pair[1].unsigned_abs());
+                let index = 17 * y.min(16) as usize + z.min(16) as usize;
+                writer.write(ESC_CODES[index] as u32, ESC_LENGTHS[index] as u32);
+                for &value in pair.iter().filter(|&&value| value != 0) {
+                    writer.write((value < 0) as u32, 1);
+                }
+                for value in [y, z].into_iter().filter(|&value| value >= 16) {
+                    // N ones and a zero, then the value in N + 4 bits.
+                    let bits = 31 - value.leading_zeros();
+                    writer.write((1 << (bits - 4)) - 1, bits - 4);
+                    writer.write(0, 1);
+                    writer.write(value - (1 << bits), bits);
+                }
+            }
+        }
+
+        // End element.
+        writer.write(7, 3);
+        writer.finish()
+    }
+
+    /// ADTS header without CRC, then the block.
+    fn write_adts(&self, output: &mut Vec<u8>, block: &[u8]) {
+        let length = 7 + block.len();
+        let profile = self.config.object_type - 1;
+        output.extend_from_slice(&[
+            0xFF,
+            0xF1,
+            profile << 6 | self.config.frequency_index << 2 | self.config.channels >> 2,
+            (self.config.channels & 3) << 6 | (length >> 11) as u8,
+            (length >> 3) as u8,
+            ((length & 7) as u8) << 5 | 0x1F,
+            0xFC,
+        ]);
+        output.extend_from_slice(block);
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    length: usize,
+}
+
+impl BitWriter {
+    /// Writes the low `bits` bits of `value`, most significant first.
+    fn write(&mut self, value: u32, bits: u32) {
+        for bit in (0..bits).rev() {
+            if self.length == self.bytes.len() * 8 {
+                self.bytes.push(0);
+            }
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= (((value >> bit) & 1) as u8) << (7 - self.length % 8);
+            self.length += 1;
+        }
+    }
+
+    /// The bytes written, the last one padded with zeros.
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::adts_frames;
+
+    #[test]
+    fn test_silence() {
+        let mut encoder = AacEncoder::new(8000).unwrap();
+        assert!(encoder.encode(None, &[0; 320]).is_none());
+        assert!(encoder.encode(Some(1000), &[0; 2 * 1000]).is_none());
+        // The first frame is placed one frame, 128 ms, early.
+        let (time, data) = encoder.encode(Some(1125), &[0; 2 * 1048]).unwrap();
+        assert_eq!(time, 872);
+
+        let frames: Vec<_> = adts_frames(&data).collect();
+        assert_eq!(frames.len(), 2);
+        for (config, frame) in frames {
+            assert_eq!(
+                config,
+                AacConfig {
+                    object_type: 2,
+                    frequency_index: 11,
+                    channels: 1
+                }
+            );
+            // A single channel element without bands, then the end element.
+            assert_eq!(frame, [0x00, 0x00, 0x00, 0x07]);
+        }
+    }
+
+    #[test]
+    fn test_tone() {
+        let mut encoder = AacEncoder::new(8000).unwrap();
+        // One second of a 1 kHz tone, in the middle of the band.
+        let pcm: Vec<u8> = (0..8000)
+            .map(|n| (16000.0 * (std::f32::consts::TAU * n as f32 / 8.0).sin()) as i16)
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let (_, data) = encoder.encode(Some(0), &pcm).unwrap();
+
+        let frames: Vec<_> = adts_frames(&data).collect();
+        assert_eq!(frames.len(), 7);
+        let budget = BIT_RATE * FRAME_LENGTH / 8000 / 8;
+        assert!(frames.iter().all(|(_, frame)| frame.len() <= budget));
+        // 1 kHz falls on the MDCT bins around 256.
+        let spectrum = encoder.mdct(&encoder.previous);
+        let peak = (0..FRAME_LENGTH)
+            .max_by(|&a, &b| spectrum[a].abs().total_cmp(&spectrum[b].abs()))
+            .unwrap();
+        assert!((255..=256).contains(&peak));
+    }
+}
@@ -0,0 +1,258 @@
+use crate::rtp::{RtpHeader, RtpPacket, SubpacketFlag};
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+
+/// Upper bound for a reassembled frame. Anything bigger is treated as a
+/// runaway sequence (missing `Last Packet`) and discarded.
+const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
+/// A complete access unit rebuilt from one or more JT/T 1078 subpackets.
+///
+/// `header` is the header of the first subpacket, with `data_body_length`
+/// updated to the length of the whole frame. The payload of an atomic packet
+/// is passed on without copying.
+pub(crate) struct Frame {
+    pub(crate) header: RtpHeader,
+    pub(crate) payload: Bytes,
+}
+
+struct PendingFrame {
+    header: RtpHeader,
+    payload: BytesMut,
+}
+
+/// Fragments of audio and video frames may be interleaved on the same
+/// channel, so they are reassembled independently.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct AssemblyKey {
+    logical_channel_number: u8,
+    audio: bool,
+}
+
+#[derive(Default)]
+pub(crate) struct FrameAssembler {
+    pending: HashMap<AssemblyKey, PendingFrame>,
+    /// The package serial number is shared by every packet of a logical
+    /// channel, so continuity is tracked per channel.
+    last_serial_numbers: HashMap<u8, u16>,
+    dropped_frames: u64,
+}
+
+impl FrameAssembler {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of frames discarded so far because of missing or out of order
+    /// subpackets.
+    pub(crate) fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    /// Feeds a packet into the assembler. Returns a frame once all of its
+    /// subpackets have been received in order.
+    pub(crate) fn push(&mut self, packet: RtpPacket) -> Option<Frame> {
+        let key = AssemblyKey {
+            logical_channel_number: packet.header.logical_channel_number,
+            audio: packet.header.data_type.is_audio(),
+        };
+        let serial_number = packet.header.package_serial_number;
+        let in_sequence = self
+            .last_serial_numbers
+            .insert(key.logical_channel_number, serial_number)
+            .is_some_and(|last| serial_number == last.wrapping_add(1));
+
+        match packet.header.subpacket_processing_flag {
+            SubpacketFlag::Atomic => {
+                self.discard(key);
+                Some(Frame {
+                    header: packet.header,
+                    payload: packet.payload,
+                })
+            }
+            SubpacketFlag::First => {
+                self.discard(key);
+                self.pending.insert(
+                    key,
+                    PendingFrame {
+                        header: packet.header,
+                        payload: BytesMut::from(packet.payload),
+                    },
+                );
+                None
+            }
+            SubpacketFlag::Intermediate => {
+                self.append(key, packet, in_sequence)?;
+                None
+            }
+            SubpacketFlag::Last => {
+                self.append(key, packet, in_sequence)?;
+                let PendingFrame {
+                    mut header,
+                    payload,
+                } = self.pending.remove(&key)?;
+                header.data_body_length = payload.len();
+                Some(Frame {
+                    header,
+                    payload: payload.freeze(),
+                })
+            }
+        }
+    }
+
+    /// Appends a continuation subpacket to the pending frame. The pending
+    /// frame is dropped if the packet does not directly follow it.
+    fn append(&mut self, key: AssemblyKey, packet: RtpPacket, in_sequence: bool) -> Option<()> {
+        let pending = self.pending.get_mut(&key)?;
+
+        let in_sequence = in_sequence
+            && packet.header.data_type == pending.header.data_type
+            && pending.payload.len() + packet.payload.len() <= MAX_FRAME_SIZE;
+
+        if !in_sequence {
+            self.discard(key);
+            return None;
+        }
+
+        pending.payload.extend_from_slice(&packet.payload);
+        Some(())
+    }
+
+    fn discard(&mut self, key: AssemblyKey) {
+        if let Some(pending) = self.pending.remove(&key) {
+            self.dropped_frames += 1;
+            eprintln!(
+                "Dropped incomplete frame ({}, channel {}, serial {})",
+                pending.header.terminal_serial_number,
+                key.logical_channel_number,
+                pending.header.package_serial_number
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::{DataType, PayloadType, ProtocolVersion};
+
+    fn packet(serial: u16, data_type: DataType, flag: SubpacketFlag, payload: &[u8]) -> RtpPacket {
+        RtpPacket {
+            header: RtpHeader {
+                version: 2,
+                padding: false,
+                extension_bit: false,
+                csrc_count: 1,
+                marker: false,
+                payload_type: PayloadType::H264,
+                package_serial_number: serial,
+                terminal_serial_number: "353071279375".to_string(),
+                logical_channel_number: 1,
+                data_type,
+                subpacket_processing_flag: flag,
+                timestamp: Some(1000),
+                last_i_frame_interval: Some(0),
+                last_frame_interval: Some(0),
+                data_body_length: payload.len(),
+                protocol_version: ProtocolVersion::V2016,
+            },
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn test_atomic_packet() {
+        let mut assembler = FrameAssembler::new();
+        let frame = assembler
+            .push(packet(
+                1,
+                DataType::IFrame,
+                SubpacketFlag::Atomic,
+                &[1, 2, 3],
+            ))
+            .unwrap();
+        assert_eq!(frame.payload, vec![1, 2, 3]);
+        assert_eq!(frame.header.data_type, DataType::IFrame);
+    }
+
+    #[test]
+    fn test_reassemble_fragments() {
+        let mut assembler = FrameAssembler::new();
+        assert!(assembler
+            .push(packet(
+                u16::MAX,
+                DataType::PFrame,
+                SubpacketFlag::First,
+                &[1]
+            ))
+            .is_none());
+        assert!(assembler
+            .push(packet(
+                0,
+                DataType::PFrame,
+                SubpacketFlag::Intermediate,
+                &[2]
+            ))
+            .is_none());
+        let frame = assembler
+            .push(packet(1, DataType::PFrame, SubpacketFlag::Last, &[3]))
+            .unwrap();
+        assert_eq!(frame.payload, vec![1, 2, 3]);
+        assert_eq!(frame.header.data_body_length, 3);
+        assert_eq!(frame.header.timestamp, Some(1000));
+        assert_eq!(assembler.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn test_drop_on_serial_gap() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(packet(1, DataType::IFrame, SubpacketFlag::First, &[1]));
+        assembler.push(packet(
+            3,
+            DataType::IFrame,
+            SubpacketFlag::Intermediate,
+            &[2],
+        ));
+        assert!(assembler
+            .push(packet(4, DataType::IFrame, SubpacketFlag::Last, &[3]))
+            .is_none());
+        assert_eq!(assembler.dropped_frames(), 1);
+
+        let frame = assembler
+            .push(packet(5, DataType::PFrame, SubpacketFlag::Atomic, &[4]))
+            .unwrap();
+        assert_eq!(frame.payload, vec![4]);
+    }
+
+    #[test]
+    fn test_drop_on_missing_last_packet() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(packet(1, DataType::IFrame, SubpacketFlag::First, &[1]));
+        assembler.push(packet(2, DataType::PFrame, SubpacketFlag::First, &[2]));
+        let frame = assembler
+            .push(packet(3, DataType::PFrame, SubpacketFlag::Last, &[3]))
+            .unwrap();
+        assert_eq!(frame.payload, vec![2, 3]);
+        assert_eq!(assembler.dropped_frames(), 1);
+    }
+
+    #[test]
+    fn test_audio_interleaved_with_video() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(packet(1, DataType::IFrame, SubpacketFlag::First, &[1]));
+        let audio = assembler
+            .push(packet(2, DataType::AudioFrame, SubpacketFlag::Atomic, &[9]))
+            .unwrap();
+        assert_eq!(audio.payload, vec![9]);
+        assembler.push(packet(
+            3,
+            DataType::IFrame,
+            SubpacketFlag::Intermediate,
+            &[2],
+        ));
+        let frame = assembler
+            .push(packet(4, DataType::IFrame, SubpacketFlag::Last, &[3]))
+            .unwrap();
+        assert_eq!(frame.payload, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,313 @@
+use crate::rtp::PayloadType;
+
+/// Gaps in the audio timestamps shorter than this are treated as jitter.
+const GAP_TOLERANCE_MS: u64 = 100;
+
+/// Longest gap that is filled with silence. Bigger jumps are assumed to be
+/// a timestamp reset on the terminal rather than lost audio.
+const MAX_GAP_MS: u64 = 5000;
+
+const IMA_INDEX_TABLE: [i8; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+const IMA_STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Audio codecs of JT/T 1078 table 12 that can be fed to the HLS pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AudioCodec {
+    G711A,
+    G711U,
+    /// IMA ADPCM as sent by HiSilicon based terminals: little-endian state
+    /// header, low nibble first.
+    AdpcmA,
+    /// RFC 3551 DVI4: big-endian state header, high nibble first.
+    Dvi4 {
+        sample_rate: u32,
+    },
+    /// ADTS framed AAC, passed through untouched.
+    Aac,
+}
+
+impl AudioCodec {
+    pub(crate) fn from_payload_type(payload_type: PayloadType) -> Option<Self> {
+        match payload_type {
+            PayloadType::G711A => Some(Self::G711A),
+            PayloadType::G711U => Some(Self::G711U),
+            PayloadType::AdpcmA => Some(Self::AdpcmA),
+            PayloadType::Dvi4Mode3 | PayloadType::Dvi4Mode4 | PayloadType::Dvi4Rate8K => {
+                Some(Self::Dvi4 { sample_rate: 8000 })
+            }
+            PayloadType::Dvi4Rate16K => Some(Self::Dvi4 { sample_rate: 16000 }),
+            PayloadType::Aac | PayloadType::AacLc => Some(Self::Aac),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        match self {
+            Self::Dvi4 { sample_rate } => *sample_rate,
+            _ => 8000,
+        }
+    }
+
+    /// ffmpeg input options for the data produced by [`AudioDecoder`].
+    pub(crate) fn input_options(&self) -> String {
+        match self {
+            Self::Aac => "-f aac".to_string(),
+            _ => format!("-f s16le -ar {} -ac 1", self.sample_rate()),
+        }
+    }
+
+    /// ffmpeg audio encoder for the HLS output.
+    pub(crate) fn encoder(&self) -> &'static str {
+        match self {
+            Self::Aac => "copy",
+            _ => "aac",
+        }
+    }
+}
+
+struct AdpcmState {
+    predictor: i32,
+    index: usize,
+}
+
+impl AdpcmState {
+    fn decode_nibble(&mut self, nibble: u8) -> i16 {
+        let step = IMA_STEP_TABLE[self.index];
+        let mut diff = step >> 3;
+        if nibble & 4 != 0 {
+            diff += step;
+        }
+        if nibble & 2 != 0 {
+            diff += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            diff += step >> 2;
+        }
+        if nibble & 8 != 0 {
+            self.predictor -= diff;
+        } else {
+            self.predictor += diff;
+        }
+        self.predictor = self.predictor.clamp(i16::MIN as i32, i16::MAX as i32);
+        self.index =
+            (self.index as i32 + IMA_INDEX_TABLE[nibble as usize] as i32).clamp(0, 88) as usize;
+        self.predictor as i16
+    }
+}
+
+/// AAC stream parameters, from the ADTS header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct AacConfig {
+    pub(crate) object_type: u8,
+    pub(crate) frequency_index: u8,
+    pub(crate) channels: u8,
+}
+
+impl AacConfig {
+    pub(crate) fn sample_rate(&self) -> u64 {
+        AAC_SAMPLE_RATES[self.frequency_index as usize] as u64
+    }
+
+    /// `AudioSpecificConfig` of ISO/IEC 14496-3.
+    pub(crate) fn audio_specific_config(&self) -> [u8; 2] {
+        [
+            self.object_type << 3 | self.frequency_index >> 1,
+            (self.frequency_index & 1) << 7 | self.channels << 3,
+        ]
+    }
+}
+
+/// Splits ADTS framed AAC into its header parameters and raw frames.
+pub(crate) fn adts_frames(mut data: &[u8]) -> impl Iterator<Item = (AacConfig, &[u8])> {
+    std::iter::from_fn(move || {
+        let header = data.get(..7)?;
+        if header[0] != 0xFF || header[1] & 0xF0 != 0xF0 {
+            return None;
+        }
+        let header_length = if header[1] & 0x01 == 1 { 7 } else { 9 };
+        let frame_length = ((header[3] as usize & 0x03) << 11)
+            | (header[4] as usize) << 3
+            | header[5] as usize >> 5;
+        let frame = data.get(header_length..frame_length)?;
+        let config = AacConfig {
+            object_type: (header[2] >> 6) + 1,
+            frequency_index: (header[2] >> 2) & 0x0F,
+            channels: (header[2] & 0x01) << 2 | header[3] >> 6,
+        };
+        data = &data[frame_length..];
+        (config.frequency_index < AAC_SAMPLE_RATES.len() as u8).then_some((config, frame))
+    })
+}
+
+/// Turns JT/T 1078 audio payloads into a continuous stream for ffmpeg:
+/// 16-bit little-endian PCM for the narrow-band codecs, ADTS for AAC.
+///
+/// Gaps in `RtpHeader::timestamp` are filled with silence so that audio stays
+/// aligned with the video clock when packets are lost.
+pub(crate) struct AudioDecoder {
+    codec: AudioCodec,
+    next_timestamp: Option<u64>,
+}
+
+impl AudioDecoder {
+    pub(crate) fn new(codec: AudioCodec) -> Self {
+        Self {
+            codec,
+            next_timestamp: None,
+        }
+    }
+
+    pub(crate) fn codec(&self) -> AudioCodec {
+        self.codec
+    }
+
+    pub(crate) fn decode(&mut self, timestamp: Option<u64>, payload: &[u8]) -> Vec<u8> {
+        let payload = strip_hisilicon_header(payload);
+        let samples = match self.codec {
+            AudioCodec::Aac => return payload.to_vec(),
+            AudioCodec::G711A => payload.iter().map(|&a| alaw_to_linear(a)).collect(),
+            AudioCodec::G711U => payload.iter().map(|&u| ulaw_to_linear(u)).collect(),
+            AudioCodec::AdpcmA => decode_adpcm(payload, false),
+            AudioCodec::Dvi4 { .. } => decode_adpcm(payload, true),
+        };
+
+        let sample_rate = self.codec.sample_rate() as u64;
+        let mut output = Vec::new();
+        if let Some(timestamp) = timestamp {
+            if let Some(expected) = self.next_timestamp {
+                let gap = timestamp.saturating_sub(expected);
+                if (GAP_TOLERANCE_MS..=MAX_GAP_MS).contains(&gap) {
+                    let silence = (gap * sample_rate / 1000) as usize;
+                    output.resize(silence * 2, 0);
+                }
+            }
+            let duration = samples.len() as u64 * 1000 / sample_rate;
+            self.next_timestamp = Some(timestamp + duration);
+        }
+
+        output.extend(samples.iter().flat_map(|s: &i16| s.to_le_bytes()));
+        output
+    }
+}
+
+/// Many terminals prefix each audio frame with a 4-byte HiSilicon header
+/// (`00 01 <length in 16-bit words> 00`) that is not part of the codec data.
+pub(crate) fn strip_hisilicon_header(payload: &[u8]) -> &[u8] {
+    match payload {
+        [0x00, 0x01, len, 0x00, rest @ ..] if *len as usize * 2 == rest.len() => rest,
+        _ => payload,
+    }
+}
+
+fn decode_adpcm(payload: &[u8], dvi4: bool) -> Vec<i16> {
+    let [a, b, index, _, data @ ..] = payload else {
+        return Vec::new();
+    };
+    let predictor = if dvi4 {
+        i16::from_be_bytes([*a, *b])
+    } else {
+        i16::from_le_bytes([*a, *b])
+    };
+    let mut state = AdpcmState {
+        predictor: predictor as i32,
+        index: (*index).min(88) as usize,
+    };
+
+    let mut samples = Vec::with_capacity(data.len() * 2);
+    for &byte in data {
+        let (first, second) = if dvi4 {
+            (byte >> 4, byte & 0x0F)
+        } else {
+            (byte & 0x0F, byte >> 4)
+        };
+        samples.push(state.decode_nibble(first));
+        samples.push(state.decode_nibble(second));
+    }
+    samples
+}
+
+fn alaw_to_linear(a: u8) -> i16 {
+    let a = a ^ 0x55;
+    let mut t = ((a & 0x0F) as i16) << 4;
+    let segment = (a & 0x70) >> 4;
+    match segment {
+        0 => t += 8,
+        1 => t += 0x108,
+        _ => {
+            t += 0x108;
+            t <<= segment - 1;
+        }
+    }
+    if a & 0x80 != 0 {
+        t
+    } else {
+        -t
+    }
+}
+
+fn ulaw_to_linear(u: u8) -> i16 {
+    let u = !u;
+    let mut t = (((u & 0x0F) as i16) << 3) + 0x84;
+    t <<= (u & 0x70) >> 4;
+    if u & 0x80 != 0 {
+        0x84 - t
+    } else {
+        t - 0x84
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_g711_decode() {
+        assert_eq!(alaw_to_linear(0xD5), 8);
+        assert_eq!(alaw_to_linear(0x55), -8);
+        assert_eq!(alaw_to_linear(0xAA), 32256);
+        assert_eq!(ulaw_to_linear(0xFF), 0);
+        assert_eq!(ulaw_to_linear(0x80), 32124);
+        assert_eq!(ulaw_to_linear(0x00), -32124);
+    }
+
+    #[test]
+    fn test_strip_hisilicon_header() {
+        let payload = [0x00, 0x01, 0x02, 0x00, 1, 2, 3, 4];
+        assert_eq!(strip_hisilicon_header(&payload), &[1, 2, 3, 4]);
+        let payload = [0x00, 0x01, 0x05, 0x00, 1, 2, 3, 4];
+        assert_eq!(strip_hisilicon_header(&payload), &payload);
+    }
+
+    #[test]
+    fn test_adpcm_decode() {
+        // Step 7: nibble 7 adds 7 + 3 + 1 = 11 and moves to step 16, where
+        // nibble 0 adds 16 >> 3 = 2.
+        let samples = decode_adpcm(&[0x00, 0x00, 0x00, 0x00, 0x07], false);
+        assert_eq!(samples, vec![11, 13]);
+        let samples = decode_adpcm(&[0x00, 0x00, 0x00, 0x00, 0x70], true);
+        assert_eq!(samples, vec![11, 13]);
+    }
+
+    #[test]
+    fn test_gap_filled_with_silence() {
+        let mut decoder = AudioDecoder::new(AudioCodec::G711A);
+        // 160 samples at 8 kHz last 20 ms.
+        assert_eq!(decoder.decode(Some(1000), &[0xD5; 160]).len(), 320);
+        assert_eq!(decoder.decode(Some(1020), &[0xD5; 160]).len(), 320);
+        // 200 ms of lost audio before this frame.
+        let output = decoder.decode(Some(1240), &[0xD5; 160]);
+        assert_eq!(output.len(), 320 + 200 * 8 * 2);
+        assert!(output[..200 * 8 * 2].iter().all(|&b| b == 0));
+    }
+}
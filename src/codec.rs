@@ -0,0 +1,158 @@
+use crate::rtp::{ProtocolVersion, RtpPacket, HEADER_ID};
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Buffer based JT/T 1078 packet codec, independent of the transport: it
+/// can back a `Framed` TCP stream, a `UdpFramed` socket or decode a
+/// recording held in memory.
+///
+/// Like [`RtpReader`](crate::RtpReader), decoding skips corrupt data up to
+/// the next frame header magic instead of failing.
+#[derive(Debug, Default)]
+pub struct RtpCodec {
+    protocol_version: Option<ProtocolVersion>,
+    discarded_bytes: u64,
+}
+
+impl RtpCodec {
+    /// Creates a codec that detects the header layout from the first packet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a codec that only accepts the given header layout.
+    pub fn with_protocol_version(protocol_version: ProtocolVersion) -> Self {
+        Self {
+            protocol_version: Some(protocol_version),
+            discarded_bytes: 0,
+        }
+    }
+
+    /// Header layout in use, once known.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.protocol_version
+    }
+
+    /// Number of bytes skipped so far while searching for a frame header.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_bytes
+    }
+
+    fn discard(&mut self, src: &mut BytesMut, len: usize) {
+        if len > 0 {
+            eprintln!("Discarded {len} bytes before frame header");
+            self.discarded_bytes += len as u64;
+            src.advance(len);
+        }
+    }
+}
+
+impl Decoder for RtpCodec {
+    type Item = RtpPacket;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let Some(start) = src.windows(HEADER_ID.len()).position(|w| w == HEADER_ID) else {
+                // Keep a magic that may be split across reads.
+                let partial = (1..HEADER_ID.len())
+                    .rev()
+                    .find(|&len| src.ends_with(&HEADER_ID[..len]))
+                    .unwrap_or(0);
+                self.discard(src, src.len() - partial);
+                return Ok(None);
+            };
+            self.discard(src, start);
+
+            match RtpPacket::decode(src, self.protocol_version) {
+                Ok(Some(packet)) => {
+                    self.protocol_version = Some(packet.header().protocol_version());
+                    return Ok(Some(packet));
+                }
+                Ok(None) => return Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    eprintln!("Failed to parse packet: {e}");
+                    self.discard(src, HEADER_ID.len());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let packet = self.decode(src)?;
+        if packet.is_none() {
+            // Whatever is left is a packet cut short by the end of the stream.
+            let len = src.len();
+            self.discard(src, len);
+        }
+        Ok(packet)
+    }
+}
+
+impl Encoder<RtpPacket> for RtpCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, packet: RtpPacket, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        packet.encode(dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::{DataType, PayloadType, RtpHeader, SubpacketFlag};
+
+    fn packet(serial_number: u16, protocol_version: ProtocolVersion) -> RtpPacket {
+        let header = RtpHeader::new(PayloadType::H264, "13912345678", 2, DataType::PFrame)
+            .with_marker(true)
+            .with_package_serial_number(serial_number)
+            .with_subpacket_processing_flag(SubpacketFlag::Last)
+            .with_timestamp(1040)
+            .with_frame_intervals(40, 40)
+            .with_protocol_version(protocol_version);
+        RtpPacket::new(header, vec![serial_number as u8; 100])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for protocol_version in [ProtocolVersion::V2016, ProtocolVersion::V2019] {
+            let mut codec = RtpCodec::with_protocol_version(protocol_version);
+            let mut bytes = BytesMut::new();
+            codec
+                .encode(packet(7, protocol_version), &mut bytes)
+                .unwrap();
+
+            let decoded = codec.decode(&mut bytes).unwrap().unwrap();
+            let mut expected = packet(7, protocol_version);
+            expected.header.data_body_length = 100;
+            assert!(bytes.is_empty());
+            assert_eq!(decoded, expected);
+        }
+    }
+
+    #[test]
+    fn test_decode_byte_by_byte() {
+        let mut encoded = BytesMut::new();
+        encoded.extend_from_slice(&[0x30, 0x31, 0x00]);
+        for serial_number in 0..3 {
+            packet(serial_number, ProtocolVersion::V2019)
+                .encode(&mut encoded)
+                .unwrap();
+        }
+
+        let mut codec = RtpCodec::new();
+        let mut src = BytesMut::new();
+        let mut serial_numbers = Vec::new();
+        for &bytee in encoded.iter() {
+            src.extend_from_slice(&[bytee]);
+            while let Some(packet) = codec.decode(&mut src).unwrap() {
+                serial_numbers.push(packet.header().package_serial_number());
+            }
+        }
+
+        assert_eq!(serial_numbers, vec![0, 1, 2]);
+        assert_eq!(codec.protocol_version(), Some(ProtocolVersion::V2019));
+        assert_eq!(codec.discarded_bytes(), 3);
+    }
+}
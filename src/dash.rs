@@ -0,0 +1,196 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Timescale of the segment timeline, the fMP4 video clock.
+const TIMESCALE: u64 = 90_000;
+
+/// Players reload the manifest about as often as the shortest segments
+/// are cut.
+const MINIMUM_UPDATE_PERIOD: &str = "PT1S";
+const MIN_BUFFER_TIME: &str = "PT2S";
+
+/// Entry of the segment timeline.
+pub(crate) struct DashSegment {
+    pub(crate) sequence: u64,
+    pub(crate) start_ms: u64,
+    pub(crate) duration_ms: u64,
+    pub(crate) size: usize,
+}
+
+/// Live profile MPEG-DASH manifest over the fMP4 segments of the HLS
+/// playlist: the same initialisation segment, the same segment numbers and
+/// the same window.
+pub(crate) struct DashManifest {
+    /// Wall clock time of media time zero.
+    pub(crate) availability_start: SystemTime,
+    /// RFC 6381 codecs of the multiplexed tracks.
+    pub(crate) codecs: String,
+    pub(crate) dimensions: (u32, u32),
+    /// Period of the segments, a new one after every switch of stream.
+    pub(crate) period: u64,
+    pub(crate) initialization: String,
+}
+
+impl DashManifest {
+    /// Renders the manifest for `segments`, which must be consecutive.
+    /// Once `ended`, players stop reloading it.
+    pub(crate) fn render(
+        &self,
+        segments: &[DashSegment],
+        max_segment_duration: u64,
+        ended: bool,
+        now: SystemTime,
+    ) -> String {
+        let first = segments.first();
+        let duration_ms: u64 = segments.iter().map(|s| s.duration_ms).sum();
+        let size: usize = segments.iter().map(|s| s.size).sum();
+        let bandwidth = (size as u64 * 8 * 1000 / duration_ms.max(1)).max(1);
+
+        let mut mpd = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = write!(
+            mpd,
+            "<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\" \
+            profiles=\"urn:mpeg:dash:profile:isoff-live:2011\" type=\"dynamic\" \
+            availabilityStartTime=\"{}\" publishTime=\"{}\" ",
+            iso8601(self.availability_start),
+            iso8601(now)
+        );
+        if ended {
+            let end_ms = first.map_or(0, |first| first.start_ms + duration_ms);
+            let _ = write!(mpd, "mediaPresentationDuration=\"{}\" ", duration(end_ms));
+        } else {
+            let _ = write!(mpd, "minimumUpdatePeriod=\"{MINIMUM_UPDATE_PERIOD}\" ");
+        }
+        let _ = writeln!(
+            mpd,
+            "minBufferTime=\"{MIN_BUFFER_TIME}\" timeShiftBufferDepth=\"{}\" \
+            maxSegmentDuration=\"PT{max_segment_duration}S\">",
+            duration(duration_ms)
+        );
+
+        let (width, height) = self.dimensions;
+        let _ = writeln!(
+            mpd,
+            "  <Period id=\"{}\" start=\"PT0S\">\n    \
+            <AdaptationSet id=\"0\" mimeType=\"video/mp4\" segmentAlignment=\"true\" startWithSAP=\"1\">\n      \
+            <Representation id=\"0\" codecs=\"{}\" bandwidth=\"{bandwidth}\" width=\"{width}\" height=\"{height}\">\n        \
+            <SegmentTemplate timescale=\"{TIMESCALE}\" initialization=\"{}\" \
+            media=\"$Number$.m4s\" startNumber=\"{}\">\n          \
+            <SegmentTimeline>",
+            self.period,
+            self.codecs,
+            self.initialization,
+            first.map_or(0, |first| first.sequence)
+        );
+        for (n, segment) in segments.iter().enumerate() {
+            let d = segment.duration_ms * TIMESCALE / 1000;
+            if n == 0 {
+                let t = segment.start_ms * TIMESCALE / 1000;
+                let _ = writeln!(mpd, "            <S t=\"{t}\" d=\"{d}\"/>");
+            } else {
+                let _ = writeln!(mpd, "            <S d=\"{d}\"/>");
+            }
+        }
+        mpd.push_str(
+            "          </SegmentTimeline>\n        </SegmentTemplate>\n      \
+            </Representation>\n    </AdaptationSet>\n  </Period>\n</MPD>\n",
+        );
+        mpd
+    }
+}
+
+fn duration(ms: u64) -> String {
+    format!("PT{}.{:03}S", ms / 1000, ms % 1000)
+}
+
+/// UTC date and time in the `xs:dateTime` format of the manifest.
+fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(iso8601(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn test_manifest() {
+        let segments = [
+            DashSegment {
+                sequence: 4,
+                start_ms: 4000,
+                duration_ms: 1000,
+                size: 25_000,
+            },
+            DashSegment {
+                sequence: 5,
+                start_ms: 5000,
+                duration_ms: 1500,
+                size: 50_000,
+            },
+        ];
+        let manifest = DashManifest {
+            availability_start: UNIX_EPOCH,
+            codecs: "avc1.640029,mp4a.40.2".to_string(),
+            dimensions: (1280, 720),
+            period: 0,
+            initialization: "init.mp4".to_string(),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(7);
+
+        let mpd = manifest.render(&segments, 6, false, now);
+        assert_eq!(
+            mpd,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="dynamic" availabilityStartTime="1970-01-01T00:00:00.000Z" publishTime="1970-01-01T00:00:07.000Z" minimumUpdatePeriod="PT1S" minBufferTime="PT2S" timeShiftBufferDepth="PT2.500S" maxSegmentDuration="PT6S">
+  <Period id="0" start="PT0S">
+    <AdaptationSet id="0" mimeType="video/mp4" segmentAlignment="true" startWithSAP="1">
+      <Representation id="0" codecs="avc1.640029,mp4a.40.2" bandwidth="240000" width="1280" height="720">
+        <SegmentTemplate timescale="90000" initialization="init.mp4" media="$Number$.m4s" startNumber="4">
+          <SegmentTimeline>
+            <S t="360000" d="90000"/>
+            <S d="135000"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>
+"#
+        );
+
+        let mpd = manifest.render(&segments, 6, true, now);
+        assert!(mpd.contains("mediaPresentationDuration=\"PT6.500S\""));
+        assert!(!mpd.contains("minimumUpdatePeriod"));
+    }
+}
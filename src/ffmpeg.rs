@@ -0,0 +1,171 @@
+use crate::audio::AudioCodec;
+use crate::processor::{HlsContainer, VideoCodec};
+use crate::registry::StreamKey;
+use crate::Result;
+use nix::sys::stat::Mode;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::pipe;
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// Audio chunks queued for the audio pipe before new ones are dropped.
+const AUDIO_QUEUE_SIZE: usize = 256;
+
+impl VideoCodec {
+    fn demuxer(&self) -> &'static str {
+        match self {
+            Self::H264 => "h264",
+            Self::H265 => "hevc",
+        }
+    }
+
+    /// Segments are named by their media sequence number, like those of
+    /// the native segmenter.
+    fn hls_options(&self, key: &StreamKey, container: HlsContainer) -> String {
+        let extension = container.segment_extension();
+        let segments = format!("-hls_segment_filename {key}/streams/%d.{extension}");
+        match container {
+            HlsContainer::MpegTs => segments,
+            HlsContainer::Fmp4 => format!(
+                "-tag:v {} -hls_segment_type fmp4 -hls_fmp4_init_filename init.mp4 {segments}",
+                String::from_utf8_lossy(self.sample_entry())
+            ),
+        }
+    }
+}
+
+/// An `ffmpeg` subprocess copying the video into HLS, with the decoded
+/// audio fed through a named pipe and encoded to AAC.
+pub(crate) struct FfmpegPipeline {
+    audio_task: Option<JoinHandle<()>>,
+    audio_tx: Option<mpsc::Sender<Vec<u8>>>,
+    child_stdin: Option<ChildStdin>,
+    key: StreamKey,
+    process: Child,
+}
+
+impl FfmpegPipeline {
+    /// Starts ffmpeg. `audio_offset` is the offset of the first audio frame
+    /// relative to the first video frame, in seconds, so ffmpeg can line
+    /// both inputs up on the terminal clock.
+    pub(crate) async fn start(
+        key: &StreamKey,
+        codec: VideoCodec,
+        container: HlsContainer,
+        audio: Option<AudioCodec>,
+        audio_offset: f64,
+    ) -> Result<Self> {
+        let mut inputs = format!("-re -f {} -i pipe:", codec.demuxer());
+        let mut codecs = "-c:v copy".to_string();
+        let mut audio_pipe = None;
+        if let Some(audio_codec) = audio {
+            let (input, task, tx) = Self::init_audio_pipe(key, audio_codec, audio_offset).await?;
+            inputs = format!("{inputs} {input}");
+            codecs = format!("-map 0:v -map 1:a {codecs} -c:a {}", audio_codec.encoder());
+            audio_pipe = Some((task, tx));
+        }
+
+        let arguments = format!(
+            "-hide_banner -loglevel error {inputs} \
+            {codecs} {} -hls_init_time 1 -hls_time 6 \
+            -hls_list_size 10 -hls_flags delete_segments -f hls {key}/playlist.m3u8",
+            codec.hls_options(key, container)
+        );
+
+        let arguments: Vec<&str> = arguments.split_whitespace().collect();
+        let mut process = Command::new("ffmpeg")
+            .stdin(Stdio::piped())
+            .args(&arguments)
+            .spawn()?;
+        let stdin = process.stdin.take().ok_or(std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "Failed to open stdin.",
+        ))?;
+
+        let (audio_task, audio_tx) = audio_pipe.unzip();
+        Ok(Self {
+            audio_task,
+            audio_tx,
+            child_stdin: Some(stdin),
+            key: key.clone(),
+            process,
+        })
+    }
+
+    async fn init_audio_pipe(
+        key: &StreamKey,
+        codec: AudioCodec,
+        audio_offset: f64,
+    ) -> Result<(String, JoinHandle<()>, mpsc::Sender<Vec<u8>>)> {
+        let fifo = key.dir().join("audio.fifo");
+        let _ = fs::remove_file(&fifo).await;
+        nix::unistd::mkfifo(&fifo, Mode::S_IRUSR | Mode::S_IWUSR)?;
+
+        // Opening read-write does not block until ffmpeg opens the other end.
+        let mut sender = pipe::OpenOptions::new()
+            .read_write(true)
+            .open_sender(&fifo)?;
+
+        // Audio is written from its own task so that ffmpeg reading its
+        // inputs in a different order can never stall the video pipe.
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(AUDIO_QUEUE_SIZE);
+        let task = tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                if sender.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let input = format!(
+            "-itsoffset {audio_offset:.3} {} -i {}",
+            codec.input_options(),
+            fifo.display()
+        );
+        Ok((input, task, tx))
+    }
+
+    pub(crate) async fn write_video(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(stdin) = &mut self.child_stdin {
+            stdin.write_all(data).await?;
+            stdin.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Queues decoded audio for the audio pipe.
+    pub(crate) fn write_audio(&mut self, data: Vec<u8>) {
+        let Some(tx) = &self.audio_tx else {
+            return;
+        };
+        if tx.try_send(data).is_err() {
+            eprintln!("Audio pipe is full, dropping audio frame ({})", self.key);
+        }
+    }
+
+    pub(crate) async fn stop(mut self) {
+        if let Some(mut stdin) = self.child_stdin.take() {
+            if let Err(e) = stdin.shutdown().await {
+                eprintln!("Failed to shutdown ffmpeg stdin ({}): {e}", self.key);
+            }
+        }
+
+        // Closing the queue lets the audio task drain and close the pipe.
+        self.audio_tx = None;
+
+        if let Err(e) = timeout(Duration::from_secs(10), self.process.wait()).await {
+            eprintln!("Failed to wait for ffmpeg process ({}): {e}", self.key);
+            let _ = self.process.kill().await;
+        }
+
+        if let Some(audio_task) = self.audio_task.take() {
+            audio_task.abort();
+            let _ = audio_task.await;
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use crate::audio::{adts_frames, AacConfig, AudioCodec};
+use crate::live::{FrameKind, LiveFrame, LiveSubscription};
+use crate::nal::{nal_units, NalKind, ParameterSets};
+use crate::processor::VideoCodec;
+use bytes::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use std::future::ready;
+
+/// Tag types, which are also the RTMP message types of the media.
+pub(crate) const TAG_AUDIO: u8 = 8;
+pub(crate) const TAG_VIDEO: u8 = 9;
+
+/// Length of the tag header, counted in the size that follows each tag.
+const TAG_HEADER_LENGTH: u32 = 11;
+
+const FRAME_TYPE_KEY: u8 = 1;
+const FRAME_TYPE_INTER: u8 = 2;
+
+const AVC_CODEC_ID: u8 = 7;
+const AVC_SEQUENCE_HEADER: u8 = 0;
+const AVC_NALU: u8 = 1;
+
+/// Enhanced FLV (E-RTMP) signals HEVC with a FourCC instead of a codec ID.
+const EX_HEADER: u8 = 0x80;
+const PACKET_TYPE_SEQUENCE_START: u8 = 0;
+/// Coded frames without composition time offset.
+const PACKET_TYPE_CODED_FRAMES_X: u8 = 3;
+
+/// AAC, which FLV always declares as 44 kHz, 16-bit stereo.
+const AAC_SOUND_HEADER: u8 = 0xAF;
+const AAC_SEQUENCE_HEADER: u8 = 0;
+const AAC_RAW: u8 = 1;
+
+/// FLV tag, which RTMP sends as a message of its own.
+#[derive(Debug)]
+pub(crate) struct FlvTag {
+    pub(crate) kind: u8,
+    pub(crate) time: u32,
+    pub(crate) body: Vec<u8>,
+}
+
+impl FlvTag {
+    fn write(&self, out: &mut Vec<u8>) {
+        let size = self.body.len() as u32;
+        out.push(self.kind);
+        out.extend_from_slice(&size.to_be_bytes()[1..]);
+        out.extend_from_slice(&self.time.to_be_bytes()[1..]);
+        out.push((self.time >> 24) as u8);
+        out.extend_from_slice(&[0, 0, 0]);
+        out.extend_from_slice(&self.body);
+        out.extend_from_slice(&(TAG_HEADER_LENGTH + size).to_be_bytes());
+    }
+}
+
+/// FLV muxer of a live viewer: the file header, the decoder configuration
+/// once known, then one tag per frame, starting at timestamp zero.
+pub(crate) struct FlvMuxer {
+    codec: VideoCodec,
+    audio: bool,
+    parameter_sets: ParameterSets,
+    video_configured: bool,
+    aac_config: Option<AacConfig>,
+    origin_ms: Option<u64>,
+}
+
+impl FlvMuxer {
+    pub(crate) fn new(codec: VideoCodec, audio: bool) -> Self {
+        Self {
+            codec,
+            audio,
+            parameter_sets: ParameterSets::new(codec),
+            video_configured: false,
+            aac_config: None,
+            origin_ms: None,
+        }
+    }
+
+    pub(crate) fn write_header(&self, out: &mut Vec<u8>) {
+        let flags = if self.audio { 0x05 } else { 0x01 };
+        out.extend_from_slice(&[b'F', b'L', b'V', 1, flags, 0, 0, 0, 9]);
+        out.extend_from_slice(&0u32.to_be_bytes());
+    }
+
+    pub(crate) fn write_frame(&mut self, out: &mut Vec<FlvTag>, frame: &LiveFrame) {
+        match frame.kind {
+            FrameKind::Video { keyframe } => {
+                self.write_video(out, frame.time_ms, keyframe, &frame.data)
+            }
+            FrameKind::Audio => self.write_audio(out, frame.time_ms, &frame.data),
+        }
+    }
+
+    /// Writes an Annex-B access unit. Frames before the first I-frame with
+    /// its parameter sets are dropped, as they cannot be decoded.
+    fn write_video(&mut self, out: &mut Vec<FlvTag>, time_ms: u64, keyframe: bool, data: &[u8]) {
+        let mut nals = Vec::new();
+        for nal in nal_units(data) {
+            if self.parameter_sets.update(nal)
+                || self.codec.nal_kind(nal) == NalKind::AccessUnitDelimiter
+            {
+                continue;
+            }
+            nals.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            nals.extend_from_slice(nal);
+        }
+
+        if !self.video_configured {
+            if !keyframe {
+                return;
+            }
+            let Some(configuration) = self.parameter_sets.decoder_configuration() else {
+                return;
+            };
+            let time = self.timestamp(time_ms);
+            let mut body = self.video_prefix(true, true);
+            body.extend_from_slice(&configuration);
+            out.push(FlvTag {
+                kind: TAG_VIDEO,
+                time,
+                body,
+            });
+            self.video_configured = true;
+        }
+        if nals.is_empty() {
+            return;
+        }
+
+        let time = self.timestamp(time_ms);
+        let mut body = self.video_prefix(keyframe, false);
+        body.extend_from_slice(&nals);
+        out.push(FlvTag {
+            kind: TAG_VIDEO,
+            time,
+            body,
+        });
+    }
+
+    /// Writes one or more ADTS frames, once the video has started.
+    fn write_audio(&mut self, out: &mut Vec<FlvTag>, time_ms: u64, data: &[u8]) {
+        if !self.audio || !self.video_configured {
+            return;
+        }
+        let time = self.timestamp(time_ms);
+        for (config, frame) in adts_frames(data) {
+            if self.aac_config != Some(config) {
+                let mut body = vec![AAC_SOUND_HEADER, AAC_SEQUENCE_HEADER];
+                body.extend_from_slice(&config.audio_specific_config());
+                out.push(FlvTag {
+                    kind: TAG_AUDIO,
+                    time,
+                    body,
+                });
+                self.aac_config = Some(config);
+            }
+            let mut body = vec![AAC_SOUND_HEADER, AAC_RAW];
+            body.extend_from_slice(frame);
+            out.push(FlvTag {
+                kind: TAG_AUDIO,
+                time,
+                body,
+            });
+        }
+    }
+
+    /// Video tag header up to the data.
+    fn video_prefix(&self, keyframe: bool, sequence_header: bool) -> Vec<u8> {
+        let frame_type = if keyframe {
+            FRAME_TYPE_KEY
+        } else {
+            FRAME_TYPE_INTER
+        };
+        match self.codec {
+            VideoCodec::H264 => {
+                let packet_type = if sequence_header {
+                    AVC_SEQUENCE_HEADER
+                } else {
+                    AVC_NALU
+                };
+                vec![frame_type << 4 | AVC_CODEC_ID, packet_type, 0, 0, 0]
+            }
+            VideoCodec::H265 => {
+                let packet_type = if sequence_header {
+                    PACKET_TYPE_SEQUENCE_START
+                } else {
+                    PACKET_TYPE_CODED_FRAMES_X
+                };
+                let mut prefix = vec![EX_HEADER | frame_type << 4 | packet_type];
+                prefix.extend_from_slice(self.codec.sample_entry());
+                prefix
+            }
+        }
+    }
+
+    fn timestamp(&mut self, time_ms: u64) -> u32 {
+        let origin = *self.origin_ms.get_or_insert(time_ms);
+        time_ms.saturating_sub(origin) as u32
+    }
+}
+
+/// FLV byte stream of a live viewer: the file header, then the tags of
+/// each frame from the cached GOP on.
+pub(crate) fn live_body(subscription: LiveSubscription) -> impl Stream<Item = Bytes> {
+    let tracks = subscription.tracks;
+    let mut muxer = FlvMuxer::new(tracks.video, tracks.audio == Some(AudioCodec::Aac));
+    let mut header = Vec::new();
+    muxer.write_header(&mut header);
+
+    let tags = subscription.into_stream().filter_map(move |frame| {
+        let mut tags = Vec::new();
+        muxer.write_frame(&mut tags, &frame);
+        let mut out = Vec::new();
+        for tag in &tags {
+            tag.write(&mut out);
+        }
+        ready((!out.is_empty()).then(|| Bytes::from(out)))
+    });
+    stream::once(ready(Bytes::from(header))).chain(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x29, 0xAC, 0x15, 0x6A, 0x05, 0x00, 0x5B, 0x90,
+    ];
+    const PPS: &[u8] = &[0x68, 0xEE, 0x3C, 0xB0];
+
+    /// Tag types, timestamps and first body bytes after the file header.
+    fn tags(mut data: &[u8]) -> Vec<(u8, u32, u8)> {
+        let mut tags = Vec::new();
+        while data.len() >= 11 {
+            let size = u32::from_be_bytes([0, data[1], data[2], data[3]]) as usize;
+            let time = u32::from_be_bytes([data[7], data[4], data[5], data[6]]);
+            tags.push((data[0], time, data[12]));
+            assert_eq!(
+                u32::from_be_bytes(data[11 + size..15 + size].try_into().unwrap()),
+                11 + size as u32
+            );
+            data = &data[15 + size..];
+        }
+        tags
+    }
+
+    #[test]
+    fn test_avc_tags() {
+        let mut muxer = FlvMuxer::new(VideoCodec::H264, true);
+        let mut out = Vec::new();
+        muxer.write_header(&mut out);
+        assert_eq!(&out[..5], b"FLV\x01\x05");
+
+        let adts = [0xFF, 0xF1, 0x6C, 0x40, 0x01, 0x3F, 0xFC, 0xAA, 0xBB];
+        let idr = [
+            &[0, 0, 0, 1],
+            SPS,
+            &[0, 0, 0, 1],
+            PPS,
+            &[0, 0, 0, 1, 0x65, 0x88],
+        ]
+        .concat();
+        let mut flv_tags = Vec::new();
+        muxer.write_audio(&mut flv_tags, 960, &adts);
+        muxer.write_video(&mut flv_tags, 980, false, &[0, 0, 0, 1, 0x41, 0x9A]);
+        assert!(flv_tags.is_empty());
+        muxer.write_video(&mut flv_tags, 1000, true, &idr);
+        muxer.write_audio(&mut flv_tags, 1020, &adts);
+        muxer.write_video(&mut flv_tags, 1040, false, &[0, 0, 0, 1, 0x41, 0x9A]);
+
+        let mut out = Vec::new();
+        for tag in &flv_tags {
+            tag.write(&mut out);
+        }
+        assert_eq!(
+            tags(&out),
+            [
+                (TAG_VIDEO, 0, AVC_SEQUENCE_HEADER),
+                (TAG_VIDEO, 0, AVC_NALU),
+                (TAG_AUDIO, 20, AAC_SEQUENCE_HEADER),
+                (TAG_AUDIO, 20, AAC_RAW),
+                (TAG_VIDEO, 40, AVC_NALU),
+            ]
+        );
+        assert_eq!(out[11], FRAME_TYPE_KEY << 4 | AVC_CODEC_ID);
+    }
+}
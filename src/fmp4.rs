@@ -0,0 +1,518 @@
+use crate::audio::{adts_frames, AacConfig};
+use crate::nal::{nal_units, NalKind, ParameterSets};
+use crate::processor::VideoCodec;
+
+const VIDEO_TRACK_ID: u32 = 1;
+const AUDIO_TRACK_ID: u32 = 2;
+
+/// Timescale of the video track, the same 90 kHz clock as MPEG-TS.
+const VIDEO_TIMESCALE: u64 = 90_000;
+
+/// Samples per AAC frame.
+const AAC_FRAME_LENGTH: u64 = 1024;
+
+/// Audio is realigned with the terminal clock once it runs behind by more
+/// than this, e.g. after lost audio packets.
+const MAX_AUDIO_DRIFT_MS: u64 = 100;
+
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// Identity transformation matrix of `mvhd` and `tkhd`.
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+impl VideoCodec {
+    /// Sample entry type. HEVC is tagged `hvc1` as Apple devices require,
+    /// with the parameter sets only in the sample entry.
+    pub(crate) fn sample_entry(&self) -> &'static [u8; 4] {
+        match self {
+            Self::H264 => b"avc1",
+            Self::H265 => b"hvc1",
+        }
+    }
+
+    fn configuration_box(&self) -> &'static [u8; 4] {
+        match self {
+            Self::H264 => b"avcC",
+            Self::H265 => b"hvcC",
+        }
+    }
+}
+
+struct Sample {
+    time: u64,
+    size: u32,
+    keyframe: bool,
+}
+
+#[derive(Default)]
+struct Track {
+    samples: Vec<Sample>,
+    data: Vec<u8>,
+}
+
+impl Track {
+    /// Sample durations, up to `end` for the last sample.
+    fn durations(&self, end: u64) -> impl Iterator<Item = u32> + '_ {
+        let ends = self.samples.iter().skip(1).map(|sample| sample.time);
+        self.samples
+            .iter()
+            .zip(ends.chain([end]))
+            .map(|(sample, end)| end.saturating_sub(sample.time).max(1) as u32)
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.data.clear();
+    }
+}
+
+/// Fragmented MP4 (CMAF) muxer for a video and an optional AAC track: an
+/// initialisation segment with the decoder configuration, then one
+/// `moof`/`mdat` fragment per media segment.
+pub(crate) struct Fmp4Muxer {
+    codec: VideoCodec,
+    parameter_sets: ParameterSets,
+    video: Track,
+    audio: Option<Track>,
+    aac_config: Option<AacConfig>,
+    next_audio_time: Option<u64>,
+    fragments: u32,
+}
+
+impl Fmp4Muxer {
+    pub(crate) fn new(codec: VideoCodec, audio: bool) -> Self {
+        Self {
+            codec,
+            parameter_sets: ParameterSets::new(codec),
+            video: Track::default(),
+            audio: audio.then(Track::default),
+            aac_config: None,
+            next_audio_time: None,
+            fragments: 0,
+        }
+    }
+
+    /// Buffers a video access unit in Annex-B format for the next fragment.
+    /// The parameter sets are taken out of band into the initialisation
+    /// segment.
+    pub(crate) fn write_video(&mut self, time_ms: u64, keyframe: bool, data: &[u8]) {
+        let start = self.video.data.len();
+        for nal in nal_units(data) {
+            if self.parameter_sets.update(nal)
+                || self.codec.nal_kind(nal) == NalKind::AccessUnitDelimiter
+            {
+                continue;
+            }
+            self.video
+                .data
+                .extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            self.video.data.extend_from_slice(nal);
+        }
+
+        let size = (self.video.data.len() - start) as u32;
+        if size > 0 {
+            self.video.samples.push(Sample {
+                time: time_ms * VIDEO_TIMESCALE / 1000,
+                size,
+                keyframe,
+            });
+        }
+    }
+
+    /// Buffers one or more ADTS frames starting at `time_ms`.
+    pub(crate) fn write_audio(&mut self, time_ms: u64, data: &[u8]) {
+        let Some(track) = &mut self.audio else {
+            return;
+        };
+        for (config, frame) in adts_frames(data) {
+            // The sample entry cannot change once the initialisation
+            // segment is out.
+            if *self.aac_config.get_or_insert(config) != config {
+                continue;
+            }
+            let sample_rate = config.sample_rate();
+            let time = time_ms * sample_rate / 1000;
+            let next = match self.next_audio_time {
+                Some(next) if time <= next + MAX_AUDIO_DRIFT_MS * sample_rate / 1000 => next,
+                _ => time,
+            };
+
+            track.samples.push(Sample {
+                time: next,
+                size: frame.len() as u32,
+                keyframe: true,
+            });
+            track.data.extend_from_slice(frame);
+            self.next_audio_time = Some(next + AAC_FRAME_LENGTH);
+        }
+    }
+
+    /// RFC 6381 codecs of the tracks in the initialisation segment.
+    pub(crate) fn codecs(&self) -> Option<String> {
+        let mut codecs = self.parameter_sets.codec_string()?;
+        if let (Some(_), Some(config)) = (&self.audio, self.aac_config) {
+            codecs.push_str(&format!(",mp4a.40.{}", config.object_type));
+        }
+        Some(codecs)
+    }
+
+    /// Coded picture size, once known.
+    pub(crate) fn dimensions(&self) -> Option<(u32, u32)> {
+        self.parameter_sets.dimensions()
+    }
+
+    /// Builds the initialisation segment, once the parameter sets and, if
+    /// there is an audio track, the AAC configuration are known. An audio
+    /// track still without configuration is left out.
+    pub(crate) fn init_segment(&mut self) -> Option<Vec<u8>> {
+        let configuration = self.parameter_sets.decoder_configuration()?;
+        let (width, height) = self.parameter_sets.dimensions().unwrap_or_default();
+        if self.aac_config.is_none() {
+            self.audio = None;
+        }
+
+        let mut out = Vec::new();
+        write_box(&mut out, b"ftyp", |out| {
+            out.extend_from_slice(b"iso6");
+            out.extend_from_slice(&0u32.to_be_bytes());
+            for brand in [b"iso6", b"cmfc", b"mp41"] {
+                out.extend_from_slice(brand);
+            }
+        });
+        write_box(&mut out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                write_u32s(out, &[0, 0, 1000, 0, 0x0001_0000]);
+                out.extend_from_slice(&[0x01, 0x00]);
+                out.extend_from_slice(&[0; 10]);
+                write_u32s(out, &MATRIX);
+                out.extend_from_slice(&[0; 24]);
+                write_u32s(out, &[AUDIO_TRACK_ID + 1]);
+            });
+            write_track(
+                out,
+                VIDEO_TRACK_ID,
+                VIDEO_TIMESCALE as u32,
+                (width, height),
+                |out| {
+                    write_box(out, self.codec.sample_entry(), |out| {
+                        out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+                        out.extend_from_slice(&[0; 16]);
+                        out.extend_from_slice(&(width as u16).to_be_bytes());
+                        out.extend_from_slice(&(height as u16).to_be_bytes());
+                        write_u32s(out, &[0x0048_0000, 0x0048_0000, 0]);
+                        out.extend_from_slice(&[0x00, 0x01]);
+                        out.extend_from_slice(&[0; 32]);
+                        out.extend_from_slice(&[0x00, 0x18, 0xFF, 0xFF]);
+                        write_box(out, self.codec.configuration_box(), |out| {
+                            out.extend_from_slice(&configuration)
+                        });
+                    });
+                },
+            );
+            if let (Some(_), Some(config)) = (&self.audio, self.aac_config) {
+                let sample_rate = config.sample_rate() as u32;
+                write_track(out, AUDIO_TRACK_ID, sample_rate, (0, 0), |out| {
+                    write_box(out, b"mp4a", |out| {
+                        out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+                        out.extend_from_slice(&[0; 8]);
+                        out.extend_from_slice(&[0x00, config.channels, 0x00, 0x10]);
+                        write_u32s(out, &[0, sample_rate.min(0xFFFF) << 16]);
+                        write_full_box(out, b"esds", 0, 0, |out| write_es_descriptor(out, config));
+                    });
+                });
+            }
+            write_box(out, b"mvex", |out| {
+                for track_id in [VIDEO_TRACK_ID, AUDIO_TRACK_ID] {
+                    if track_id == AUDIO_TRACK_ID && self.audio.is_none() {
+                        continue;
+                    }
+                    write_full_box(out, b"trex", 0, 0, |out| {
+                        write_u32s(out, &[track_id, 1, 0, 0, 0]);
+                    });
+                }
+            });
+        });
+        Some(out)
+    }
+
+    /// Writes the samples buffered since the last fragment as a new
+    /// fragment, the video lasting until `end_ms`. Only valid once the
+    /// initialisation segment was built.
+    pub(crate) fn write_fragment(&mut self, out: &mut Vec<u8>, end_ms: u64) {
+        let video_end = end_ms * VIDEO_TIMESCALE / 1000;
+        let mut tracks = vec![(VIDEO_TRACK_ID, &self.video, video_end)];
+        if let Some(audio) = &self.audio {
+            let end = audio
+                .samples
+                .last()
+                .map_or(0, |s| s.time + AAC_FRAME_LENGTH);
+            tracks.push((AUDIO_TRACK_ID, audio, end));
+        }
+        tracks.retain(|(_, track, _)| !track.samples.is_empty());
+        if tracks.is_empty() {
+            return;
+        }
+
+        self.fragments += 1;
+        let moof_start = out.len();
+        let mut data_offsets = Vec::new();
+        write_box(out, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| {
+                write_u32s(out, &[self.fragments]);
+            });
+            for (track_id, track, end) in &tracks {
+                write_box(out, b"traf", |out| {
+                    // default-base-is-moof
+                    write_full_box(out, b"tfhd", 0, 0x02_0000, |out| {
+                        write_u32s(out, &[*track_id]);
+                    });
+                    write_full_box(out, b"tfdt", 1, 0, |out| {
+                        out.extend_from_slice(&track.samples[0].time.to_be_bytes());
+                    });
+                    // data-offset, sample-duration, sample-size, sample-flags
+                    write_full_box(out, b"trun", 0, 0x0701, |out| {
+                        write_u32s(out, &[track.samples.len() as u32]);
+                        data_offsets.push(out.len());
+                        write_u32s(out, &[0]);
+                        for (sample, duration) in track.samples.iter().zip(track.durations(*end)) {
+                            let flags = if sample.keyframe {
+                                SYNC_SAMPLE_FLAGS
+                            } else {
+                                NON_SYNC_SAMPLE_FLAGS
+                            };
+                            write_u32s(out, &[duration, sample.size, flags]);
+                        }
+                    });
+                });
+            }
+        });
+
+        // Sample data of each track follows the mdat header in track order.
+        let mut offset = (out.len() - moof_start + 8) as u32;
+        for ((_, track, _), position) in tracks.iter().zip(data_offsets) {
+            out[position..position + 4].copy_from_slice(&offset.to_be_bytes());
+            offset += track.data.len() as u32;
+        }
+        write_box(out, b"mdat", |out| {
+            for (_, track, _) in &tracks {
+                out.extend_from_slice(&track.data);
+            }
+        });
+
+        self.discard();
+    }
+
+    /// Drops the samples buffered for the next fragment.
+    pub(crate) fn discard(&mut self) {
+        self.video.clear();
+        if let Some(audio) = &mut self.audio {
+            audio.clear();
+        }
+    }
+}
+
+fn write_u32s(out: &mut Vec<u8>, values: &[u32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], content: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    content(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    content: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        write_u32s(out, &[(version as u32) << 24 | flags]);
+        content(out);
+    });
+}
+
+/// Writes a `trak` without samples, which are all in the fragments.
+fn write_track(
+    out: &mut Vec<u8>,
+    track_id: u32,
+    timescale: u32,
+    (width, height): (u32, u32),
+    sample_entry: impl FnOnce(&mut Vec<u8>),
+) {
+    let video = track_id == VIDEO_TRACK_ID;
+    write_box(out, b"trak", |out| {
+        // Track enabled and in the movie.
+        write_full_box(out, b"tkhd", 0, 0x03, |out| {
+            write_u32s(out, &[0, 0, track_id, 0, 0, 0, 0, 0]);
+            let volume: u16 = if video { 0 } else { 0x0100 };
+            out.extend_from_slice(&volume.to_be_bytes());
+            out.extend_from_slice(&[0, 0]);
+            write_u32s(out, &MATRIX);
+            write_u32s(out, &[width << 16, height << 16]);
+        });
+        write_box(out, b"mdia", |out| {
+            write_full_box(out, b"mdhd", 0, 0, |out| {
+                write_u32s(out, &[0, 0, timescale, 0]);
+                // Undetermined language.
+                out.extend_from_slice(&[0x55, 0xC4, 0x00, 0x00]);
+            });
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                write_u32s(out, &[0]);
+                out.extend_from_slice(if video { b"vide" } else { b"soun" });
+                out.extend_from_slice(&[0; 12]);
+                let name: &[u8] = if video {
+                    b"VideoHandler\0"
+                } else {
+                    b"SoundHandler\0"
+                };
+                out.extend_from_slice(name);
+            });
+            write_box(out, b"minf", |out| {
+                if video {
+                    write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                } else {
+                    write_full_box(out, b"smhd", 0, 0, |out| out.extend_from_slice(&[0; 4]));
+                }
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        write_u32s(out, &[1]);
+                        // Media data in the same file.
+                        write_full_box(out, b"url ", 0, 1, |_| ());
+                    });
+                });
+                write_box(out, b"stbl", |out| {
+                    write_full_box(out, b"stsd", 0, 0, |out| {
+                        write_u32s(out, &[1]);
+                        sample_entry(out);
+                    });
+                    write_full_box(out, b"stts", 0, 0, |out| write_u32s(out, &[0]));
+                    write_full_box(out, b"stsc", 0, 0, |out| write_u32s(out, &[0]));
+                    write_full_box(out, b"stsz", 0, 0, |out| write_u32s(out, &[0, 0]));
+                    write_full_box(out, b"stco", 0, 0, |out| write_u32s(out, &[0]));
+                });
+            });
+        });
+    });
+}
+
+/// `ES_Descriptor` of ISO/IEC 14496-1 for AAC.
+fn write_es_descriptor(out: &mut Vec<u8>, config: AacConfig) {
+    let audio_specific_config = config.audio_specific_config();
+    let decoder_specific_info = [&[0x05, 0x02][..], &audio_specific_config].concat();
+    let mut decoder_config = vec![0x04, 13 + decoder_specific_info.len() as u8];
+    // MPEG-4 audio stream without buffer size or bitrates.
+    decoder_config.extend_from_slice(&[0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    decoder_config.extend_from_slice(&decoder_specific_info);
+    // SLConfigDescriptor with the predefined MP4 configuration.
+    let sl_config = [0x06, 0x01, 0x02];
+
+    out.extend_from_slice(&[0x03, (3 + decoder_config.len() + sl_config.len()) as u8]);
+    out.extend_from_slice(&[0x00, AUDIO_TRACK_ID as u8, 0x00]);
+    out.extend_from_slice(&decoder_config);
+    out.extend_from_slice(&sl_config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Top level boxes as (type, content) pairs.
+    fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        while data.len() >= 8 {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            boxes.push((data[4..8].try_into().unwrap(), &data[8..size]));
+            data = &data[size..];
+        }
+        assert!(data.is_empty());
+        boxes
+    }
+
+    fn muxer_with_frame() -> Fmp4Muxer {
+        let mut muxer = Fmp4Muxer::new(VideoCodec::H264, true);
+        let keyframe = [
+            &[0x00, 0x00, 0x00, 0x01, 0x09, 0xF0][..],
+            &[
+                0x00, 0x00, 0x00, 0x01, 0x67, 0x64, 0x00, 0x29, 0xAC, 0x15, 0x6A,
+            ],
+            &[
+                0x05, 0x00, 0x5B, 0x90, 0x00, 0x00, 0x00, 0x01, 0x68, 0xEE, 0x3C, 0xB0,
+            ],
+            &[0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x80],
+        ]
+        .concat();
+        muxer.write_video(0, true, &keyframe);
+        muxer.write_video(40, false, &[0x00, 0x00, 0x01, 0x41, 0x9A, 0x02]);
+        // Two AAC-LC frames at 8 kHz, mono.
+        let adts = [0xFF, 0xF1, 0x6C, 0x40, 0x01, 0x3F, 0xFC, 0xAA, 0xBB];
+        muxer.write_audio(20, &[adts, adts].concat());
+        muxer
+    }
+
+    #[test]
+    fn test_init_segment() {
+        let mut muxer = muxer_with_frame();
+        let init = muxer.init_segment().unwrap();
+
+        let top = boxes(&init);
+        assert_eq!(
+            top.iter().map(|b| &b.0).collect::<Vec<_>>(),
+            [b"ftyp", b"moov"]
+        );
+        let moov = boxes(top[1].1);
+        let types: Vec<_> = moov.iter().map(|b| &b.0).collect();
+        assert_eq!(types, [b"mvhd", b"trak", b"trak", b"mvex"]);
+        assert!(init.windows(4).any(|w| w == b"avcC"));
+        // AudioSpecificConfig of AAC-LC, 8 kHz, mono.
+        assert!(init.windows(4).any(|w| w == [0x05, 0x02, 0x15, 0x88]));
+    }
+
+    #[test]
+    fn test_fragment() {
+        let mut muxer = muxer_with_frame();
+        muxer.init_segment().unwrap();
+        let mut out = Vec::new();
+        muxer.write_fragment(&mut out, 80);
+
+        let top = boxes(&out);
+        assert_eq!(
+            top.iter().map(|b| &b.0).collect::<Vec<_>>(),
+            [b"moof", b"mdat"]
+        );
+        // Parameter sets and the delimiter are left out of the samples.
+        let mdat = top[1].1;
+        assert_eq!(
+            mdat,
+            [
+                &[0x00, 0x00, 0x00, 0x03, 0x65, 0x88, 0x80][..],
+                &[0x00, 0x00, 0x00, 0x03, 0x41, 0x9A, 0x02],
+                &[0xAA, 0xBB, 0xAA, 0xBB],
+            ]
+            .concat()
+        );
+
+        let moof = boxes(top[0].1);
+        assert_eq!(moof[0], (*b"mfhd", &[0, 0, 0, 0, 0, 0, 0, 1][..]));
+        let video = boxes(moof[1].1);
+        let trun = video[2].1;
+        // Two samples of 3600 ticks whose data starts right after the mdat
+        // header.
+        assert_eq!(trun[4..8], 2u32.to_be_bytes());
+        let data_offset = u32::from_be_bytes(trun[8..12].try_into().unwrap()) as usize;
+        assert_eq!(data_offset, top[0].1.len() + 16);
+        assert_eq!(trun[12..16], 3600u32.to_be_bytes());
+        assert_eq!(trun[24..28], 3600u32.to_be_bytes());
+
+        let audio = boxes(moof[2].1);
+        assert_eq!(audio[1].1[4..], 160u64.to_be_bytes());
+    }
+}
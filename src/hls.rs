@@ -0,0 +1,851 @@
+use crate::dash::{DashManifest, DashSegment};
+use crate::fmp4::Fmp4Muxer;
+use crate::mpegts::TsMuxer;
+use crate::processor::{HlsContainer, VideoCodec};
+use crate::rtp::{DataType, RtpHeader};
+use crate::Result;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::sync::watch;
+
+/// Segments are cut on the first I-frame after this much media, like
+/// ffmpeg's `-hls_init_time 1 -hls_time 6`: short segments until the first
+/// playlist is full for a quick start, longer ones afterwards.
+const INIT_SEGMENT_DURATION_MS: u64 = 1000;
+const SEGMENT_DURATION_MS: u64 = 6000;
+
+/// Target duration advertised in the playlist. Longer GOPs are cut on a
+/// P-frame so that no segment exceeds it.
+const TARGET_DURATION: u64 = SEGMENT_DURATION_MS / 1000;
+
+/// How long the files of an ended stream are kept, for players to load
+/// the end of the playlist before they go.
+pub(crate) const CLEAN_UP_DELAY: Duration = Duration::from_millis(SEGMENT_DURATION_MS);
+
+/// Number of segments listed in the playlist.
+const PLAYLIST_LENGTH: usize = 10;
+
+/// Segments that left the playlist are kept a little longer for clients
+/// that are still downloading them.
+const DELETE_THRESHOLD: usize = 1;
+
+/// Low-latency mode splits the segments into parts of at most this length,
+/// which clients can load while the segment is still being written.
+const PART_TARGET_MS: u64 = 300;
+
+/// Parts are listed for the segment in progress and the last complete one.
+const PART_SEGMENTS: usize = 1;
+
+/// How long a blocking playlist reload may wait, three target durations
+/// as recommended by the LL-HLS specification.
+pub(crate) const BLOCKING_RELOAD_TIMEOUT: Duration = Duration::from_millis(3 * SEGMENT_DURATION_MS);
+
+pub(crate) const DEFAULT_FRAME_INTERVAL_MS: u64 = 40;
+
+/// Timestamp jumps bigger than this are treated as a reset of the terminal
+/// clock rather than as lost frames.
+const MAX_TIMESTAMP_JUMP_MS: u64 = 5000;
+
+/// Offset of the first frame on the MPEG-TS clock, keeping early audio
+/// clear of a negative timestamp.
+const PTS_OFFSET_MS: u64 = 1000;
+
+struct Part {
+    duration_ms: u64,
+    independent: bool,
+}
+
+struct Segment {
+    sequence: u64,
+    start_ms: u64,
+    duration_ms: u64,
+    size: usize,
+    parts: Vec<Part>,
+    /// Whether the segment starts a new period, after a switch of stream.
+    discontinuity: bool,
+    period: u64,
+}
+
+struct OpenSegment {
+    sequence: u64,
+    start_ms: u64,
+    discontinuity: bool,
+    period: u64,
+    data: Vec<u8>,
+    parts: Vec<Part>,
+    /// Start of the part in progress, in `data` and on the media clock.
+    part_offset: usize,
+    part_start_ms: u64,
+    /// Whether the part in progress starts with an I-frame, once it has a
+    /// frame.
+    part_independent: Option<bool>,
+}
+
+/// How far a low-latency playlist has got: the segment being written and
+/// how many of its parts are out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct PlaylistProgress {
+    pub(crate) sequence: u64,
+    pub(crate) parts: u64,
+}
+
+impl PlaylistProgress {
+    /// Whether the playlist lists segment `msn`, or its part `part`.
+    pub(crate) fn contains(&self, msn: u64, part: Option<u64>) -> bool {
+        match part {
+            Some(part) => msn < self.sequence || (msn == self.sequence && part < self.parts),
+            None => msn < self.sequence,
+        }
+    }
+}
+
+enum Muxer {
+    MpegTs(TsMuxer),
+    Fmp4(Box<Fmp4Muxer>),
+}
+
+impl Muxer {
+    fn new(container: HlsContainer, codec: VideoCodec, audio: bool) -> Self {
+        match container {
+            HlsContainer::MpegTs => Self::MpegTs(TsMuxer::new(codec, audio)),
+            HlsContainer::Fmp4 => Self::Fmp4(Box::new(Fmp4Muxer::new(codec, audio))),
+        }
+    }
+}
+
+/// Maps the terminal timestamps onto a continuous media clock, starting at
+/// zero with the first video frame.
+#[derive(Default)]
+pub(crate) struct Clock {
+    origin: Option<i64>,
+    last_video_ms: Option<u64>,
+}
+
+impl Clock {
+    pub(crate) fn video(&mut self, timestamp: Option<u64>, interval_ms: u64) -> u64 {
+        let expected = self.last_video_ms.map_or(0, |last| last + interval_ms);
+        let time = match (timestamp, self.origin) {
+            (Some(timestamp), Some(origin)) => {
+                let time = timestamp as i64 - origin;
+                if time < 0 || time.abs_diff(expected as i64) > MAX_TIMESTAMP_JUMP_MS {
+                    self.origin = Some(timestamp as i64 - expected as i64);
+                    expected
+                } else {
+                    time as u64
+                }
+            }
+            (Some(timestamp), None) => {
+                self.origin = Some(timestamp as i64);
+                0
+            }
+            (None, _) => expected,
+        };
+        self.last_video_ms = Some(time);
+        time
+    }
+
+    pub(crate) fn audio(&self, timestamp: Option<u64>) -> Option<u64> {
+        let time = timestamp? as i64 - self.origin?;
+        Some(time.max(0) as u64)
+    }
+}
+
+/// In-process HLS writer: muxes the reassembled frames into MPEG-TS or
+/// fMP4 segments under `streams/` and keeps `playlist.m3u8` up to date, in
+/// the same layout as the ffmpeg backend: segments are named by their media
+/// sequence number, `0.ts`, `1.ts` and so on.
+///
+/// A switch between the main and the sub stream starts a new period: the
+/// next segment follows an `EXT-X-DISCONTINUITY` and, for fMP4, has its
+/// own initialisation segment, `init-<period>.mp4`.
+pub(crate) struct HlsSegmenter {
+    audio: bool,
+    /// Wall clock time of media time zero, for the DASH manifest.
+    availability_start: Option<SystemTime>,
+    clock: Clock,
+    codec: VideoCodec,
+    container: HlsContainer,
+    current: Option<OpenSegment>,
+    dash: bool,
+    dir: PathBuf,
+    /// Whether the next segment starts a new period.
+    discontinuity: bool,
+    /// Discontinuities that left the playlist.
+    discontinuity_sequence: u64,
+    expired: VecDeque<Segment>,
+    frame_interval_ms: u64,
+    initialised: bool,
+    low_latency: bool,
+    muxer: Muxer,
+    next_sequence: u64,
+    period: u64,
+    progress: Option<watch::Sender<PlaylistProgress>>,
+    segments: VecDeque<Segment>,
+    /// Whether a switch of stream waits for its first I-frame.
+    switch_pending: bool,
+}
+
+impl HlsSegmenter {
+    /// Creates a segmenter writing into `dir`, which must already contain
+    /// the `streams` directory.
+    pub(crate) fn new(dir: &Path, codec: VideoCodec, audio: bool, container: HlsContainer) -> Self {
+        Self {
+            audio,
+            availability_start: None,
+            clock: Clock::default(),
+            codec,
+            container,
+            current: None,
+            dash: false,
+            dir: dir.to_path_buf(),
+            discontinuity: false,
+            discontinuity_sequence: 0,
+            expired: VecDeque::new(),
+            frame_interval_ms: DEFAULT_FRAME_INTERVAL_MS,
+            initialised: false,
+            low_latency: false,
+            muxer: Muxer::new(container, codec, audio),
+            next_sequence: 0,
+            period: 0,
+            progress: None,
+            segments: VecDeque::new(),
+            switch_pending: false,
+        }
+    }
+
+    /// Writes an LL-HLS playlist with partial segments.
+    pub(crate) fn with_low_latency(mut self) -> Self {
+        self.low_latency = true;
+        self
+    }
+
+    /// Also writes `manifest.mpd`, an MPEG-DASH manifest over the same
+    /// segments. Only fMP4 segments can be shared with DASH.
+    pub(crate) fn with_dash(mut self) -> Self {
+        self.dash = self.container == HlsContainer::Fmp4;
+        self
+    }
+
+    /// Reports every playlist update to `progress`.
+    pub(crate) fn with_progress(mut self, progress: watch::Sender<PlaylistProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub(crate) async fn write_video(&mut self, header: &RtpHeader, data: &[u8]) -> Result<()> {
+        if let Some(interval) = header.last_frame_interval.filter(|&interval| interval > 0) {
+            self.frame_interval_ms = interval as u64;
+        }
+        let time = self.clock.video(header.timestamp, self.frame_interval_ms);
+        let keyframe = header.data_type == DataType::IFrame;
+
+        let target = if (self.next_sequence as usize) < PLAYLIST_LENGTH {
+            INIT_SEGMENT_DURATION_MS
+        } else {
+            SEGMENT_DURATION_MS
+        };
+        let switch = keyframe && self.switch_pending;
+        let cut = switch
+            || match &self.current {
+                None => keyframe,
+                Some(segment) => {
+                    let elapsed = time.saturating_sub(segment.start_ms);
+                    (keyframe && elapsed >= target)
+                        || elapsed + self.frame_interval_ms > SEGMENT_DURATION_MS
+                }
+            };
+        if cut {
+            self.close_segment(time, false).await?;
+            if switch {
+                self.start_period();
+            }
+            self.open_segment(time);
+        }
+
+        // Ends the part before this frame would take it past the target.
+        let part_full = self.current.as_ref().is_some_and(|segment| {
+            time > segment.part_start_ms
+                && time + self.frame_interval_ms - segment.part_start_ms > PART_TARGET_MS
+        });
+        if self.low_latency && part_full {
+            self.close_part(time).await?;
+        }
+
+        // Frames before the first I-frame cannot be decoded.
+        let Some(segment) = &mut self.current else {
+            return Ok(());
+        };
+        segment.part_independent.get_or_insert(keyframe);
+        match &mut self.muxer {
+            Muxer::MpegTs(muxer) => muxer.write_video(&mut segment.data, pts(time), keyframe, data),
+            Muxer::Fmp4(muxer) => muxer.write_video(time, keyframe, data),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_audio(&mut self, timestamp: Option<u64>, data: &[u8]) {
+        let (Some(segment), Some(time)) = (&mut self.current, self.clock.audio(timestamp)) else {
+            return;
+        };
+        match &mut self.muxer {
+            Muxer::MpegTs(muxer) => muxer.write_audio(&mut segment.data, pts(time), data),
+            Muxer::Fmp4(muxer) => muxer.write_audio(time, data),
+        }
+    }
+
+    /// Writes out the segment in progress, as when the terminal pauses the
+    /// transfer. The next segment starts on an I-frame.
+    pub(crate) async fn cut(&mut self) -> Result<()> {
+        self.close_segment(self.end_ms(), false).await
+    }
+
+    /// Starts a new period on the next I-frame, which comes from another
+    /// stream of the terminal with possibly other parameter sets.
+    pub(crate) fn switch(&mut self) {
+        self.switch_pending = true;
+    }
+
+    /// Writes out the segment in progress and ends the playlist.
+    pub(crate) async fn finish(&mut self) -> Result<()> {
+        self.close_segment(self.end_ms(), true).await
+    }
+
+    /// End of the last video frame on the media clock.
+    fn end_ms(&self) -> u64 {
+        self.clock.last_video_ms.unwrap_or_default() + self.frame_interval_ms
+    }
+
+    fn start_period(&mut self) {
+        self.switch_pending = false;
+        self.discontinuity = self.next_sequence > 0;
+        self.period += 1;
+        self.muxer = Muxer::new(self.container, self.codec, self.audio);
+        self.initialised = false;
+    }
+
+    fn open_segment(&mut self, start_ms: u64) {
+        self.availability_start
+            .get_or_insert_with(|| SystemTime::now() - Duration::from_millis(start_ms));
+        let mut data = Vec::new();
+        if let Muxer::MpegTs(muxer) = &mut self.muxer {
+            muxer.write_tables(&mut data);
+        }
+        self.current = Some(OpenSegment {
+            sequence: self.next_sequence,
+            start_ms,
+            discontinuity: std::mem::take(&mut self.discontinuity),
+            period: self.period,
+            data,
+            parts: Vec::new(),
+            part_offset: 0,
+            part_start_ms: start_ms,
+            part_independent: None,
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Moves the fMP4 samples buffered since the last fragment into the
+    /// segment, writing the initialisation segment first if needed. Returns
+    /// `false` if there are no parameter sets yet and the samples were
+    /// dropped.
+    async fn write_fragment(&mut self, end_ms: u64) -> Result<bool> {
+        let (Muxer::Fmp4(muxer), Some(segment)) = (&mut self.muxer, &mut self.current) else {
+            return Ok(true);
+        };
+        if !self.initialised {
+            let Some(init) = muxer.init_segment() else {
+                muxer.discard();
+                return Ok(false);
+            };
+            write_atomic(&self.dir.join(init_name(self.period)), &init).await?;
+            self.initialised = true;
+        }
+        muxer.write_fragment(&mut segment.data, end_ms);
+        Ok(true)
+    }
+
+    async fn close_part(&mut self, end_ms: u64) -> Result<()> {
+        if !self.write_fragment(end_ms).await? {
+            eprintln!(
+                "No parameter sets yet, dropping part ({})",
+                self.dir.display()
+            );
+        }
+        let Some(segment) = &self.current else {
+            return Ok(());
+        };
+        let path = self.part_path(segment.sequence, segment.parts.len());
+        let Some(segment) = &mut self.current else {
+            return Ok(());
+        };
+        let data = &segment.data[segment.part_offset..];
+        if !data.is_empty() {
+            write_atomic(&path, data).await?;
+            segment.parts.push(Part {
+                duration_ms: end_ms.saturating_sub(segment.part_start_ms),
+                independent: segment.part_independent.unwrap_or(false),
+            });
+        }
+        segment.part_offset = segment.data.len();
+        segment.part_start_ms = end_ms;
+        segment.part_independent = None;
+
+        self.write_playlist(false).await
+    }
+
+    async fn close_segment(&mut self, end_ms: u64, end_list: bool) -> Result<()> {
+        if self.current.is_none() {
+            return Ok(());
+        }
+        if self.low_latency {
+            self.close_part(end_ms).await?;
+        } else if !self.write_fragment(end_ms).await? {
+            eprintln!(
+                "No parameter sets yet, dropping segment ({})",
+                self.dir.display()
+            );
+            self.current = None;
+            return Ok(());
+        }
+        let Some(segment) = self.current.take() else {
+            return Ok(());
+        };
+
+        write_atomic(&self.segment_path(segment.sequence), &segment.data).await?;
+
+        // A timestamp jump may end the segment past the target duration,
+        // the gap is left out.
+        let duration_ms = end_ms
+            .saturating_sub(segment.start_ms)
+            .min(SEGMENT_DURATION_MS);
+        self.segments.push_back(Segment {
+            sequence: segment.sequence,
+            start_ms: segment.start_ms,
+            duration_ms,
+            size: segment.data.len(),
+            parts: segment.parts,
+            discontinuity: segment.discontinuity,
+            period: segment.period,
+        });
+        while self.segments.len() > PLAYLIST_LENGTH {
+            if let Some(segment) = self.segments.pop_front() {
+                if segment.discontinuity {
+                    self.discontinuity_sequence += 1;
+                }
+                self.expired.push_back(segment);
+            }
+        }
+
+        self.write_playlist(end_list).await?;
+        if self.dash {
+            self.write_manifest(end_list).await?;
+        }
+
+        while self.expired.len() > DELETE_THRESHOLD {
+            if let Some(segment) = self.expired.pop_front() {
+                let _ = fs::remove_file(self.segment_path(segment.sequence)).await;
+                for part in 0..segment.parts.len() {
+                    let _ = fs::remove_file(self.part_path(segment.sequence, part)).await;
+                }
+                // The initialisation segment goes with the last segment of
+                // its period.
+                let oldest = self.expired.front().or(self.segments.front());
+                if oldest.map_or(self.period, |s| s.period) > segment.period {
+                    let _ = fs::remove_file(self.dir.join(init_name(segment.period))).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_playlist(&self, end_list: bool) -> Result<()> {
+        write_atomic(
+            &self.dir.join("playlist.m3u8"),
+            self.playlist(end_list).as_bytes(),
+        )
+        .await?;
+
+        if let Some(progress) = &self.progress {
+            progress.send_replace(PlaylistProgress {
+                sequence: self
+                    .current
+                    .as_ref()
+                    .map_or(self.next_sequence, |s| s.sequence),
+                parts: self.current.as_ref().map_or(0, |s| s.parts.len() as u64),
+            });
+        }
+        Ok(())
+    }
+
+    async fn write_manifest(&self, ended: bool) -> Result<()> {
+        let (Muxer::Fmp4(muxer), Some(availability_start)) = (&self.muxer, self.availability_start)
+        else {
+            return Ok(());
+        };
+        let Some(codecs) = muxer.codecs() else {
+            return Ok(());
+        };
+        let manifest = DashManifest {
+            availability_start,
+            codecs,
+            dimensions: muxer.dimensions().unwrap_or_default(),
+            period: self.period,
+            initialization: init_name(self.period),
+        };
+        // Only the segments of the current period, those of the stream
+        // before a switch need other parameters.
+        let segments: Vec<DashSegment> = self
+            .segments
+            .iter()
+            .filter(|segment| segment.period == self.period)
+            .map(|segment| DashSegment {
+                sequence: segment.sequence,
+                start_ms: segment.start_ms,
+                duration_ms: segment.duration_ms,
+                size: segment.size,
+            })
+            .collect();
+        let mpd = manifest.render(&segments, TARGET_DURATION, ended, SystemTime::now());
+        write_atomic(&self.dir.join("manifest.mpd"), mpd.as_bytes()).await
+    }
+
+    fn segment_path(&self, sequence: u64) -> PathBuf {
+        let extension = self.container.segment_extension();
+        self.dir
+            .join("streams")
+            .join(format!("{sequence}.{extension}"))
+    }
+
+    fn part_name(&self, sequence: u64, part: usize) -> String {
+        format!("{sequence}.{part}.{}", self.container.segment_extension())
+    }
+
+    fn part_path(&self, sequence: u64, part: usize) -> PathBuf {
+        self.dir
+            .join("streams")
+            .join(self.part_name(sequence, part))
+    }
+
+    fn playlist(&self, end_list: bool) -> String {
+        let media_sequence = self.segments.front().map_or(0, |s| s.sequence);
+        // EXT-X-MAP outside of I-frame playlists needs version 6.
+        let version = match self.container {
+            HlsContainer::MpegTs => 3,
+            HlsContainer::Fmp4 => 7,
+        };
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:{version}\n#EXT-X-TARGETDURATION:{TARGET_DURATION}\n#EXT-X-MEDIA-SEQUENCE:{media_sequence}\n"
+        );
+        if self.low_latency {
+            let _ = write!(
+                playlist,
+                "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}\n\
+                #EXT-X-PART-INF:PART-TARGET={:.3}\n",
+                3.0 * PART_TARGET_MS as f64 / 1000.0,
+                PART_TARGET_MS as f64 / 1000.0
+            );
+        }
+        if self.discontinuity_sequence > 0 {
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+                self.discontinuity_sequence
+            );
+        }
+        if self.container == HlsContainer::Fmp4 {
+            let period = self.segments.front().map_or(self.period, |s| s.period);
+            let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"{}\"", init_name(period));
+        }
+        let extension = self.container.segment_extension();
+        let with_parts = self.segments.len().saturating_sub(PART_SEGMENTS);
+        for (i, segment) in self.segments.iter().enumerate() {
+            if segment.discontinuity {
+                self.write_discontinuity(&mut playlist, segment.period, i > 0);
+            }
+            if self.low_latency && i >= with_parts {
+                self.write_parts(&mut playlist, segment.sequence, &segment.parts);
+            }
+            let _ = write!(
+                playlist,
+                "#EXTINF:{:.3},\n{}.{extension}\n",
+                segment.duration_ms as f64 / 1000.0,
+                segment.sequence
+            );
+        }
+        if end_list {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        } else if let Some(segment) = self.current.as_ref().filter(|_| self.low_latency) {
+            if segment.discontinuity {
+                self.write_discontinuity(&mut playlist, segment.period, !self.segments.is_empty());
+            }
+            self.write_parts(&mut playlist, segment.sequence, &segment.parts);
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"",
+                self.part_name(segment.sequence, segment.parts.len())
+            );
+        }
+        playlist
+    }
+
+    /// Starts `period` in the playlist, with its initialisation segment
+    /// unless `map` is false because the playlist starts with it.
+    fn write_discontinuity(&self, playlist: &mut String, period: u64, map: bool) {
+        playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        if map && self.container == HlsContainer::Fmp4 {
+            let _ = writeln!(playlist, "#EXT-X-MAP:URI=\"{}\"", init_name(period));
+        }
+    }
+
+    fn write_parts(&self, playlist: &mut String, sequence: u64, parts: &[Part]) {
+        for (n, part) in parts.iter().enumerate() {
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PART:DURATION={:.3},URI=\"{}\"{}",
+                part.duration_ms as f64 / 1000.0,
+                self.part_name(sequence, n),
+                if part.independent {
+                    ",INDEPENDENT=YES"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+/// Name of the fMP4 initialisation segment of `period`.
+fn init_name(period: u64) -> String {
+    match period {
+        0 => "init.mp4".to_string(),
+        period => format!("init-{period}.mp4"),
+    }
+}
+
+fn pts(time_ms: u64) -> u64 {
+    (time_ms + PTS_OFFSET_MS) * 90
+}
+
+/// Writes through a temporary file so the web server never serves a
+/// partially written playlist or segment.
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::PayloadType;
+
+    #[tokio::test]
+    async fn test_segments_cut_on_i_frames() {
+        let dir = std::env::temp_dir().join(format!("hls-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("streams")).await.unwrap();
+
+        let mut segmenter = HlsSegmenter::new(&dir, VideoCodec::H264, false, HlsContainer::MpegTs);
+        // 20 ms frames with an I-frame every 500 ms, starting on a P-frame.
+        for n in 1..100u64 {
+            let data_type = if n % 25 == 0 {
+                DataType::IFrame
+            } else {
+                DataType::PFrame
+            };
+            let header = RtpHeader::new(PayloadType::H264, "13912345678", 1, data_type)
+                .with_timestamp(5000 + n * 20)
+                .with_frame_intervals(0, 20);
+            segmenter
+                .write_video(&header, &[0x00, 0x00, 0x00, 0x01, 0x41, 0x00])
+                .await
+                .unwrap();
+        }
+        segmenter.finish().await.unwrap();
+
+        let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXTINF:1.000,\n0.ts\n#EXTINF:0.500,\n1.ts\n#EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_gop_cut_at_target_duration() {
+        let dir = std::env::temp_dir().join(format!("hls-gop-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("streams")).await.unwrap();
+
+        let mut segmenter = HlsSegmenter::new(&dir, VideoCodec::H264, false, HlsContainer::MpegTs);
+        // 40 ms frames with an I-frame every 10 s.
+        for n in 0..500u64 {
+            let data_type = if n % 250 == 0 {
+                DataType::IFrame
+            } else {
+                DataType::PFrame
+            };
+            let header = RtpHeader::new(PayloadType::H264, "13912345678", 1, data_type)
+                .with_timestamp(n * 40)
+                .with_frame_intervals(0, 40);
+            segmenter
+                .write_video(&header, &[0x00, 0x00, 0x00, 0x01, 0x41, 0x00])
+                .await
+                .unwrap();
+        }
+        segmenter.finish().await.unwrap();
+
+        let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXTINF:6.000,\n0.ts\n#EXTINF:4.000,\n1.ts\n\
+            #EXTINF:6.000,\n2.ts\n#EXTINF:4.000,\n3.ts\n#EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fmp4_playlist() {
+        let dir = std::env::temp_dir().join(format!("hls-fmp4-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("streams")).await.unwrap();
+
+        let mut segmenter = HlsSegmenter::new(&dir, VideoCodec::H264, false, HlsContainer::Fmp4);
+        let parameter_sets = [
+            0x00, 0x00, 0x00, 0x01, 0x67, 0x64, 0x00, 0x29, 0xAC, 0x15, 0x6A, 0x05, 0x00, 0x5B,
+            0x90, 0x00, 0x00, 0x00, 0x01, 0x68, 0xEE, 0x3C, 0xB0,
+        ];
+        for n in 0..50u64 {
+            let (data_type, frame) = if n % 25 == 0 {
+                (
+                    DataType::IFrame,
+                    [&parameter_sets[..], &[0x00, 0x00, 0x01, 0x65, 0x88]].concat(),
+                )
+            } else {
+                (DataType::PFrame, vec![0x00, 0x00, 0x01, 0x41, 0x9A])
+            };
+            let header = RtpHeader::new(PayloadType::H264, "13912345678", 1, data_type)
+                .with_timestamp(n * 40)
+                .with_frame_intervals(0, 40);
+            segmenter.write_video(&header, &frame).await.unwrap();
+        }
+        segmenter.finish().await.unwrap();
+
+        let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+        let init = std::fs::read(dir.join("init.mp4")).unwrap();
+        let segment = std::fs::read(dir.join("streams/1.m4s")).unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:1.000,\n0.m4s\n#EXTINF:1.000,\n1.m4s\n#EXT-X-ENDLIST\n"
+        );
+        assert_eq!(init[4..8], *b"ftyp");
+        assert_eq!(segment[4..8], *b"moof");
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_switch() {
+        let dir = std::env::temp_dir().join(format!("hls-switch-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("streams")).await.unwrap();
+
+        let mut segmenter = HlsSegmenter::new(&dir, VideoCodec::H264, false, HlsContainer::Fmp4);
+        let parameter_sets = [
+            0x00, 0x00, 0x00, 0x01, 0x67, 0x64, 0x00, 0x29, 0xAC, 0x15, 0x6A, 0x05, 0x00, 0x5B,
+            0x90, 0x00, 0x00, 0x00, 0x01, 0x68, 0xEE, 0x3C, 0xB0,
+        ];
+        // 40 ms frames with an I-frame every second, and one more when the
+        // other stream starts.
+        for n in 0..100u64 {
+            match n {
+                11 => segmenter.cut().await.unwrap(),
+                55 => segmenter.switch(),
+                _ => (),
+            }
+            let (data_type, frame) = if n % 25 == 0 || n == 60 {
+                (
+                    DataType::IFrame,
+                    [&parameter_sets[..], &[0x00, 0x00, 0x01, 0x65, 0x88]].concat(),
+                )
+            } else {
+                (DataType::PFrame, vec![0x00, 0x00, 0x01, 0x41, 0x9A])
+            };
+            let header = RtpHeader::new(PayloadType::H264, "13912345678", 1, data_type)
+                .with_timestamp(n * 40)
+                .with_frame_intervals(0, 40);
+            segmenter.write_video(&header, &frame).await.unwrap();
+        }
+        segmenter.finish().await.unwrap();
+
+        let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+        let init = std::fs::read(dir.join("init-1.mp4")).unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:0.440,\n0.m4s\n#EXTINF:1.000,\n1.m4s\n\
+            #EXTINF:0.400,\n2.m4s\n#EXT-X-DISCONTINUITY\n#EXT-X-MAP:URI=\"init-1.mp4\"\n\
+            #EXTINF:1.600,\n3.m4s\n#EXT-X-ENDLIST\n"
+        );
+        assert_eq!(init[4..8], *b"ftyp");
+    }
+
+    #[tokio::test]
+    async fn test_low_latency_playlist() {
+        let dir = std::env::temp_dir().join(format!("hls-ll-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("streams")).await.unwrap();
+
+        let (progress, receiver) = watch::channel(PlaylistProgress::default());
+        let mut segmenter = HlsSegmenter::new(&dir, VideoCodec::H264, false, HlsContainer::MpegTs)
+            .with_low_latency()
+            .with_progress(progress);
+        // 40 ms frames with an I-frame every second.
+        for n in 0..33u64 {
+            let data_type = if n % 25 == 0 {
+                DataType::IFrame
+            } else {
+                DataType::PFrame
+            };
+            let header = RtpHeader::new(PayloadType::H264, "13912345678", 1, data_type)
+                .with_timestamp(n * 40)
+                .with_frame_intervals(0, 40);
+            segmenter
+                .write_video(&header, &[0x00, 0x00, 0x00, 0x01, 0x41, 0x00])
+                .await
+                .unwrap();
+        }
+
+        let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+        let part = std::fs::metadata(dir.join("streams/1.0.ts")).unwrap();
+        fs::remove_dir_all(&dir).await.unwrap();
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=0.900\n\
+            #EXT-X-PART-INF:PART-TARGET=0.300\n\
+            #EXT-X-PART:DURATION=0.280,URI=\"0.0.ts\",INDEPENDENT=YES\n\
+            #EXT-X-PART:DURATION=0.280,URI=\"0.1.ts\"\n\
+            #EXT-X-PART:DURATION=0.280,URI=\"0.2.ts\"\n\
+            #EXT-X-PART:DURATION=0.160,URI=\"0.3.ts\"\n\
+            #EXTINF:1.000,\n0.ts\n\
+            #EXT-X-PART:DURATION=0.280,URI=\"1.0.ts\",INDEPENDENT=YES\n\
+            #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"1.1.ts\"\n"
+        );
+        assert!(part.len() > 0);
+        let progress = *receiver.borrow();
+        assert_eq!(
+            progress,
+            PlaylistProgress {
+                sequence: 1,
+                parts: 1
+            }
+        );
+        assert!(progress.contains(0, None));
+        assert!(progress.contains(1, Some(0)));
+        assert!(!progress.contains(1, Some(1)));
+        assert!(!progress.contains(1, None));
+    }
+}
@@ -0,0 +1,105 @@
+use crate::jt808::{AvControl, CloseMedia, TerminalResponse, REALTIME_AV_CONTROL, RESULT_SUCCESS};
+use crate::registry::{LiveStream, StreamKey};
+use crate::terminal::TerminalRegistry;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Interval between two checks for viewers.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do with streams nobody watches.
+#[derive(Clone, Default)]
+pub(crate) struct IdlePolicy {
+    /// Streams without viewers for this long are stopped. `None` keeps
+    /// them running until the terminal hangs up.
+    pub(crate) timeout: Option<Duration>,
+    /// Terminals signed in over JT/T 808, which are asked to close the
+    /// transfer with 0x9102 rather than only losing their connection.
+    pub(crate) terminals: Option<TerminalRegistry>,
+    /// URL notified with a JSON event of every stream stopped.
+    pub(crate) webhook: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct IdleEvent<'a> {
+    event: &'static str,
+    imei: &'a str,
+    channel: u8,
+    idle_seconds: u64,
+    /// Whether the terminal was asked to close the transfer over JT/T 808.
+    signaled: bool,
+}
+
+/// Stops `stream` once it has had no viewers for the timeout of `policy`.
+/// Live subscribers (HTTP-FLV, WebSocket, WebRTC, RTSP and RTMP relays)
+/// count as viewers for as long as they are connected, HLS and DASH
+/// players by their requests.
+pub(crate) async fn stop_when_idle(stream: Arc<LiveStream>, key: StreamKey, policy: IdlePolicy) {
+    let Some(timeout) = policy.timeout else {
+        return;
+    };
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        if stream.feed.subscribers() > 0 {
+            stream.viewed();
+        }
+        if stream.media.borrow().paused {
+            continue;
+        }
+        let idle = stream.idle_for();
+        if idle >= timeout {
+            println!("No viewers for {}s, stopping {key}", idle.as_secs());
+            let signaled = close_transfer(&policy, &key).await;
+            if let Some(webhook) = policy.webhook.clone() {
+                let event = IdleEvent {
+                    event: "stream_idle_stopped",
+                    imei: &key.imei,
+                    channel: key.channel,
+                    idle_seconds: idle.as_secs(),
+                    signaled,
+                };
+                let body = serde_json::to_vec(&event).expect("Failed to serialize idle event");
+                // The stream ends below, which aborts this task.
+                tokio::spawn(notify(webhook, body));
+            }
+            stream.media.send_modify(|media| media.closed = true);
+            return;
+        }
+    }
+}
+
+/// Asks the terminal of `key`, if signed in, to close the transfer.
+/// Returns whether it accepted.
+async fn close_transfer(policy: &IdlePolicy, key: &StreamKey) -> bool {
+    let Some(terminals) = &policy.terminals else {
+        return false;
+    };
+    if terminals.get(&key.imei).is_none() {
+        return false;
+    }
+    let body = AvControl::Close(CloseMedia::All).body(key.channel);
+    match terminals
+        .request(&key.imei, REALTIME_AV_CONTROL, body)
+        .await
+    {
+        Ok(response) => TerminalResponse::parse(&response)
+            .is_ok_and(|response| response.result == RESULT_SUCCESS),
+        Err(e) => {
+            eprintln!("Failed to close the transfer of {key}: {e}");
+            false
+        }
+    }
+}
+
+async fn notify(webhook: String, body: Vec<u8>) {
+    let response = reqwest::Client::new()
+        .post(&webhook)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(e) = response {
+        eprintln!("Failed to notify {webhook}: {e}");
+    }
+}
@@ -0,0 +1,1055 @@
+use crate::rtp::RtpHeader;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use encoding_rs::GBK;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio_util::codec::{Decoder, Encoder};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+const FLAG: u8 = 0x7E;
+const ESCAPE: u8 = 0x7D;
+
+/// Upper bound of a frame, against a stream that never ends one.
+const MAX_FRAME_LENGTH: usize = 64 * 1024;
+
+const BODY_LENGTH_MASK: u16 = 0x03FF;
+const ENCRYPTION_MASK: u16 = 0x1C00;
+const SUBPACKAGE_BIT: u16 = 0x2000;
+/// Set by JT/T 808-2019 terminals, which add a protocol version and
+/// longer phone numbers to the header.
+const VERSION_BIT: u16 = 0x4000;
+
+/// Length of the BCD phone number in each revision.
+const PHONE_LENGTH_2013: usize = 6;
+const PHONE_LENGTH_2019: usize = 10;
+
+/// Terminal messages.
+pub(crate) const TERMINAL_RESPONSE: u16 = 0x0001;
+pub(crate) const HEARTBEAT: u16 = 0x0002;
+pub(crate) const DEREGISTRATION: u16 = 0x0003;
+pub(crate) const REGISTRATION: u16 = 0x0100;
+pub(crate) const AUTHENTICATION: u16 = 0x0102;
+/// JT/T 1078 list of recorded audio and video, answering 0x9205.
+pub(crate) const RESOURCE_LIST: u16 = 0x1205;
+
+/// Platform messages.
+pub(crate) const PLATFORM_RESPONSE: u16 = 0x8001;
+pub(crate) const REGISTRATION_RESPONSE: u16 = 0x8100;
+/// JT/T 1078 real-time audio and video request.
+pub(crate) const REALTIME_AV_REQUEST: u16 = 0x9101;
+/// JT/T 1078 control of a real-time transfer.
+pub(crate) const REALTIME_AV_CONTROL: u16 = 0x9102;
+/// JT/T 1078 query of the recorded audio and video.
+pub(crate) const RESOURCE_QUERY: u16 = 0x9205;
+
+/// Results of [`PLATFORM_RESPONSE`].
+pub(crate) const RESULT_SUCCESS: u8 = 0;
+pub(crate) const RESULT_FAILURE: u8 = 1;
+pub(crate) const RESULT_INVALID: u8 = 2;
+
+/// JT/T 808 message, or one sub-package of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Jt808Message {
+    pub(crate) id: u16,
+    /// Protocol version of JT/T 808-2019, `None` for 2013.
+    pub(crate) version: Option<u8>,
+    /// Phone number of the terminal, like the SIM card number of the
+    /// JT/T 1078 header.
+    pub(crate) phone: String,
+    pub(crate) serial: u16,
+    /// Package count and 1-based package index of a sub-package.
+    pub(crate) package: Option<(u16, u16)>,
+    pub(crate) body: Bytes,
+}
+
+impl Jt808Message {
+    /// Message to the terminal of `request`, in the same revision.
+    pub(crate) fn reply(request: &Self, id: u16, serial: u16, body: Bytes) -> Self {
+        Self {
+            id,
+            version: request.version,
+            phone: request.phone.clone(),
+            serial,
+            package: None,
+            body,
+        }
+    }
+
+    /// Parses an unescaped frame without its flags: the header, the body,
+    /// then the XOR checksum of both.
+    fn parse(frame: &[u8]) -> Result<Self> {
+        let Some((&checksum, mut data)) = frame.split_last() else {
+            return Err(invalid("Empty JT/T 808 frame"));
+        };
+        if data.iter().fold(0, |sum, byte| sum ^ byte) != checksum {
+            return Err(invalid("JT/T 808 checksum mismatch"));
+        }
+        if data.len() < 4 {
+            return Err(invalid("JT/T 808 header too short"));
+        }
+        let id = data.get_u16();
+        let properties = data.get_u16();
+        if properties & ENCRYPTION_MASK != 0 {
+            return Err(invalid("Encrypted JT/T 808 messages are not supported"));
+        }
+
+        let (version, phone_length) = if properties & VERSION_BIT != 0 {
+            if data.is_empty() {
+                return Err(invalid("JT/T 808 header too short"));
+            }
+            (Some(data.get_u8()), PHONE_LENGTH_2019)
+        } else {
+            (None, PHONE_LENGTH_2013)
+        };
+        let package_length = if properties & SUBPACKAGE_BIT != 0 {
+            4
+        } else {
+            0
+        };
+        if data.len() < phone_length + 2 + package_length {
+            return Err(invalid("JT/T 808 header too short"));
+        }
+        let phone = RtpHeader::parse_terminal_serial_number(&data[..phone_length]);
+        data.advance(phone_length);
+        let serial = data.get_u16();
+        let package = (package_length > 0).then(|| (data.get_u16(), data.get_u16()));
+
+        let body_length = (properties & BODY_LENGTH_MASK) as usize;
+        if data.len() != body_length {
+            return Err(invalid("JT/T 808 body length mismatch"));
+        }
+        Ok(Self {
+            id,
+            version,
+            phone,
+            serial,
+            package,
+            body: Bytes::copy_from_slice(data),
+        })
+    }
+
+    /// Writes the frame with its flags, escaped.
+    fn write(&self, dst: &mut BytesMut) -> Result<()> {
+        if self.body.len() > BODY_LENGTH_MASK as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "JT/T 808 body too long for a single package",
+            ));
+        }
+        let mut properties = self.body.len() as u16;
+        if self.version.is_some() {
+            properties |= VERSION_BIT;
+        }
+        if self.package.is_some() {
+            properties |= SUBPACKAGE_BIT;
+        }
+
+        let mut data = BytesMut::with_capacity(17 + self.body.len() + 4);
+        data.put_u16(self.id);
+        data.put_u16(properties);
+        let phone_length = match self.version {
+            Some(version) => {
+                data.put_u8(version);
+                PHONE_LENGTH_2019
+            }
+            None => PHONE_LENGTH_2013,
+        };
+        data.put_slice(&RtpHeader::encode_terminal_serial_number(
+            &self.phone,
+            phone_length,
+        )?);
+        data.put_u16(self.serial);
+        if let Some((total, index)) = self.package {
+            data.put_u16(total);
+            data.put_u16(index);
+        }
+        data.put_slice(&self.body);
+        let checksum = data.iter().fold(0, |sum, byte| sum ^ byte);
+        data.put_u8(checksum);
+
+        dst.reserve(data.len() + 2);
+        dst.put_u8(FLAG);
+        for &byte in data.iter() {
+            match byte {
+                FLAG => dst.put_slice(&[ESCAPE, 0x02]),
+                ESCAPE => dst.put_slice(&[ESCAPE, 0x01]),
+                byte => dst.put_u8(byte),
+            }
+        }
+        dst.put_u8(FLAG);
+        Ok(())
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn unescape(data: &[u8]) -> Result<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != ESCAPE {
+            unescaped.push(byte);
+            continue;
+        }
+        match bytes.next() {
+            Some(0x01) => unescaped.push(ESCAPE),
+            Some(0x02) => unescaped.push(FLAG),
+            _ => return Err(invalid("Invalid JT/T 808 escape sequence")),
+        }
+    }
+    Ok(unescaped)
+}
+
+/// JT/T 808 codec: frames between `0x7E` flags, escaped, with an XOR
+/// checksum. Like [`RtpCodec`](crate::RtpCodec), decoding skips corrupt
+/// frames instead of failing.
+#[derive(Debug, Default)]
+pub(crate) struct Jt808Codec;
+
+impl Decoder for Jt808Codec {
+    type Item = Jt808Message;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        loop {
+            let Some(start) = src.iter().position(|&byte| byte == FLAG) else {
+                src.clear();
+                return Ok(None);
+            };
+            src.advance(start);
+            let Some(length) = src[1..].iter().position(|&byte| byte == FLAG) else {
+                if src.len() > MAX_FRAME_LENGTH {
+                    eprintln!("Discarded {} bytes without a JT/T 808 frame end", src.len());
+                    src.clear();
+                }
+                return Ok(None);
+            };
+            // Back to back flags: the second one starts the frame.
+            if length == 0 {
+                src.advance(1);
+                continue;
+            }
+            let frame = src.split_to(length + 2);
+            match unescape(&frame[1..=length]).and_then(|frame| Jt808Message::parse(&frame)) {
+                Ok(message) => return Ok(Some(message)),
+                Err(e) => eprintln!("Failed to parse JT/T 808 frame: {e}"),
+            }
+        }
+    }
+}
+
+impl Encoder<Jt808Message> for Jt808Codec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, message: Jt808Message, dst: &mut BytesMut) -> Result<()> {
+        message.write(dst)
+    }
+}
+
+/// Upper bound of the sub-packages of a message: with bodies of at most
+/// 1023 bytes, a joined message stays under 256 KiB.
+const MAX_PACKAGES: u16 = 256;
+
+/// Messages joined at the same time, the oldest giving way to a new one.
+const MAX_PENDING_MESSAGES: usize = 8;
+
+/// Incomplete messages are dropped once their first package is this old.
+const PACKAGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct PendingMessage {
+    started: Instant,
+    packages: Vec<Option<Jt808Message>>,
+}
+
+impl PendingMessage {
+    fn new(total: u16, started: Instant) -> Self {
+        Self {
+            started,
+            packages: vec![None; total as usize],
+        }
+    }
+}
+
+/// Joins the sub-packages of long messages, keyed by message ID.
+#[derive(Default)]
+pub(crate) struct SubpackageAssembler {
+    pending: HashMap<u16, PendingMessage>,
+}
+
+impl SubpackageAssembler {
+    /// Returns the message once complete: right away unless sub-packaged.
+    pub(crate) fn push(&mut self, message: Jt808Message) -> Option<Jt808Message> {
+        self.push_at(message, Instant::now())
+    }
+
+    fn push_at(&mut self, message: Jt808Message, now: Instant) -> Option<Jt808Message> {
+        let Some((total, index)) = message.package else {
+            return Some(message);
+        };
+        if total == 0 || index == 0 || index > total || total > MAX_PACKAGES {
+            eprintln!("Dropping JT/T 808 package {index} of {total}");
+            return None;
+        }
+        self.pending
+            .retain(|_, pending| now.duration_since(pending.started) < PACKAGE_TIMEOUT);
+        let id = message.id;
+        if !self.pending.contains_key(&id) && self.pending.len() >= MAX_PENDING_MESSAGES {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pending)| pending.started)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+        let pending = self
+            .pending
+            .entry(id)
+            .or_insert_with(|| PendingMessage::new(total, now));
+        if pending.packages.len() != total as usize {
+            // A new message of the same ID replaces the incomplete one.
+            *pending = PendingMessage::new(total, now);
+        }
+        pending.packages[index as usize - 1] = Some(message);
+        if pending.packages.iter().any(Option::is_none) {
+            return None;
+        }
+
+        let pending = self.pending.remove(&id)?;
+        let mut packages = pending.packages.into_iter().flatten();
+        let mut message = packages.next()?;
+        let mut body = BytesMut::from(&message.body[..]);
+        for package in packages {
+            body.put_slice(&package.body);
+        }
+        message.body = body.freeze();
+        message.package = None;
+        Some(message)
+    }
+}
+
+/// 0x8001 body: serial and ID of the message answered, then the result.
+pub(crate) fn platform_response(request: &Jt808Message, result: u8) -> Bytes {
+    let mut body = BytesMut::with_capacity(5);
+    body.put_u16(request.serial);
+    body.put_u16(request.id);
+    body.put_u8(result);
+    body.freeze()
+}
+
+/// 0x8100 body: serial of the registration, the result, and the
+/// authentication code on success.
+pub(crate) fn registration_response(
+    request: &Jt808Message,
+    result: u8,
+    auth_code: Option<&str>,
+) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u16(request.serial);
+    body.put_u8(result);
+    if let Some(auth_code) = auth_code {
+        body.put_slice(&GBK.encode(auth_code).0);
+    }
+    body.freeze()
+}
+
+/// Answer of a terminal to a platform message: serial and ID of the
+/// message answered, then the result.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct TerminalResponse {
+    pub(crate) serial: u16,
+    pub(crate) id: u16,
+    pub(crate) result: u8,
+}
+
+impl TerminalResponse {
+    pub(crate) fn parse(message: &Jt808Message) -> Result<Self> {
+        let mut body = &message.body[..];
+        if body.len() < 5 {
+            return Err(invalid("JT/T 808 terminal response too short"));
+        }
+        Ok(Self {
+            serial: body.get_u16(),
+            id: body.get_u16(),
+            result: body.get_u8(),
+        })
+    }
+}
+
+/// Media a terminal is asked to send by 0x9101.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DataType {
+    #[default]
+    AudioVideo = 0,
+    Video = 1,
+    Intercom = 2,
+    Monitor = 3,
+    Broadcast = 4,
+    Passthrough = 5,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StreamType {
+    #[default]
+    Main = 0,
+    Sub = 1,
+}
+
+/// Media a 0x9102 close ends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CloseMedia {
+    /// Audio and video of the channel.
+    #[default]
+    All = 0,
+    /// Audio only, the video goes on.
+    Audio = 1,
+    /// Video only, the audio goes on.
+    Video = 2,
+}
+
+/// 0x9102 control command of a real-time transfer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AvControl {
+    Close(CloseMedia),
+    Switch(StreamType),
+    Pause,
+    Resume,
+    CloseIntercom,
+}
+
+impl AvControl {
+    pub(crate) fn body(&self, channel: u8) -> Bytes {
+        let (command, media, stream_type) = match *self {
+            Self::Close(media) => (0, media, StreamType::Main),
+            Self::Switch(stream_type) => (1, CloseMedia::All, stream_type),
+            Self::Pause => (2, CloseMedia::All, StreamType::Main),
+            Self::Resume => (3, CloseMedia::All, StreamType::Main),
+            Self::CloseIntercom => (4, CloseMedia::All, StreamType::Main),
+        };
+        Bytes::from(vec![channel, command, media as u8, stream_type as u8])
+    }
+}
+
+/// 0x9101 request: where the terminal connects to stream `channel`.
+pub(crate) struct RealtimeAvRequest<'a> {
+    pub(crate) host: &'a str,
+    pub(crate) tcp_port: u16,
+    /// Zero when the server takes no media over UDP.
+    pub(crate) udp_port: u16,
+    pub(crate) channel: u8,
+    pub(crate) data_type: DataType,
+    pub(crate) stream_type: StreamType,
+}
+
+impl RealtimeAvRequest<'_> {
+    pub(crate) fn body(&self) -> Bytes {
+        let mut body = BytesMut::with_capacity(8 + self.host.len());
+        body.put_u8(self.host.len() as u8);
+        body.put_slice(self.host.as_bytes());
+        body.put_u16(self.tcp_port);
+        body.put_u16(self.udp_port);
+        body.put_u8(self.channel);
+        body.put_u8(self.data_type as u8);
+        body.put_u8(self.stream_type as u8);
+        body.freeze()
+    }
+}
+
+/// Local time of the terminal: BCD `YYMMDDhhmmss` on the wire and
+/// `YYYY-MM-DDThh:mm:ss` in the API. All zeros leaves a bound of a query
+/// open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct BcdTime {
+    /// Years since 2000, month, day, hour, minute and second.
+    fields: [u8; 6],
+}
+
+impl BcdTime {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mut fields = [0u8; 6];
+        for (field, &byte) in fields.iter_mut().zip(data) {
+            let (high, low) = (byte >> 4, byte & 0x0F);
+            if high > 9 || low > 9 {
+                return Err(invalid("Invalid BCD time"));
+            }
+            *field = high * 10 + low;
+        }
+        let time = Self { fields };
+        if !time.is_valid() {
+            return Err(invalid("Invalid BCD time"));
+        }
+        Ok(time)
+    }
+
+    /// Whether the fields are in range, or all zero.
+    fn is_valid(&self) -> bool {
+        let [_, month, day, hour, minute, second] = self.fields;
+        self.fields == [0; 6]
+            || (1..=12).contains(&month)
+                && (1..=31).contains(&day)
+                && hour < 24
+                && minute < 60
+                && second < 60
+    }
+
+    fn write(&self, dst: &mut BytesMut) {
+        for field in self.fields {
+            dst.put_u8(((field / 10) << 4) | (field % 10));
+        }
+    }
+}
+
+impl FromStr for BcdTime {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let error = || invalid("Expected a time like 2024-05-01T08:30:00");
+        let (date, time) = s.split_once(['T', ' ']).ok_or_else(error)?;
+        let mut values = date.split('-').chain(time.split(':'));
+        let mut fields = [0u8; 6];
+        for (index, field) in fields.iter_mut().enumerate() {
+            let value: u16 = values
+                .next()
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(error)?;
+            *field = match index {
+                0 if (2000..2100).contains(&value) => value - 2000,
+                0 => return Err(error()),
+                _ if value < 100 => value,
+                _ => return Err(error()),
+            } as u8;
+        }
+        let time = Self { fields };
+        if values.next().is_some() || !time.is_valid() {
+            return Err(error());
+        }
+        Ok(time)
+    }
+}
+
+impl fmt::Display for BcdTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [year, month, day, hour, minute, second] = self.fields;
+        write!(
+            f,
+            "{:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}",
+            2000 + year as u16
+        )
+    }
+}
+
+impl serde::Serialize for BcdTime {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for BcdTime {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        time.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Media of a recording.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MediaType {
+    AudioVideo = 0,
+    Audio = 1,
+    Video = 2,
+    /// Video with or without audio, in queries.
+    #[default]
+    AudioOrVideo = 3,
+}
+
+/// Stream of a recording, `All` in queries only.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RecordStream {
+    #[default]
+    All = 0,
+    Main = 1,
+    Sub = 2,
+}
+
+/// Storage of a recording, `All` in queries only.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Storage {
+    #[default]
+    All = 0,
+    Main = 1,
+    Backup = 2,
+}
+
+/// 0x9205 query of the recordings of `channel`, zero for every channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ResourceQuery {
+    pub(crate) channel: u8,
+    pub(crate) start: BcdTime,
+    pub(crate) end: BcdTime,
+    /// Alarm bits the recordings must have, zero for any.
+    pub(crate) alarm: u64,
+    pub(crate) media_type: MediaType,
+    pub(crate) stream_type: RecordStream,
+    pub(crate) storage_type: Storage,
+}
+
+impl ResourceQuery {
+    pub(crate) fn body(&self) -> Bytes {
+        let mut body = BytesMut::with_capacity(24);
+        body.put_u8(self.channel);
+        self.start.write(&mut body);
+        self.end.write(&mut body);
+        body.put_u64(self.alarm);
+        body.put_u8(self.media_type as u8);
+        body.put_u8(self.stream_type as u8);
+        body.put_u8(self.storage_type as u8);
+        body.freeze()
+    }
+}
+
+/// Recording in a 0x1205 list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct Recording {
+    pub(crate) channel: u8,
+    pub(crate) start: BcdTime,
+    pub(crate) end: BcdTime,
+    pub(crate) alarm: u64,
+    pub(crate) media_type: MediaType,
+    pub(crate) stream_type: RecordStream,
+    pub(crate) storage_type: Storage,
+    /// Size of the file in bytes.
+    pub(crate) size: u32,
+}
+
+/// Length of a recording in a 0x1205 list.
+const RECORDING_LENGTH: usize = 28;
+
+/// 0x1205 list of the recordings matching a 0x9205 query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ResourceList {
+    /// Serial of the query answered.
+    pub(crate) serial: u16,
+    pub(crate) recordings: Vec<Recording>,
+}
+
+impl ResourceList {
+    pub(crate) fn parse(message: &Jt808Message) -> Result<Self> {
+        let mut body = &message.body[..];
+        if body.len() < 6 {
+            return Err(invalid("JT/T 1078 resource list too short"));
+        }
+        let serial = body.get_u16();
+        let count = body.get_u32() as usize;
+        if body.len() != count * RECORDING_LENGTH {
+            return Err(invalid("JT/T 1078 resource list length mismatch"));
+        }
+        let recordings = body
+            .chunks_exact(RECORDING_LENGTH)
+            .map(|mut data| {
+                let channel = data.get_u8();
+                let start = BcdTime::parse(&data[..6])?;
+                let end = BcdTime::parse(&data[6..12])?;
+                data.advance(12);
+                let alarm = data.get_u64();
+                let media_type = match data.get_u8() {
+                    0 => MediaType::AudioVideo,
+                    1 => MediaType::Audio,
+                    2 => MediaType::Video,
+                    _ => return Err(invalid("Invalid recording media type")),
+                };
+                let stream_type = match data.get_u8() {
+                    1 => RecordStream::Main,
+                    2 => RecordStream::Sub,
+                    _ => return Err(invalid("Invalid recording stream type")),
+                };
+                let storage_type = match data.get_u8() {
+                    1 => Storage::Main,
+                    2 => Storage::Backup,
+                    _ => return Err(invalid("Invalid recording storage type")),
+                };
+                Ok(Recording {
+                    channel,
+                    start,
+                    end,
+                    alarm,
+                    media_type,
+                    stream_type,
+                    storage_type,
+                    size: data.get_u32(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { serial, recordings })
+    }
+}
+
+/// Terminal details of a 0x0100 registration.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Registration {
+    pub(crate) province: u16,
+    pub(crate) city: u16,
+    pub(crate) manufacturer: String,
+    pub(crate) model: String,
+    pub(crate) terminal_id: String,
+    pub(crate) plate_color: u8,
+    pub(crate) plate: String,
+}
+
+impl Registration {
+    pub(crate) fn parse(message: &Jt808Message) -> Result<Self> {
+        let (manufacturer, model, terminal_id) = match message.version {
+            Some(_) => (11, 30, 30),
+            None => (5, 20, 7),
+        };
+        let mut body = &message.body[..];
+        if body.len() < 4 + manufacturer + model + terminal_id + 1 {
+            return Err(invalid("JT/T 808 registration too short"));
+        }
+        let province = body.get_u16();
+        let city = body.get_u16();
+        let mut string = |length| {
+            let value = gbk_string(&body[..length]);
+            body.advance(length);
+            value
+        };
+        let manufacturer = string(manufacturer);
+        let model = string(model);
+        let terminal_id = string(terminal_id);
+        let plate_color = body.get_u8();
+        Ok(Self {
+            province,
+            city,
+            manufacturer,
+            model,
+            terminal_id,
+            plate_color,
+            plate: gbk_string(body),
+        })
+    }
+}
+
+/// Authentication code of a 0x0102 authentication.
+pub(crate) fn parse_auth_code(message: &Jt808Message) -> Result<String> {
+    match message.version {
+        Some(_) => {
+            let (&length, rest) = message
+                .body
+                .split_first()
+                .ok_or_else(|| invalid("JT/T 808 authentication too short"))?;
+            let code = rest
+                .get(..length as usize)
+                .ok_or_else(|| invalid("JT/T 808 authentication too short"))?;
+            Ok(gbk_string(code))
+        }
+        None => Ok(gbk_string(&message.body)),
+    }
+}
+
+/// Decodes a fixed-length GBK field, padded with NULs or spaces.
+fn gbk_string(data: &[u8]) -> String {
+    GBK.decode(data).0.trim_end_matches(['\0', ' ']).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(version: Option<u8>, body: &[u8]) -> Jt808Message {
+        Jt808Message {
+            id: REGISTRATION,
+            version,
+            phone: "13912345678".to_string(),
+            serial: 0x7E7D,
+            package: None,
+            body: Bytes::copy_from_slice(body),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for version in [None, Some(1)] {
+            let message = message(version, &[0x7E, 0x01, 0x7D, 0x02]);
+            let mut bytes = BytesMut::from(&[0x00, 0x7E][..]);
+            Jt808Codec.encode(message.clone(), &mut bytes).unwrap();
+            // Only the flags are left unescaped.
+            assert_eq!(bytes.iter().filter(|&&byte| byte == FLAG).count(), 3);
+
+            let decoded = Jt808Codec.decode(&mut bytes).unwrap().unwrap();
+            assert_eq!(decoded, message);
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_corrupt_frame_skipped() {
+        let mut bytes = BytesMut::new();
+        Jt808Codec
+            .encode(message(None, &[1, 2, 3]), &mut bytes)
+            .unwrap();
+        let length = bytes.len();
+        bytes[length - 2] ^= 0xFF;
+        Jt808Codec
+            .encode(message(None, &[4, 5]), &mut bytes)
+            .unwrap();
+
+        let decoded = Jt808Codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(decoded.body, [4, 5][..]);
+    }
+
+    #[test]
+    fn test_subpackages() {
+        let mut assembler = SubpackageAssembler::default();
+        let mut second = message(None, &[3, 4]);
+        second.package = Some((2, 2));
+        let mut first = message(None, &[1, 2]);
+        first.package = Some((2, 1));
+
+        assert!(assembler.push(second.clone()).is_none());
+        let joined = assembler.push(first.clone()).unwrap();
+        assert_eq!(joined.body, [1, 2, 3, 4][..]);
+        assert_eq!(joined.package, None);
+
+        // Too many packages are refused before anything is allocated.
+        let mut huge = first.clone();
+        huge.package = Some((u16::MAX, 1));
+        assert!(assembler.push(huge).is_none());
+        assert!(assembler.pending.is_empty());
+
+        // Incomplete messages expire, and only a few are kept at a time.
+        let now = Instant::now();
+        assert!(assembler.push_at(second.clone(), now).is_none());
+        let later = now + PACKAGE_TIMEOUT;
+        assert!(assembler.push_at(first.clone(), later).is_none());
+        for id in 1..=MAX_PENDING_MESSAGES as u16 {
+            let mut package = first.clone();
+            package.id = id;
+            let time = later + Duration::from_millis(id.into());
+            assert!(assembler.push_at(package, time).is_none());
+        }
+        assert_eq!(assembler.pending.len(), MAX_PENDING_MESSAGES);
+        assert!(!assembler.pending.contains_key(&REGISTRATION));
+    }
+
+    #[test]
+    fn test_registration() {
+        let mut body = vec![0, 44, 1, 0x2C];
+        body.extend_from_slice(b"ACME\0");
+        body.extend_from_slice(&[b' '; 20]);
+        body[9..12].copy_from_slice(b"X-1");
+        body.extend_from_slice(b"T000001");
+        body.push(1);
+        body.extend_from_slice(&GBK.encode("粤B12345").0);
+
+        let registration = Registration::parse(&message(None, &body)).unwrap();
+        assert_eq!(registration.city, 300);
+        assert_eq!(registration.manufacturer, "ACME");
+        assert_eq!(registration.model, "X-1");
+        assert_eq!(registration.terminal_id, "T000001");
+        assert_eq!(registration.plate, "粤B12345");
+    }
+
+    #[test]
+    fn test_realtime_av_request() {
+        let request = RealtimeAvRequest {
+            host: "10.0.0.1",
+            tcp_port: 8000,
+            udp_port: 0,
+            channel: 2,
+            data_type: DataType::Video,
+            stream_type: StreamType::Sub,
+        };
+        let mut expected = vec![8];
+        expected.extend_from_slice(b"10.0.0.1");
+        expected.extend_from_slice(&[0x1F, 0x40, 0, 0, 2, 1, 1]);
+        assert_eq!(request.body(), expected);
+
+        assert_eq!(AvControl::Switch(StreamType::Sub).body(2), [2, 1, 0, 1][..]);
+        assert_eq!(
+            AvControl::Close(CloseMedia::Audio).body(1),
+            [1, 0, 1, 0][..]
+        );
+
+        let response = message(None, &[0x00, 0x05, 0x91, 0x01, 0]);
+        assert_eq!(
+            TerminalResponse::parse(&response).unwrap(),
+            TerminalResponse {
+                serial: 5,
+                id: REALTIME_AV_REQUEST,
+                result: RESULT_SUCCESS,
+            }
+        );
+    }
+
+    #[test]
+    fn test_resource_query() {
+        let query = ResourceQuery {
+            channel: 1,
+            start: "2024-05-01T08:00:00".parse().unwrap(),
+            end: "2024-05-01 09:30:59".parse().unwrap(),
+            alarm: 0,
+            media_type: MediaType::Video,
+            stream_type: RecordStream::Main,
+            storage_type: Storage::All,
+        };
+        // Channel, start, end, alarm bits, then media, stream and storage.
+        let fixture = [
+            0x01, 0x24, 0x05, 0x01, 0x08, 0x00, 0x00, 0x24, 0x05, 0x01, 0x09, 0x30, 0x59, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x00,
+        ];
+        assert_eq!(query.body(), fixture[..]);
+        assert!("2024-13".parse::<BcdTime>().is_err());
+        assert!("1999-05-01T08:00:00".parse::<BcdTime>().is_err());
+        assert!("2024-13-01T08:00:00".parse::<BcdTime>().is_err());
+        assert!("2024-05-01T24:00:00".parse::<BcdTime>().is_err());
+        assert!(BcdTime::parse(&[0x24, 0x13, 0x01, 0x08, 0x00, 0x00]).is_err());
+        assert!(BcdTime::parse(&[0x24, 0x05, 0x01, 0x08, 0x60, 0x00]).is_err());
+        assert_eq!(BcdTime::parse(&[0; 6]).unwrap(), BcdTime::default());
+    }
+
+    /// 0x1205 body as a terminal writes it.
+    fn resource_list_body(list: &ResourceList) -> Bytes {
+        let mut body = BytesMut::new();
+        body.put_u16(list.serial);
+        body.put_u32(list.recordings.len() as u32);
+        for recording in &list.recordings {
+            body.put_u8(recording.channel);
+            recording.start.write(&mut body);
+            recording.end.write(&mut body);
+            body.put_u64(recording.alarm);
+            body.put_u8(recording.media_type as u8);
+            body.put_u8(recording.stream_type as u8);
+            body.put_u8(recording.storage_type as u8);
+            body.put_u32(recording.size);
+        }
+        body.freeze()
+    }
+
+    #[test]
+    fn test_resource_round_trip() {
+        let query = ResourceQuery {
+            channel: 1,
+            start: "2024-05-01T00:00:00".parse().unwrap(),
+            end: "2024-05-01T23:59:59".parse().unwrap(),
+            alarm: 0,
+            media_type: MediaType::AudioOrVideo,
+            stream_type: RecordStream::All,
+            storage_type: Storage::All,
+        };
+        let mut bytes = BytesMut::new();
+        let request = Jt808Message {
+            id: RESOURCE_QUERY,
+            serial: 0x007E,
+            body: query.body(),
+            ..message(None, &[])
+        };
+        Jt808Codec.encode(request, &mut bytes).unwrap();
+        let request = Jt808Codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(request.body, query.body());
+
+        // A list too long for one message, in two sub-packages, with
+        // sizes that need escaping.
+        let recordings = (0..40u8)
+            .map(|n| Recording {
+                channel: request.body[0],
+                start: format!("2024-05-01T{:02}:{:02}:00", n / 2, n % 2 * 30)
+                    .parse()
+                    .unwrap(),
+                end: format!("2024-05-01T{:02}:{:02}:00", n / 2, n % 2 * 30 + 29)
+                    .parse()
+                    .unwrap(),
+                alarm: u64::from(n % 3),
+                media_type: MediaType::AudioVideo,
+                stream_type: RecordStream::Main,
+                storage_type: Storage::Main,
+                size: 0x7E7D_0000 + u32::from(n),
+            })
+            .collect();
+        let list = ResourceList {
+            serial: request.serial,
+            recordings,
+        };
+        let body = resource_list_body(&list);
+        let middle = body.len() / 2;
+        for (index, part) in [body.slice(..middle), body.slice(middle..)]
+            .into_iter()
+            .enumerate()
+        {
+            let package = Jt808Message {
+                id: RESOURCE_LIST,
+                serial: 0x0100 + index as u16,
+                package: Some((2, index as u16 + 1)),
+                body: part,
+                ..message(None, &[])
+            };
+            Jt808Codec.encode(package, &mut bytes).unwrap();
+        }
+
+        let mut assembler = SubpackageAssembler::default();
+        let first = Jt808Codec.decode(&mut bytes).unwrap().unwrap();
+        assert!(assembler.push(first).is_none());
+        let second = Jt808Codec.decode(&mut bytes).unwrap().unwrap();
+        let response = assembler.push(second).unwrap();
+        assert!(bytes.is_empty());
+        assert_eq!(response.id, RESOURCE_LIST);
+        assert_eq!(ResourceList::parse(&response).unwrap(), list);
+    }
+
+    #[test]
+    fn test_resource_list() {
+        // 0x1205 of serial 0x007E answering the query of serial 0x0010: two
+        // recordings of channel 1, the second one with the emergency alarm
+        // bit. The serial and the size of the first recording are escaped.
+        let frame = [
+            0x7E, 0x12, 0x05, 0x00, 0x3E, 0x01, 0x39, 0x12, 0x34, 0x56, 0x78, 0x00, 0x7D, 0x02,
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x02, //
+            0x01, 0x24, 0x05, 0x01, 0x08, 0x00, 0x00, 0x24, 0x05, 0x01, 0x08, 0x15, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x9C, 0x7D, 0x01,
+            0x00, //
+            0x01, 0x24, 0x05, 0x01, 0x08, 0x15, 0x00, 0x24, 0x05, 0x01, 0x08, 0x30, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x02, 0x02, 0x00, 0x4E, 0x20, 0x00,
+            0xC9, 0x7E,
+        ];
+        let mut bytes = BytesMut::from(&frame[..]);
+        let mut message = Jt808Codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!((message.id, message.serial), (RESOURCE_LIST, 0x007E));
+        assert_eq!(message.phone, "13912345678");
+        let list = ResourceList::parse(&message).unwrap();
+        assert_eq!(list.serial, 0x0010);
+        assert_eq!(list.recordings.len(), 2);
+        assert_eq!(
+            list.recordings[1],
+            Recording {
+                channel: 1,
+                start: "2024-05-01T08:15:00".parse().unwrap(),
+                end: "2024-05-01T08:30:00".parse().unwrap(),
+                alarm: 1,
+                media_type: MediaType::Video,
+                stream_type: RecordStream::Sub,
+                storage_type: Storage::Backup,
+                size: 0x004E_2000,
+            }
+        );
+        assert_eq!(list.recordings[0].start.to_string(), "2024-05-01T08:00:00");
+        assert_eq!(list.recordings[0].size, 0x009C_7D00);
+
+        message.body = message.body.slice(..message.body.len() - 1);
+        assert!(ResourceList::parse(&message).is_err());
+    }
+}
@@ -1,7 +1,36 @@
+pub(crate) mod aac;
+pub(crate) mod assembler;
+pub(crate) mod audio;
+pub(crate) mod codec;
+pub(crate) mod dash;
+pub(crate) mod ffmpeg;
+pub(crate) mod flv;
+pub(crate) mod fmp4;
 pub(crate) mod helper;
+pub(crate) mod hls;
+pub(crate) mod idle;
+pub(crate) mod jt808;
+pub(crate) mod live;
+pub(crate) mod mpegts;
+pub(crate) mod nal;
 pub(crate) mod processor;
+pub(crate) mod registry;
+pub(crate) mod relay;
+pub(crate) mod rtmp;
 pub(crate) mod rtp;
+pub(crate) mod rtsp;
 pub mod server;
+pub(crate) mod terminal;
+pub(crate) mod whep;
+
+pub use codec::RtpCodec;
+pub use processor::{HlsBackend, HlsContainer};
+pub use registry::{StreamKey, StreamRegistry};
+pub use relay::RtmpPushRule;
+pub use rtp::{
+    DataType, PayloadType, ProtocolVersion, RtpHeader, RtpPacket, RtpReader, SubpacketFlag,
+};
+pub use terminal::{Terminal, TerminalRegistry};
 
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
 
@@ -37,7 +66,11 @@ impl TcpServerTask {
 }
 
 pub fn run_tcp_server(address: &str, port: u16) -> TcpServerTask {
-    let tcp_server = TcpServer::new(address, port);
+    spawn_tcp_server(TcpServer::new(address, port))
+}
+
+/// Runs an already configured [`TcpServer`] in the background.
+pub fn spawn_tcp_server(tcp_server: TcpServer) -> TcpServerTask {
     let (tx, rx) = broadcast::channel(1);
     let tcp_sever_task = tokio::spawn(tcp_server.run(rx));
 
@@ -0,0 +1,332 @@
+use crate::audio::AudioCodec;
+use crate::nal::{nal_units, ParameterSets};
+use crate::processor::VideoCodec;
+use crate::rtp::RtpHeader;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::{stream, Stream, StreamExt};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Frames buffered for each subscriber before it counts as lagging.
+const CHANNEL_CAPACITY: usize = 512;
+
+/// Upper bound of the cached GOP, for terminals with a very long or no
+/// I-frame interval.
+const MAX_GOP_FRAMES: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FrameKind {
+    Video {
+        keyframe: bool,
+    },
+    /// ADTS framed AAC or G.711 samples.
+    Audio,
+}
+
+/// Frame of the live feed, on the media clock of the stream.
+#[derive(Clone, Debug)]
+pub(crate) struct LiveFrame {
+    pub(crate) time_ms: u64,
+    pub(crate) kind: FrameKind,
+    /// Annex-B access unit for video.
+    pub(crate) data: Bytes,
+    /// Header of the reassembled frame as sent by the terminal.
+    pub(crate) header: Arc<RtpHeader>,
+}
+
+/// JSON header of a raw frame message.
+#[derive(Serialize)]
+struct RawFrameHeader<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    codec: &'static str,
+    keyframe: bool,
+    timestamp: u64,
+    rtp: &'a RtpHeader,
+}
+
+impl LiveFrame {
+    fn is_keyframe(&self) -> bool {
+        self.kind == FrameKind::Video { keyframe: true }
+    }
+
+    /// Raw frame message: the length of a JSON header as a big-endian
+    /// `u32`, the header, then the frame as received.
+    pub(crate) fn raw_message(&self, tracks: LiveTracks) -> Bytes {
+        let (kind, codec, keyframe) = match self.kind {
+            FrameKind::Video { keyframe } => {
+                let codec = match tracks.video {
+                    VideoCodec::H264 => "h264",
+                    VideoCodec::H265 => "h265",
+                };
+                ("video", codec, keyframe)
+            }
+            FrameKind::Audio => {
+                let codec = match tracks.audio {
+                    Some(AudioCodec::G711A) => "pcma",
+                    Some(AudioCodec::G711U) => "pcmu",
+                    _ => "aac",
+                };
+                ("audio", codec, true)
+            }
+        };
+        let header = serde_json::to_vec(&RawFrameHeader {
+            kind,
+            codec,
+            keyframe,
+            timestamp: self.time_ms,
+            rtp: &self.header,
+        })
+        .expect("Raw frame header is serializable");
+
+        let mut message = BytesMut::with_capacity(4 + header.len() + self.data.len());
+        message.put_u32(header.len() as u32);
+        message.put_slice(&header);
+        message.put_slice(&self.data);
+        message.freeze()
+    }
+}
+
+/// Tracks of the live feed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct LiveTracks {
+    pub(crate) video: VideoCodec,
+    /// AAC or G.711, which the live outputs take as they are.
+    pub(crate) audio: Option<AudioCodec>,
+}
+
+/// Live feed from the start of the cached GOP on.
+pub(crate) struct LiveSubscription {
+    pub(crate) tracks: LiveTracks,
+    pub(crate) gop: Vec<LiveFrame>,
+    pub(crate) frames: broadcast::Receiver<LiveFrame>,
+}
+
+impl LiveSubscription {
+    /// The cached GOP, then the live frames until the stream ends. A
+    /// subscriber that falls too far behind skips to the next I-frame.
+    pub(crate) fn into_stream(self) -> impl Stream<Item = LiveFrame> {
+        let live = stream::unfold(self.frames, |mut frames| async move {
+            let mut resync = false;
+            loop {
+                match frames.recv().await {
+                    Ok(frame) if resync && !frame.is_keyframe() => (),
+                    Ok(frame) => return Some((frame, frames)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        eprintln!(
+                            "Live subscriber fell {skipped} frames behind, resuming at the next I-frame"
+                        );
+                        resync = true;
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+        stream::iter(self.gop).chain(live)
+    }
+}
+
+#[derive(Default)]
+struct FeedState {
+    sender: Option<broadcast::Sender<LiveFrame>>,
+    tracks: Option<LiveTracks>,
+    parameter_sets: Option<ParameterSets>,
+    gop: Vec<LiveFrame>,
+}
+
+/// Per-stream hub fanning the reassembled frames out to any number of
+/// subscribers: live viewers, recorders and the like. It keeps the GOP in
+/// progress so that a new subscriber can start decoding right away, and
+/// repeats the parameter sets on every I-frame that lacks them.
+#[derive(Default)]
+pub(crate) struct LiveFeed {
+    state: Mutex<FeedState>,
+}
+
+impl LiveFeed {
+    /// (Re)starts the feed with new tracks, which ends every current
+    /// subscription.
+    pub(crate) fn start(&self, tracks: LiveTracks) {
+        let mut state = self.state.lock().expect("Live feed poisoned");
+        *state = FeedState {
+            sender: Some(broadcast::Sender::new(CHANNEL_CAPACITY)),
+            tracks: Some(tracks),
+            parameter_sets: Some(ParameterSets::new(tracks.video)),
+            gop: Vec::new(),
+        };
+    }
+
+    /// Ends every current subscription, until the feed is started again.
+    pub(crate) fn stop(&self) {
+        *self.state.lock().expect("Live feed poisoned") = FeedState::default();
+    }
+
+    pub(crate) fn publish(&self, mut frame: LiveFrame) {
+        let mut state = self.state.lock().expect("Live feed poisoned");
+        if let (true, Some(parameter_sets)) = (frame.is_keyframe(), &mut state.parameter_sets) {
+            let mut has_parameter_sets = false;
+            for nal in nal_units(&frame.data) {
+                has_parameter_sets |= parameter_sets.update(nal);
+            }
+            if let (false, Some(annex_b)) = (has_parameter_sets, parameter_sets.annex_b()) {
+                frame.data = [&annex_b[..], &frame.data].concat().into();
+            }
+        }
+        let Some(sender) = &state.sender else {
+            return;
+        };
+        // Without receivers this only drops the frame.
+        let _ = sender.send(frame.clone());
+
+        match frame.kind {
+            FrameKind::Video { keyframe: true } => {
+                state.gop.clear();
+                state.gop.push(frame);
+            }
+            _ if state.gop.is_empty() => (),
+            _ if state.gop.len() >= MAX_GOP_FRAMES => state.gop.clear(),
+            _ => state.gop.push(frame),
+        }
+    }
+
+    /// Number of current subscriptions.
+    pub(crate) fn subscribers(&self) -> usize {
+        let state = self.state.lock().expect("Live feed poisoned");
+        state
+            .sender
+            .as_ref()
+            .map_or(0, |sender| sender.receiver_count())
+    }
+
+    /// Subscribes to the feed, once it has started.
+    pub(crate) fn subscribe(&self) -> Option<LiveSubscription> {
+        let state = self.state.lock().expect("Live feed poisoned");
+        // Subscribing under the lock, no frame is both cached and received.
+        Some(LiveSubscription {
+            tracks: state.tracks?,
+            gop: state.gop.clone(),
+            frames: state.sender.as_ref()?.subscribe(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::{DataType, PayloadType};
+
+    fn frame(time_ms: u64, kind: FrameKind) -> LiveFrame {
+        LiveFrame {
+            time_ms,
+            kind,
+            data: Bytes::new(),
+            header: Arc::new(RtpHeader::new(
+                PayloadType::H264,
+                "353071279375",
+                1,
+                DataType::IFrame,
+            )),
+        }
+    }
+
+    #[test]
+    fn test_gop_cache() {
+        let feed = LiveFeed::default();
+        assert!(feed.subscribe().is_none());
+        feed.start(LiveTracks {
+            video: VideoCodec::H264,
+            audio: Some(AudioCodec::Aac),
+        });
+
+        feed.publish(frame(0, FrameKind::Video { keyframe: false }));
+        feed.publish(frame(40, FrameKind::Video { keyframe: true }));
+        feed.publish(frame(60, FrameKind::Audio));
+        let mut subscription = feed.subscribe().unwrap();
+        let times: Vec<_> = subscription.gop.iter().map(|f| f.time_ms).collect();
+        assert_eq!(times, [40, 60]);
+
+        feed.publish(frame(80, FrameKind::Video { keyframe: false }));
+        assert_eq!(subscription.frames.try_recv().unwrap().time_ms, 80);
+
+        feed.publish(frame(120, FrameKind::Video { keyframe: true }));
+        let subscription = feed.subscribe().unwrap();
+        assert_eq!(subscription.gop.len(), 1);
+        assert_eq!(subscription.gop[0].time_ms, 120);
+    }
+
+    #[test]
+    fn test_raw_message() {
+        let tracks = LiveTracks {
+            video: VideoCodec::H265,
+            audio: None,
+        };
+        let mut frame = frame(40, FrameKind::Video { keyframe: true });
+        frame.data = Bytes::from_static(&[0, 0, 0, 1, 0x26, 0x01]);
+
+        let message = frame.raw_message(tracks);
+        let length = u32::from_be_bytes(message[..4].try_into().unwrap()) as usize;
+        let header: serde_json::Value = serde_json::from_slice(&message[4..4 + length]).unwrap();
+        assert_eq!(header["type"], "video");
+        assert_eq!(header["codec"], "h265");
+        assert_eq!(header["keyframe"], true);
+        assert_eq!(header["timestamp"], 40);
+        assert_eq!(header["rtp"]["terminal_serial_number"], "353071279375");
+        assert_eq!(message[4 + length..], frame.data);
+    }
+
+    #[test]
+    fn test_parameter_sets_repeated() {
+        let feed = LiveFeed::default();
+        feed.start(LiveTracks {
+            video: VideoCodec::H264,
+            audio: None,
+        });
+        let sps = [
+            0x67, 0x64, 0x00, 0x29, 0xAC, 0x15, 0x6A, 0x05, 0x00, 0x5B, 0x90,
+        ];
+        let pps = [0x68, 0xEE, 0x3C, 0xB0];
+        let start_code = [0x00, 0x00, 0x00, 0x01];
+
+        let mut idr = frame(0, FrameKind::Video { keyframe: true });
+        idr.data = [
+            &start_code[..],
+            &sps,
+            &start_code,
+            &pps,
+            &start_code,
+            &[0x65, 0x88],
+        ]
+        .concat()
+        .into();
+        feed.publish(idr.clone());
+        let mut next = frame(40, FrameKind::Video { keyframe: true });
+        next.data = [&start_code[..], &[0x65, 0x88]].concat().into();
+        feed.publish(next);
+
+        let subscription = feed.subscribe().unwrap();
+        assert_eq!(subscription.gop[0].data, idr.data);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_skips_to_keyframe() {
+        let feed = LiveFeed::default();
+        feed.start(LiveTracks {
+            video: VideoCodec::H264,
+            audio: None,
+        });
+        let frames = feed.subscribe().unwrap().into_stream();
+
+        for n in 0..CHANNEL_CAPACITY as u64 + 10 {
+            feed.publish(frame(n, FrameKind::Video { keyframe: n == 0 }));
+        }
+        feed.publish(frame(1000, FrameKind::Audio));
+        feed.publish(frame(1000, FrameKind::Video { keyframe: true }));
+        feed.publish(frame(1040, FrameKind::Video { keyframe: false }));
+        drop(feed);
+
+        let times: Vec<_> = frames.map(|f| f.time_ms).collect().await;
+        assert_eq!(times, [1000, 1040]);
+    }
+}
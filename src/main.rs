@@ -1,10 +1,29 @@
-use jt1078_video_server::run_tcp_server;
-use jt1078_video_server::server::WebServer;
+use jt1078_video_server::server::{Jt808Server, RtspServer, TcpServer, WebServer};
+use jt1078_video_server::{spawn_tcp_server, StreamRegistry, TerminalRegistry};
 
 #[tokio::main]
 async fn main() {
-    let web_server = WebServer::new("127.0.0.1", 8080).expect("Failed to create web server");
-    let tcp_sever_task = run_tcp_server("0.0.0.0", 8000);
+    let registry = StreamRegistry::new();
+    let terminals = TerminalRegistry::new();
+    let web_server = WebServer::new("127.0.0.1", 8080)
+        .expect("Failed to create web server")
+        .with_registry(registry.clone())
+        .with_terminals(terminals.clone());
+    let rtsp_server = RtspServer::new("0.0.0.0", 8554)
+        .expect("Failed to create RTSP server")
+        .with_registry(registry.clone());
+    let rtsp_server_task = tokio::spawn(rtsp_server.run());
+    let jt808_server = Jt808Server::new("0.0.0.0", 7611)
+        .expect("Failed to create JT/T 808 server")
+        .with_terminals(terminals.clone());
+    let jt808_server_task = tokio::spawn(jt808_server.run());
+    let tcp_sever_task = spawn_tcp_server(
+        TcpServer::new("0.0.0.0", 8000)
+            .with_registry(registry)
+            .with_terminals(terminals),
+    );
     let _ = web_server.run().await;
+    rtsp_server_task.abort();
+    jt808_server_task.abort();
     tcp_sever_task.end().await;
 }
@@ -0,0 +1,320 @@
+use crate::processor::VideoCodec;
+
+const TS_PACKET_SIZE: usize = 188;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+
+const VIDEO_STREAM_ID: u8 = 0xE0;
+const AUDIO_STREAM_ID: u8 = 0xC0;
+
+/// ISO/IEC 13818-1 stream type of ADTS framed AAC.
+const AAC_STREAM_TYPE: u8 = 0x0F;
+
+impl VideoCodec {
+    fn stream_type(&self) -> u8 {
+        match self {
+            Self::H264 => 0x1B,
+            Self::H265 => 0x24,
+        }
+    }
+
+    /// Access unit delimiter that HLS requires in front of every frame.
+    fn access_unit_delimiter(&self) -> &'static [u8] {
+        match self {
+            Self::H264 => &[0x00, 0x00, 0x00, 0x01, 0x09, 0xF0],
+            Self::H265 => &[0x00, 0x00, 0x00, 0x01, 0x46, 0x01, 0x50],
+        }
+    }
+
+    fn is_access_unit_delimiter(&self, nal: &[u8]) -> bool {
+        match self {
+            Self::H264 => nal.first().is_some_and(|&bytee| bytee & 0x1F == 9),
+            Self::H265 => nal.first().is_some_and(|&bytee| (bytee >> 1) & 0x3F == 35),
+        }
+    }
+}
+
+/// Minimal MPEG-TS muxer for one program with a video and an optional AAC
+/// track, as required by HLS.
+pub(crate) struct TsMuxer {
+    codec: VideoCodec,
+    audio: bool,
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    video_continuity: u8,
+    audio_continuity: u8,
+}
+
+impl TsMuxer {
+    pub(crate) fn new(codec: VideoCodec, audio: bool) -> Self {
+        Self {
+            codec,
+            audio,
+            pat_continuity: 0,
+            pmt_continuity: 0,
+            video_continuity: 0,
+            audio_continuity: 0,
+        }
+    }
+
+    /// Writes the PAT and PMT, which start every segment so that each one
+    /// can be decoded on its own.
+    pub(crate) fn write_tables(&mut self, out: &mut Vec<u8>) {
+        let mut pat = vec![
+            0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00, 0x01, 0xE0, 0x00,
+        ];
+        pat[10] |= (PMT_PID >> 8) as u8;
+        pat[11] = PMT_PID as u8;
+        write_section(out, PAT_PID, &mut self.pat_continuity, pat);
+
+        let mut streams = vec![(self.codec.stream_type(), VIDEO_PID)];
+        if self.audio {
+            streams.push((AAC_STREAM_TYPE, AUDIO_PID));
+        }
+        let section_length = 9 + 5 * streams.len() + 4;
+        let mut pmt = vec![
+            0x02,
+            0xB0 | (section_length >> 8) as u8,
+            section_length as u8,
+            0x00,
+            0x01,
+            0xC1,
+            0x00,
+            0x00,
+            0xE0 | (VIDEO_PID >> 8) as u8,
+            VIDEO_PID as u8,
+            0xF0,
+            0x00,
+        ];
+        for (stream_type, pid) in streams {
+            pmt.extend_from_slice(&[stream_type, 0xE0 | (pid >> 8) as u8, pid as u8, 0xF0, 0x00]);
+        }
+        write_section(out, PMT_PID, &mut self.pmt_continuity, pmt);
+    }
+
+    /// Writes a video access unit in Annex-B format. `pts` is in 90 kHz
+    /// units and also serves as the program clock.
+    pub(crate) fn write_video(&mut self, out: &mut Vec<u8>, pts: u64, keyframe: bool, data: &[u8]) {
+        let mut pes = pes_header(VIDEO_STREAM_ID, pts, None);
+        if !self.codec.is_access_unit_delimiter(first_nal(data)) {
+            pes.extend_from_slice(self.codec.access_unit_delimiter());
+        }
+        pes.extend_from_slice(data);
+
+        let adaptation = AdaptationField {
+            random_access: keyframe,
+            pcr: Some(pts),
+        };
+        write_pes(out, VIDEO_PID, &mut self.video_continuity, adaptation, &pes);
+    }
+
+    /// Writes one or more ADTS frames starting at `pts`.
+    pub(crate) fn write_audio(&mut self, out: &mut Vec<u8>, pts: u64, data: &[u8]) {
+        if !self.audio {
+            return;
+        }
+        let mut pes = pes_header(AUDIO_STREAM_ID, pts, Some(data.len()));
+        pes.extend_from_slice(data);
+
+        let adaptation = AdaptationField {
+            random_access: true,
+            pcr: None,
+        };
+        write_pes(out, AUDIO_PID, &mut self.audio_continuity, adaptation, &pes);
+    }
+}
+
+struct AdaptationField {
+    random_access: bool,
+    pcr: Option<u64>,
+}
+
+impl AdaptationField {
+    /// Adaptation field of the first packet of a PES, without its length.
+    fn encode(&self) -> Vec<u8> {
+        let mut field = vec![0x00];
+        if self.random_access {
+            field[0] |= 0x40;
+        }
+        if let Some(pcr) = self.pcr {
+            field[0] |= 0x10;
+            let base = pcr & 0x1_FFFF_FFFF;
+            field.extend_from_slice(&[
+                (base >> 25) as u8,
+                (base >> 17) as u8,
+                (base >> 9) as u8,
+                (base >> 1) as u8,
+                ((base & 1) as u8) << 7 | 0x7E,
+                0x00,
+            ]);
+        }
+        field
+    }
+}
+
+fn first_nal(data: &[u8]) -> &[u8] {
+    match data {
+        [0, 0, 1, rest @ ..] | [0, 0, 0, 1, rest @ ..] => rest,
+        _ => data,
+    }
+}
+
+fn pes_header(stream_id: u8, pts: u64, payload_length: Option<usize>) -> Vec<u8> {
+    // Video PES packets may leave the length unbounded.
+    let length = payload_length
+        .map(|len| len + 8)
+        .filter(|&len| len <= u16::MAX as usize)
+        .unwrap_or(0) as u16;
+
+    let pts = pts & 0x1_FFFF_FFFF;
+    let mut header = vec![0x00, 0x00, 0x01, stream_id];
+    header.extend_from_slice(&length.to_be_bytes());
+    header.extend_from_slice(&[
+        0x80,
+        0x80,
+        0x05,
+        0x21 | ((pts >> 29) as u8 & 0x0E),
+        (pts >> 22) as u8,
+        (pts >> 14) as u8 | 0x01,
+        (pts >> 7) as u8,
+        (pts << 1) as u8 | 0x01,
+    ]);
+    header
+}
+
+fn write_pes(
+    out: &mut Vec<u8>,
+    pid: u16,
+    continuity: &mut u8,
+    adaptation: AdaptationField,
+    pes: &[u8],
+) {
+    let mut offset = 0;
+    while offset < pes.len() {
+        let first = offset == 0;
+        let mut field = first.then(|| adaptation.encode());
+
+        let field_length = field.as_ref().map_or(0, |field| field.len() + 1);
+        let remaining = pes.len() - offset;
+        let mut payload_length = TS_PACKET_SIZE - 4 - field_length;
+        if remaining < payload_length {
+            // The last packet is padded with adaptation field stuffing.
+            let stuffing = payload_length - remaining;
+            match &mut field {
+                Some(field) => field.resize(field.len() + stuffing, 0xFF),
+                None if stuffing == 1 => field = Some(Vec::new()),
+                None => {
+                    let mut stuffed = vec![0x00];
+                    stuffed.resize(stuffing - 1, 0xFF);
+                    field = Some(stuffed);
+                }
+            }
+            payload_length = remaining;
+        }
+
+        write_packet_header(out, pid, first, continuity, field.is_some());
+        if let Some(field) = field {
+            out.push(field.len() as u8);
+            out.extend_from_slice(&field);
+        }
+        out.extend_from_slice(&pes[offset..offset + payload_length]);
+        offset += payload_length;
+    }
+}
+
+fn write_section(out: &mut Vec<u8>, pid: u16, continuity: &mut u8, mut section: Vec<u8>) {
+    let crc = crc32(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+
+    let start = out.len();
+    write_packet_header(out, pid, true, continuity, false);
+    out.push(0x00);
+    out.extend_from_slice(&section);
+    out.resize(start + TS_PACKET_SIZE, 0xFF);
+}
+
+fn write_packet_header(
+    out: &mut Vec<u8>,
+    pid: u16,
+    payload_unit_start: bool,
+    continuity: &mut u8,
+    adaptation: bool,
+) {
+    let adaptation_control = if adaptation { 0x30 } else { 0x10 };
+    out.extend_from_slice(&[
+        0x47,
+        u8::from(payload_unit_start) << 6 | (pid >> 8) as u8 & 0x1F,
+        pid as u8,
+        adaptation_control | *continuity,
+    ]);
+    *continuity = (*continuity + 1) & 0x0F;
+}
+
+/// CRC-32/MPEG-2 of a PSI section.
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFF, |crc, &bytee| {
+        (0..8).fold(crc ^ (bytee as u32) << 24, |crc, _| {
+            if crc & 0x8000_0000 != 0 {
+                crc << 1 ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tables() {
+        let mut muxer = TsMuxer::new(VideoCodec::H264, true);
+        let mut out = Vec::new();
+        muxer.write_tables(&mut out);
+
+        assert_eq!(out.len(), 2 * TS_PACKET_SIZE);
+        // Well known PAT of a single program on PID 0x1000.
+        assert_eq!(
+            out[..21],
+            [
+                0x47, 0x40, 0x00, 0x10, 0x00, 0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00,
+                0x01, 0xF0, 0x00, 0x2A, 0xB1, 0x04, 0xB2
+            ]
+        );
+        assert_eq!(out[TS_PACKET_SIZE..TS_PACKET_SIZE + 3], [0x47, 0x50, 0x00]);
+    }
+
+    #[test]
+    fn test_video_packets() {
+        let mut muxer = TsMuxer::new(VideoCodec::H264, false);
+        let frame = [[0x00, 0x00, 0x00, 0x01, 0x65].as_slice(), &[0xAB; 500]].concat();
+        let mut out = Vec::new();
+        muxer.write_video(&mut out, 90_000, true, &frame);
+        muxer.write_video(&mut out, 93_600, false, &frame);
+
+        assert_eq!(out.len() % TS_PACKET_SIZE, 0);
+        let packets: Vec<&[u8]> = out.chunks(TS_PACKET_SIZE).collect();
+        assert!(packets.iter().all(|packet| packet[0] == 0x47));
+        let continuity: Vec<u8> = packets.iter().map(|packet| packet[3] & 0x0F).collect();
+        assert_eq!(continuity, (0..packets.len() as u8).collect::<Vec<_>>());
+
+        // Keyframe: random access indicator and PCR, then the PES header
+        // and the inserted access unit delimiter.
+        assert_eq!(packets[0][1], 0x41);
+        assert_eq!(packets[0][5], 0x50);
+        let pes = &packets[0][12..];
+        assert_eq!(pes[..4], [0x00, 0x00, 0x01, 0xE0]);
+        assert_eq!(pes[14..20], [0x00, 0x00, 0x00, 0x01, 0x09, 0xF0]);
+    }
+
+    #[test]
+    fn test_pts() {
+        let header = pes_header(AUDIO_STREAM_ID, 0x1_2345_6789, Some(10));
+        assert_eq!(header[4..6], [0x00, 18]);
+        assert_eq!(header[9..14], [0x29, 0x8D, 0x15, 0xCF, 0x13]);
+    }
+}
@@ -0,0 +1,504 @@
+use crate::processor::VideoCodec;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum NalKind {
+    Vps,
+    Sps,
+    Pps,
+    AccessUnitDelimiter,
+    Other,
+}
+
+impl VideoCodec {
+    pub(crate) fn nal_kind(&self, nal: &[u8]) -> NalKind {
+        let Some(&header) = nal.first() else {
+            return NalKind::Other;
+        };
+        match (self, header) {
+            (Self::H264, header) => match header & 0x1F {
+                7 => NalKind::Sps,
+                8 => NalKind::Pps,
+                9 => NalKind::AccessUnitDelimiter,
+                _ => NalKind::Other,
+            },
+            (Self::H265, header) => match (header >> 1) & 0x3F {
+                32 => NalKind::Vps,
+                33 => NalKind::Sps,
+                34 => NalKind::Pps,
+                35 => NalKind::AccessUnitDelimiter,
+                _ => NalKind::Other,
+            },
+        }
+    }
+}
+
+/// Splits an Annex-B byte stream into its NAL units. Data without any start
+/// code is returned as a single unit.
+pub(crate) fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0x00, 0x00, 0x01] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    if starts.is_empty() {
+        return vec![data];
+    }
+
+    let ends = starts.iter().skip(1).map(|&start| start - 3);
+    starts
+        .iter()
+        .zip(ends.chain([data.len()]))
+        .map(|(&start, end)| {
+            // Drops the leading zero of a 4-byte start code and any
+            // trailing_zero_8bits.
+            let mut nal = &data[start..end];
+            while let [rest @ .., 0x00] = nal {
+                nal = rest;
+            }
+            nal
+        })
+        .filter(|nal| !nal.is_empty())
+        .collect()
+}
+
+/// The latest parameter sets seen in the stream, from which the decoder
+/// configuration records of the containers are built.
+pub(crate) struct ParameterSets {
+    codec: VideoCodec,
+    vps: Option<Vec<u8>>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl ParameterSets {
+    pub(crate) fn new(codec: VideoCodec) -> Self {
+        Self {
+            codec,
+            vps: None,
+            sps: None,
+            pps: None,
+        }
+    }
+
+    /// Stores `nal` if it is a parameter set. Returns whether it was one.
+    pub(crate) fn update(&mut self, nal: &[u8]) -> bool {
+        let slot = match self.codec.nal_kind(nal) {
+            NalKind::Vps => &mut self.vps,
+            NalKind::Sps => &mut self.sps,
+            NalKind::Pps => &mut self.pps,
+            _ => return false,
+        };
+        if slot.as_deref() != Some(nal) {
+            *slot = Some(nal.to_vec());
+        }
+        true
+    }
+
+    /// Whether every parameter set the codec needs has been seen.
+    pub(crate) fn is_complete(&self) -> bool {
+        let vps = self.codec == VideoCodec::H264 || self.vps.is_some();
+        vps && self.sps.is_some() && self.pps.is_some()
+    }
+
+    /// Coded picture size, from the SPS.
+    pub(crate) fn dimensions(&self) -> Option<(u32, u32)> {
+        let sps = rbsp(self.sps.as_deref()?);
+        match self.codec {
+            VideoCodec::H264 => h264_dimensions(&sps),
+            VideoCodec::H265 => H265Sps::parse(&sps).map(|sps| (sps.width, sps.height)),
+        }
+    }
+
+    /// RFC 6381 codec string, as used in the `CODECS` of HLS and the
+    /// `codecs` of DASH.
+    pub(crate) fn codec_string(&self) -> Option<String> {
+        let sps = self.sps.as_deref()?;
+        match self.codec {
+            VideoCodec::H264 => {
+                let profile = sps.get(1..4)?;
+                Some(format!(
+                    "avc1.{:02X}{:02X}{:02X}",
+                    profile[0], profile[1], profile[2]
+                ))
+            }
+            VideoCodec::H265 => {
+                let ptl = H265Sps::parse(&rbsp(sps))?.profile_tier_level;
+                let space = ["", "A", "B", "C"][ptl[0] as usize >> 6];
+                let tier = if ptl[0] & 0x20 == 0 { 'L' } else { 'H' };
+                let compatibility = u32::from_be_bytes(ptl[1..5].try_into().ok()?).reverse_bits();
+                let mut codec = format!(
+                    "hvc1.{space}{}.{compatibility:X}.{tier}{}",
+                    ptl[0] & 0x1F,
+                    ptl[11]
+                );
+                let constraints = &ptl[5..11];
+                let used = constraints
+                    .iter()
+                    .rposition(|&b| b != 0)
+                    .map_or(0, |i| i + 1);
+                for constraint in &constraints[..used] {
+                    let _ = write!(codec, ".{constraint:X}");
+                }
+                Some(codec)
+            }
+        }
+    }
+
+    /// The parameter sets in Annex-B format, once complete.
+    pub(crate) fn annex_b(&self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut data = Vec::new();
+        for nal in [&self.vps, &self.sps, &self.pps].into_iter().flatten() {
+            data.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            data.extend_from_slice(nal);
+        }
+        Some(data)
+    }
+
+    /// `AVCDecoderConfigurationRecord` (ISO/IEC 14496-15 5.3.3) or
+    /// `HEVCDecoderConfigurationRecord` (8.3.3), with 4-byte NAL lengths.
+    pub(crate) fn decoder_configuration(&self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        match self.codec {
+            VideoCodec::H264 => self.avc_configuration(),
+            VideoCodec::H265 => self.hevc_configuration(),
+        }
+    }
+
+    fn avc_configuration(&self) -> Option<Vec<u8>> {
+        let sps = self.sps.as_deref()?;
+        let pps = self.pps.as_deref()?;
+        let profile = sps.get(1..4)?;
+
+        let mut record = vec![0x01, profile[0], profile[1], profile[2], 0xFF, 0xE1];
+        record.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        record.extend_from_slice(sps);
+        record.push(0x01);
+        record.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        record.extend_from_slice(pps);
+        Some(record)
+    }
+
+    fn hevc_configuration(&self) -> Option<Vec<u8>> {
+        let vps = self.vps.as_deref()?;
+        let sps = self.sps.as_deref()?;
+        let pps = self.pps.as_deref()?;
+        let parsed = H265Sps::parse(&rbsp(sps))?;
+
+        let mut record = vec![0x01];
+        record.extend_from_slice(&parsed.profile_tier_level);
+        record.extend_from_slice(&[
+            // min_spatial_segmentation_idc and parallelismType unknown.
+            0xF0,
+            0x00,
+            0xFC,
+            0xFC | parsed.chroma_format_idc as u8,
+            0xF8 | parsed.bit_depth_luma_minus8 as u8,
+            0xF8 | parsed.bit_depth_chroma_minus8 as u8,
+            // Average frame rate unknown.
+            0x00,
+            0x00,
+            (parsed.max_sub_layers as u8) << 3 | u8::from(parsed.temporal_id_nesting) << 2 | 0x03,
+            0x03,
+        ]);
+        for (nal_type, nal) in [(32, vps), (33, sps), (34, pps)] {
+            // array_completeness: the parameter sets are only out of band.
+            record.extend_from_slice(&[0x80 | nal_type, 0x00, 0x01]);
+            record.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            record.extend_from_slice(nal);
+        }
+        Some(record)
+    }
+}
+
+/// Removes the emulation prevention bytes of a NAL unit.
+fn rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &bytee in nal {
+        if zeros >= 2 && bytee == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if bytee == 0x00 { zeros + 1 } else { 0 };
+        rbsp.push(bytee);
+    }
+    rbsp
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| {
+            let bytee = self.data.get(self.position / 8)?;
+            let bit = (bytee >> (7 - self.position % 8)) & 1;
+            self.position += 1;
+            Some(value << 1 | bit as u32)
+        })
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        self.bits(1).map(|bit| bit == 1)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.position += count;
+        (self.position <= self.data.len() * 8).then_some(())
+    }
+
+    /// Unsigned Exp-Golomb code.
+    fn ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while !self.flag()? {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.bits(leading_zeros)?)
+    }
+
+    /// Signed Exp-Golomb code.
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()? as i64;
+        Some(if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -value / 2
+        } as i32)
+    }
+}
+
+/// Picture size from an H.264 SPS (ITU-T H.264 7.3.2.1.1).
+fn h264_dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    let mut reader = BitReader::new(sps.get(1..)?);
+    let profile_idc = reader.bits(8)?;
+    reader.skip(16)?;
+    reader.ue()?;
+
+    let mut chroma_format_idc = 1;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = reader.ue()?;
+        if chroma_format_idc == 3 {
+            reader.skip(1)?;
+        }
+        reader.ue()?;
+        reader.ue()?;
+        reader.skip(1)?;
+        if reader.flag()? {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if reader.flag()? {
+                    skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    reader.ue()?;
+    match reader.ue()? {
+        0 => {
+            reader.ue()?;
+        }
+        1 => {
+            reader.skip(1)?;
+            reader.se()?;
+            reader.se()?;
+            for _ in 0..reader.ue()? {
+                reader.se()?;
+            }
+        }
+        _ => (),
+    }
+    reader.ue()?;
+    reader.skip(1)?;
+
+    let width_in_mbs = reader.ue()? + 1;
+    let height_in_map_units = reader.ue()? + 1;
+    let frame_mbs_only = reader.flag()? as u32;
+    if frame_mbs_only == 0 {
+        reader.skip(1)?;
+    }
+    reader.skip(1)?;
+
+    let mut width = width_in_mbs * 16;
+    let mut height = (2 - frame_mbs_only) * height_in_map_units * 16;
+    if reader.flag()? {
+        let (crop_x, crop_y) = match chroma_format_idc {
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        let (left, right) = (reader.ue()?, reader.ue()?);
+        let (top, bottom) = (reader.ue()?, reader.ue()?);
+        width = width.checked_sub(crop_x * (left + right))?;
+        height = height.checked_sub(crop_y * (top + bottom))?;
+    }
+    Some((width, height))
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Option<()> {
+    let mut last = 8;
+    let mut next = 8;
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + reader.se()? + 256) % 256;
+        }
+        if next != 0 {
+            last = next;
+        }
+    }
+    Some(())
+}
+
+/// Fields of an H.265 SPS (ITU-T H.265 7.3.2.2) needed for the decoder
+/// configuration record.
+struct H265Sps {
+    /// general_profile_space up to general_level_idc, as coded.
+    profile_tier_level: [u8; 12],
+    max_sub_layers: u32,
+    temporal_id_nesting: bool,
+    chroma_format_idc: u32,
+    width: u32,
+    height: u32,
+    bit_depth_luma_minus8: u32,
+    bit_depth_chroma_minus8: u32,
+}
+
+impl H265Sps {
+    fn parse(sps: &[u8]) -> Option<Self> {
+        let mut reader = BitReader::new(sps.get(2..)?);
+        reader.skip(4)?;
+        let max_sub_layers_minus1 = reader.bits(3)?;
+        let temporal_id_nesting = reader.flag()?;
+
+        let profile_tier_level = sps.get(3..15)?.try_into().ok()?;
+        reader.skip(96)?;
+        let mut sub_layers = Vec::new();
+        for _ in 0..max_sub_layers_minus1 {
+            sub_layers.push((reader.flag()?, reader.flag()?));
+        }
+        if max_sub_layers_minus1 > 0 {
+            reader.skip(2 * (8 - max_sub_layers_minus1 as usize))?;
+        }
+        for (profile_present, level_present) in sub_layers {
+            if profile_present {
+                reader.skip(88)?;
+            }
+            if level_present {
+                reader.skip(8)?;
+            }
+        }
+
+        reader.ue()?;
+        let chroma_format_idc = reader.ue()?;
+        if chroma_format_idc == 3 {
+            reader.skip(1)?;
+        }
+        let mut width = reader.ue()?;
+        let mut height = reader.ue()?;
+        if reader.flag()? {
+            let (crop_x, crop_y) = match chroma_format_idc {
+                1 => (2, 2),
+                2 => (2, 1),
+                _ => (1, 1),
+            };
+            let (left, right) = (reader.ue()?, reader.ue()?);
+            let (top, bottom) = (reader.ue()?, reader.ue()?);
+            width = width.checked_sub(crop_x * (left + right))?;
+            height = height.checked_sub(crop_y * (top + bottom))?;
+        }
+
+        Some(Self {
+            profile_tier_level,
+            max_sub_layers: max_sub_layers_minus1 + 1,
+            temporal_id_nesting,
+            chroma_format_idc,
+            width,
+            height,
+            bit_depth_luma_minus8: reader.ue()?,
+            bit_depth_chroma_minus8: reader.ue()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nal_units() {
+        let data = [
+            0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x00, 0x00, 0x01, 0x68, 0xCE, 0x00, 0x00, 0x00,
+            0x01, 0x65, 0x88, 0x00,
+        ];
+        assert_eq!(
+            nal_units(&data),
+            vec![&[0x67, 0x42][..], &[0x68, 0xCE], &[0x65, 0x88]]
+        );
+        assert_eq!(nal_units(&[0x41, 0x9A]), vec![&[0x41, 0x9A][..]]);
+    }
+
+    #[test]
+    fn test_avc_configuration() {
+        let mut parameter_sets = ParameterSets::new(VideoCodec::H264);
+        let sps = [
+            0x67, 0x64, 0x00, 0x29, 0xAC, 0x15, 0x6A, 0x05, 0x00, 0x5B, 0x90,
+        ];
+        assert!(parameter_sets.update(&sps));
+        assert!(!parameter_sets.update(&[0x65, 0x88]));
+        assert_eq!(parameter_sets.decoder_configuration(), None);
+        assert!(parameter_sets.update(&[0x68, 0xEE, 0x3C, 0xB0]));
+
+        assert_eq!(parameter_sets.dimensions(), Some((1280, 720)));
+        assert_eq!(parameter_sets.codec_string().unwrap(), "avc1.640029");
+        let annex_b = parameter_sets.annex_b().unwrap();
+        assert_eq!(
+            nal_units(&annex_b),
+            vec![&sps[..], &[0x68, 0xEE, 0x3C, 0xB0]]
+        );
+        let record = parameter_sets.decoder_configuration().unwrap();
+        assert_eq!(record[..8], [0x01, 0x64, 0x00, 0x29, 0xFF, 0xE1, 0x00, 11]);
+        assert_eq!(record[19..], [0x01, 0x00, 0x04, 0x68, 0xEE, 0x3C, 0xB0]);
+    }
+
+    #[test]
+    fn test_hevc_configuration() {
+        let data = std::fs::read("data/test_stream.h265").unwrap();
+        let mut parameter_sets = ParameterSets::new(VideoCodec::H265);
+        for nal in nal_units(&data).into_iter().take(3) {
+            assert!(parameter_sets.update(nal));
+        }
+
+        assert_eq!(parameter_sets.dimensions(), Some((320, 240)));
+        assert_eq!(parameter_sets.codec_string().unwrap(), "hvc1.1.6.L93.90");
+        let record = parameter_sets.decoder_configuration().unwrap();
+        // Main profile, level 3.1, 4:2:0 8-bit, three parameter set arrays.
+        assert_eq!(record[..2], [0x01, 0x01]);
+        assert_eq!(record[12], 93);
+        assert_eq!(record[16..19], [0xFD, 0xF8, 0xF8]);
+        assert_eq!(record[22], 3);
+        assert_eq!(record[23], 0x80 | 32);
+    }
+}
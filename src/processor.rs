@@ -1,109 +1,630 @@
-use crate::rtp::RtpPacket;
+use crate::aac::AacEncoder;
+use crate::assembler::Frame;
+use crate::audio::{strip_hisilicon_header, AudioCodec, AudioDecoder};
+use crate::ffmpeg::FfmpegPipeline;
+use crate::hls::{Clock, HlsSegmenter, CLEAN_UP_DELAY, DEFAULT_FRAME_INTERVAL_MS};
+use crate::idle::{stop_when_idle, IdlePolicy};
+use crate::live::{FrameKind, LiveFrame, LiveTracks};
+use crate::registry::{MediaState, StreamKey, StreamRegistration, StreamRegistry};
+use crate::relay::{relay, RtmpPushRule};
+use crate::rtp::{DataType, PayloadType};
 use crate::Result;
-use std::path::PathBuf;
-use std::process::Stdio;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
-use tokio::process::{Child, ChildStdin, Command};
 use tokio::sync::mpsc::Receiver;
-use tokio::time::timeout;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// How long to wait for an audio frame before starting a video-only
+/// pipeline. Neither backend can add a track once it is running.
+const AUDIO_PROBE_MS: u64 = 1000;
+
+/// Upper bound of frames held back while probing for audio.
+const MAX_PENDING_FRAMES: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum VideoCodec {
+    H264,
+    H265,
+}
+
+impl VideoCodec {
+    fn from_payload_type(payload_type: PayloadType) -> Option<Self> {
+        match payload_type {
+            PayloadType::H264 => Some(Self::H264),
+            PayloadType::H265 => Some(Self::H265),
+            _ => None,
+        }
+    }
+
+    /// HEVC in MPEG-TS does not play in most browsers or on Apple devices.
+    fn default_container(&self) -> HlsContainer {
+        match self {
+            Self::H264 => HlsContainer::MpegTs,
+            Self::H265 => HlsContainer::Fmp4,
+        }
+    }
+}
+
+/// What turns the frames into HLS.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HlsBackend {
+    /// The built-in segmenter. It copies the video and AAC audio, and
+    /// encodes G.711 and ADPCM audio to AAC.
+    #[default]
+    Native,
+    /// One `ffmpeg` process per stream, which transcodes G.711 and ADPCM
+    /// audio to AAC.
+    Ffmpeg,
+}
+
+impl FromStr for HlsBackend {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "native" => Ok(Self::Native),
+            "ffmpeg" => Ok(Self::Ffmpeg),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid HLS backend '{s}'"),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HlsBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Native => "native",
+            Self::Ffmpeg => "ffmpeg",
+        })
+    }
+}
+
+/// Segment format of the HLS output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HlsContainer {
+    /// MPEG-TS segments.
+    MpegTs,
+    /// Fragmented MP4 (CMAF) segments with an `init.mp4` initialisation
+    /// segment, referenced by `EXT-X-MAP`.
+    Fmp4,
+}
+
+impl HlsContainer {
+    pub(crate) fn segment_extension(&self) -> &'static str {
+        match self {
+            Self::MpegTs => "ts",
+            Self::Fmp4 => "m4s",
+        }
+    }
+}
+
+impl FromStr for HlsContainer {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "mpegts" => Ok(Self::MpegTs),
+            "fmp4" => Ok(Self::Fmp4),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid HLS container '{s}'"),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HlsContainer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::MpegTs => "mpegts",
+            Self::Fmp4 => "fmp4",
+        })
+    }
+}
+
+/// HLS output settings.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct HlsConfig {
+    pub(crate) backend: HlsBackend,
+    /// Without a container, each codec gets the one that plays most widely.
+    pub(crate) container: Option<HlsContainer>,
+    /// LL-HLS with partial segments, native backend only.
+    pub(crate) low_latency: bool,
+    /// MPEG-DASH manifest for fMP4 streams, native backend only.
+    pub(crate) dash: bool,
+}
+
+enum Pipeline {
+    /// With an encoder for audio other than AAC.
+    Native(HlsSegmenter, Option<AacEncoder>),
+    Ffmpeg(FfmpegPipeline),
+}
 
 pub(crate) struct RtpProcessor {
-    child_stdin: Option<ChildStdin>,
+    audio: Option<AudioDecoder>,
+    codec: Option<VideoCodec>,
+    /// How long the files of the stream are kept once it has ended.
+    clean_up_delay: Duration,
+    config: HlsConfig,
     dir_init: bool,
-    ffmpeg_process: Option<Child>,
-    imei: String,
+    frame_interval_ms: u64,
+    idle: IdlePolicy,
+    /// Stops the stream once nobody watches it.
+    idle_watch: Option<JoinHandle<()>>,
+    /// Media clock of the live feed, which runs with either backend.
+    live_clock: Clock,
+    pending: Vec<Frame>,
+    pipeline: Option<Pipeline>,
+    registration: Option<StreamRegistration>,
+    registry: StreamRegistry,
+    /// RTMP publishing of the live feed, which outlives pipeline restarts.
+    relays: Vec<JoinHandle<()>>,
+    rtmp_push: Arc<[RtmpPushRule]>,
+    unsupported: HashSet<PayloadType>,
 }
 
 impl RtpProcessor {
-    pub fn new() -> Self {
+    pub fn new(registry: StreamRegistry, config: HlsConfig) -> Self {
         Self {
-            child_stdin: None,
+            audio: None,
+            codec: None,
+            clean_up_delay: CLEAN_UP_DELAY,
+            config,
             dir_init: false,
-            ffmpeg_process: None,
-            imei: String::new(),
+            frame_interval_ms: DEFAULT_FRAME_INTERVAL_MS,
+            idle: IdlePolicy::default(),
+            idle_watch: None,
+            live_clock: Clock::default(),
+            pending: Vec::new(),
+            pipeline: None,
+            registration: None,
+            registry,
+            relays: Vec::new(),
+            rtmp_push: Arc::new([]),
+            unsupported: HashSet::new(),
         }
     }
 
-    pub async fn listen(&mut self, mut channel: Receiver<RtpPacket>) {
-        while let Some(packet) = channel.recv().await {
-            if let Err(e) = self.process(packet).await {
-                eprintln!("Failed to process packet ({}): {e}", self.imei);
+    /// Publishes the stream over RTMP for each rule matching it.
+    pub fn with_rtmp_push(mut self, rtmp_push: Arc<[RtmpPushRule]>) -> Self {
+        self.rtmp_push = rtmp_push;
+        self
+    }
+
+    /// Stops the stream according to `idle` once nobody watches it.
+    pub(crate) fn with_idle_policy(mut self, idle: IdlePolicy) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// Keeps the files of the ended stream for `delay` rather than one
+    /// target duration.
+    pub(crate) fn with_clean_up_delay(mut self, delay: Duration) -> Self {
+        self.clean_up_delay = delay;
+        self
+    }
+
+    fn name(&self) -> String {
+        match &self.registration {
+            Some(registration) => registration.key().to_string(),
+            None => "unregistered".to_string(),
+        }
+    }
+
+    pub async fn listen(&mut self, mut channel: Receiver<Frame>) {
+        let mut media: Option<watch::Receiver<MediaState>> = None;
+        let mut state = MediaState::default();
+        loop {
+            let changed = async {
+                match &mut media {
+                    Some(media) => media
+                        .wait_for(|media| *media != state)
+                        .await
+                        .ok()
+                        .map(|m| *m),
+                    None => std::future::pending().await,
+                }
+            };
+            let frame = tokio::select! {
+                frame = channel.recv() => frame,
+                Some(changed) = changed => {
+                    if changed.closed {
+                        println!("Transfer closed by the platform ({})", self.name());
+                        break;
+                    }
+                    if let Err(e) = self.change_media(state, changed).await {
+                        eprintln!("Failed to follow the transfer state ({}): {e}", self.name());
+                        break;
+                    }
+                    state = changed;
+                    continue;
+                }
+            };
+            let Some(frame) = frame else {
+                break;
+            };
+            if let Err(e) = self.process(frame).await {
+                eprintln!("Failed to process frame ({}): {e}", self.name());
                 break;
             }
+            if media.is_none() {
+                media = self
+                    .registration
+                    .as_ref()
+                    .map(|registration| registration.stream().media.subscribe());
+            }
         }
 
-        if let Some(mut stdin) = self.child_stdin.take() {
-            if let Err(e) = stdin.shutdown().await {
-                eprintln!("Failed to shutdown ffmpeg stdin ({}): {e}", self.imei);
-            }
+        for relay in self.relays.drain(..) {
+            relay.abort();
+        }
+        if let Some(idle_watch) = self.idle_watch.take() {
+            idle_watch.abort();
         }
+        self.stop_pipeline().await;
 
-        if let Some(mut process) = self.ffmpeg_process.take() {
-            // if let Err(e) = process.kill().await {
-            //     eprintln!("Failed to kill ffmpeg process ({}): {e}", self.imei);
-            // }
-            if let Err(e) = timeout(Duration::from_secs(10), process.wait()).await {
-                eprintln!("Failed to wait for ffmpeg process ({}): {e}", self.imei);
-                let _ = process.kill().await;
-            }
+        // Hangs up on the terminal, the playlist stays up a while with its
+        // end for the players.
+        drop(channel);
+        if let Some(registration) = &self.registration {
+            registration.end();
+            tokio::time::sleep(self.clean_up_delay).await;
         }
 
         if let Err(e) = self.clean_up().await {
-            eprintln!("Failed to clean up directories ({}): {e}", self.imei);
+            eprintln!("Failed to clean up directories ({}): {e}", self.name());
         }
+
+        // Release the stream only once its files are gone, so a new
+        // publisher cannot race with the clean up.
+        self.registration = None;
+    }
+
+    /// Follows the 0x9102 commands of the platform in the native segmenter:
+    /// a pause ends the segment in progress, a switch between the main and
+    /// the sub stream starts a new period.
+    async fn change_media(&mut self, previous: MediaState, state: MediaState) -> Result<()> {
+        let Some(Pipeline::Native(segmenter, _)) = &mut self.pipeline else {
+            return Ok(());
+        };
+        if state.paused && !previous.paused {
+            segmenter.cut().await?;
+        }
+        if state.stream_type != previous.stream_type {
+            segmenter.switch();
+        }
+        Ok(())
     }
 
-    async fn process(&mut self, packet: RtpPacket) -> Result<()> {
+    async fn process(&mut self, frame: Frame) -> Result<()> {
         if !self.dir_init {
-            let imei = &packet.header.terminal_serial_number;
-            self.imei.push_str(imei);
-            self.init_dir(imei).await?;
-            self.init_ffmpeg_process().await?;
+            let key = StreamKey::new(
+                &frame.header.terminal_serial_number,
+                frame.header.logical_channel_number,
+            );
+            let registration = self.registry.register_after_end(key.clone()).await?;
+            if self.idle.timeout.is_some() {
+                self.idle_watch = Some(tokio::spawn(stop_when_idle(
+                    Arc::clone(registration.stream()),
+                    key.clone(),
+                    self.idle.clone(),
+                )));
+            }
+            self.registration = Some(registration);
+            self.init_dir(&key).await?;
+        } else if let Some(registration) = &self.registration {
+            let key = registration.key();
+            if key.imei != frame.header.terminal_serial_number
+                || key.channel != frame.header.logical_channel_number
+            {
+                eprintln!(
+                    "Ignoring frame for {}/{} on connection publishing {key}",
+                    frame.header.terminal_serial_number, frame.header.logical_channel_number
+                );
+                return Ok(());
+            }
+        }
+
+        if frame.header.data_type.is_video() {
+            if !self.select_codec(frame.header.payload_type).await? {
+                return Ok(());
+            }
+        } else if frame.header.data_type.is_audio() {
+            if !self.select_audio_codec(frame.header.payload_type) {
+                return Ok(());
+            }
+        } else {
+            return Ok(());
         }
 
-        if let Some(stdin) = &mut self.child_stdin {
-            stdin.write_all(&packet.payload).await?;
-            stdin.flush().await?;
+        if self.pipeline.is_none() {
+            self.pending.push(frame);
+            if self.ready_to_start() {
+                self.init_pipeline().await?;
+                for frame in std::mem::take(&mut self.pending) {
+                    self.write_frame(frame).await?;
+                }
+            }
+            return Ok(());
         }
 
-        Ok(())
+        self.write_frame(frame).await
+    }
+
+    async fn write_frame(&mut self, frame: Frame) -> Result<()> {
+        self.publish_live(&frame);
+
+        if frame.header.data_type.is_audio() {
+            let Some(decoder) = &mut self.audio else {
+                return Ok(());
+            };
+            let timestamp = frame.header.timestamp;
+            match &mut self.pipeline {
+                Some(Pipeline::Native(segmenter, None)) if decoder.codec() == AudioCodec::Aac => {
+                    segmenter.write_audio(timestamp, &decoder.decode(timestamp, &frame.payload))
+                }
+                Some(Pipeline::Native(segmenter, Some(encoder))) => {
+                    let pcm = decoder.decode(timestamp, &frame.payload);
+                    if let Some((time, data)) = encoder.encode(timestamp, &pcm) {
+                        segmenter.write_audio(Some(time), &data);
+                    }
+                }
+                Some(Pipeline::Ffmpeg(ffmpeg)) => {
+                    ffmpeg.write_audio(decoder.decode(timestamp, &frame.payload))
+                }
+                _ => (),
+            }
+            return Ok(());
+        }
+
+        match &mut self.pipeline {
+            Some(Pipeline::Native(segmenter, _)) => {
+                segmenter.write_video(&frame.header, &frame.payload).await
+            }
+            Some(Pipeline::Ffmpeg(ffmpeg)) => ffmpeg.write_video(&frame.payload).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Passes the frame on to the live subscribers. Only AAC and G.711
+    /// audio is shared, which the live outputs take as they are.
+    fn publish_live(&mut self, frame: &Frame) {
+        let Some(registration) = &self.registration else {
+            return;
+        };
+        let header = &frame.header;
+        let (time_ms, kind) = if header.data_type.is_audio() {
+            if self.live_audio().is_none() {
+                return;
+            }
+            let Some(time_ms) = self.live_clock.audio(header.timestamp) else {
+                return;
+            };
+            (time_ms, FrameKind::Audio)
+        } else {
+            if let Some(interval) = header.last_frame_interval.filter(|&interval| interval > 0) {
+                self.frame_interval_ms = interval as u64;
+            }
+            let time_ms = self
+                .live_clock
+                .video(header.timestamp, self.frame_interval_ms);
+            let keyframe = header.data_type == DataType::IFrame;
+            (time_ms, FrameKind::Video { keyframe })
+        };
+        registration.stream().feed.publish(LiveFrame {
+            time_ms,
+            kind,
+            data: frame
+                .payload
+                .slice_ref(strip_hisilicon_header(&frame.payload)),
+            header: Arc::new(frame.header.clone()),
+        });
+    }
+
+    fn live_audio(&self) -> Option<AudioCodec> {
+        let codec = self.audio.as_ref()?.codec();
+        matches!(
+            codec,
+            AudioCodec::Aac | AudioCodec::G711A | AudioCodec::G711U
+        )
+        .then_some(codec)
+    }
+
+    /// Selects the codec of the video track, restarting the pipeline from a
+    /// clean directory if the terminal switched codecs mid-session. Returns
+    /// `false` if the frame cannot be used, in which case it is dropped.
+    async fn select_codec(&mut self, payload_type: PayloadType) -> Result<bool> {
+        let Some(codec) = VideoCodec::from_payload_type(payload_type) else {
+            // Report the unsupported codec once rather than for every frame.
+            if self.unsupported.insert(payload_type) {
+                eprintln!(
+                    "Unsupported video payload type '{payload_type}', dropping video ({})",
+                    self.name()
+                );
+            }
+            return Ok(false);
+        };
+
+        if self.codec == Some(codec) {
+            return Ok(true);
+        }
+
+        if self.codec.is_some() {
+            let key = match &self.registration {
+                Some(registration) => registration.key().clone(),
+                None => return Ok(true),
+            };
+            println!("Codec changed to {payload_type}, restarting pipeline ({key})");
+            self.stop_pipeline().await;
+            self.pending.clear();
+            self.audio = self.audio.as_ref().map(|a| AudioDecoder::new(a.codec()));
+            fs::remove_dir_all(key.dir()).await?;
+            self.init_dir(&key).await?;
+        }
+
+        self.codec = Some(codec);
+        Ok(true)
     }
 
-    async fn init_dir(&mut self, name: &str) -> Result<()> {
-        let streams_dir = PathBuf::from(format!("{name}/streams"));
+    /// Selects the codec of the audio track. Returns `false` if the frame
+    /// cannot be used, in which case it is dropped.
+    fn select_audio_codec(&mut self, payload_type: PayloadType) -> bool {
+        if let Some(audio) = &self.audio {
+            return AudioCodec::from_payload_type(payload_type) == Some(audio.codec());
+        }
+
+        if self.pipeline.is_some() {
+            return false;
+        }
+
+        match AudioCodec::from_payload_type(payload_type) {
+            Some(codec) => {
+                self.audio = Some(AudioDecoder::new(codec));
+                true
+            }
+            None => {
+                if self.unsupported.insert(payload_type) {
+                    eprintln!(
+                        "Unsupported audio payload type '{payload_type}', dropping audio ({})",
+                        self.name()
+                    );
+                }
+                false
+            }
+        }
+    }
+
+    /// The pipeline starts once the video codec is known and either an audio
+    /// track was seen or it is clear that the terminal sends no audio.
+    fn ready_to_start(&self) -> bool {
+        if self.codec.is_none() {
+            return false;
+        }
+        if self.audio.is_some() || self.pending.len() >= MAX_PENDING_FRAMES {
+            return true;
+        }
+
+        let first = self.pending.first().and_then(|f| f.header.timestamp);
+        let last = self.pending.last().and_then(|f| f.header.timestamp);
+        match (first, last) {
+            (Some(first), Some(last)) => last.saturating_sub(first) >= AUDIO_PROBE_MS,
+            _ => false,
+        }
+    }
+
+    async fn init_dir(&mut self, key: &StreamKey) -> Result<()> {
+        let streams_dir = key.dir().join("streams");
         fs::create_dir_all(&streams_dir).await?;
         self.dir_init = true;
         Ok(())
     }
 
-    async fn init_ffmpeg_process(&mut self) -> Result<()> {
-        let arguments = format!(
-            "-hide_banner -loglevel error -re -f h264 -i pipe: \
-            -c copy -preset:v fast -strftime 1 -hls_init_time 1 \
-            -hls_time 6 -hls_segment_filename {}/streams/%Y-%m-%d_%H-%M-%S.ts \
-            -hls_list_size 10 -hls_flags delete_segments -f hls {}/playlist.m3u8",
-            self.imei, self.imei
-        );
-
-        let arguments: Vec<&str> = arguments.split(' ').collect();
-        let mut child = Command::new("ffmpeg")
-            .stdin(Stdio::piped())
-            .args(&arguments)
-            .spawn()?;
-        let stdin = child.stdin.take().ok_or(std::io::Error::new(
-            std::io::ErrorKind::BrokenPipe,
-            "Failed to open stdin.",
-        ))?;
-        self.ffmpeg_process = Some(child);
-        self.child_stdin = Some(stdin);
+    /// Offset of the first audio frame relative to the first video frame,
+    /// in seconds.
+    fn audio_offset(&self) -> f64 {
+        let first_timestamp = |audio: bool| {
+            self.pending
+                .iter()
+                .find(|f| f.header.data_type.is_audio() == audio)
+                .and_then(|f| f.header.timestamp)
+        };
+        match (first_timestamp(true), first_timestamp(false)) {
+            (Some(audio), Some(video)) => (audio as f64 - video as f64) / 1000.0,
+            _ => 0.0,
+        }
+    }
+
+    async fn init_pipeline(&mut self) -> Result<()> {
+        let (Some(codec), Some(registration)) = (self.codec, &self.registration) else {
+            return Ok(());
+        };
+        let key = registration.key().clone();
+        let audio = self.audio.as_ref().map(|a| a.codec());
+        let container = self
+            .config
+            .container
+            .unwrap_or_else(|| codec.default_container());
+
+        let pipeline = match self.config.backend {
+            HlsBackend::Native => {
+                let encoder = audio
+                    .filter(|&audio| audio != AudioCodec::Aac)
+                    .and_then(|audio| AacEncoder::new(audio.sample_rate()));
+                let audio = audio == Some(AudioCodec::Aac) || encoder.is_some();
+                let mut segmenter = HlsSegmenter::new(&key.dir(), codec, audio, container)
+                    .with_progress(registration.stream().playlist.clone());
+                if self.config.low_latency {
+                    segmenter = segmenter.with_low_latency();
+                }
+                if self.config.dash {
+                    if container != HlsContainer::Fmp4 {
+                        println!("MPEG-DASH needs fMP4 segments, ignoring it ({key})");
+                    }
+                    segmenter = segmenter.with_dash();
+                }
+                Pipeline::Native(segmenter, encoder)
+            }
+            HlsBackend::Ffmpeg => {
+                if self.config.low_latency {
+                    println!("Low-latency HLS needs the native backend, ignoring it ({key})");
+                }
+                if self.config.dash {
+                    println!("MPEG-DASH needs the native backend, ignoring it ({key})");
+                }
+                let audio_offset = self.audio_offset();
+                let ffmpeg =
+                    FfmpegPipeline::start(&key, codec, container, audio, audio_offset).await?;
+                Pipeline::Ffmpeg(ffmpeg)
+            }
+        };
+        self.pipeline = Some(pipeline);
+        registration.stream().feed.start(LiveTracks {
+            video: codec,
+            audio: self.live_audio(),
+        });
+        if self.relays.is_empty() {
+            for rule in self.rtmp_push.iter().filter(|rule| rule.matches(&key)) {
+                self.relays.push(tokio::spawn(relay(
+                    Arc::clone(registration.stream()),
+                    key.clone(),
+                    rule.url(&key),
+                )));
+            }
+        }
 
         Ok(())
     }
 
+    async fn stop_pipeline(&mut self) {
+        match self.pipeline.take() {
+            Some(Pipeline::Native(mut segmenter, _)) => {
+                if let Err(e) = segmenter.finish().await {
+                    eprintln!("Failed to finish HLS playlist ({}): {e}", self.name());
+                }
+            }
+            Some(Pipeline::Ffmpeg(ffmpeg)) => ffmpeg.stop().await,
+            None => (),
+        }
+    }
+
     async fn clean_up(&mut self) -> Result<()> {
-        fs::remove_dir_all(&self.imei).await?;
+        let Some(registration) = &self.registration else {
+            return Ok(());
+        };
+        let dir = registration.key().dir();
+        fs::remove_dir_all(&dir).await?;
+
+        // Remove the terminal directory too once its last channel is gone.
+        if let Some(parent) = dir.parent() {
+            let _ = fs::remove_dir(parent).await;
+        }
         Ok(())
     }
 }
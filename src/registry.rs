@@ -0,0 +1,252 @@
+use crate::hls::PlaylistProgress;
+use crate::jt808::StreamType;
+use crate::live::LiveFeed;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// Identity of a live stream: a terminal can publish several logical
+/// channels (e.g. cabin and road cameras) at the same time.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StreamKey {
+    pub imei: String,
+    pub channel: u8,
+}
+
+impl StreamKey {
+    pub fn new(imei: &str, channel: u8) -> Self {
+        Self {
+            imei: imei.to_string(),
+            channel,
+        }
+    }
+
+    /// Directory holding the playlist and segments of this stream.
+    pub(crate) fn dir(&self) -> PathBuf {
+        PathBuf::from(&self.imei).join(self.channel.to_string())
+    }
+}
+
+impl fmt::Display for StreamKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.imei, self.channel)
+    }
+}
+
+/// Transfer state set by the platform with 0x9102 control commands.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct MediaState {
+    pub(crate) paused: bool,
+    pub(crate) stream_type: StreamType,
+    /// Set once the transfer is closed: the stream ends without waiting
+    /// for the terminal to drop the connection.
+    pub(crate) closed: bool,
+}
+
+/// State of a published stream shared with the web server.
+pub(crate) struct LiveStream {
+    /// Progress of the native segmenter, for blocking playlist reloads.
+    pub(crate) playlist: watch::Sender<PlaylistProgress>,
+    /// Frames for the live endpoints.
+    pub(crate) feed: LiveFeed,
+    pub(crate) media: watch::Sender<MediaState>,
+    /// Time of the last request of an HLS or DASH player, or of the last
+    /// check that found live subscribers.
+    last_viewed: Mutex<Instant>,
+    /// Set once the stream has ended and only its files are kept a while.
+    ended: AtomicBool,
+}
+
+impl LiveStream {
+    fn new() -> Self {
+        Self {
+            playlist: watch::Sender::new(PlaylistProgress::default()),
+            feed: LiveFeed::default(),
+            media: watch::Sender::new(MediaState::default()),
+            last_viewed: Mutex::new(Instant::now()),
+            ended: AtomicBool::new(false),
+        }
+    }
+
+    /// Records that someone is watching.
+    pub(crate) fn viewed(&self) {
+        *self.last_viewed.lock().expect("Live stream poisoned") = Instant::now();
+    }
+
+    /// Time since someone last watched, or since the stream was published.
+    pub(crate) fn idle_for(&self) -> Duration {
+        self.last_viewed
+            .lock()
+            .expect("Live stream poisoned")
+            .elapsed()
+    }
+
+    fn is_ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed)
+    }
+}
+
+/// Keeps track of the streams currently being published, so that a second
+/// connection for the same (terminal, channel) cannot overwrite the files of
+/// the first one. Clones share the same streams, so that the web server can
+/// follow what the TCP server publishes.
+#[derive(Clone, Default)]
+pub struct StreamRegistry {
+    streams: Arc<Mutex<HashMap<StreamKey, Arc<LiveStream>>>>,
+    /// Notified of every new publisher and of every stream released.
+    changed: Arc<watch::Sender<()>>,
+}
+
+impl StreamRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claims `key` for a new publisher. Fails if the stream is already being
+    /// published; the claim is released when the returned guard is dropped.
+    pub(crate) fn register(&self, key: StreamKey) -> std::io::Result<StreamRegistration> {
+        let mut streams = self.streams.lock().expect("Stream registry poisoned");
+        if streams.contains_key(&key) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Stream {key} already has a publisher"),
+            ));
+        }
+        let stream = Arc::new(LiveStream::new());
+        streams.insert(key.clone(), stream.clone());
+        self.changed.send_replace(());
+        Ok(StreamRegistration {
+            key,
+            registry: self.clone(),
+            stream,
+        })
+    }
+
+    /// Like [`register`](Self::register), but first waits for a stream
+    /// that has ended under `key` to be cleaned up.
+    pub(crate) async fn register_after_end(
+        &self,
+        key: StreamKey,
+    ) -> std::io::Result<StreamRegistration> {
+        let mut changed = self.changed.subscribe();
+        loop {
+            let error = match self.register(key.clone()) {
+                Ok(registration) => return Ok(registration),
+                Err(e) => e,
+            };
+            let ended = {
+                let streams = self.streams.lock().expect("Stream registry poisoned");
+                streams.get(&key).is_some_and(|stream| stream.is_ended())
+            };
+            if !ended {
+                return Err(error);
+            }
+            // The sender lives as long as `self`.
+            let _ = changed.changed().await;
+        }
+    }
+
+    /// The stream published under `key`, if any and still live.
+    pub(crate) fn get(&self, key: &StreamKey) -> Option<Arc<LiveStream>> {
+        let streams = self.streams.lock().expect("Stream registry poisoned");
+        streams
+            .get(key)
+            .filter(|stream| !stream.is_ended())
+            .cloned()
+    }
+
+    /// Waits until `key` is published.
+    pub(crate) async fn wait_for(&self, key: &StreamKey) -> Arc<LiveStream> {
+        let mut changed = self.changed.subscribe();
+        loop {
+            if let Some(stream) = self.get(key) {
+                return stream;
+            }
+            // The sender lives as long as `self`.
+            let _ = changed.changed().await;
+        }
+    }
+}
+
+pub(crate) struct StreamRegistration {
+    key: StreamKey,
+    registry: StreamRegistry,
+    stream: Arc<LiveStream>,
+}
+
+impl StreamRegistration {
+    pub(crate) fn key(&self) -> &StreamKey {
+        &self.key
+    }
+
+    pub(crate) fn stream(&self) -> &Arc<LiveStream> {
+        &self.stream
+    }
+
+    /// Ends the stream while keeping the claim on `key`, for as long as
+    /// its files are still served: live subscriptions end and the stream is
+    /// no longer found, but a new publisher waits for the release.
+    pub(crate) fn end(&self) {
+        self.stream.feed.stop();
+        self.stream.ended.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        if let Ok(mut streams) = self.registry.streams.lock() {
+            streams.remove(&self.key);
+        }
+        self.registry.changed.send_replace(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_publisher_refused() {
+        let registry = StreamRegistry::new();
+        let key = StreamKey::new("353071279375", 1);
+
+        let registration = registry.register(key.clone()).unwrap();
+        assert_eq!(registration.key(), &key);
+        assert!(registry.register(key.clone()).is_err());
+        assert!(registry.get(&key).is_some());
+        assert!(registry.register(StreamKey::new("353071279375", 2)).is_ok());
+
+        drop(registration);
+        assert!(registry.get(&key).is_none());
+        assert!(registry.register(key).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_register_after_end() {
+        let registry = StreamRegistry::new();
+        let key = StreamKey::new("353071279375", 1);
+
+        let registration = registry.register(key.clone()).unwrap();
+        let waiting = tokio::spawn({
+            let (registry, key) = (registry.clone(), key.clone());
+            async move { registry.register_after_end(key).await }
+        });
+        // A live stream is refused right away.
+        assert!(waiting.await.unwrap().is_err());
+
+        registration.end();
+        assert!(registry.get(&key).is_none());
+        let waiting = tokio::spawn({
+            let (registry, key) = (registry.clone(), key.clone());
+            async move { registry.register_after_end(key).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+        drop(registration);
+        assert!(waiting.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+use crate::audio::AudioCodec;
+use crate::flv::FlvMuxer;
+use crate::live::LiveSubscription;
+use crate::processor::VideoCodec;
+use crate::registry::{LiveStream, StreamKey};
+use crate::rtmp::{Amf0, RtmpPublisher, RtmpUrl};
+use crate::Result;
+use futures_util::StreamExt;
+use std::pin::pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Delay before the first reconnection, doubled after each failed attempt.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// A connection that lasted this long counts as working again, resetting
+/// the reconnection delay.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// FLV codec IDs of `onMetaData`.
+const AVC_CODEC_ID: f64 = 7.0;
+const HEVC_FOURCC: &[u8; 4] = b"hvc1";
+const AAC_CODEC_ID: f64 = 10.0;
+
+/// Publishes the streams it matches to an RTMP server. The URL template
+/// may use `{imei}` and `{channel}`, e.g.
+/// `rtmp://media.example.com/live/{imei}_{channel}`.
+///
+/// Parsed from `[filter=]template`, where the filter is `imei`,
+/// `imei/channel` or `*/channel`. Without a filter the rule matches every
+/// stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtmpPushRule {
+    imei: Option<String>,
+    channel: Option<u8>,
+    url_template: String,
+}
+
+impl RtmpPushRule {
+    /// Publishes every stream to `url_template`.
+    pub fn new(url_template: &str) -> Self {
+        Self {
+            imei: None,
+            channel: None,
+            url_template: url_template.to_string(),
+        }
+    }
+
+    /// Only publishes the channels of terminal `imei`.
+    pub fn with_imei(mut self, imei: &str) -> Self {
+        self.imei = Some(imei.to_string());
+        self
+    }
+
+    /// Only publishes logical channel `channel`.
+    pub fn with_channel(mut self, channel: u8) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub(crate) fn matches(&self, key: &StreamKey) -> bool {
+        self.imei.as_ref().is_none_or(|imei| *imei == key.imei)
+            && self.channel.is_none_or(|channel| channel == key.channel)
+    }
+
+    pub(crate) fn url(&self, key: &StreamKey) -> String {
+        self.url_template
+            .replace("{imei}", &key.imei)
+            .replace("{channel}", &key.channel.to_string())
+    }
+}
+
+impl FromStr for RtmpPushRule {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid RTMP push rule '{s}'"),
+            )
+        };
+        // Only split at an `=` before the URL, as its query may have some.
+        let (filter, url_template) = match s.split_once('=') {
+            Some((filter, url_template)) if !filter.contains("://") => (Some(filter), url_template),
+            _ => (None, s),
+        };
+        let mut rule = Self::new(url_template.trim());
+        if let Some(filter) = filter {
+            let (imei, channel) = match filter.trim().split_once('/') {
+                Some((imei, channel)) => (imei, Some(channel)),
+                None => (filter.trim(), None),
+            };
+            if imei.is_empty() {
+                return Err(invalid());
+            }
+            if imei != "*" {
+                rule = rule.with_imei(imei);
+            }
+            if let Some(channel) = channel {
+                rule = rule.with_channel(channel.parse().map_err(|_| invalid())?);
+            }
+        }
+        // Catches typos in the template early rather than on every retry.
+        RtmpUrl::from_str(&rule.url(&StreamKey::new("0", 0)))?;
+        Ok(rule)
+    }
+}
+
+/// Publishes `stream` to `url` until aborted. The relay reconnects after
+/// errors, with an increasing delay, and restarts from the cached GOP.
+pub(crate) async fn relay(stream: Arc<LiveStream>, key: StreamKey, url: String) {
+    let url: RtmpUrl = match url.parse() {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("Not publishing {key} over RTMP: {e}");
+            return;
+        }
+    };
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        let Some(subscription) = stream.feed.subscribe() else {
+            tokio::time::sleep(delay).await;
+            continue;
+        };
+        let started = Instant::now();
+        match publish(&url, subscription).await {
+            // The feed restarted with new tracks, which need a new publish.
+            Ok(()) => {
+                println!("Republishing {key} to {url}");
+                delay = MIN_RECONNECT_DELAY;
+                continue;
+            }
+            Err(e) => eprintln!("RTMP push of {key} to {url} failed: {e}"),
+        }
+        if started.elapsed() >= STABLE_CONNECTION {
+            delay = MIN_RECONNECT_DELAY;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn publish(url: &RtmpUrl, subscription: LiveSubscription) -> Result<()> {
+    let mut publisher = RtmpPublisher::connect(url).await?;
+    println!("Publishing to {url}");
+
+    let tracks = subscription.tracks;
+    let aac = tracks.audio == Some(AudioCodec::Aac);
+    let video_codec_id = match tracks.video {
+        VideoCodec::H264 => AVC_CODEC_ID,
+        VideoCodec::H265 => u32::from_be_bytes(*HEVC_FOURCC) as f64,
+    };
+    let mut metadata = vec![("videocodecid".to_string(), Amf0::Number(video_codec_id))];
+    if aac {
+        metadata.push(("audiocodecid".to_string(), Amf0::Number(AAC_CODEC_ID)));
+    }
+    publisher.send_metadata(metadata).await?;
+
+    let mut muxer = FlvMuxer::new(tracks.video, aac);
+    let mut frames = pin!(subscription.into_stream());
+    let mut tags = Vec::new();
+    while let Some(frame) = frames.next().await {
+        muxer.write_frame(&mut tags, &frame);
+        for tag in tags.drain(..) {
+            publisher.send_tag(&tag).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_rules() {
+        let key = StreamKey::new("353071279375", 2);
+        let rule: RtmpPushRule = "rtmp://media/live/{imei}_{channel}?key=a=b"
+            .parse()
+            .unwrap();
+        assert!(rule.matches(&key));
+        assert_eq!(rule.url(&key), "rtmp://media/live/353071279375_2?key=a=b");
+
+        let rule: RtmpPushRule = "*/2=rtmp://media/live/cam".parse().unwrap();
+        assert_eq!(
+            rule,
+            RtmpPushRule::new("rtmp://media/live/cam").with_channel(2)
+        );
+        assert!(rule.matches(&key));
+        assert!(!rule.matches(&StreamKey::new("353071279375", 1)));
+
+        let rule: RtmpPushRule = "353071279376=rtmp://media/live/cam".parse().unwrap();
+        assert!(!rule.matches(&key));
+
+        assert!("353071279375/x=rtmp://media/live/cam"
+            .parse::<RtmpPushRule>()
+            .is_err());
+        assert!("rtmp://media/{imei}".parse::<RtmpPushRule>().is_err());
+    }
+}
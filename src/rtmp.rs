@@ -0,0 +1,734 @@
+use crate::flv::FlvTag;
+use crate::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+const DEFAULT_PORT: u16 = 1935;
+
+const RTMP_VERSION: u8 = 3;
+const HANDSHAKE_SIZE: usize = 1536;
+
+/// How long connecting, the handshake and the publish negotiation may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Chunk size of both sides until changed.
+const DEFAULT_CHUNK_SIZE: usize = 128;
+/// Chunk size of what we send, so that most frames take a few chunks.
+const CHUNK_SIZE: usize = 4096;
+
+/// Message types of RTMP.
+const SET_CHUNK_SIZE: u8 = 1;
+const USER_CONTROL: u8 = 4;
+const DATA_AMF0: u8 = 18;
+const COMMAND_AMF0: u8 = 20;
+
+/// User control events.
+const PING_REQUEST: u16 = 6;
+const PING_RESPONSE: u16 = 7;
+
+/// Chunk streams of what we send, as most encoders use them.
+const CONTROL_CHUNK_STREAM: u8 = 2;
+const COMMAND_CHUNK_STREAM: u8 = 3;
+const AUDIO_CHUNK_STREAM: u8 = 4;
+const VIDEO_CHUNK_STREAM: u8 = 6;
+
+/// Largest timestamp of a chunk header before it moves to the extended
+/// timestamp field.
+const MAX_TIMESTAMP: u32 = 0xFF_FFFF;
+
+/// Transaction IDs of the commands that get a response.
+const CONNECT_TRANSACTION: f64 = 1.0;
+const CREATE_STREAM_TRANSACTION: f64 = 4.0;
+
+/// Publishing target: `rtmp://host[:port]/app/stream`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RtmpUrl {
+    host: String,
+    port: u16,
+    app: String,
+    stream: String,
+}
+
+impl RtmpUrl {
+    /// URL of the application, without the stream key.
+    fn tc_url(&self) -> String {
+        format!("rtmp://{}:{}/{}", self.host, self.port, self.app)
+    }
+}
+
+impl FromStr for RtmpUrl {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid RTMP URL '{s}'"),
+            )
+        };
+        let rest = s.strip_prefix("rtmp://").ok_or_else(invalid)?;
+        let (authority, path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, DEFAULT_PORT),
+        };
+        let (app, stream) = path.rsplit_once('/').ok_or_else(invalid)?;
+        if host.is_empty() || app.is_empty() || stream.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            app: app.to_string(),
+            stream: stream.to_string(),
+        })
+    }
+}
+
+/// The URL without the stream name, which often holds a secret key.
+impl fmt::Display for RtmpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.tc_url())
+    }
+}
+
+/// AMF0 value, the encoding of RTMP commands and metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Amf0 {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Vec<(String, Amf0)>),
+    Null,
+    Undefined,
+    EcmaArray(Vec<(String, Amf0)>),
+}
+
+impl Amf0 {
+    fn string(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Number(number) => {
+                out.push(0x00);
+                out.extend_from_slice(&number.to_be_bytes());
+            }
+            Self::Boolean(boolean) => out.extend_from_slice(&[0x01, *boolean as u8]),
+            Self::String(string) => {
+                out.push(0x02);
+                encode_utf8(out, string);
+            }
+            Self::Object(properties) => {
+                out.push(0x03);
+                encode_properties(out, properties);
+            }
+            Self::Null => out.push(0x05),
+            Self::Undefined => out.push(0x06),
+            Self::EcmaArray(properties) => {
+                out.push(0x08);
+                out.extend_from_slice(&(properties.len() as u32).to_be_bytes());
+                encode_properties(out, properties);
+            }
+        }
+    }
+
+    /// Decodes the next value of `data`. `None` at the end of the data or
+    /// at a type this client has no use for.
+    fn decode(data: &mut &[u8]) -> Option<Self> {
+        let (&marker, rest) = data.split_first()?;
+        *data = rest;
+        match marker {
+            0x00 => {
+                let number = f64::from_be_bytes(take(data, 8)?.try_into().ok()?);
+                Some(Self::Number(number))
+            }
+            0x01 => Some(Self::Boolean(take(data, 1)?[0] != 0)),
+            0x02 => Some(Self::String(decode_utf8(data)?)),
+            0x03 => Some(Self::Object(decode_properties(data)?)),
+            0x05 => Some(Self::Null),
+            0x06 => Some(Self::Undefined),
+            0x08 => {
+                take(data, 4)?;
+                Some(Self::EcmaArray(decode_properties(data)?))
+            }
+            0x0C => {
+                let length = u32::from_be_bytes(take(data, 4)?.try_into().ok()?) as usize;
+                let string = take(data, length)?;
+                Some(Self::String(String::from_utf8_lossy(string).into_owned()))
+            }
+            _ => None,
+        }
+    }
+
+    fn decode_all(mut data: &[u8]) -> Vec<Self> {
+        std::iter::from_fn(|| Self::decode(&mut data)).collect()
+    }
+
+    fn property(&self, name: &str) -> Option<&Self> {
+        match self {
+            Self::Object(properties) | Self::EcmaArray(properties) => properties
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(string) => Some(string),
+            _ => None,
+        }
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    if data.len() < length {
+        return None;
+    }
+    let (taken, rest) = data.split_at(length);
+    *data = rest;
+    Some(taken)
+}
+
+fn encode_utf8(out: &mut Vec<u8>, string: &str) {
+    out.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn decode_utf8(data: &mut &[u8]) -> Option<String> {
+    let length = u16::from_be_bytes(take(data, 2)?.try_into().ok()?) as usize;
+    Some(String::from_utf8_lossy(take(data, length)?).into_owned())
+}
+
+fn encode_properties(out: &mut Vec<u8>, properties: &[(String, Amf0)]) {
+    for (key, value) in properties {
+        encode_utf8(out, key);
+        value.encode(out);
+    }
+    // An empty key and the object end marker.
+    out.extend_from_slice(&[0x00, 0x00, 0x09]);
+}
+
+fn decode_properties(data: &mut &[u8]) -> Option<Vec<(String, Amf0)>> {
+    let mut properties = Vec::new();
+    loop {
+        let key = decode_utf8(data)?;
+        if key.is_empty() && data.first() == Some(&0x09) {
+            *data = &data[1..];
+            return Some(properties);
+        }
+        properties.push((key, Amf0::decode(data)?));
+    }
+}
+
+fn command(values: &[Amf0]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for value in values {
+        value.encode(&mut payload);
+    }
+    payload
+}
+
+/// Message reassembled from its chunks.
+#[derive(Debug)]
+struct Message {
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+/// Header state of a chunk stream, which later chunks may leave out.
+#[derive(Default)]
+struct ChunkStream {
+    timestamp: u32,
+    delta: u32,
+    length: usize,
+    kind: u8,
+    extended: bool,
+    payload: Vec<u8>,
+}
+
+/// Reassembles the messages of the chunk streams of the server.
+struct ChunkReader<R> {
+    reader: R,
+    chunk_size: usize,
+    streams: HashMap<u32, ChunkStream>,
+}
+
+impl<R: AsyncRead + Unpin> ChunkReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            streams: HashMap::new(),
+        }
+    }
+
+    async fn read_u24(&mut self) -> std::io::Result<u32> {
+        let mut bytes = [0u8; 3];
+        self.reader.read_exact(&mut bytes).await?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+
+    async fn read_message(&mut self) -> Result<Message> {
+        loop {
+            let first = self.reader.read_u8().await?;
+            let format = first >> 6;
+            let id = match first & 0x3F {
+                0 => 64 + self.reader.read_u8().await? as u32,
+                1 => 64 + self.reader.read_u16_le().await? as u32,
+                id => id as u32,
+            };
+
+            let mut timestamp = None;
+            if format < 3 {
+                timestamp = Some(self.read_u24().await?);
+            }
+            let mut header = (None, None);
+            if format < 2 {
+                let length = self.read_u24().await? as usize;
+                let kind = self.reader.read_u8().await?;
+                header = (Some(length), Some(kind));
+            }
+            if format == 0 {
+                // The message stream ID, little-endian for once.
+                self.reader.read_u32_le().await?;
+            }
+            let extended = match timestamp {
+                Some(timestamp) => timestamp == MAX_TIMESTAMP,
+                None => self.streams.get(&id).is_some_and(|stream| stream.extended),
+            };
+            let mut extended_timestamp = None;
+            if extended {
+                extended_timestamp = Some(self.reader.read_u32().await?);
+            }
+
+            let stream = self.streams.entry(id).or_default();
+            if let Some(timestamp) = timestamp {
+                stream.extended = timestamp == MAX_TIMESTAMP;
+            }
+            let timestamp = extended_timestamp.or(timestamp);
+            if let (Some(length), Some(kind)) = header {
+                stream.length = length;
+                stream.kind = kind;
+            }
+            if stream.payload.is_empty() {
+                match (format, timestamp) {
+                    (0, Some(timestamp)) => {
+                        stream.timestamp = timestamp;
+                        stream.delta = 0;
+                    }
+                    (1 | 2, Some(delta)) => {
+                        stream.delta = delta;
+                        stream.timestamp = stream.timestamp.wrapping_add(delta);
+                    }
+                    _ => stream.timestamp = stream.timestamp.wrapping_add(stream.delta),
+                }
+            }
+
+            let remaining = stream.length - stream.payload.len();
+            let mut chunk = vec![0u8; remaining.min(self.chunk_size)];
+            self.reader.read_exact(&mut chunk).await?;
+            stream.payload.extend_from_slice(&chunk);
+            if stream.payload.len() < stream.length {
+                continue;
+            }
+
+            let message = Message {
+                kind: stream.kind,
+                payload: std::mem::take(&mut stream.payload),
+            };
+            if message.kind == SET_CHUNK_SIZE && message.payload.len() >= 4 {
+                let size = u32::from_be_bytes(message.payload[..4].try_into()?) & 0x7FFF_FFFF;
+                self.chunk_size = (size as usize).max(1);
+            }
+            return Ok(message);
+        }
+    }
+}
+
+/// Splits a message into chunks of [`CHUNK_SIZE`]: a full header, then
+/// continuation chunks that only repeat the chunk stream.
+fn write_chunks(
+    out: &mut Vec<u8>,
+    chunk_stream: u8,
+    kind: u8,
+    stream_id: u32,
+    timestamp: u32,
+    payload: &[u8],
+) {
+    let extended = timestamp >= MAX_TIMESTAMP;
+    out.push(chunk_stream);
+    out.extend_from_slice(&timestamp.min(MAX_TIMESTAMP).to_be_bytes()[1..]);
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.extend_from_slice(&stream_id.to_le_bytes());
+    for (index, chunk) in payload.chunks(CHUNK_SIZE).enumerate() {
+        if index > 0 {
+            out.push(0xC0 | chunk_stream);
+        }
+        if extended {
+            out.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    if payload.is_empty() && extended {
+        out.extend_from_slice(&timestamp.to_be_bytes());
+    }
+}
+
+/// RTMP client publishing one stream, negotiated by [`connect`](Self::connect)
+/// like an encoder does: `connect`, `releaseStream`, `FCPublish`,
+/// `createStream`, then `publish`.
+pub(crate) struct RtmpPublisher {
+    writer: OwnedWriteHalf,
+    stream_id: u32,
+    /// Responses of the reader task to the pings of the server. Closed
+    /// once the server has closed the connection.
+    responses: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl RtmpPublisher {
+    pub(crate) async fn connect(url: &RtmpUrl) -> Result<Self> {
+        let stream = timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect((url.host.as_str(), url.port)),
+        )
+        .await??;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let mut reader = ChunkReader::new(reader);
+        let mut publisher = Self {
+            writer,
+            stream_id: 0,
+            responses: mpsc::unbounded_channel().1,
+        };
+        timeout(CONNECT_TIMEOUT, publisher.negotiate(&mut reader, url))
+            .await
+            .map_err(|_| anyhow::anyhow!("RTMP negotiation timed out"))??;
+
+        let (responses_tx, responses_rx) = mpsc::unbounded_channel();
+        publisher.responses = responses_rx;
+        tokio::spawn(async move {
+            while let Ok(message) = reader.read_message().await {
+                if let Some(response) = ping_response(&message) {
+                    if responses_tx.send(response).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(publisher)
+    }
+
+    async fn negotiate(
+        &mut self,
+        reader: &mut ChunkReader<OwnedReadHalf>,
+        url: &RtmpUrl,
+    ) -> Result<()> {
+        self.handshake(&mut reader.reader).await?;
+
+        let mut out = Vec::new();
+        let chunk_size = (CHUNK_SIZE as u32).to_be_bytes();
+        write_chunks(
+            &mut out,
+            CONTROL_CHUNK_STREAM,
+            SET_CHUNK_SIZE,
+            0,
+            0,
+            &chunk_size,
+        );
+        let connect = command(&[
+            Amf0::string("connect"),
+            Amf0::Number(CONNECT_TRANSACTION),
+            Amf0::Object(vec![
+                ("app".to_string(), Amf0::string(&url.app)),
+                ("type".to_string(), Amf0::string("nonprivate")),
+                (
+                    "flashVer".to_string(),
+                    Amf0::string("FMLE/3.0 (compatible; jt1078)"),
+                ),
+                ("tcUrl".to_string(), Amf0::String(url.tc_url())),
+            ]),
+        ]);
+        write_chunks(&mut out, COMMAND_CHUNK_STREAM, COMMAND_AMF0, 0, 0, &connect);
+        self.writer.write_all(&out).await?;
+        self.wait_for_result(reader, CONNECT_TRANSACTION).await?;
+
+        let mut out = Vec::new();
+        for (name, transaction) in [("releaseStream", 2.0), ("FCPublish", 3.0)] {
+            let payload = command(&[
+                Amf0::string(name),
+                Amf0::Number(transaction),
+                Amf0::Null,
+                Amf0::string(&url.stream),
+            ]);
+            write_chunks(&mut out, COMMAND_CHUNK_STREAM, COMMAND_AMF0, 0, 0, &payload);
+        }
+        let create_stream = command(&[
+            Amf0::string("createStream"),
+            Amf0::Number(CREATE_STREAM_TRANSACTION),
+            Amf0::Null,
+        ]);
+        write_chunks(
+            &mut out,
+            COMMAND_CHUNK_STREAM,
+            COMMAND_AMF0,
+            0,
+            0,
+            &create_stream,
+        );
+        self.writer.write_all(&out).await?;
+        let result = self
+            .wait_for_result(reader, CREATE_STREAM_TRANSACTION)
+            .await?;
+        self.stream_id = match result.get(3) {
+            Some(Amf0::Number(stream_id)) => *stream_id as u32,
+            _ => anyhow::bail!("No stream ID in the createStream result"),
+        };
+
+        let publish = command(&[
+            Amf0::string("publish"),
+            Amf0::Number(5.0),
+            Amf0::Null,
+            Amf0::string(&url.stream),
+            Amf0::string("live"),
+        ]);
+        let mut out = Vec::new();
+        write_chunks(
+            &mut out,
+            COMMAND_CHUNK_STREAM,
+            COMMAND_AMF0,
+            self.stream_id,
+            0,
+            &publish,
+        );
+        self.writer.write_all(&out).await?;
+        loop {
+            let message = self.read_message(reader).await?;
+            if message.kind != COMMAND_AMF0 {
+                continue;
+            }
+            let values = Amf0::decode_all(&message.payload);
+            if values.first().and_then(Amf0::as_str) != Some("onStatus") {
+                continue;
+            }
+            let info = values.get(3);
+            let field = |name| {
+                info.and_then(|info| info.property(name))
+                    .and_then(Amf0::as_str)
+            };
+            match (field("level"), field("code")) {
+                (_, Some("NetStream.Publish.Start")) => return Ok(()),
+                (Some("error"), code) => anyhow::bail!(
+                    "Server refused to publish: {} {}",
+                    code.unwrap_or_default(),
+                    field("description").unwrap_or_default()
+                ),
+                _ => (),
+            }
+        }
+    }
+
+    /// Simple handshake: C0 and C1, then C2 echoing S1.
+    async fn handshake(&mut self, reader: &mut OwnedReadHalf) -> Result<()> {
+        let mut c0c1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+        c0c1[0] = RTMP_VERSION;
+        rand::Rng::fill(&mut rand::thread_rng(), &mut c0c1[9..]);
+        self.writer.write_all(&c0c1).await?;
+
+        let mut s0s1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+        reader.read_exact(&mut s0s1).await?;
+        if s0s1[0] != RTMP_VERSION {
+            anyhow::bail!("Unsupported RTMP version {}", s0s1[0]);
+        }
+        self.writer.write_all(&s0s1[1..]).await?;
+        let mut s2 = vec![0u8; HANDSHAKE_SIZE];
+        reader.read_exact(&mut s2).await?;
+        Ok(())
+    }
+
+    /// Reads the next message, answering pings on the way.
+    async fn read_message(&mut self, reader: &mut ChunkReader<OwnedReadHalf>) -> Result<Message> {
+        let message = reader.read_message().await?;
+        if let Some(response) = ping_response(&message) {
+            self.writer.write_all(&response).await?;
+        }
+        Ok(message)
+    }
+
+    /// Reads messages up to the `_result` of `transaction`, returning its
+    /// values.
+    async fn wait_for_result(
+        &mut self,
+        reader: &mut ChunkReader<OwnedReadHalf>,
+        transaction: f64,
+    ) -> Result<Vec<Amf0>> {
+        loop {
+            let message = self.read_message(reader).await?;
+            if message.kind != COMMAND_AMF0 {
+                continue;
+            }
+            let values = Amf0::decode_all(&message.payload);
+            if values.get(1) != Some(&Amf0::Number(transaction)) {
+                continue;
+            }
+            match values.first().and_then(Amf0::as_str) {
+                Some("_result") => return Ok(values),
+                Some("_error") => {
+                    let description = values
+                        .get(3)
+                        .and_then(|info| info.property("description"))
+                        .and_then(Amf0::as_str)
+                        .unwrap_or_default();
+                    anyhow::bail!("RTMP command failed: {description}");
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Sends `@setDataFrame` with the codecs of the stream.
+    pub(crate) async fn send_metadata(&mut self, properties: Vec<(String, Amf0)>) -> Result<()> {
+        let payload = command(&[
+            Amf0::string("@setDataFrame"),
+            Amf0::string("onMetaData"),
+            Amf0::EcmaArray(properties),
+        ]);
+        let mut out = Vec::new();
+        write_chunks(
+            &mut out,
+            COMMAND_CHUNK_STREAM,
+            DATA_AMF0,
+            self.stream_id,
+            0,
+            &payload,
+        );
+        self.writer.write_all(&out).await?;
+        Ok(())
+    }
+
+    /// Sends an FLV tag as an audio or video message.
+    pub(crate) async fn send_tag(&mut self, tag: &FlvTag) -> Result<()> {
+        let mut out = Vec::new();
+        loop {
+            match self.responses.try_recv() {
+                Ok(response) => out.extend_from_slice(&response),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    anyhow::bail!("Connection closed by the RTMP server")
+                }
+            }
+        }
+        let chunk_stream = if tag.kind == crate::flv::TAG_AUDIO {
+            AUDIO_CHUNK_STREAM
+        } else {
+            VIDEO_CHUNK_STREAM
+        };
+        write_chunks(
+            &mut out,
+            chunk_stream,
+            tag.kind,
+            self.stream_id,
+            tag.time,
+            &tag.body,
+        );
+        self.writer.write_all(&out).await?;
+        Ok(())
+    }
+}
+
+/// Response to a ping request of the server.
+fn ping_response(message: &Message) -> Option<Vec<u8>> {
+    if message.kind != USER_CONTROL || message.payload.len() < 6 {
+        return None;
+    }
+    let event = u16::from_be_bytes([message.payload[0], message.payload[1]]);
+    if event != PING_REQUEST {
+        return None;
+    }
+    let mut payload = PING_RESPONSE.to_be_bytes().to_vec();
+    payload.extend_from_slice(&message.payload[2..6]);
+    let mut out = Vec::new();
+    write_chunks(&mut out, CONTROL_CHUNK_STREAM, USER_CONTROL, 0, 0, &payload);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let url: RtmpUrl = "rtmp://media.example.com/live/353071279375_1?key=secret"
+            .parse()
+            .unwrap();
+        assert_eq!(url.port, DEFAULT_PORT);
+        assert_eq!(url.app, "live");
+        assert_eq!(url.stream, "353071279375_1?key=secret");
+        assert_eq!(url.to_string(), "rtmp://media.example.com:1935/live");
+        assert!("rtmp://media.example.com/live".parse::<RtmpUrl>().is_err());
+        assert!("http://media.example.com/live/stream"
+            .parse::<RtmpUrl>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_amf0_round_trip() {
+        let values = [
+            Amf0::string("_result"),
+            Amf0::Number(4.0),
+            Amf0::Null,
+            Amf0::Object(vec![
+                ("level".to_string(), Amf0::string("status")),
+                ("secure".to_string(), Amf0::Boolean(true)),
+            ]),
+            Amf0::EcmaArray(vec![("width".to_string(), Amf0::Number(1280.0))]),
+        ];
+        let payload = command(&values);
+        assert_eq!(Amf0::decode_all(&payload), values);
+        assert_eq!(
+            values[3].property("level").and_then(Amf0::as_str),
+            Some("status")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunks_round_trip() {
+        let body: Vec<u8> = (0..10_000).map(|n| n as u8).collect();
+        let mut out = Vec::new();
+        let chunk_size = (CHUNK_SIZE as u32).to_be_bytes();
+        write_chunks(
+            &mut out,
+            CONTROL_CHUNK_STREAM,
+            SET_CHUNK_SIZE,
+            0,
+            0,
+            &chunk_size,
+        );
+        write_chunks(&mut out, VIDEO_CHUNK_STREAM, 9, 1, 0x0100_0000, &body);
+        write_chunks(&mut out, VIDEO_CHUNK_STREAM, 9, 1, 40, &body[..10]);
+
+        let mut reader = ChunkReader::new(&out[..]);
+        reader.read_message().await.unwrap();
+        assert_eq!(reader.chunk_size, CHUNK_SIZE);
+        let message = reader.read_message().await.unwrap();
+        assert_eq!((message.kind, message.payload), (9, body.clone()));
+        assert_eq!(
+            reader.streams[&(VIDEO_CHUNK_STREAM as u32)].timestamp,
+            0x0100_0000
+        );
+        let message = reader.read_message().await.unwrap();
+        assert_eq!(message.payload, body[..10]);
+        assert_eq!(reader.streams[&(VIDEO_CHUNK_STREAM as u32)].timestamp, 40);
+    }
+}
@@ -1,40 +1,563 @@
+use crate::codec::RtpCodec;
 use crate::helper::{get_bit_at, get_num_at};
-use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::StreamExt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt};
+use tokio_util::codec::FramedRead;
 
 type Result<T> = std::result::Result<T, std::io::Error>;
 
-#[derive(Serialize, Deserialize)]
+/// Frame header magic that starts every packet (`0x30316364`).
+pub(crate) const HEADER_ID: [u8; 4] = [0x30, 0x31, 0x63, 0x64];
+
+/// Largest data body of a packet, longer frames are split into subpackets.
+pub(crate) const MAX_DATA_BODY_LENGTH: usize = 950;
+
+/// Revision of the JT/T 1078 header layout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// JT/T 1078-2016: 6-byte BCD SIM card number.
+    #[default]
+    V2016,
+    /// JT/T 1078-2019: 10-byte BCD SIM card number, matching JT/T 808-2019.
+    V2019,
+}
+
+impl ProtocolVersion {
+    fn terminal_serial_number_len(&self) -> usize {
+        match self {
+            Self::V2016 => 6,
+            Self::V2019 => 10,
+        }
+    }
+
+    /// Length of the header up to and including the data type byte.
+    fn fixed_header_length(&self) -> usize {
+        10 + self.terminal_serial_number_len()
+    }
+
+    /// Guesses the layout from the packet at the start of `src`. A 2019
+    /// header pads the phone number to 20 digits, so that even a 13-digit
+    /// number leaves 7 leading zero digits, which no real 2016 SIM card
+    /// number has. The channel and data type must also be valid where the
+    /// 2019 layout puts them. Returns `None` while `src` is too short to tell.
+    fn detect(src: &[u8]) -> Option<Self> {
+        let sim = src.get(8..12)?;
+        if sim[..3] != [0; 3] || sim[3] >> 4 != 0 {
+            return Some(Self::V2016);
+        }
+        let fixed_header_length = Self::V2019.fixed_header_length();
+        let channel = *src.get(fixed_header_length - 2)?;
+        let data_type = *src.get(fixed_header_length - 1)?;
+        let plausible = channel != 0
+            && RtpHeader::parse_data_type(data_type).is_ok()
+            && RtpHeader::parse_subpacket_flag(data_type).is_ok();
+        Some(if plausible { Self::V2019 } else { Self::V2016 })
+    }
+}
+
+/// Payload types of JT/T 1078 table 12.
+///
+/// Reserved and vendor defined values are kept as `Unknown` so that the
+/// packet still reaches the processor, which decides what to do with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PayloadType {
+    G721,
+    G722,
+    G723,
+    G728,
+    G729,
+    G711A,
+    G711U,
+    G726,
+    G729A,
+    Dvi4Mode3,
+    Dvi4Mode4,
+    Dvi4Rate8K,
+    Dvi4Rate16K,
+    Lpc,
+    S16BeStereo,
+    S16BeMono,
+    MpegAudio,
+    Lpcm,
+    Aac,
+    Wma9Std,
+    HeAac,
+    PcmVoice,
+    PcmAudio,
+    AacLc,
+    Mp3,
+    AdpcmA,
+    Mp4Audio,
+    Amr,
+    Transparent,
+    H264,
+    H265,
+    Avs,
+    Svac,
+    Unknown(u8),
+}
+
+impl PayloadType {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::G721 => 1,
+            Self::G722 => 2,
+            Self::G723 => 3,
+            Self::G728 => 4,
+            Self::G729 => 5,
+            Self::G711A => 6,
+            Self::G711U => 7,
+            Self::G726 => 8,
+            Self::G729A => 9,
+            Self::Dvi4Mode3 => 10,
+            Self::Dvi4Mode4 => 11,
+            Self::Dvi4Rate8K => 12,
+            Self::Dvi4Rate16K => 13,
+            Self::Lpc => 14,
+            Self::S16BeStereo => 15,
+            Self::S16BeMono => 16,
+            Self::MpegAudio => 17,
+            Self::Lpcm => 18,
+            Self::Aac => 19,
+            Self::Wma9Std => 20,
+            Self::HeAac => 21,
+            Self::PcmVoice => 22,
+            Self::PcmAudio => 23,
+            Self::AacLc => 24,
+            Self::Mp3 => 25,
+            Self::AdpcmA => 26,
+            Self::Mp4Audio => 27,
+            Self::Amr => 28,
+            Self::Transparent => 91,
+            Self::H264 => 98,
+            Self::H265 => 99,
+            Self::Avs => 100,
+            Self::Svac => 101,
+            Self::Unknown(id) => *id,
+        }
+    }
+
+    pub fn is_audio(&self) -> bool {
+        (1..=28).contains(&self.id())
+    }
+
+    pub fn is_video(&self) -> bool {
+        (98..=101).contains(&self.id())
+    }
+}
+
+impl From<u8> for PayloadType {
+    fn from(id: u8) -> Self {
+        match id {
+            1 => Self::G721,
+            2 => Self::G722,
+            3 => Self::G723,
+            4 => Self::G728,
+            5 => Self::G729,
+            6 => Self::G711A,
+            7 => Self::G711U,
+            8 => Self::G726,
+            9 => Self::G729A,
+            10 => Self::Dvi4Mode3,
+            11 => Self::Dvi4Mode4,
+            12 => Self::Dvi4Rate8K,
+            13 => Self::Dvi4Rate16K,
+            14 => Self::Lpc,
+            15 => Self::S16BeStereo,
+            16 => Self::S16BeMono,
+            17 => Self::MpegAudio,
+            18 => Self::Lpcm,
+            19 => Self::Aac,
+            20 => Self::Wma9Std,
+            21 => Self::HeAac,
+            22 => Self::PcmVoice,
+            23 => Self::PcmAudio,
+            24 => Self::AacLc,
+            25 => Self::Mp3,
+            26 => Self::AdpcmA,
+            27 => Self::Mp4Audio,
+            28 => Self::Amr,
+            91 => Self::Transparent,
+            98 => Self::H264,
+            99 => Self::H265,
+            100 => Self::Avs,
+            101 => Self::Svac,
+            id => Self::Unknown(id),
+        }
+    }
+}
+
+impl fmt::Display for PayloadType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::G721 => "G.721",
+            Self::G722 => "G.722",
+            Self::G723 => "G.723",
+            Self::G728 => "G.728",
+            Self::G729 => "G.729",
+            Self::G711A => "G.711A",
+            Self::G711U => "G.711U",
+            Self::G726 => "G.726",
+            Self::G729A => "G.729A",
+            Self::Dvi4Mode3 => "DVI4_3",
+            Self::Dvi4Mode4 => "DVI4_4",
+            Self::Dvi4Rate8K => "DVI4_8K",
+            Self::Dvi4Rate16K => "DVI4_16K",
+            Self::Lpc => "LPC",
+            Self::S16BeStereo => "S16BE_STEREO",
+            Self::S16BeMono => "S16BE_MONO",
+            Self::MpegAudio => "MPEGAUDIO",
+            Self::Lpcm => "LPCM",
+            Self::Aac => "AAC",
+            Self::Wma9Std => "WMA9STD",
+            Self::HeAac => "HEAAC",
+            Self::PcmVoice => "PCM_VOICE",
+            Self::PcmAudio => "PCM_AUDIO",
+            Self::AacLc => "AACLC",
+            Self::Mp3 => "MP3",
+            Self::AdpcmA => "ADPCMA",
+            Self::Mp4Audio => "MP4AUDIO",
+            Self::Amr => "AMR",
+            Self::Transparent => "Transparent",
+            Self::H264 => "H.264",
+            Self::H265 => "H.265",
+            Self::Avs => "AVS",
+            Self::Svac => "SVAC",
+            Self::Unknown(id) => return write!(f, "Unknown({id})"),
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for PayloadType {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(id) = s.strip_prefix("Unknown(").and_then(|s| s.strip_suffix(')')) {
+            if let Ok(id) = id.parse::<u8>() {
+                return Ok(Self::Unknown(id));
+            }
+        }
+        (0..=127)
+            .map(Self::from)
+            .find(|pt| !matches!(pt, Self::Unknown(_)) && pt.to_string() == s)
+            .ok_or(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid payload type '{s}'"),
+            ))
+    }
+}
+
+impl Serialize for PayloadType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PayloadType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Type of data carried by a packet (JT/T 1078 table 19, bits 7-4 of byte 15).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DataType {
+    #[serde(rename = "Video I Frame")]
+    IFrame,
+    #[serde(rename = "Video P Frame")]
+    PFrame,
+    #[serde(rename = "Video B Frame")]
+    BFrame,
+    #[serde(rename = "Audio Frame")]
+    AudioFrame,
+    #[serde(rename = "Transparent Data Transmission")]
+    TransparentData,
+}
+
+impl DataType {
+    /// Code of the data type in bits 7-4 of byte 15.
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::IFrame => 0,
+            Self::PFrame => 1,
+            Self::BFrame => 2,
+            Self::AudioFrame => 3,
+            Self::TransparentData => 4,
+        }
+    }
+
+    pub fn is_video(&self) -> bool {
+        matches!(self, Self::IFrame | Self::PFrame | Self::BFrame)
+    }
+
+    pub fn is_audio(&self) -> bool {
+        *self == Self::AudioFrame
+    }
+
+    /// Length of the header fields following byte 15, which depend on the
+    /// data type: timestamp, frame intervals and data body length.
+    fn remaining_header_length(&self) -> usize {
+        match self {
+            Self::IFrame | Self::PFrame | Self::BFrame => 14,
+            Self::AudioFrame => 10,
+            Self::TransparentData => 2,
+        }
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::IFrame => "Video I Frame",
+            Self::PFrame => "Video P Frame",
+            Self::BFrame => "Video B Frame",
+            Self::AudioFrame => "Audio Frame",
+            Self::TransparentData => "Transparent Data Transmission",
+        })
+    }
+}
+
+/// Position of a packet within a fragmented frame (bits 3-0 of byte 15).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SubpacketFlag {
+    #[serde(rename = "Atomic Packet")]
+    Atomic,
+    #[serde(rename = "First Packet")]
+    First,
+    #[serde(rename = "Last Packet")]
+    Last,
+    #[serde(rename = "Intermediate Packet")]
+    Intermediate,
+}
+
+impl SubpacketFlag {
+    /// Code of the flag in bits 3-0 of byte 15.
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::Atomic => 0,
+            Self::First => 1,
+            Self::Last => 2,
+            Self::Intermediate => 3,
+        }
+    }
+}
+
+impl fmt::Display for SubpacketFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Atomic => "Atomic Packet",
+            Self::First => "First Packet",
+            Self::Last => "Last Packet",
+            Self::Intermediate => "Intermediate Packet",
+        })
+    }
+}
+
+/// A single JT/T 1078 media packet as read from the wire.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct RtpPacket {
     pub(crate) header: RtpHeader,
-    pub(crate) payload: Vec<u8>,
+    pub(crate) payload: Bytes,
 }
 
 impl RtpPacket {
+    pub fn new(header: RtpHeader, payload: impl Into<Bytes>) -> Self {
+        Self {
+            header,
+            payload: payload.into(),
+        }
+    }
+
+    pub fn header(&self) -> &RtpHeader {
+        &self.header
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// The payload as a slice of the buffer the packet was decoded from.
+    pub fn into_payload(self) -> Bytes {
+        self.payload
+    }
+
+    /// Reads the packet starting at the current position of `reader`.
     pub async fn parse<T>(reader: &mut T) -> Result<Self>
     where
         T: AsyncBufReadExt + std::marker::Unpin,
     {
-        let header = RtpHeader::parse(reader).await?;
-        let mut payload = vec![0x00; header.data_body_length];
-        reader.read_exact(&mut payload).await?;
-        Ok(Self { header, payload })
+        let mut buffer = BytesMut::new();
+        read_to(reader, &mut buffer, 12).await?;
+        RtpHeader::parse_header_id(&buffer[..4])?;
+
+        let protocol_version = match ProtocolVersion::detect(&buffer) {
+            Some(protocol_version) => protocol_version,
+            None => {
+                read_to(
+                    reader,
+                    &mut buffer,
+                    ProtocolVersion::V2019.fixed_header_length(),
+                )
+                .await?;
+                ProtocolVersion::detect(&buffer).expect("The 2019 fixed header has been read")
+            }
+        };
+        let fixed_header_length = protocol_version.fixed_header_length();
+        read_to(reader, &mut buffer, fixed_header_length).await?;
+
+        let data_type = RtpHeader::parse_data_type(buffer[fixed_header_length - 1])?;
+        let header_length = fixed_header_length + data_type.remaining_header_length();
+        read_to(reader, &mut buffer, header_length).await?;
+
+        let data_body_length = RtpHeader::parse_data_body_length(&buffer[header_length - 2..])?;
+        read_to(reader, &mut buffer, header_length + data_body_length).await?;
+
+        match Self::decode(&mut buffer, Some(protocol_version))? {
+            Some(packet) => Ok(packet),
+            None => unreachable!("The whole packet has been read"),
+        }
+    }
+
+    /// Decodes the packet at the start of `src`, which begins with the frame
+    /// header magic. Returns `None`, leaving `src` untouched, while it does
+    /// not hold the whole packet. Otherwise the packet is split off `src`
+    /// and its payload shares the buffer without being copied.
+    pub(crate) fn decode(
+        src: &mut BytesMut,
+        protocol_version: Option<ProtocolVersion>,
+    ) -> Result<Option<Self>> {
+        let Some((header, header_length)) = RtpHeader::decode(src, protocol_version)? else {
+            return Ok(None);
+        };
+
+        let length = header_length + header.data_body_length;
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+        let mut payload = src.split_to(length);
+        payload.advance(header_length);
+        Ok(Some(Self {
+            header,
+            payload: payload.freeze(),
+        }))
+    }
+
+    /// Appends the packet to `dst`. The data body length is taken from the
+    /// payload rather than from the header.
+    pub fn encode(&self, dst: &mut BytesMut) -> Result<()> {
+        if self.payload.len() > MAX_DATA_BODY_LENGTH {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Payload does not fit in a single packet",
+            ));
+        }
+        self.header.encode(self.payload.len() as u16, dst)?;
+        dst.extend_from_slice(&self.payload);
+        Ok(())
+    }
+}
+
+/// Reads from `reader` until `buffer` holds at least `len` bytes.
+async fn read_to<T>(reader: &mut T, buffer: &mut BytesMut, len: usize) -> Result<()>
+where
+    T: AsyncBufReadExt + std::marker::Unpin,
+{
+    let start = buffer.len();
+    if len > start {
+        buffer.resize(len, 0);
+        reader.read_exact(&mut buffer[start..]).await?;
+    }
+    Ok(())
+}
+
+/// Reads packets from a byte stream, resynchronising on the frame header
+/// magic whenever corrupt data is encountered instead of giving up on the
+/// rest of the stream.
+pub struct RtpReader<T> {
+    framed: FramedRead<T, RtpCodec>,
+}
+
+impl<T> RtpReader<T>
+where
+    T: AsyncRead + std::marker::Unpin,
+{
+    /// Creates a reader that detects the header layout from the first packet.
+    pub fn new(reader: T) -> Self {
+        Self {
+            framed: FramedRead::new(reader, RtpCodec::new()),
+        }
+    }
+
+    /// Creates a reader that only accepts the given header layout.
+    pub fn with_protocol_version(reader: T, protocol_version: ProtocolVersion) -> Self {
+        Self {
+            framed: FramedRead::new(reader, RtpCodec::with_protocol_version(protocol_version)),
+        }
+    }
+
+    /// Header layout in use, once known.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.framed.decoder().protocol_version()
+    }
+
+    /// Number of bytes skipped so far while searching for a frame header.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.framed.decoder().discarded_bytes()
+    }
+
+    pub async fn read_packet(&mut self) -> Result<RtpPacket> {
+        match self.framed.next().await {
+            Some(result) => result,
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Stream ended while searching for frame header",
+            )),
+        }
+    }
+}
+
+/// Keeps the RTP version a string in JSON, as it was before it became a
+/// number in [`RtpHeader`].
+mod version_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        version: &u8,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(version)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<u8, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// The JT/T 1078 RTP header (JT/T 1078 table 19).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RtpHeader {
-    pub(crate) version: String,
+    #[serde(with = "version_string")]
+    pub(crate) version: u8,
     pub(crate) padding: bool,
     pub(crate) extension_bit: bool,
     pub(crate) csrc_count: u8,
     pub(crate) marker: bool,
-    pub(crate) payload_type: String,
+    pub(crate) payload_type: PayloadType,
     pub(crate) package_serial_number: u16,
     pub(crate) terminal_serial_number: String,
     pub(crate) logical_channel_number: u8,
-    pub(crate) data_type: String,
-    pub(crate) subpacket_processing_flag: String,
+    pub(crate) data_type: DataType,
+    pub(crate) subpacket_processing_flag: SubpacketFlag,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) timestamp: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -42,18 +565,160 @@ pub struct RtpHeader {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) last_frame_interval: Option<u16>,
     pub(crate) data_body_length: usize,
+    #[serde(skip)]
+    pub(crate) protocol_version: ProtocolVersion,
 }
 
 impl RtpHeader {
-    async fn parse<T>(reader: &mut T) -> Result<Self>
-    where
-        T: AsyncBufReadExt + std::marker::Unpin,
-    {
-        let mut buffer = [0; 16];
-        reader.read_exact(&mut buffer).await?;
+    /// Creates the header of an atomic packet in the 2016 layout. The other
+    /// fields can be filled in with the `with_*` methods.
+    pub fn new(
+        payload_type: PayloadType,
+        terminal_serial_number: &str,
+        logical_channel_number: u8,
+        data_type: DataType,
+    ) -> Self {
+        Self {
+            version: 2,
+            padding: false,
+            extension_bit: false,
+            csrc_count: 1,
+            marker: false,
+            payload_type,
+            package_serial_number: 0,
+            terminal_serial_number: terminal_serial_number.to_string(),
+            logical_channel_number,
+            data_type,
+            subpacket_processing_flag: SubpacketFlag::Atomic,
+            timestamp: (data_type != DataType::TransparentData).then_some(0),
+            last_i_frame_interval: data_type.is_video().then_some(0),
+            last_frame_interval: data_type.is_video().then_some(0),
+            data_body_length: 0,
+            protocol_version: ProtocolVersion::V2016,
+        }
+    }
+
+    pub fn with_marker(mut self, marker: bool) -> Self {
+        self.marker = marker;
+        self
+    }
+
+    pub fn with_package_serial_number(mut self, package_serial_number: u16) -> Self {
+        self.package_serial_number = package_serial_number;
+        self
+    }
+
+    pub fn with_subpacket_processing_flag(mut self, flag: SubpacketFlag) -> Self {
+        self.subpacket_processing_flag = flag;
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn with_frame_intervals(
+        mut self,
+        last_i_frame_interval: u16,
+        last_frame_interval: u16,
+    ) -> Self {
+        self.last_i_frame_interval = Some(last_i_frame_interval);
+        self.last_frame_interval = Some(last_frame_interval);
+        self
+    }
+
+    pub fn with_protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn padding(&self) -> bool {
+        self.padding
+    }
+
+    pub fn extension_bit(&self) -> bool {
+        self.extension_bit
+    }
+
+    pub fn csrc_count(&self) -> u8 {
+        self.csrc_count
+    }
+
+    pub fn marker(&self) -> bool {
+        self.marker
+    }
+
+    pub fn payload_type(&self) -> PayloadType {
+        self.payload_type
+    }
+
+    pub fn package_serial_number(&self) -> u16 {
+        self.package_serial_number
+    }
+
+    pub fn terminal_serial_number(&self) -> &str {
+        &self.terminal_serial_number
+    }
+
+    pub fn logical_channel_number(&self) -> u8 {
+        self.logical_channel_number
+    }
+
+    pub fn data_type(&self) -> DataType {
+        self.data_type
+    }
+
+    pub fn subpacket_processing_flag(&self) -> SubpacketFlag {
+        self.subpacket_processing_flag
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    pub fn last_i_frame_interval(&self) -> Option<u16> {
+        self.last_i_frame_interval
+    }
 
-        // Parse header id. If it's not 0x30316364, return an error.
-        Self::parse_header_id(&buffer[0..4])?;
+    pub fn last_frame_interval(&self) -> Option<u16> {
+        self.last_frame_interval
+    }
+
+    pub fn data_body_length(&self) -> usize {
+        self.data_body_length
+    }
+
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Decodes the header at the start of `src`, which begins with the frame
+    /// header magic. Without an explicit `protocol_version` the layout is
+    /// detected from the SIM card number. Returns `None` while `src` does not
+    /// hold the whole header, otherwise the header and its length.
+    pub(crate) fn decode(
+        src: &[u8],
+        protocol_version: Option<ProtocolVersion>,
+    ) -> Result<Option<(Self, usize)>> {
+        if src.len() < 12 {
+            return Ok(None);
+        }
+        Self::parse_header_id(&src[..4])?;
+
+        let Some(protocol_version) = protocol_version.or_else(|| ProtocolVersion::detect(src))
+        else {
+            return Ok(None);
+        };
+        let fixed_header_length = protocol_version.fixed_header_length();
+        if src.len() < fixed_header_length {
+            return Ok(None);
+        }
+        let buffer = src;
 
         let num = buffer[4];
         let version = Self::parse_version(num)?;
@@ -67,7 +732,7 @@ impl RtpHeader {
 
         let package_serial_number = Self::parse_package_serial_number(&buffer[6..8])?;
 
-        let terminal_serial_number_len = 6;
+        let terminal_serial_number_len = protocol_version.terminal_serial_number_len();
         let terminal_serial_number =
             Self::parse_terminal_serial_number(&buffer[8..(8 + terminal_serial_number_len)]);
 
@@ -75,13 +740,14 @@ impl RtpHeader {
         let logical_channel_number = buffer[logical_channel_number_idx];
 
         let num = buffer[logical_channel_number_idx + 1];
-        let mut data_type = 5;
-        let type_of_data = Self::parse_data_type(num, &mut data_type)?;
+        let data_type = Self::parse_data_type(num)?;
         let subpacket_processing_flag = Self::parse_subpacket_flag(num)?;
 
-        let buff_len = Self::get_remaining_data_length(data_type)?;
-        let mut buffer = vec![0; buff_len];
-        reader.read_exact(&mut buffer).await?;
+        let header_length = fixed_header_length + data_type.remaining_header_length();
+        if src.len() < header_length {
+            return Ok(None);
+        }
+        let buffer = &src[fixed_header_length..header_length];
 
         let mut timestamp = None;
         let mut last_i_frame_interval = None;
@@ -91,19 +757,19 @@ impl RtpHeader {
         let mut last_i_frame_interval_len = 0;
         let mut last_frame_interval_len = 0;
 
-        if (0..4).contains(&data_type) {
+        if data_type != DataType::TransparentData {
             timestamp_len = 8;
             timestamp = Some(Self::parse_timestamp(&buffer[0..timestamp_len])?);
         }
 
-        if (0..3).contains(&data_type) {
+        if data_type.is_video() {
             last_i_frame_interval_len = 2;
             last_i_frame_interval = Some(Self::parse_last_i_frame_interval(
                 &buffer[timestamp_len..(timestamp_len + last_i_frame_interval_len)],
             )?);
         }
 
-        if (0..3).contains(&data_type) {
+        if data_type.is_video() {
             last_frame_interval_len = 2;
             let offset = timestamp_len + last_i_frame_interval_len;
             last_frame_interval = Some(Self::parse_last_frame_interval(
@@ -116,7 +782,7 @@ impl RtpHeader {
         let data_body_length =
             Self::parse_data_body_length(&buffer[offset..(offset + data_body_length_len)])?;
 
-        Ok(Self {
+        let header = Self {
             version,
             padding,
             extension_bit,
@@ -126,13 +792,48 @@ impl RtpHeader {
             package_serial_number,
             terminal_serial_number,
             logical_channel_number,
-            data_type: type_of_data,
+            data_type,
             subpacket_processing_flag,
             timestamp,
             last_i_frame_interval,
             last_frame_interval,
             data_body_length,
-        })
+            protocol_version,
+        };
+        Ok(Some((header, header_length)))
+    }
+
+    /// Appends the header to `dst` with the given data body length.
+    pub(crate) fn encode(&self, data_body_length: u16, dst: &mut BytesMut) -> Result<()> {
+        let terminal_serial_number = Self::encode_terminal_serial_number(
+            &self.terminal_serial_number,
+            self.protocol_version.terminal_serial_number_len(),
+        )?;
+
+        dst.reserve(
+            self.protocol_version.fixed_header_length() + self.data_type.remaining_header_length(),
+        );
+        dst.put_slice(&HEADER_ID);
+        dst.put_u8(
+            (self.version & 0x03) << 6
+                | u8::from(self.padding) << 5
+                | u8::from(self.extension_bit) << 4
+                | (self.csrc_count & 0x0F),
+        );
+        dst.put_u8(u8::from(self.marker) << 7 | (self.payload_type.id() & 0x7F));
+        dst.put_u16(self.package_serial_number);
+        dst.put_slice(&terminal_serial_number);
+        dst.put_u8(self.logical_channel_number);
+        dst.put_u8(self.data_type.id() << 4 | self.subpacket_processing_flag.id());
+        if self.data_type != DataType::TransparentData {
+            dst.put_u64(self.timestamp.unwrap_or_default());
+        }
+        if self.data_type.is_video() {
+            dst.put_u16(self.last_i_frame_interval.unwrap_or_default());
+            dst.put_u16(self.last_frame_interval.unwrap_or_default());
+        }
+        dst.put_u16(data_body_length);
+        Ok(())
     }
 
     fn parse_header_id(bytees: &[u8]) -> Result<u32> {
@@ -154,9 +855,9 @@ impl RtpHeader {
         }
     }
 
-    fn parse_version(bytee: u8) -> Result<String> {
+    fn parse_version(bytee: u8) -> Result<u8> {
         match get_num_at(bytee, 7, 2) {
-            Ok(v) => Ok(v.to_string()),
+            Ok(v) => Ok(v),
             Err(_) => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Failed to parse 'version' field",
@@ -204,20 +905,14 @@ impl RtpHeader {
         }
     }
 
-    fn parse_payload_type(bytee: u8) -> Result<String> {
-        let pt = match get_num_at(bytee, 6, 7) {
-            Ok(98) => "H.264",
-            Ok(99) => "H.265",
-            Ok(6) => "G.711A",
-            Ok(7) => "G.711U",
-            Ok(_) | Err(_) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Failed to parse 'payload_type' field",
-                ))
-            }
-        };
-        Ok(pt.to_string())
+    fn parse_payload_type(bytee: u8) -> Result<PayloadType> {
+        match get_num_at(bytee, 6, 7) {
+            Ok(pt) => Ok(PayloadType::from(pt)),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Failed to parse 'payload_type' field",
+            )),
+        }
     }
 
     fn parse_package_serial_number(bytees: &[u8]) -> Result<u16> {
@@ -233,26 +928,45 @@ impl RtpHeader {
         Ok(u16::from_be_bytes(bytees))
     }
 
-    fn parse_terminal_serial_number(bytees: &[u8]) -> String {
-        bytees.iter().fold(String::new(), |mut acc, &bytee| {
+    /// Decodes the BCD SIM card number, dropping the zero padding in front
+    /// of the phone number.
+    pub(crate) fn parse_terminal_serial_number(bytees: &[u8]) -> String {
+        let digits = bytees.iter().fold(String::new(), |mut acc, &bytee| {
             acc.push_str(&format!("{:02X}", bytee));
             acc
-        })
+        });
+        match digits.trim_start_matches('0') {
+            "" => "0".to_string(),
+            digits => digits.to_string(),
+        }
+    }
+
+    /// Packs the SIM card number into `len` BCD bytes, zero padded in front.
+    pub(crate) fn encode_terminal_serial_number(digits: &str, len: usize) -> Result<Vec<u8>> {
+        if digits.len() > len * 2 || !digits.bytes().all(|bytee| bytee.is_ascii_hexdigit()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid terminal serial number '{digits}'"),
+            ));
+        }
+        let digits = format!("{digits:0>width$}", width = len * 2);
+        Ok(digits
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                let digit = |bytee: u8| (bytee as char).to_digit(16).unwrap_or_default() as u8;
+                digit(pair[0]) << 4 | digit(pair[1])
+            })
+            .collect())
     }
 
-    fn parse_data_type(bytee: u8, data_type: &mut u8) -> Result<String> {
-        let types = [
-            "Video I Frame",
-            "Video P Frame",
-            "Video B Frame",
-            "Audio Frame",
-            "Transparent Data Transmission",
-        ];
+    fn parse_data_type(bytee: u8) -> Result<DataType> {
         match get_num_at(bytee, 7, 4) {
-            Ok(i) if (0..5).contains(&i) => {
-                *data_type = i;
-                Ok(types[i as usize].to_string())
-            }
+            Ok(0) => Ok(DataType::IFrame),
+            Ok(1) => Ok(DataType::PFrame),
+            Ok(2) => Ok(DataType::BFrame),
+            Ok(3) => Ok(DataType::AudioFrame),
+            Ok(4) => Ok(DataType::TransparentData),
             Ok(_) | Err(_) => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Failed to parse 'type_of_data' field",
@@ -260,16 +974,12 @@ impl RtpHeader {
         }
     }
 
-    fn parse_subpacket_flag(bytee: u8) -> Result<String> {
-        let flags = [
-            "Atomic Packet",
-            "First Packet",
-            "Last Packet",
-            "Intermediate Packet",
-        ];
-
+    fn parse_subpacket_flag(bytee: u8) -> Result<SubpacketFlag> {
         match get_num_at(bytee, 3, 4) {
-            Ok(i) if (0..4).contains(&i) => Ok(flags[i as usize].to_string()),
+            Ok(0) => Ok(SubpacketFlag::Atomic),
+            Ok(1) => Ok(SubpacketFlag::First),
+            Ok(2) => Ok(SubpacketFlag::Last),
+            Ok(3) => Ok(SubpacketFlag::Intermediate),
             Ok(_) | Err(_) => Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Failed to parse 'subpacket_processing_flag' field",
@@ -277,18 +987,6 @@ impl RtpHeader {
         }
     }
 
-    fn get_remaining_data_length(bytee: u8) -> Result<usize> {
-        match bytee {
-            0..3 => Ok(14),
-            3 => Ok(10),
-            4 => Ok(2),
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid 'data_type' field",
-            )),
-        }
-    }
-
     fn parse_timestamp(bytees: &[u8]) -> Result<u64> {
         let bytees: [u8; 8] = match bytees.try_into() {
             Ok(b) => b,
@@ -338,6 +1036,206 @@ impl RtpHeader {
                 ))
             }
         };
-        Ok(u16::from_be_bytes(bytees) as usize)
+        let data_body_length = u16::from_be_bytes(bytees) as usize;
+        // A longer body means the header is corrupt, or not a header at all.
+        if data_body_length > MAX_DATA_BODY_LENGTH {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Data body length {data_body_length} exceeds {MAX_DATA_BODY_LENGTH} bytes"),
+            ));
+        }
+        Ok(data_body_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(serial_number: u16, payload: &[u8]) -> Vec<u8> {
+        packet_with_sim(
+            serial_number,
+            &[0x35, 0x30, 0x71, 0x27, 0x93, 0x75],
+            payload,
+        )
+    }
+
+    fn packet_with_sim(serial_number: u16, sim: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut bytes = HEADER_ID.to_vec();
+        bytes.extend_from_slice(&[0x81, 0x80 | 98]);
+        bytes.extend_from_slice(&serial_number.to_be_bytes());
+        bytes.extend_from_slice(sim);
+        bytes.extend_from_slice(&[0x01, 0x00]);
+        bytes.extend_from_slice(&1000u64.to_be_bytes());
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00, 0x28]);
+        bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// Deterministic pseudo-random junk.
+    fn junk(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_parse_packet() {
+        let bytes = packet(7, &[1, 2, 3]);
+        let packet = RtpPacket::parse(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(packet.header().package_serial_number(), 7);
+        assert_eq!(packet.header().terminal_serial_number(), "353071279375");
+        assert_eq!(packet.header().data_type(), DataType::IFrame);
+        assert_eq!(packet.header().timestamp(), Some(1000));
+        assert_eq!(packet.payload(), &[1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_resync_on_junk() {
+        let mut bytes = Vec::new();
+        bytes.extend(junk(13, 1));
+        bytes.extend(packet(1, &[1; 20]));
+        bytes.extend(junk(517, 2));
+        bytes.extend(packet(2, &[2; 20]));
+        // A partial header magic followed by a real packet.
+        bytes.extend_from_slice(&[0x30, 0x31, 0x63]);
+        bytes.extend(packet(3, &[3; 20]));
+        // A packet whose header is cut short by junk.
+        bytes.extend(packet(4, &[4; 20])[..10].to_vec());
+        bytes.extend(junk(64, 3));
+        bytes.extend(packet(5, &[5; 20]));
+        // A stray magic followed by a header claiming a body longer than a
+        // packet can carry, which must not swallow the packets after it.
+        let mut bogus = packet(6, &[]);
+        let length = bogus.len();
+        bogus[length - 2..].copy_from_slice(&[0xFF, 0xFF]);
+        bytes.extend(bogus);
+        bytes.extend(junk(200, 4));
+        bytes.extend(packet(7, &[7; 20]));
+        bytes.extend(packet(8, &[8; 20]));
+
+        let mut reader = RtpReader::new(bytes.as_slice());
+        let mut serial_numbers = Vec::new();
+        loop {
+            match reader.read_packet().await {
+                Ok(packet) => serial_numbers.push(packet.header().package_serial_number()),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => panic!("Unexpected error: {e}"),
+            }
+        }
+
+        assert_eq!(serial_numbers, vec![1, 2, 3, 5, 7, 8]);
+        assert!(reader.discarded_bytes() >= 13 + 517 + 3 + 200);
+    }
+
+    #[tokio::test]
+    async fn test_parse_2019_packet() {
+        const SIM_2019: [u8; 10] = [0x00, 0x00, 0x00, 0x00, 0x01, 0x39, 0x12, 0x34, 0x56, 0x78];
+
+        let mut bytes = packet_with_sim(1, &SIM_2019, &[1; 20]);
+        bytes.extend(packet_with_sim(2, &SIM_2019, &[2; 20]));
+        let mut reader = RtpReader::new(bytes.as_slice());
+        for serial_number in 1..=2 {
+            let packet = reader.read_packet().await.unwrap();
+            assert_eq!(packet.header().package_serial_number(), serial_number);
+            assert_eq!(packet.header().terminal_serial_number(), "13912345678");
+            assert_eq!(packet.header().logical_channel_number(), 1);
+            assert_eq!(packet.header().timestamp(), Some(1000));
+            assert_eq!(packet.payload(), &[serial_number as u8; 20]);
+        }
+        assert_eq!(reader.protocol_version(), Some(ProtocolVersion::V2019));
+
+        // A 13-digit number only leaves 7 zero digits of padding.
+        let sim = [0x00, 0x00, 0x00, 0x01, 0x39, 0x12, 0x34, 0x56, 0x78, 0x90];
+        let bytes = packet_with_sim(3, &sim, &[3; 20]);
+        let packet = RtpPacket::parse(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(packet.header().protocol_version(), ProtocolVersion::V2019);
+        assert_eq!(packet.header().terminal_serial_number(), "1391234567890");
+        assert_eq!(packet.payload(), &[3; 20]);
+        let mut reader = RtpReader::new(bytes.as_slice());
+        reader.read_packet().await.unwrap();
+        assert_eq!(reader.protocol_version(), Some(ProtocolVersion::V2019));
+
+        // A listener pinned to the 2019 layout also accepts SIM card numbers
+        // without zero padding.
+        let bytes = packet_with_sim(3, &[0x12; 10], &[3; 20]);
+        let mut reader = RtpReader::with_protocol_version(bytes.as_slice(), ProtocolVersion::V2019);
+        let packet = reader.read_packet().await.unwrap();
+        assert_eq!(
+            packet.header().terminal_serial_number(),
+            "12121212121212121212"
+        );
+    }
+
+    #[test]
+    fn test_parse_terminal_serial_number() {
+        assert_eq!(
+            RtpHeader::parse_terminal_serial_number(&[0x01, 0x39, 0x12, 0x34, 0x56, 0x78]),
+            "13912345678"
+        );
+        assert_eq!(RtpHeader::parse_terminal_serial_number(&[0x00; 6]), "0");
+    }
+
+    #[test]
+    fn test_parse_payload_type() {
+        assert_eq!(
+            RtpHeader::parse_payload_type(0x80 | 98).unwrap(),
+            PayloadType::H264
+        );
+        assert_eq!(
+            RtpHeader::parse_payload_type(99).unwrap(),
+            PayloadType::H265
+        );
+        assert_eq!(
+            RtpHeader::parse_payload_type(26).unwrap(),
+            PayloadType::AdpcmA
+        );
+        assert_eq!(
+            RtpHeader::parse_payload_type(0x80 | 120).unwrap(),
+            PayloadType::Unknown(120)
+        );
+    }
+
+    #[test]
+    fn test_parse_data_type_and_subpacket_flag() {
+        assert_eq!(RtpHeader::parse_data_type(0x01).unwrap(), DataType::IFrame);
+        assert_eq!(
+            RtpHeader::parse_data_type(0x32).unwrap(),
+            DataType::AudioFrame
+        );
+        assert!(RtpHeader::parse_data_type(0x50).is_err());
+        assert_eq!(
+            RtpHeader::parse_subpacket_flag(0x13).unwrap(),
+            SubpacketFlag::Intermediate
+        );
+        assert!(RtpHeader::parse_subpacket_flag(0x04).is_err());
+    }
+
+    #[test]
+    fn test_payload_type_round_trip() {
+        for id in 0..=127 {
+            let pt = PayloadType::from(id);
+            assert_eq!(pt.id(), id);
+            assert_eq!(pt.to_string().parse::<PayloadType>().unwrap(), pt);
+        }
+        assert_eq!(PayloadType::G711A.to_string(), "G.711A");
+        assert!(PayloadType::Amr.is_audio());
+        assert!(PayloadType::Svac.is_video());
+        assert!(!PayloadType::Transparent.is_audio());
+    }
+
+    #[test]
+    fn test_header_json() {
+        let header = RtpHeader::new(PayloadType::H264, "353071279375", 1, DataType::IFrame);
+        let json = serde_json::to_value(&header).unwrap();
+        assert_eq!(json["version"], "2");
+        assert_eq!(json["payload_type"], "H.264");
+        let decoded: RtpHeader = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded, header);
     }
 }
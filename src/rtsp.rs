@@ -0,0 +1,403 @@
+use crate::audio::{adts_frames, AacConfig, AudioCodec};
+use crate::live::{FrameKind, LiveFrame, LiveSubscription};
+use crate::nal::{nal_units, NalKind};
+use crate::processor::VideoCodec;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt::Write;
+
+/// Largest RTP payload, so that packets fit an Ethernet MTU over UDP.
+const MAX_PAYLOAD: usize = 1400;
+
+const RTP_VERSION: u8 = 2;
+
+/// Dynamic payload types of the video and of AAC.
+const VIDEO_PAYLOAD_TYPE: u8 = 96;
+const AAC_PAYLOAD_TYPE: u8 = 97;
+/// Static payload types of RFC 3551.
+const PCMU_PAYLOAD_TYPE: u8 = 0;
+const PCMA_PAYLOAD_TYPE: u8 = 8;
+
+const H264_FU_A: u8 = 28;
+const H265_FU: u8 = 49;
+
+/// RTP clock rate of the video and of G.711.
+const VIDEO_CLOCK_RATE: u64 = 90_000;
+const G711_CLOCK_RATE: u64 = 8000;
+
+/// Samples per AAC frame.
+const AAC_FRAME_SAMPLES: u32 = 1024;
+
+/// Track IDs in the `a=control` attributes.
+pub(crate) const VIDEO_TRACK: usize = 0;
+pub(crate) const AUDIO_TRACK: usize = 1;
+
+/// Audio of an RTSP session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AudioFormat {
+    Pcma,
+    Pcmu,
+    Aac(AacConfig),
+}
+
+impl AudioFormat {
+    fn payload_type(&self) -> u8 {
+        match self {
+            Self::Pcma => PCMA_PAYLOAD_TYPE,
+            Self::Pcmu => PCMU_PAYLOAD_TYPE,
+            Self::Aac(_) => AAC_PAYLOAD_TYPE,
+        }
+    }
+
+    fn clock_rate(&self) -> u64 {
+        match self {
+            Self::Pcma | Self::Pcmu => G711_CLOCK_RATE,
+            Self::Aac(config) => config.sample_rate(),
+        }
+    }
+}
+
+/// Media of a live stream as announced by DESCRIBE, taken from its cached
+/// GOP: the parameter sets for the SDP and, for AAC, the audio config.
+#[derive(Clone, Debug)]
+pub(crate) struct MediaDescription {
+    pub(crate) video: VideoCodec,
+    pub(crate) audio: Option<AudioFormat>,
+    vps: Option<Vec<u8>>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+impl MediaDescription {
+    pub(crate) fn new(subscription: &LiveSubscription) -> Self {
+        let video = subscription.tracks.video;
+        let mut description = Self {
+            video,
+            audio: match subscription.tracks.audio {
+                Some(AudioCodec::G711A) => Some(AudioFormat::Pcma),
+                Some(AudioCodec::G711U) => Some(AudioFormat::Pcmu),
+                _ => None,
+            },
+            vps: None,
+            sps: None,
+            pps: None,
+        };
+        for frame in &subscription.gop {
+            match frame.kind {
+                FrameKind::Video { keyframe: true } => {
+                    for nal in nal_units(&frame.data) {
+                        let parameter_set = match video.nal_kind(nal) {
+                            NalKind::Vps => &mut description.vps,
+                            NalKind::Sps => &mut description.sps,
+                            NalKind::Pps => &mut description.pps,
+                            _ => continue,
+                        };
+                        parameter_set.get_or_insert_with(|| nal.to_vec());
+                    }
+                }
+                FrameKind::Audio if subscription.tracks.audio == Some(AudioCodec::Aac) => {
+                    if let (None, Some((config, _))) =
+                        (description.audio, adts_frames(&frame.data).next())
+                    {
+                        description.audio = Some(AudioFormat::Aac(config));
+                    }
+                }
+                _ => (),
+            }
+        }
+        description
+    }
+
+    /// Session description of RFC 8866, one media section per track.
+    pub(crate) fn sdp(&self, session_id: u64, name: &str) -> String {
+        let mut sdp = String::new();
+        let _ = write!(
+            sdp,
+            "v=0\r\n\
+             o=- {session_id} 1 IN IP4 0.0.0.0\r\n\
+             s={name}\r\n\
+             c=IN IP4 0.0.0.0\r\n\
+             t=0 0\r\n\
+             a=control:*\r\n\
+             m=video 0 RTP/AVP {VIDEO_PAYLOAD_TYPE}\r\n"
+        );
+        match self.video {
+            VideoCodec::H264 => {
+                let _ = write!(
+                    sdp,
+                    "a=rtpmap:{VIDEO_PAYLOAD_TYPE} H264/{VIDEO_CLOCK_RATE}\r\n\
+                     a=fmtp:{VIDEO_PAYLOAD_TYPE} packetization-mode=1"
+                );
+                if let Some(sps) = self.sps.as_ref().filter(|sps| sps.len() >= 4) {
+                    let _ = write!(
+                        sdp,
+                        ";profile-level-id={:02X}{:02X}{:02X}",
+                        sps[1], sps[2], sps[3]
+                    );
+                }
+                if let (Some(sps), Some(pps)) = (&self.sps, &self.pps) {
+                    let _ = write!(
+                        sdp,
+                        ";sprop-parameter-sets={},{}",
+                        BASE64.encode(sps),
+                        BASE64.encode(pps)
+                    );
+                }
+            }
+            VideoCodec::H265 => {
+                let _ = write!(
+                    sdp,
+                    "a=rtpmap:{VIDEO_PAYLOAD_TYPE} H265/{VIDEO_CLOCK_RATE}\r\n\
+                     a=fmtp:{VIDEO_PAYLOAD_TYPE} "
+                );
+                let parameter_sets = [
+                    ("sprop-vps", &self.vps),
+                    ("sprop-sps", &self.sps),
+                    ("sprop-pps", &self.pps),
+                ];
+                let fmtp: Vec<_> = parameter_sets
+                    .iter()
+                    .filter_map(|(name, nal)| {
+                        Some(format!("{name}={}", BASE64.encode(nal.as_ref()?)))
+                    })
+                    .collect();
+                sdp.push_str(&fmtp.join(";"));
+            }
+        }
+        let _ = write!(sdp, "\r\na=control:trackID={VIDEO_TRACK}\r\n");
+
+        if let Some(audio) = self.audio {
+            let payload_type = audio.payload_type();
+            let _ = write!(sdp, "m=audio 0 RTP/AVP {payload_type}\r\n");
+            match audio {
+                AudioFormat::Pcma => {
+                    let _ = write!(sdp, "a=rtpmap:{payload_type} PCMA/{G711_CLOCK_RATE}\r\n");
+                }
+                AudioFormat::Pcmu => {
+                    let _ = write!(sdp, "a=rtpmap:{payload_type} PCMU/{G711_CLOCK_RATE}\r\n");
+                }
+                AudioFormat::Aac(config) => {
+                    let [config_hi, config_lo] = config.audio_specific_config();
+                    let _ = write!(
+                        sdp,
+                        "a=rtpmap:{payload_type} MPEG4-GENERIC/{}/{}\r\n\
+                         a=fmtp:{payload_type} streamtype=5;profile-level-id=1;mode=AAC-hbr;\
+                         sizelength=13;indexlength=3;indexdeltalength=3;config={config_hi:02X}{config_lo:02X}\r\n",
+                        config.sample_rate(),
+                        config.channels
+                    );
+                }
+            }
+            let _ = write!(sdp, "a=control:trackID={AUDIO_TRACK}\r\n");
+        }
+        sdp
+    }
+}
+
+/// RTP packetizer of one track of an RTSP session, with the payload
+/// formats of RFC 6184 (H.264), RFC 7798 (H.265), RFC 3551 (G.711) and
+/// RFC 3640 (AAC).
+pub(crate) struct RtpPacketizer {
+    payload_type: u8,
+    clock_rate: u64,
+    ssrc: u32,
+    sequence: u16,
+}
+
+impl RtpPacketizer {
+    pub(crate) fn video() -> Self {
+        Self::new(VIDEO_PAYLOAD_TYPE, VIDEO_CLOCK_RATE)
+    }
+
+    pub(crate) fn audio(format: AudioFormat) -> Self {
+        Self::new(format.payload_type(), format.clock_rate())
+    }
+
+    fn new(payload_type: u8, clock_rate: u64) -> Self {
+        Self {
+            payload_type,
+            clock_rate,
+            ssrc: rand::random(),
+            sequence: rand::random(),
+        }
+    }
+
+    /// Sequence number of the next packet, for `RTP-Info`.
+    pub(crate) fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// RTP timestamp of a time on the media clock of the stream.
+    pub(crate) fn timestamp(&self, time_ms: u64) -> u32 {
+        (time_ms * self.clock_rate / 1000) as u32
+    }
+
+    /// Packets of a frame of the live feed.
+    pub(crate) fn packetize(&mut self, frame: &LiveFrame, codec: VideoCodec) -> Vec<Bytes> {
+        let timestamp = self.timestamp(frame.time_ms);
+        match frame.kind {
+            FrameKind::Video { .. } => self.packetize_video(codec, timestamp, &frame.data),
+            FrameKind::Audio if self.payload_type == AAC_PAYLOAD_TYPE => {
+                self.packetize_aac(timestamp, &frame.data)
+            }
+            FrameKind::Audio => frame
+                .data
+                .chunks(MAX_PAYLOAD)
+                .map(|samples| self.packet(timestamp, true, &[samples]))
+                .collect(),
+        }
+    }
+
+    /// An Annex-B access unit as single NAL unit packets, or fragmentation
+    /// units for NAL units too big for one packet. The marker is set on the
+    /// last packet of the access unit.
+    fn packetize_video(&mut self, codec: VideoCodec, timestamp: u32, data: &[u8]) -> Vec<Bytes> {
+        let nals: Vec<_> = nal_units(data)
+            .into_iter()
+            .filter(|nal| codec.nal_kind(nal) != NalKind::AccessUnitDelimiter)
+            .collect();
+        let mut packets = Vec::new();
+        for (index, nal) in nals.iter().enumerate() {
+            let last_nal = index + 1 == nals.len();
+            if nal.len() <= MAX_PAYLOAD {
+                packets.push(self.packet(timestamp, last_nal, &[nal]));
+                continue;
+            }
+
+            let (indicator, header_length): (&[u8], usize) = match codec {
+                VideoCodec::H264 => (&[nal[0] & 0xE0 | H264_FU_A], 1),
+                VideoCodec::H265 => (&[nal[0] & 0x81 | H265_FU << 1, nal[1]], 2),
+            };
+            let nal_type = match codec {
+                VideoCodec::H264 => nal[0] & 0x1F,
+                VideoCodec::H265 => (nal[0] >> 1) & 0x3F,
+            };
+            let fragments: Vec<_> = nal[header_length..]
+                .chunks(MAX_PAYLOAD - header_length - 1)
+                .collect();
+            for (number, fragment) in fragments.iter().enumerate() {
+                let start = number == 0;
+                let end = number + 1 == fragments.len();
+                let fu_header = (start as u8) << 7 | (end as u8) << 6 | nal_type;
+                packets.push(self.packet(
+                    timestamp,
+                    last_nal && end,
+                    &[indicator, &[fu_header], fragment],
+                ));
+            }
+        }
+        packets
+    }
+
+    /// One packet per ADTS frame, each with a single AU header of the
+    /// AAC-hbr mode.
+    fn packetize_aac(&mut self, mut timestamp: u32, data: &[u8]) -> Vec<Bytes> {
+        adts_frames(data)
+            .map(|(_, frame)| {
+                let au_headers_length = 16u16.to_be_bytes();
+                let au_header = ((frame.len() as u16) << 3).to_be_bytes();
+                let packet = self.packet(timestamp, true, &[&au_headers_length, &au_header, frame]);
+                timestamp = timestamp.wrapping_add(AAC_FRAME_SAMPLES);
+                packet
+            })
+            .collect()
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, payload: &[&[u8]]) -> Bytes {
+        let length = payload.iter().map(|part| part.len()).sum::<usize>();
+        let mut packet = BytesMut::with_capacity(12 + length);
+        packet.put_u8(RTP_VERSION << 6);
+        packet.put_u8((marker as u8) << 7 | self.payload_type);
+        packet.put_u16(self.sequence);
+        packet.put_u32(timestamp);
+        packet.put_u32(self.ssrc);
+        for part in payload {
+            packet.put_slice(part);
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        packet.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::live::LiveTracks;
+    use crate::rtp::{DataType, PayloadType, RtpHeader};
+    use std::sync::Arc;
+    use tokio::sync::broadcast;
+
+    const SPS: &[u8] = &[
+        0x67, 0x64, 0x00, 0x29, 0xAC, 0x15, 0x6A, 0x05, 0x00, 0x5B, 0x90,
+    ];
+    const PPS: &[u8] = &[0x68, 0xEE, 0x3C, 0xB0];
+
+    fn frame(kind: FrameKind, data: Vec<u8>) -> LiveFrame {
+        LiveFrame {
+            time_ms: 1000,
+            kind,
+            data: data.into(),
+            header: Arc::new(RtpHeader::new(
+                PayloadType::H264,
+                "353071279375",
+                1,
+                DataType::IFrame,
+            )),
+        }
+    }
+
+    #[test]
+    fn test_sdp() {
+        let idr = [&[0, 0, 0, 1], SPS, &[0, 0, 0, 1], PPS, &[0, 0, 0, 1, 0x65]].concat();
+        let subscription = LiveSubscription {
+            tracks: LiveTracks {
+                video: VideoCodec::H264,
+                audio: Some(AudioCodec::G711A),
+            },
+            gop: vec![frame(FrameKind::Video { keyframe: true }, idr)],
+            frames: broadcast::channel(1).1,
+        };
+
+        let sdp = MediaDescription::new(&subscription).sdp(1, "353071279375/1");
+        assert!(sdp.contains(
+            "a=fmtp:96 packetization-mode=1;profile-level-id=640029;\
+             sprop-parameter-sets=Z2QAKawVagUAW5A=,aO48sA==\r\n"
+        ));
+        assert!(sdp.contains("m=audio 0 RTP/AVP 8\r\na=rtpmap:8 PCMA/8000\r\n"));
+        assert!(sdp.ends_with("a=control:trackID=1\r\n"));
+    }
+
+    #[test]
+    fn test_fu_a() {
+        let mut nal = vec![0x65];
+        nal.extend((0..3000).map(|n| n as u8));
+        let access_unit = [&[0, 0, 0, 1][..], PPS, &[0, 0, 0, 1], &nal].concat();
+        let mut packetizer = RtpPacketizer::video();
+        let first_sequence = packetizer.sequence();
+
+        let packets = packetizer.packetize(
+            &frame(FrameKind::Video { keyframe: true }, access_unit),
+            VideoCodec::H264,
+        );
+        assert_eq!(packets.len(), 4);
+        assert_eq!(&packets[0][12..], PPS);
+        assert_eq!(packets[1][12..14], [0x60 | H264_FU_A, 0x80 | 5]);
+        assert_eq!(packets[2][13], 5);
+        assert_eq!(packets[3][13], 0x40 | 5);
+        // The marker is only set on the last packet of the access unit.
+        let markers: Vec<_> = packets.iter().map(|packet| packet[1] >> 7).collect();
+        assert_eq!(markers, [0, 0, 0, 1]);
+        assert_eq!(
+            packets[3][2..4],
+            (first_sequence.wrapping_add(3)).to_be_bytes()
+        );
+        assert_eq!(packets[0][4..8], 90_000u32.to_be_bytes());
+
+        let reassembled: Vec<u8> = packets[1..]
+            .iter()
+            .flat_map(|packet| packet[14..].to_vec())
+            .collect();
+        assert_eq!(reassembled, nal[1..]);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::jt808::{
+    parse_auth_code, platform_response, registration_response, Jt808Codec, Jt808Message,
+    Registration, SubpackageAssembler, AUTHENTICATION, DEREGISTRATION, HEARTBEAT,
+    PLATFORM_RESPONSE, REGISTRATION, REGISTRATION_RESPONSE, RESOURCE_LIST, RESULT_FAILURE,
+    RESULT_INVALID, RESULT_SUCCESS, TERMINAL_RESPONSE,
+};
+use crate::terminal::{Command, TerminalRegistry};
+use crate::Result;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout_at, Instant};
+use tokio_util::codec::Framed;
+
+/// A connection without any message for this long, heartbeats included,
+/// is closed.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Result of 0x8100 for a registration that could not be parsed: "no such
+/// terminal in the database".
+const REGISTRATION_UNKNOWN_TERMINAL: u8 = 4;
+
+/// JT/T 808 signaling server: terminals register (0x0100), sign in
+/// (0x0102) and keep their session alive with heartbeats (0x0002). The
+/// terminals signed in are kept in a [`TerminalRegistry`].
+pub struct Jt808Server {
+    address: SocketAddr,
+    listener: std::net::TcpListener,
+    terminals: TerminalRegistry,
+}
+
+impl Jt808Server {
+    pub fn new(host: &str, port: u16) -> Result<Self> {
+        let port: u16 = std::env::var("JT808_PORT")
+            .unwrap_or_else(|_| port.to_string())
+            .parse()
+            .expect("Failed to parse JT/T 808 port");
+
+        let address: SocketAddr = format!("{}:{}", host, port)
+            .parse()
+            .expect("Failed to parse address");
+
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+
+        Ok(Self {
+            address,
+            listener,
+            terminals: TerminalRegistry::new(),
+        })
+    }
+
+    /// Keeps the terminals signed in in `terminals`, to look them up from
+    /// the media side.
+    pub fn with_terminals(mut self, terminals: TerminalRegistry) -> Self {
+        self.terminals = terminals;
+        self
+    }
+
+    pub async fn run(self) -> std::io::Result<()> {
+        println!("JT/T 808 Server listening on {}", self.address);
+
+        let listener = TcpListener::from_std(self.listener)?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            tokio::spawn(handle_connection(stream, peer, self.terminals.clone()));
+        }
+    }
+}
+
+/// Session of a terminal connection once signed in.
+struct Session {
+    phone: String,
+    version: Option<u8>,
+    connection: u64,
+}
+
+async fn handle_connection(stream: TcpStream, peer: SocketAddr, terminals: TerminalRegistry) {
+    let mut framed = Framed::new(stream, Jt808Codec);
+    let mut assembler = SubpackageAssembler::default();
+    let mut session: Option<Session> = None;
+    let mut serial: u16 = 0;
+    let (commands_tx, mut commands) = mpsc::unbounded_channel::<Command>();
+    // Platform messages waiting for the answer of the terminal, by serial.
+    let mut pending: HashMap<u16, oneshot::Sender<Jt808Message>> = HashMap::new();
+    let mut deadline = Instant::now() + SESSION_TIMEOUT;
+
+    loop {
+        let message = tokio::select! {
+            message = timeout_at(deadline, framed.next()) => match message {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(e))) => {
+                    eprintln!("Failed to read JT/T 808 message from {peer}: {e}");
+                    break;
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    println!("JT/T 808 connection from {peer} timed out");
+                    break;
+                }
+            },
+            Some(command) = commands.recv() => {
+                let Some(session) = &session else {
+                    continue;
+                };
+                serial = serial.wrapping_add(1);
+                let message = Jt808Message {
+                    id: command.id,
+                    version: session.version,
+                    phone: session.phone.clone(),
+                    serial,
+                    package: None,
+                    body: command.body,
+                };
+                if let Err(e) = framed.send(message).await {
+                    eprintln!("Failed to send message to terminal {}: {e}", session.phone);
+                    break;
+                }
+                pending.retain(|_, waiter| !waiter.is_closed());
+                pending.insert(serial, command.response);
+                continue;
+            }
+        };
+        deadline = Instant::now() + SESSION_TIMEOUT;
+
+        let signed_in = session
+            .as_ref()
+            .filter(|session| session.phone == message.phone);
+        if let Some(session) = signed_in {
+            terminals.touch(&session.phone, session.connection);
+        }
+        let signed_in = signed_in.is_some();
+
+        // Sub-packages are held until the message is complete, which only
+        // terminals signed in may make the server do.
+        if message.package.is_some() && !signed_in {
+            eprintln!(
+                "Dropping JT/T 808 sub-package of terminal {} before sign-in",
+                message.phone
+            );
+            continue;
+        }
+        let Some(message) = assembler.push(message) else {
+            continue;
+        };
+
+        let reply = match message.id {
+            REGISTRATION => Some(match Registration::parse(&message) {
+                Ok(registration) => {
+                    println!(
+                        "Terminal {} registered: {} {} ({})",
+                        message.phone, registration.manufacturer, registration.model, peer
+                    );
+                    let auth_code = terminals.register(&message.phone, registration);
+                    let body = registration_response(&message, RESULT_SUCCESS, Some(&auth_code));
+                    (REGISTRATION_RESPONSE, body)
+                }
+                Err(e) => {
+                    eprintln!("Invalid registration of terminal {}: {e}", message.phone);
+                    let body = registration_response(&message, REGISTRATION_UNKNOWN_TERMINAL, None);
+                    (REGISTRATION_RESPONSE, body)
+                }
+            }),
+            AUTHENTICATION => {
+                let result = match parse_auth_code(&message) {
+                    Ok(auth_code) => match terminals.authenticate(
+                        &message.phone,
+                        &auth_code,
+                        peer,
+                        commands_tx.clone(),
+                    ) {
+                        Some(connection) => {
+                            println!("Terminal {} signed in from {peer}", message.phone);
+                            if let Some(previous) = session.take() {
+                                terminals.disconnect(&previous.phone, previous.connection);
+                            }
+                            session = Some(Session {
+                                phone: message.phone.clone(),
+                                version: message.version,
+                                connection,
+                            });
+                            RESULT_SUCCESS
+                        }
+                        None => {
+                            eprintln!("Terminal {} failed to authenticate", message.phone);
+                            RESULT_FAILURE
+                        }
+                    },
+                    Err(_) => RESULT_INVALID,
+                };
+                Some((PLATFORM_RESPONSE, platform_response(&message, result)))
+            }
+            // Only the terminal itself, signed in on this connection, may
+            // give up its registration.
+            DEREGISTRATION => {
+                let result = match session.take_if(|_| signed_in) {
+                    Some(session) => {
+                        terminals.deregister(&session.phone);
+                        RESULT_SUCCESS
+                    }
+                    None => {
+                        eprintln!("Terminal {} failed to deregister", message.phone);
+                        RESULT_FAILURE
+                    }
+                };
+                Some((PLATFORM_RESPONSE, platform_response(&message, result)))
+            }
+            // Heartbeats only keep the session alive.
+            HEARTBEAT => Some(acknowledge(&message, signed_in)),
+            // Answers to platform messages need no reply.
+            TERMINAL_RESPONSE => {
+                answer(&mut pending, &message);
+                None
+            }
+            // Answers carrying data are acknowledged like other messages.
+            RESOURCE_LIST => {
+                answer(&mut pending, &message);
+                Some(acknowledge(&message, signed_in))
+            }
+            // Messages this server does not act on are acknowledged too,
+            // so that the terminal does not send them again.
+            _ => Some(acknowledge(&message, signed_in)),
+        };
+
+        let Some((id, body)) = reply else {
+            continue;
+        };
+        serial = serial.wrapping_add(1);
+        if let Err(e) = framed
+            .send(Jt808Message::reply(&message, id, serial, body))
+            .await
+        {
+            eprintln!("Failed to reply to terminal {}: {e}", message.phone);
+            break;
+        }
+    }
+
+    if let Some(session) = session {
+        println!("Terminal {} signed out", session.phone);
+        terminals.disconnect(&session.phone, session.connection);
+    }
+}
+
+/// Passes an answer to the platform message it names by serial, in the
+/// first word of its body, to whoever waits for it.
+fn answer(pending: &mut HashMap<u16, oneshot::Sender<Jt808Message>>, message: &Jt808Message) {
+    let Some(serial) = message.body.get(..2) else {
+        return;
+    };
+    let serial = u16::from_be_bytes([serial[0], serial[1]]);
+    if let Some(waiter) = pending.remove(&serial) {
+        let _ = waiter.send(message.clone());
+    }
+}
+
+/// 0x8001 reply to a message that needs a session: success once signed in.
+fn acknowledge(message: &Jt808Message, signed_in: bool) -> (u16, Bytes) {
+    let result = if signed_in {
+        RESULT_SUCCESS
+    } else {
+        RESULT_FAILURE
+    };
+    (PLATFORM_RESPONSE, platform_response(message, result))
+}
@@ -1,5 +1,9 @@
+mod jt808_server;
+mod rtsp_server;
 mod tcp_server;
 mod web_server;
 
+pub use jt808_server::Jt808Server;
+pub use rtsp_server::RtspServer;
 pub use tcp_server::TcpServer;
 pub use web_server::WebServer;
@@ -0,0 +1,554 @@
+use crate::live::{FrameKind, LiveSubscription};
+use crate::processor::VideoCodec;
+use crate::registry::{StreamKey, StreamRegistry};
+use crate::rtsp::{MediaDescription, RtpPacketizer, AUDIO_TRACK, VIDEO_TRACK};
+use crate::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_util::StreamExt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::pin;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// Session timeout announced to clients, in seconds. Sessions actually
+/// last as long as their control connection.
+const SESSION_TIMEOUT: u64 = 60;
+
+/// Upper bound of a request header, against clients that never end it.
+const MAX_HEADER_LENGTH: usize = 16 * 1024;
+
+const PUBLIC_METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER";
+
+/// RTSP 1.0 (RFC 2326) server of the live feeds: each (terminal, channel)
+/// is `rtsp://host/{imei}/{channel}`, played with RTP over the control
+/// connection (interleaved) or over UDP.
+pub struct RtspServer {
+    address: SocketAddr,
+    listener: std::net::TcpListener,
+    registry: StreamRegistry,
+}
+
+impl RtspServer {
+    pub fn new(host: &str, port: u16) -> Result<Self> {
+        let port: u16 = std::env::var("RTSP_PORT")
+            .unwrap_or_else(|_| port.to_string())
+            .parse()
+            .expect("Failed to parse RTSP port");
+
+        let address: SocketAddr = format!("{}:{}", host, port)
+            .parse()
+            .expect("Failed to parse address");
+
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+
+        Ok(Self {
+            address,
+            listener,
+            registry: StreamRegistry::new(),
+        })
+    }
+
+    /// Plays the streams published in `registry`, shared with the
+    /// [`TcpServer`](crate::server::TcpServer).
+    pub fn with_registry(mut self, registry: StreamRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        println!("RTSP Server listening on {}", self.address);
+
+        let listener = TcpListener::from_std(self.listener)?;
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let registry = self.registry.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Connection::new(stream, peer, registry).serve().await {
+                    eprintln!("RTSP connection from {peer} failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Stream and track ID of the request URI, whether absolute or just a
+    /// path: `/{imei}/{channel}[/trackID={id}]`.
+    fn target(&self) -> Option<(StreamKey, Option<usize>)> {
+        let path = match self.uri.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("", |start| &rest[start..]),
+            None => &self.uri,
+        };
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+        let imei = segments.next()?;
+        let channel = segments.next()?.parse().ok()?;
+        let track = match segments.next() {
+            Some(segment) => Some(segment.strip_prefix("trackID=")?.parse().ok()?),
+            None => None,
+        };
+        if segments.next().is_some() {
+            return None;
+        }
+        Some((StreamKey::new(imei, channel), track))
+    }
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn new(status: u16, reason: &'static str) -> Self {
+        Self {
+            status,
+            reason,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn ok() -> Self {
+        Self::new(200, "OK")
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn serialize(&self, cseq: Option<&str>) -> String {
+        let mut response = format!("RTSP/1.0 {} {}\r\n", self.status, self.reason);
+        if let Some(cseq) = cseq {
+            response.push_str(&format!("CSeq: {cseq}\r\n"));
+        }
+        for (name, value) in &self.headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !self.body.is_empty() {
+            response.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        response.push_str("\r\n");
+        response.push_str(&self.body);
+        response
+    }
+}
+
+/// Where the packets of a track go.
+enum Transport {
+    /// Framed on the control connection, on the given channel.
+    Interleaved(u8),
+    Udp {
+        rtp: UdpSocket,
+        /// Only bound, so that the announced RTCP port is ours.
+        _rtcp: UdpSocket,
+        destination: SocketAddr,
+    },
+}
+
+impl Transport {
+    async fn send(&self, writer: &Mutex<OwnedWriteHalf>, packets: Vec<Bytes>) -> io::Result<()> {
+        match self {
+            Self::Interleaved(channel) => {
+                let length = packets.iter().map(|packet| 4 + packet.len()).sum();
+                let mut data = BytesMut::with_capacity(length);
+                for packet in packets {
+                    data.put_u8(b'$');
+                    data.put_u8(*channel);
+                    data.put_u16(packet.len() as u16);
+                    data.put_slice(&packet);
+                }
+                writer.lock().await.write_all(&data).await
+            }
+            Self::Udp {
+                rtp, destination, ..
+            } => {
+                for packet in packets {
+                    rtp.send_to(&packet, destination).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Session {
+    id: String,
+    key: StreamKey,
+    media: MediaDescription,
+    transports: [Option<Transport>; 2],
+    player: Option<JoinHandle<()>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(player) = &self.player {
+            player.abort();
+        }
+    }
+}
+
+/// Control connection of a client, with at most one session.
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    peer: SocketAddr,
+    registry: StreamRegistry,
+    session: Option<Session>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer: SocketAddr, registry: StreamRegistry) -> Self {
+        let (reader, writer) = stream.into_split();
+        Self {
+            reader: BufReader::new(reader),
+            writer: Arc::new(Mutex::new(writer)),
+            peer,
+            registry,
+            session: None,
+        }
+    }
+
+    async fn serve(mut self) -> io::Result<()> {
+        while let Some(request) = self.read_request().await? {
+            let response = match request.method.as_str() {
+                "OPTIONS" => Response::ok().header("Public", PUBLIC_METHODS),
+                "DESCRIBE" => self.describe(&request),
+                "SETUP" => self.setup(&request).await,
+                "PLAY" => self.play(&request),
+                "TEARDOWN" => {
+                    self.session = None;
+                    Response::ok()
+                }
+                "GET_PARAMETER" | "SET_PARAMETER" => Response::ok(),
+                _ => Response::new(501, "Not Implemented"),
+            };
+            let response = response.serialize(request.header("CSeq"));
+            self.writer
+                .lock()
+                .await
+                .write_all(response.as_bytes())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next request, skipping the interleaved RTCP of the client.
+    /// Returns `None` once the client has closed the connection.
+    async fn read_request(&mut self) -> io::Result<Option<Request>> {
+        loop {
+            let buffer = self.reader.fill_buf().await?;
+            match buffer.first() {
+                None => return Ok(None),
+                Some(b'$') => {
+                    let mut header = [0u8; 4];
+                    self.reader.read_exact(&mut header).await?;
+                    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+                    let mut packet = vec![0u8; length];
+                    self.reader.read_exact(&mut packet).await?;
+                }
+                Some(_) => break,
+            }
+        }
+
+        let mut lines = Vec::new();
+        let mut header_length = 0;
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line).await? == 0 {
+                return Ok(None);
+            }
+            header_length += line.len();
+            if header_length > MAX_HEADER_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "RTSP request header too long",
+                ));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line.to_string());
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid RTSP request line");
+        let request_line = lines.first().ok_or_else(invalid)?;
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(uri)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let request = Request {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers: lines[1..]
+                .iter()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                .collect(),
+        };
+
+        // Bodies, such as the parameters of GET_PARAMETER, are ignored, so
+        // they are skipped without being buffered.
+        if let Some(length) = request.header("Content-Length") {
+            let length: u64 = length.parse().map_err(|_| invalid())?;
+            let mut body = (&mut self.reader).take(length);
+            let skipped = tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+            if skipped < length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(Some(request))
+    }
+
+    fn subscribe(&self, key: &StreamKey) -> Option<LiveSubscription> {
+        self.registry
+            .get(key)
+            .and_then(|live| live.feed.subscribe())
+    }
+
+    fn describe(&self, request: &Request) -> Response {
+        let Some((key, None)) = request.target() else {
+            return Response::new(404, "Not Found");
+        };
+        let Some(subscription) = self.subscribe(&key) else {
+            return Response::new(404, "Not Found");
+        };
+        let media = MediaDescription::new(&subscription);
+        let base = format!("{}/", request.uri.trim_end_matches('/'));
+        let mut response = Response::ok()
+            .header("Content-Type", "application/sdp")
+            .header("Content-Base", base);
+        response.body = media.sdp(rand::random::<u32>() as u64, &key.to_string());
+        response
+    }
+
+    async fn setup(&mut self, request: &Request) -> Response {
+        let Some((key, track)) = request.target() else {
+            return Response::new(404, "Not Found");
+        };
+        let track = track.unwrap_or(VIDEO_TRACK);
+
+        match &self.session {
+            Some(session) if session.key != key => {
+                return Response::new(459, "Aggregate Operation Not Allowed");
+            }
+            Some(session) if session.player.is_some() => {
+                return Response::new(455, "Method Not Valid in This State");
+            }
+            Some(_) => (),
+            None => {
+                let Some(subscription) = self.subscribe(&key) else {
+                    return Response::new(404, "Not Found");
+                };
+                self.session = Some(Session {
+                    id: format!("{:016X}", rand::random::<u64>()),
+                    key,
+                    media: MediaDescription::new(&subscription),
+                    transports: [None, None],
+                    player: None,
+                });
+            }
+        }
+        let session = self.session.as_mut().expect("Session was just set up");
+        if track > AUDIO_TRACK || (track == AUDIO_TRACK && session.media.audio.is_none()) {
+            return Response::new(404, "Not Found");
+        }
+
+        let Some(transport) = request.header("Transport") else {
+            return Response::new(461, "Unsupported Transport");
+        };
+        let (transport, reply) = match negotiate_transport(transport, track, self.peer).await {
+            Ok(Some(negotiated)) => negotiated,
+            Ok(None) => return Response::new(461, "Unsupported Transport"),
+            Err(e) => {
+                eprintln!("Failed to bind RTP sockets: {e}");
+                return Response::new(500, "Internal Server Error");
+            }
+        };
+        session.transports[track] = Some(transport);
+        Response::ok().header("Transport", reply).header(
+            "Session",
+            format!("{};timeout={SESSION_TIMEOUT}", session.id),
+        )
+    }
+
+    fn play(&mut self, request: &Request) -> Response {
+        let Some(session) = &mut self.session else {
+            return Response::new(454, "Session Not Found");
+        };
+        let id = request
+            .header("Session")
+            .and_then(|session| session.split(';').next());
+        if id != Some(session.id.as_str()) {
+            return Response::new(454, "Session Not Found");
+        }
+        let response = Response::ok().header("Session", session.id.clone());
+        if session.player.is_some() {
+            return response;
+        }
+        let subscription = self
+            .registry
+            .get(&session.key)
+            .and_then(|live| live.feed.subscribe());
+        let Some(subscription) = subscription else {
+            return Response::new(404, "Not Found");
+        };
+        if subscription.tracks.video != session.media.video {
+            return Response::new(404, "Not Found");
+        }
+
+        let mut packetizers = [None, None];
+        if session.transports[VIDEO_TRACK].is_some() {
+            packetizers[VIDEO_TRACK] = Some(RtpPacketizer::video());
+        }
+        if let (Some(_), Some(audio)) = (&session.transports[AUDIO_TRACK], session.media.audio) {
+            packetizers[AUDIO_TRACK] = Some(RtpPacketizer::audio(audio));
+        }
+        let base = request.uri.trim_end_matches('/');
+        let start_ms = subscription.gop.first().map_or(0, |frame| frame.time_ms);
+        let rtp_info: Vec<_> = packetizers
+            .iter()
+            .enumerate()
+            .filter_map(|(track, packetizer)| {
+                let packetizer = packetizer.as_ref()?;
+                Some(format!(
+                    "url={base}/trackID={track};seq={};rtptime={}",
+                    packetizer.sequence(),
+                    packetizer.timestamp(start_ms)
+                ))
+            })
+            .collect();
+
+        let transports = std::mem::take(&mut session.transports);
+        session.player = Some(tokio::spawn(send_frames(
+            subscription,
+            session.media.video,
+            transports,
+            packetizers,
+            Arc::clone(&self.writer),
+        )));
+        response
+            .header("Range", "npt=0.000-")
+            .header("RTP-Info", rtp_info.join(","))
+    }
+}
+
+/// Transport of a track from the `Transport` header of SETUP, and the
+/// header of the reply. `None` if no transport offered is supported.
+async fn negotiate_transport(
+    header: &str,
+    track: usize,
+    peer: SocketAddr,
+) -> io::Result<Option<(Transport, String)>> {
+    for transport in header.split(',') {
+        let mut parameters = transport.split(';').map(str::trim);
+        let protocol = parameters.next().unwrap_or_default();
+        let parameters: Vec<_> = parameters.collect();
+        let ports = |name: &str| -> Option<(u16, u16)> {
+            let value = parameters
+                .iter()
+                .find_map(|parameter| parameter.strip_prefix(name)?.strip_prefix('='))?;
+            let (first, second) = value.split_once('-').unwrap_or((value, value));
+            Some((first.parse().ok()?, second.parse().ok()?))
+        };
+        if parameters.contains(&"multicast") {
+            continue;
+        }
+
+        match protocol {
+            "RTP/AVP/TCP" => {
+                let channel = match ports("interleaved") {
+                    Some((channel, _)) => channel,
+                    None => 2 * track as u16,
+                };
+                let Ok(channel) = u8::try_from(channel) else {
+                    continue;
+                };
+                let reply = format!(
+                    "RTP/AVP/TCP;unicast;interleaved={channel}-{}",
+                    channel.wrapping_add(1)
+                );
+                return Ok(Some((Transport::Interleaved(channel), reply)));
+            }
+            "RTP/AVP" | "RTP/AVP/UDP" => {
+                let Some((rtp_port, rtcp_port)) = ports("client_port") else {
+                    continue;
+                };
+                let rtp = UdpSocket::bind((peer_unspecified(peer), 0)).await?;
+                let rtcp = UdpSocket::bind((peer_unspecified(peer), 0)).await?;
+                let reply = format!(
+                    "RTP/AVP;unicast;client_port={rtp_port}-{rtcp_port};server_port={}-{}",
+                    rtp.local_addr()?.port(),
+                    rtcp.local_addr()?.port()
+                );
+                let transport = Transport::Udp {
+                    rtp,
+                    _rtcp: rtcp,
+                    destination: SocketAddr::new(peer.ip(), rtp_port),
+                };
+                return Ok(Some((transport, reply)));
+            }
+            _ => (),
+        }
+    }
+    Ok(None)
+}
+
+/// Unspecified address of the family of `peer`, to send to it from.
+fn peer_unspecified(peer: SocketAddr) -> IpAddr {
+    match peer {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Sends the live feed from the cached GOP on until the stream ends or
+/// the client can no longer be reached.
+async fn send_frames(
+    subscription: LiveSubscription,
+    codec: VideoCodec,
+    transports: [Option<Transport>; 2],
+    mut packetizers: [Option<RtpPacketizer>; 2],
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+) {
+    let mut frames = pin!(subscription.into_stream());
+    while let Some(frame) = frames.next().await {
+        let track = match frame.kind {
+            FrameKind::Video { .. } => VIDEO_TRACK,
+            FrameKind::Audio => AUDIO_TRACK,
+        };
+        let (Some(transport), Some(packetizer)) = (&transports[track], &mut packetizers[track])
+        else {
+            continue;
+        };
+        let packets = packetizer.packetize(&frame, codec);
+        if let Err(e) = transport.send(&writer, packets).await {
+            eprintln!("Failed to send RTSP media: {e}");
+            return;
+        }
+    }
+}
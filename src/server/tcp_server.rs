@@ -1,15 +1,28 @@
-use crate::processor::RtpProcessor;
-use crate::rtp::RtpPacket;
+use crate::assembler::{Frame, FrameAssembler};
+use crate::hls::CLEAN_UP_DELAY;
+use crate::idle::IdlePolicy;
+use crate::processor::{HlsBackend, HlsConfig, HlsContainer, RtpProcessor};
+use crate::registry::StreamRegistry;
+use crate::relay::RtmpPushRule;
+use crate::rtp::{ProtocolVersion, RtpReader};
+use crate::terminal::TerminalRegistry;
 use std::net::SocketAddr;
-use tokio::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::sync::{broadcast, mpsc};
 use tokio::task::JoinHandle;
 
 pub struct TcpServer {
     address: SocketAddr,
+    clean_up_delay: Duration,
     handles: Vec<JoinHandle<()>>,
+    hls: HlsConfig,
+    idle: IdlePolicy,
     listener: Option<TcpListener>,
+    protocol_version: Option<ProtocolVersion>,
+    registry: StreamRegistry,
+    rtmp_push: Arc<[RtmpPushRule]>,
 }
 
 impl TcpServer {
@@ -23,17 +36,138 @@ impl TcpServer {
             .parse()
             .expect("Failed to parse server address");
 
+        let hls = HlsConfig {
+            backend: match std::env::var("HLS_BACKEND") {
+                Ok(backend) => backend.parse().expect("Failed to parse HLS backend"),
+                Err(_) => HlsBackend::default(),
+            },
+            container: std::env::var("HLS_CONTAINER")
+                .ok()
+                .map(|container| container.parse().expect("Failed to parse HLS container")),
+            low_latency: std::env::var("HLS_LOW_LATENCY")
+                .map(|low_latency| {
+                    low_latency
+                        .parse()
+                        .expect("Failed to parse HLS_LOW_LATENCY")
+                })
+                .unwrap_or_default(),
+            dash: std::env::var("DASH")
+                .map(|dash| dash.parse().expect("Failed to parse DASH"))
+                .unwrap_or_default(),
+        };
+
+        // Whitespace separated, as URLs cannot contain any.
+        let rtmp_push = std::env::var("RTMP_PUSH")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|rule| rule.parse().expect("Failed to parse RTMP_PUSH"))
+            .collect();
+
+        let idle = IdlePolicy {
+            // Seconds, zero keeping streams running.
+            timeout: std::env::var("IDLE_TIMEOUT")
+                .ok()
+                .map(|timeout| timeout.parse().expect("Failed to parse IDLE_TIMEOUT"))
+                .filter(|&timeout| timeout > 0)
+                .map(Duration::from_secs),
+            terminals: None,
+            webhook: std::env::var("IDLE_WEBHOOK").ok(),
+        };
+
         let socket = Self::prepare_socket(address);
         let listener = socket.listen(1024).expect("Failed to listen on socket");
         let address = listener.local_addr().expect("Failed to get local address");
 
         Self {
             address,
+            clean_up_delay: CLEAN_UP_DELAY,
             handles: Vec::new(),
+            hls,
+            idle,
             listener: Some(listener),
+            protocol_version: None,
+            registry: StreamRegistry::new(),
+            rtmp_push,
         }
     }
 
+    /// Only accept headers of the given JT/T 1078 revision instead of
+    /// detecting it on every connection.
+    pub fn with_protocol_version(mut self, protocol_version: ProtocolVersion) -> Self {
+        self.protocol_version = Some(protocol_version);
+        self
+    }
+
+    /// Overrides the `HLS_BACKEND` environment variable.
+    pub fn with_hls_backend(mut self, hls_backend: HlsBackend) -> Self {
+        self.hls.backend = hls_backend;
+        self
+    }
+
+    /// Overrides the `HLS_CONTAINER` environment variable. By default H.264
+    /// is segmented into MPEG-TS and H.265 into fMP4.
+    pub fn with_hls_container(mut self, hls_container: HlsContainer) -> Self {
+        self.hls.container = Some(hls_container);
+        self
+    }
+
+    /// Overrides the `HLS_LOW_LATENCY` environment variable. Low-latency
+    /// HLS adds partial segments and blocking playlist reloads, served by a
+    /// [`WebServer`](crate::server::WebServer) sharing the registry.
+    pub fn with_low_latency_hls(mut self, low_latency: bool) -> Self {
+        self.hls.low_latency = low_latency;
+        self
+    }
+
+    /// Overrides the `DASH` environment variable. Streams segmented into
+    /// fMP4 also get an MPEG-DASH manifest over the same segments.
+    pub fn with_dash(mut self, dash: bool) -> Self {
+        self.hls.dash = dash;
+        self
+    }
+
+    /// Keeps the playlist and segments of an ended stream for `delay`
+    /// instead of one target duration, for players to load its end.
+    pub fn with_clean_up_delay(mut self, delay: Duration) -> Self {
+        self.clean_up_delay = delay;
+        self
+    }
+
+    /// Overrides the `RTMP_PUSH` environment variable. Each stream is
+    /// published over RTMP to every rule matching it.
+    pub fn with_rtmp_push(mut self, rules: Vec<RtmpPushRule>) -> Self {
+        self.rtmp_push = rules.into();
+        self
+    }
+
+    /// Overrides the `IDLE_TIMEOUT` environment variable. Streams nobody
+    /// has watched for `timeout` are stopped.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle.timeout = Some(timeout);
+        self
+    }
+
+    /// Overrides the `IDLE_WEBHOOK` environment variable. Each stream
+    /// stopped for lack of viewers is posted to `url` as a JSON event.
+    pub fn with_idle_webhook(mut self, url: &str) -> Self {
+        self.idle.webhook = Some(url.to_string());
+        self
+    }
+
+    /// Asks terminals signed in to the
+    /// [`Jt808Server`](crate::server::Jt808Server) sharing `terminals` to
+    /// close the transfer of streams stopped for lack of viewers.
+    pub fn with_terminals(mut self, terminals: TerminalRegistry) -> Self {
+        self.idle.terminals = Some(terminals);
+        self
+    }
+
+    /// Publishes the streams in `registry`, to share it with the web server.
+    pub fn with_registry(mut self, registry: StreamRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
     pub fn address(&self) -> SocketAddr {
         self.address
     }
@@ -63,34 +197,62 @@ impl TcpServer {
     async fn listen(&mut self, listener: TcpListener) {
         while let Ok((stream, peer)) = listener.accept().await {
             println!("Incoming connection from: {peer}");
-            let (tx, rx) = mpsc::channel::<RtpPacket>(100);
-            let mut processor = RtpProcessor::new();
+            let (tx, rx) = mpsc::channel::<Frame>(100);
+            let mut processor = RtpProcessor::new(self.registry.clone(), self.hls)
+                .with_rtmp_push(Arc::clone(&self.rtmp_push))
+                .with_idle_policy(self.idle.clone())
+                .with_clean_up_delay(self.clean_up_delay);
             self.handles.push(tokio::spawn(async move {
                 processor.listen(rx).await;
             }));
-            tokio::spawn(Self::handle_connection(stream, tx));
+            tokio::spawn(Self::handle_connection(stream, tx, self.protocol_version));
         }
     }
 
-    async fn handle_connection(mut stream: TcpStream, tx: mpsc::Sender<RtpPacket>) {
+    async fn handle_connection(
+        mut stream: TcpStream,
+        tx: mpsc::Sender<Frame>,
+        protocol_version: Option<ProtocolVersion>,
+    ) {
         let (reader, _) = stream.split();
-        let mut buf_reader = BufReader::new(reader);
+        let mut reader = match protocol_version {
+            Some(protocol_version) => RtpReader::with_protocol_version(reader, protocol_version),
+            None => RtpReader::new(reader),
+        };
+        let mut assembler = FrameAssembler::new();
 
         loop {
-            let packet = match RtpPacket::parse(&mut buf_reader).await {
+            let packet = tokio::select! {
+                packet = reader.read_packet() => packet,
+                // The processor ended the stream, e.g. on a 0x9102 close.
+                _ = tx.closed() => {
+                    println!("Stream ended, closing connection");
+                    return;
+                }
+            };
+            let packet = match packet {
                 Ok(packet) => packet,
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                        println!("Connection closed by client");
-                        return;
+                        println!(
+                            "Connection closed by client ({} incomplete frames dropped, \
+                            {} bytes discarded)",
+                            assembler.dropped_frames(),
+                            reader.discarded_bytes()
+                        );
+                    } else {
+                        eprintln!("Failed to read packet: {e}");
                     }
-                    eprintln!("Failed to parse packet: {e}");
-                    continue;
+                    return;
                 }
             };
 
-            if let Err(e) = tx.send(packet).await {
-                eprintln!("Failed to send packet: {e}");
+            let Some(frame) = assembler.push(packet) else {
+                continue;
+            };
+
+            if let Err(e) = tx.send(frame).await {
+                eprintln!("Failed to send frame: {e}");
                 break;
             }
         }
@@ -1,8 +1,39 @@
+use std::convert::Infallible;
+use std::pin::pin;
 use std::{net::SocketAddr, path::PathBuf};
 
+use crate::flv;
+use crate::hls::BLOCKING_RELOAD_TIMEOUT;
+use crate::jt808::{
+    AvControl, BcdTime, CloseMedia, DataType, MediaType, RealtimeAvRequest, RecordStream,
+    ResourceList, ResourceQuery, Storage, StreamType, TerminalResponse, REALTIME_AV_CONTROL,
+    REALTIME_AV_REQUEST, RESOURCE_LIST, RESOURCE_QUERY, RESULT_SUCCESS,
+};
+use crate::live::LiveSubscription;
+use crate::registry::{StreamKey, StreamRegistry};
+use crate::terminal::TerminalRegistry;
+use crate::whep::WhepSessions;
 use crate::Result;
 use actix_files::NamedFile;
-use actix_web::{dev::Server, get, web, App, HttpResponse, HttpServer, Responder};
+use actix_web::error::{
+    ErrorBadGateway, ErrorBadRequest, ErrorGatewayTimeout, ErrorNotAcceptable, ErrorNotFound,
+    ErrorServiceUnavailable,
+};
+use actix_web::{delete, get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_ws::Message;
+use bytes::Bytes;
+use futures_util::StreamExt;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// A blocking playlist reload may ask for at most this many segments past
+/// the one in progress.
+const MAX_MSN_AHEAD: u64 = 2;
+
+/// Time a terminal has to connect to the media server once it has accepted
+/// a real-time request.
+const MEDIA_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[get("/health_check")]
 async fn health_check() -> impl Responder {
@@ -12,38 +43,523 @@ async fn health_check() -> impl Responder {
 #[derive(serde::Deserialize)]
 struct Stream {
     imei: String,
+    channel: u8,
+}
+
+/// LL-HLS delivery directives of a blocking playlist reload.
+#[derive(serde::Deserialize)]
+struct PlaylistQuery {
+    #[serde(rename = "_HLS_msn")]
+    msn: Option<u64>,
+    #[serde(rename = "_HLS_part")]
+    part: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+struct Segment {
+    imei: String,
+    channel: u8,
     segment: String,
 }
 
-#[get("/{imei}/{segment}.ts")]
-async fn get_segment(stream: web::Path<Stream>) -> impl Responder {
-    let path = PathBuf::from(format!("{}/streams/{}.ts", stream.imei, stream.segment));
+async fn read_file(path: PathBuf, content_type: &str) -> HttpResponse {
     if path.exists() {
         let content = match tokio::fs::read(path).await {
             Ok(content) => content,
             Err(_) => return HttpResponse::InternalServerError().finish(),
         };
-        HttpResponse::Ok().content_type("video/mp2t").body(content)
+        HttpResponse::Ok().content_type(content_type).body(content)
     } else {
         HttpResponse::NotFound().finish()
     }
 }
 
-#[get("/{imei}/playlist.m3u8")]
-async fn get_playlist(imei: web::Path<String>) -> impl Responder {
-    let path = PathBuf::from(format!("{}/playlist.m3u8", imei));
-    NamedFile::open_async(path).await
+/// Counts a request of an HLS or DASH player as a viewer of the stream,
+/// which keeps it from being stopped as idle.
+fn viewed(registry: &StreamRegistry, imei: &str, channel: u8) {
+    if let Some(live) = registry.get(&StreamKey::new(imei, channel)) {
+        live.viewed();
+    }
+}
+
+/// Holds the request for a part announced by `EXT-X-PRELOAD-HINT` until
+/// the segmenter has written it.
+async fn wait_for_part(registry: &StreamRegistry, stream: &Segment) {
+    let Some((msn, part)) = stream.segment.split_once('.') else {
+        return;
+    };
+    let (Ok(msn), Ok(part)) = (msn.parse(), part.parse()) else {
+        return;
+    };
+    let Some(live) = registry.get(&StreamKey::new(&stream.imei, stream.channel)) else {
+        return;
+    };
+    let mut progress = live.playlist.subscribe();
+    drop(live);
+    let _ = timeout(
+        BLOCKING_RELOAD_TIMEOUT,
+        progress.wait_for(|progress| progress.contains(msn, Some(part))),
+    )
+    .await;
+}
+
+#[get("/{imei}/{channel}/{segment}.ts")]
+async fn get_segment(
+    stream: web::Path<Segment>,
+    registry: web::Data<StreamRegistry>,
+) -> impl Responder {
+    let path = PathBuf::from(format!(
+        "{}/{}/streams/{}.ts",
+        stream.imei, stream.channel, stream.segment
+    ));
+    viewed(&registry, &stream.imei, stream.channel);
+    if !path.exists() {
+        wait_for_part(&registry, &stream).await;
+    }
+    read_file(path, "video/mp2t").await
+}
+
+#[get("/{imei}/{channel}/{segment}.m4s")]
+async fn get_fmp4_segment(
+    stream: web::Path<Segment>,
+    registry: web::Data<StreamRegistry>,
+) -> impl Responder {
+    let path = PathBuf::from(format!(
+        "{}/{}/streams/{}.m4s",
+        stream.imei, stream.channel, stream.segment
+    ));
+    viewed(&registry, &stream.imei, stream.channel);
+    if !path.exists() {
+        wait_for_part(&registry, &stream).await;
+    }
+    read_file(path, "video/iso.segment").await
+}
+
+/// Serves `init.mp4`, or `init-<period>.mp4` after a switch of stream.
+#[get("/{imei}/{channel}/{segment:init(-[0-9]+)?}.mp4")]
+async fn get_init_segment(
+    stream: web::Path<Segment>,
+    registry: web::Data<StreamRegistry>,
+) -> impl Responder {
+    viewed(&registry, &stream.imei, stream.channel);
+    let path = PathBuf::from(format!(
+        "{}/{}/{}.mp4",
+        stream.imei, stream.channel, stream.segment
+    ));
+    read_file(path, "video/mp4").await
+}
+
+#[get("/{imei}/{channel}/manifest.mpd")]
+async fn get_manifest(
+    stream: web::Path<Stream>,
+    registry: web::Data<StreamRegistry>,
+) -> impl Responder {
+    viewed(&registry, &stream.imei, stream.channel);
+    let path = PathBuf::from(format!("{}/{}/manifest.mpd", stream.imei, stream.channel));
+    read_file(path, "application/dash+xml").await
+}
+
+/// Serves the playlist. A blocking reload with `_HLS_msn` (and optionally
+/// `_HLS_part`) waits until the playlist contains that segment or part.
+#[get("/{imei}/{channel}/playlist.m3u8")]
+async fn get_playlist(
+    stream: web::Path<Stream>,
+    query: web::Query<PlaylistQuery>,
+    registry: web::Data<StreamRegistry>,
+) -> actix_web::Result<NamedFile> {
+    let key = StreamKey::new(&stream.imei, stream.channel);
+    viewed(&registry, &stream.imei, stream.channel);
+    match (query.msn, query.part) {
+        (None, Some(_)) => return Err(ErrorBadRequest("_HLS_part requires _HLS_msn")),
+        (Some(msn), part) => {
+            if let Some(live) = registry.get(&key) {
+                let mut progress = live.playlist.subscribe();
+                drop(live);
+                if msn > progress.borrow().sequence + MAX_MSN_AHEAD {
+                    return Err(ErrorBadRequest("_HLS_msn is too far ahead of the stream"));
+                }
+                let ready = progress.wait_for(|progress| progress.contains(msn, part));
+                // An ended stream serves its final playlist.
+                if timeout(BLOCKING_RELOAD_TIMEOUT, ready).await.is_err() {
+                    return Err(ErrorServiceUnavailable("Requested part is not available"));
+                }
+            }
+        }
+        (None, None) => (),
+    }
+
+    let path = key.dir().join("playlist.m3u8");
+    Ok(NamedFile::open_async(path).await?)
+}
+
+fn subscribe(registry: &StreamRegistry, stream: &Stream) -> actix_web::Result<LiveSubscription> {
+    let key = StreamKey::new(&stream.imei, stream.channel);
+    registry
+        .get(&key)
+        .and_then(|live| live.feed.subscribe())
+        .ok_or_else(|| ErrorNotFound("Stream is not live"))
+}
+
+/// Sends `messages` as binary WebSocket messages until either side ends
+/// the connection.
+fn serve_websocket(
+    req: &HttpRequest,
+    body: web::Payload,
+    messages: impl futures_util::Stream<Item = Bytes> + 'static,
+) -> actix_web::Result<HttpResponse> {
+    let (response, mut session, mut incoming) = actix_ws::handle(req, body)?;
+    actix_web::rt::spawn(async move {
+        let mut messages = pin!(messages);
+        loop {
+            tokio::select! {
+                message = messages.next() => match message {
+                    Some(message) => {
+                        if session.binary(message).await.is_err() {
+                            return;
+                        }
+                    }
+                    None => break,
+                },
+                message = incoming.recv() => match message {
+                    Some(Ok(Message::Ping(ping))) => {
+                        if session.pong(&ping).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => (),
+                },
+            }
+        }
+        let _ = session.close(None).await;
+    });
+    Ok(response)
+}
+
+/// Streams the live feed as HTTP-FLV, starting at the latest GOP.
+#[get("/live/{imei}/{channel}.flv")]
+async fn get_live_flv(
+    stream: web::Path<Stream>,
+    registry: web::Data<StreamRegistry>,
+) -> actix_web::Result<HttpResponse> {
+    let subscription = subscribe(&registry, &stream)?;
+    let body = flv::live_body(subscription).map(Ok::<_, Infallible>);
+    Ok(HttpResponse::Ok()
+        .content_type("video/x-flv")
+        .streaming(body))
+}
+
+/// Pushes the live feed over a WebSocket as FLV tags, for flv.js and
+/// similar players.
+#[get("/ws/{imei}/{channel}.flv")]
+async fn get_ws_flv(
+    req: HttpRequest,
+    body: web::Payload,
+    stream: web::Path<Stream>,
+    registry: web::Data<StreamRegistry>,
+) -> actix_web::Result<HttpResponse> {
+    let subscription = subscribe(&registry, &stream)?;
+    serve_websocket(&req, body, flv::live_body(subscription))
+}
+
+/// Pushes the live feed over a WebSocket one frame per message, see
+/// [`LiveFrame::raw_message`](crate::live::LiveFrame::raw_message).
+#[get("/ws/{imei}/{channel}.raw")]
+async fn get_ws_raw(
+    req: HttpRequest,
+    body: web::Payload,
+    stream: web::Path<Stream>,
+    registry: web::Data<StreamRegistry>,
+) -> actix_web::Result<HttpResponse> {
+    let subscription = subscribe(&registry, &stream)?;
+    let tracks = subscription.tracks;
+    let messages = subscription
+        .into_stream()
+        .map(move |frame| frame.raw_message(tracks));
+    serve_websocket(&req, body, messages)
+}
+
+/// WHEP endpoint: answers the SDP offer of a WebRTC player with a session
+/// playing the live feed. The answer holds every ICE candidate.
+#[post("/whep/{imei}/{channel}")]
+async fn post_whep(
+    stream: web::Path<Stream>,
+    offer: String,
+    registry: web::Data<StreamRegistry>,
+    whep: web::Data<WhepSessions>,
+) -> actix_web::Result<HttpResponse> {
+    let subscription = subscribe(&registry, &stream)?;
+    if !WhepSessions::supports(subscription.tracks.video) {
+        return Err(ErrorNotAcceptable("WebRTC playback needs H.264 video"));
+    }
+    let (id, answer) = whep
+        .start(subscription, offer)
+        .await
+        .map_err(ErrorBadRequest)?;
+    let location = format!("/whep/{}/{}/{id}", stream.imei, stream.channel);
+    Ok(HttpResponse::Created()
+        .content_type("application/sdp")
+        .insert_header(("Location", location))
+        .body(answer))
+}
+
+/// Ends a WHEP session.
+#[delete("/whep/{imei}/{channel}/{id}")]
+async fn delete_whep(
+    session: web::Path<(String, u8, String)>,
+    whep: web::Data<WhepSessions>,
+) -> HttpResponse {
+    let (_, _, id) = session.into_inner();
+    if whep.stop(&id).await {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// Address of the [`TcpServer`](crate::server::TcpServer) that terminals
+/// are asked to stream to.
+#[derive(Clone)]
+struct MediaAddress(Option<(String, u16)>);
+
+/// Options of a real-time request.
+#[derive(serde::Deserialize)]
+struct LiveQuery {
+    #[serde(default)]
+    data_type: DataType,
+    #[serde(default)]
+    stream_type: StreamType,
+}
+
+/// Asks a terminal signed in over JT/T 808 to stream a channel with 0x9101,
+/// then waits for its media connection. Answers with the playlist URL.
+#[post("/terminals/{imei}/channels/{channel}/live")]
+async fn post_live(
+    stream: web::Path<Stream>,
+    query: web::Query<LiveQuery>,
+    registry: web::Data<StreamRegistry>,
+    terminals: web::Data<TerminalRegistry>,
+    media_address: web::Data<MediaAddress>,
+) -> actix_web::Result<HttpResponse> {
+    let terminal = terminals
+        .get(&stream.imei)
+        .ok_or_else(|| ErrorNotFound("Terminal is not online"))?;
+    let key = StreamKey::new(&terminal.phone, stream.channel);
+    let playlist = format!("/streams/{key}/playlist.m3u8");
+    let answer = || Ok(HttpResponse::Ok().json(serde_json::json!({ "playlist": playlist })));
+    if registry.get(&key).is_some() {
+        return answer();
+    }
+
+    let Some((host, port)) = &media_address.0 else {
+        return Err(ErrorServiceUnavailable("No media address is configured"));
+    };
+    let request = RealtimeAvRequest {
+        host,
+        tcp_port: *port,
+        udp_port: 0,
+        channel: stream.channel,
+        data_type: query.data_type,
+        stream_type: query.stream_type,
+    };
+    send_command(
+        &terminals,
+        &terminal.phone,
+        REALTIME_AV_REQUEST,
+        request.body(),
+    )
+    .await?;
+
+    let Ok(live) = timeout(MEDIA_CONNECT_TIMEOUT, registry.wait_for(&key)).await else {
+        return Err(ErrorGatewayTimeout(
+            "Terminal did not connect to the media server in time",
+        ));
+    };
+    live.media
+        .send_modify(|media| media.stream_type = query.stream_type);
+    answer()
+}
+
+/// HTTP error of a platform message that got no answer.
+fn terminal_error(e: std::io::Error) -> actix_web::Error {
+    match e.kind() {
+        ErrorKind::NotFound => ErrorNotFound(e),
+        ErrorKind::TimedOut => ErrorGatewayTimeout(e),
+        _ => ErrorBadGateway(e),
+    }
+}
+
+/// Sends a platform message to a terminal, failing unless it answers with
+/// success.
+async fn send_command(
+    terminals: &TerminalRegistry,
+    phone: &str,
+    id: u16,
+    body: Bytes,
+) -> actix_web::Result<()> {
+    let response = terminals
+        .request(phone, id, body)
+        .await
+        .map_err(terminal_error)?;
+    match TerminalResponse::parse(&response) {
+        Ok(response) if response.result == RESULT_SUCCESS => Ok(()),
+        _ => Err(ErrorBadGateway(format!(
+            "Terminal refused message {id:#06X}"
+        ))),
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ControlCommand {
+    Close,
+    Switch,
+    Pause,
+    Resume,
+    CloseIntercom,
+}
+
+#[derive(serde::Deserialize)]
+struct Control {
+    imei: String,
+    channel: u8,
+    command: ControlCommand,
+}
+
+/// Options of a control command: the media to close, or the stream to
+/// switch to.
+#[derive(serde::Deserialize)]
+struct ControlQuery {
+    #[serde(default)]
+    media: CloseMedia,
+    #[serde(default)]
+    stream_type: StreamType,
+}
+
+/// Controls a real-time transfer with 0x9102. Closing all media ends the
+/// stream right away, without waiting for the terminal to disconnect.
+#[post("/terminals/{imei}/channels/{channel}/live/{command}")]
+async fn post_live_control(
+    control: web::Path<Control>,
+    query: web::Query<ControlQuery>,
+    registry: web::Data<StreamRegistry>,
+    terminals: web::Data<TerminalRegistry>,
+) -> actix_web::Result<HttpResponse> {
+    let terminal = terminals
+        .get(&control.imei)
+        .ok_or_else(|| ErrorNotFound("Terminal is not online"))?;
+    let command = match control.command {
+        ControlCommand::Close => AvControl::Close(query.media),
+        ControlCommand::Switch => AvControl::Switch(query.stream_type),
+        ControlCommand::Pause => AvControl::Pause,
+        ControlCommand::Resume => AvControl::Resume,
+        ControlCommand::CloseIntercom => AvControl::CloseIntercom,
+    };
+    let body = command.body(control.channel);
+    send_command(&terminals, &terminal.phone, REALTIME_AV_CONTROL, body).await?;
+
+    let key = StreamKey::new(&terminal.phone, control.channel);
+    if let Some(live) = registry.get(&key) {
+        live.media.send_modify(|media| match command {
+            AvControl::Close(CloseMedia::All) => media.closed = true,
+            AvControl::Switch(stream_type) => media.stream_type = stream_type,
+            AvControl::Pause => media.paused = true,
+            AvControl::Resume => media.paused = false,
+            AvControl::Close(_) | AvControl::CloseIntercom => (),
+        });
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Filters of a query of the recordings on a terminal, all optional.
+#[derive(serde::Deserialize)]
+struct RecordingsQuery {
+    start: Option<BcdTime>,
+    end: Option<BcdTime>,
+    #[serde(default)]
+    alarm: u64,
+    #[serde(default)]
+    media_type: MediaType,
+    #[serde(default)]
+    stream_type: RecordStream,
+    #[serde(default)]
+    storage_type: Storage,
+}
+
+/// Lists the recordings a terminal keeps for a channel, or for every
+/// channel with channel 0, with 0x9205.
+#[get("/terminals/{imei}/channels/{channel}/recordings")]
+async fn get_recordings(
+    stream: web::Path<Stream>,
+    query: web::Query<RecordingsQuery>,
+    terminals: web::Data<TerminalRegistry>,
+) -> actix_web::Result<HttpResponse> {
+    let terminal = terminals
+        .get(&stream.imei)
+        .ok_or_else(|| ErrorNotFound("Terminal is not online"))?;
+    let request = ResourceQuery {
+        channel: stream.channel,
+        start: query.start.unwrap_or_default(),
+        end: query.end.unwrap_or_default(),
+        alarm: query.alarm,
+        media_type: query.media_type,
+        stream_type: query.stream_type,
+        storage_type: query.storage_type,
+    };
+    let response = terminals
+        .request(&terminal.phone, RESOURCE_QUERY, request.body())
+        .await
+        .map_err(terminal_error)?;
+    if response.id != RESOURCE_LIST {
+        return Err(ErrorBadGateway("Terminal refused the resource query"));
+    }
+    let list = ResourceList::parse(&response).map_err(ErrorBadGateway)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "recordings": list.recordings })))
 }
 
 pub struct WebServer {
     address: SocketAddr,
-    server: Server,
+    listener: std::net::TcpListener,
+    registry: StreamRegistry,
+    terminals: TerminalRegistry,
+    media_address: MediaAddress,
 }
 
 impl WebServer {
     pub async fn run(self) -> std::io::Result<()> {
         println!("HTTP Server listening on {}", self.address);
-        self.server.await
+
+        let registry = web::Data::new(self.registry);
+        let terminals = web::Data::new(self.terminals);
+        let media_address = web::Data::new(self.media_address);
+        let whep = web::Data::new(WhepSessions::new().map_err(std::io::Error::other)?);
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(registry.clone())
+                .app_data(whep.clone())
+                .app_data(terminals.clone())
+                .app_data(media_address.clone())
+                .service(health_check)
+                .service(get_live_flv)
+                .service(get_ws_flv)
+                .service(get_ws_raw)
+                .service(post_whep)
+                .service(delete_whep)
+                .service(post_live)
+                .service(post_live_control)
+                .service(get_recordings)
+                .service(
+                    web::scope("/streams")
+                        .service(get_segment)
+                        .service(get_fmp4_segment)
+                        .service(get_init_segment)
+                        .service(get_manifest)
+                        .service(get_playlist),
+                )
+        })
+        .listen(self.listener)?
+        .run();
+        server.await
     }
 
     pub fn new(host: &str, port: u16) -> Result<Self> {
@@ -60,16 +576,43 @@ impl WebServer {
 
         let address = listener.local_addr().expect("Failed to get local address");
 
-        let server = HttpServer::new(move || {
-            App::new().service(health_check).service(
-                web::scope("/streams")
-                    .service(get_segment)
-                    .service(get_playlist),
-            )
+        // `host:port` of the media server as seen by the terminals.
+        let media_address = std::env::var("MEDIA_ADDRESS").ok().map(|address| {
+            let (host, port) = address
+                .rsplit_once(':')
+                .expect("Failed to parse media address");
+            let port = port.parse().expect("Failed to parse media port");
+            (host.to_string(), port)
+        });
+
+        Ok(Self {
+            address,
+            listener,
+            registry: StreamRegistry::new(),
+            terminals: TerminalRegistry::new(),
+            media_address: MediaAddress(media_address),
         })
-        .listen(listener)?
-        .run();
+    }
+
+    /// Follows the streams published in `registry`, shared with the
+    /// [`TcpServer`](crate::server::TcpServer), for blocking playlist
+    /// reloads.
+    pub fn with_registry(mut self, registry: StreamRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Sends real-time requests to the terminals signed in to the
+    /// [`Jt808Server`](crate::server::Jt808Server) sharing `terminals`.
+    pub fn with_terminals(mut self, terminals: TerminalRegistry) -> Self {
+        self.terminals = terminals;
+        self
+    }
 
-        Ok(Self { address, server })
+    /// Address the terminals are asked to stream to, overriding
+    /// `MEDIA_ADDRESS`.
+    pub fn with_media_address(mut self, host: &str, port: u16) -> Self {
+        self.media_address = MediaAddress(Some((host.to_string(), port)));
+        self
     }
 }
@@ -0,0 +1,229 @@
+use crate::jt808::{Jt808Message, Registration};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+/// Time a terminal has to answer a platform message.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Terminal signed in over JT/T 808.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Terminal {
+    /// Phone number, the `terminal_serial_number` of its media streams.
+    pub phone: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub terminal_id: String,
+    pub plate: String,
+    /// Address of the signaling connection.
+    pub address: SocketAddr,
+    pub connected_at: SystemTime,
+    /// Time of the last message, heartbeats included.
+    pub last_seen: SystemTime,
+}
+
+/// Platform message for the signaling connection of a terminal to send.
+pub(crate) struct Command {
+    pub(crate) id: u16,
+    pub(crate) body: Bytes,
+    /// Receives the answer of the terminal.
+    pub(crate) response: oneshot::Sender<Jt808Message>,
+}
+
+struct Session {
+    connection: u64,
+    terminal: Terminal,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+#[derive(Default)]
+struct TerminalState {
+    /// Authentication code and details of each registered terminal, kept
+    /// in memory only: after a restart terminals have to register again.
+    registrations: HashMap<String, (String, Registration)>,
+    sessions: HashMap<String, Session>,
+    next_connection: u64,
+}
+
+/// Terminals registered and signed in over JT/T 808, looked up by the
+/// phone number that their media streams carry as `terminal_serial_number`.
+/// Clones share the same table.
+#[derive(Clone, Default)]
+pub struct TerminalRegistry {
+    state: Arc<Mutex<TerminalState>>,
+}
+
+impl TerminalRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The terminal with phone number `phone`, if signed in.
+    pub fn get(&self, phone: &str) -> Option<Terminal> {
+        let state = self.state.lock().expect("Terminal registry poisoned");
+        state
+            .sessions
+            .get(normalize(phone))
+            .map(|session| session.terminal.clone())
+    }
+
+    /// Registers a terminal, returning its new authentication code.
+    pub(crate) fn register(&self, phone: &str, registration: Registration) -> String {
+        let auth_code = format!("{:016X}", rand::random::<u64>());
+        let mut state = self.state.lock().expect("Terminal registry poisoned");
+        state
+            .registrations
+            .insert(phone.to_string(), (auth_code.clone(), registration));
+        auth_code
+    }
+
+    pub(crate) fn deregister(&self, phone: &str) {
+        let mut state = self.state.lock().expect("Terminal registry poisoned");
+        state.registrations.remove(phone);
+        state.sessions.remove(phone);
+    }
+
+    /// Signs a terminal in on a new connection, which sends the platform
+    /// messages of `commands`, replacing any previous session. Returns the
+    /// ID of the connection, or `None` if the code does not match the one
+    /// given at registration.
+    pub(crate) fn authenticate(
+        &self,
+        phone: &str,
+        auth_code: &str,
+        address: SocketAddr,
+        commands: mpsc::UnboundedSender<Command>,
+    ) -> Option<u64> {
+        let mut state = self.state.lock().expect("Terminal registry poisoned");
+        let (expected, registration) = state.registrations.get(phone)?;
+        if expected != auth_code {
+            return None;
+        }
+        let now = SystemTime::now();
+        let terminal = Terminal {
+            phone: phone.to_string(),
+            manufacturer: registration.manufacturer.clone(),
+            model: registration.model.clone(),
+            terminal_id: registration.terminal_id.clone(),
+            plate: registration.plate.clone(),
+            address,
+            connected_at: now,
+            last_seen: now,
+        };
+        state.next_connection += 1;
+        let connection = state.next_connection;
+        state.sessions.insert(
+            phone.to_string(),
+            Session {
+                connection,
+                terminal,
+                commands,
+            },
+        );
+        Some(connection)
+    }
+
+    /// Sends a platform message to the terminal with phone number `phone`
+    /// and waits for its answer.
+    pub(crate) async fn request(
+        &self,
+        phone: &str,
+        id: u16,
+        body: Bytes,
+    ) -> std::io::Result<Jt808Message> {
+        let commands = {
+            let state = self.state.lock().expect("Terminal registry poisoned");
+            let session = state.sessions.get(normalize(phone)).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("Terminal {phone} is not online"),
+                )
+            })?;
+            session.commands.clone()
+        };
+        let closed = || {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                format!("Terminal {phone} went offline"),
+            )
+        };
+        let (response, answer) = oneshot::channel();
+        commands
+            .send(Command { id, body, response })
+            .map_err(|_| closed())?;
+        match timeout(COMMAND_TIMEOUT, answer).await {
+            Ok(Ok(message)) => Ok(message),
+            Ok(Err(_)) => Err(closed()),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("Terminal {phone} did not answer message {id:#06X}"),
+            )),
+        }
+    }
+
+    /// Records a message of the session of `connection`.
+    pub(crate) fn touch(&self, phone: &str, connection: u64) {
+        let mut state = self.state.lock().expect("Terminal registry poisoned");
+        if let Some(session) = state.sessions.get_mut(phone) {
+            if session.connection == connection {
+                session.terminal.last_seen = SystemTime::now();
+            }
+        }
+    }
+
+    /// Ends the session of `connection`, unless the terminal has signed in
+    /// again on another one since.
+    pub(crate) fn disconnect(&self, phone: &str, connection: u64) {
+        let mut state = self.state.lock().expect("Terminal registry poisoned");
+        if state
+            .sessions
+            .get(phone)
+            .is_some_and(|session| session.connection == connection)
+        {
+            state.sessions.remove(phone);
+        }
+    }
+}
+
+/// Phone numbers are stored without the zero padding of their BCD field.
+fn normalize(phone: &str) -> &str {
+    match phone.trim_start_matches('0') {
+        "" => "0",
+        phone => phone,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let terminals = TerminalRegistry::new();
+        let address = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let (commands, _) = mpsc::unbounded_channel();
+        let auth_code = terminals.register("13912345678", Registration::default());
+        assert!(terminals
+            .authenticate("13912345678", "wrong", address, commands.clone())
+            .is_none());
+
+        let first = terminals
+            .authenticate("13912345678", &auth_code, address, commands.clone())
+            .unwrap();
+        assert_eq!(terminals.get("013912345678").unwrap().phone, "13912345678");
+
+        // A reconnection replaces the session, which the old connection
+        // must not end.
+        let second = terminals
+            .authenticate("13912345678", &auth_code, address, commands.clone())
+            .unwrap();
+        terminals.disconnect("13912345678", first);
+        assert!(terminals.get("13912345678").is_some());
+        terminals.disconnect("13912345678", second);
+        assert!(terminals.get("13912345678").is_none());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::audio::AudioCodec;
+use crate::live::{FrameKind, LiveFrame, LiveSubscription};
+use crate::processor::VideoCodec;
+use crate::Result;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tokio::time::timeout;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_PCMA, MIME_TYPE_PCMU};
+use webrtc::api::{APIBuilder, API};
+use webrtc::interceptor::registry::Registry;
+use webrtc::media::Sample;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
+use webrtc::track::track_local::TrackLocal;
+
+/// How long a new session may take to connect before it is dropped.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sample rate of G.711.
+const G711_SAMPLE_RATE: u64 = 8000;
+
+/// WebRTC playback sessions of the live feeds, negotiated over WHEP
+/// (draft-ietf-wish-whep): an SDP offer in, an SDP answer with every ICE
+/// candidate out, then H.264 with G.711 audio over SRTP.
+#[derive(Clone)]
+pub(crate) struct WhepSessions {
+    api: Arc<API>,
+    sessions: Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>,
+}
+
+impl WhepSessions {
+    pub(crate) fn new() -> Result<Self> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs()?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)?;
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+        Ok(Self {
+            api: Arc::new(api),
+            sessions: Arc::default(),
+        })
+    }
+
+    /// Whether WebRTC can carry the video of the stream.
+    pub(crate) fn supports(video: VideoCodec) -> bool {
+        video == VideoCodec::H264
+    }
+
+    /// Answers `offer` and starts sending `subscription` once connected.
+    /// Returns the session ID and the SDP answer.
+    pub(crate) async fn start(
+        &self,
+        subscription: LiveSubscription,
+        offer: String,
+    ) -> Result<(String, String)> {
+        let peer_connection = Arc::new(
+            self.api
+                .new_peer_connection(RTCConfiguration::default())
+                .await?,
+        );
+        let (session, answer) = match self.negotiate(&peer_connection, &subscription, offer).await {
+            Ok(negotiated) => negotiated,
+            Err(e) => {
+                let _ = peer_connection.close().await;
+                return Err(e);
+            }
+        };
+
+        let id = format!("{:032x}", rand::random::<u128>());
+        self.sessions
+            .lock()
+            .expect("WHEP sessions poisoned")
+            .insert(id.clone(), Arc::clone(&peer_connection));
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            session.run(subscription).await;
+            sessions.remove(&peer_connection).await;
+        });
+        Ok((id, answer))
+    }
+
+    /// Ends session `id`. Returns whether it existed.
+    pub(crate) async fn stop(&self, id: &str) -> bool {
+        let peer_connection = self
+            .sessions
+            .lock()
+            .expect("WHEP sessions poisoned")
+            .remove(id);
+        match peer_connection {
+            Some(peer_connection) => {
+                let _ = peer_connection.close().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn negotiate(
+        &self,
+        peer_connection: &Arc<RTCPeerConnection>,
+        subscription: &LiveSubscription,
+        offer: String,
+    ) -> Result<(Session, String)> {
+        let video = Arc::new(TrackLocalStaticSample::new(
+            codec_capability(MIME_TYPE_H264),
+            "video".to_string(),
+            "jt1078".to_string(),
+        ));
+        let sender = peer_connection
+            .add_track(Arc::clone(&video) as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+        drain_rtcp(sender);
+
+        let audio_mime_type = match subscription.tracks.audio {
+            Some(AudioCodec::G711A) => Some(MIME_TYPE_PCMA),
+            Some(AudioCodec::G711U) => Some(MIME_TYPE_PCMU),
+            _ => None,
+        };
+        let audio = match audio_mime_type {
+            Some(mime_type) => {
+                let audio = Arc::new(TrackLocalStaticSample::new(
+                    codec_capability(mime_type),
+                    "audio".to_string(),
+                    "jt1078".to_string(),
+                ));
+                let sender = peer_connection
+                    .add_track(Arc::clone(&audio) as Arc<dyn TrackLocal + Send + Sync>)
+                    .await?;
+                drain_rtcp(sender);
+                Some(audio)
+            }
+            None => None,
+        };
+
+        let (state_tx, state_rx) = watch::channel(RTCPeerConnectionState::New);
+        peer_connection.on_peer_connection_state_change(Box::new(move |state| {
+            state_tx.send_replace(state);
+            Box::pin(async {})
+        }));
+
+        peer_connection
+            .set_remote_description(RTCSessionDescription::offer(offer)?)
+            .await?;
+        let answer = peer_connection.create_answer(None).await?;
+        // No trickle ICE: the answer carries every candidate.
+        let mut gathering = peer_connection.gathering_complete_promise().await;
+        peer_connection.set_local_description(answer).await?;
+        let _ = gathering.recv().await;
+        let answer = peer_connection
+            .local_description()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No local description after negotiation"))?;
+
+        let session = Session {
+            video,
+            audio,
+            state: state_rx,
+        };
+        Ok((session, answer.sdp))
+    }
+
+    async fn remove(&self, peer_connection: &Arc<RTCPeerConnection>) {
+        self.sessions
+            .lock()
+            .expect("WHEP sessions poisoned")
+            .retain(|_, session| !Arc::ptr_eq(session, peer_connection));
+        let _ = peer_connection.close().await;
+    }
+}
+
+struct Session {
+    video: Arc<TrackLocalStaticSample>,
+    audio: Option<Arc<TrackLocalStaticSample>>,
+    state: watch::Receiver<RTCPeerConnectionState>,
+}
+
+impl Session {
+    /// Sends the live feed from the cached GOP on, once connected, until
+    /// either the stream or the connection ends.
+    async fn run(mut self, subscription: LiveSubscription) {
+        let connected = self.state.wait_for(|state| {
+            !matches!(
+                state,
+                RTCPeerConnectionState::New | RTCPeerConnectionState::Connecting
+            )
+        });
+        match timeout(CONNECT_TIMEOUT, connected).await {
+            Ok(Ok(state)) if *state == RTCPeerConnectionState::Connected => (),
+            _ => return,
+        }
+
+        let mut frames = pin!(subscription.into_stream());
+        // Each video frame waits for the next one: the track advances the
+        // RTP clock by the duration of a sample after sending it.
+        let mut held: Option<LiveFrame> = None;
+        loop {
+            let frame = tokio::select! {
+                frame = frames.next() => frame,
+                _ = self.state.wait_for(|state| *state != RTCPeerConnectionState::Connected) => None,
+            };
+            let Some(frame) = frame else {
+                return;
+            };
+
+            let (track, frame, duration) = match frame.kind {
+                FrameKind::Video { .. } => {
+                    let time_ms = frame.time_ms;
+                    let Some(previous) = held.replace(frame) else {
+                        continue;
+                    };
+                    let duration = time_ms.saturating_sub(previous.time_ms);
+                    (&self.video, previous, Duration::from_millis(duration))
+                }
+                FrameKind::Audio => {
+                    let Some(audio) = &self.audio else {
+                        continue;
+                    };
+                    let samples = frame.data.len() as u64;
+                    let duration = Duration::from_micros(samples * 1_000_000 / G711_SAMPLE_RATE);
+                    (audio, frame, duration)
+                }
+            };
+            let sample = Sample {
+                data: frame.data,
+                timestamp: SystemTime::now(),
+                duration,
+                ..Default::default()
+            };
+            if let Err(e) = track.write_sample(&sample).await {
+                eprintln!("Failed to send WebRTC sample: {e}");
+                return;
+            }
+        }
+    }
+}
+
+fn codec_capability(mime_type: &str) -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: mime_type.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Reads the RTCP of a sender, which the interceptors (NACK, reports) act
+/// on, until the connection is closed.
+fn drain_rtcp(sender: Arc<RTCRtpSender>) {
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 1500];
+        while sender.read(&mut buffer).await.is_ok() {}
+    });
+}
@@ -0,0 +1,120 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Simulated JT/T 808 (2013) terminal.
+pub(crate) struct Jt808Terminal {
+    stream: TcpStream,
+    /// BCD phone number, 12 digits.
+    phone: [u8; 6],
+    serial: u16,
+    buffer: Vec<u8>,
+}
+
+/// Message received from the platform.
+#[derive(Debug)]
+pub(crate) struct Jt808Reply {
+    pub(crate) id: u16,
+    pub(crate) serial: u16,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Jt808Terminal {
+    pub(crate) async fn connect(address: &str, phone: &str) -> std::io::Result<Self> {
+        let digits = format!("{phone:0>12}");
+        let mut bcd = [0u8; 6];
+        for (byte, pair) in bcd.iter_mut().zip(digits.as_bytes().chunks(2)) {
+            *byte = ((pair[0] - b'0') << 4) | (pair[1] - b'0');
+        }
+        Ok(Self {
+            stream: TcpStream::connect(address).await?,
+            phone: bcd,
+            serial: 0,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Registers, then signs in with the authentication code received.
+    pub(crate) async fn sign_in(&mut self) -> std::io::Result<()> {
+        let mut registration = vec![0, 44, 1, 0];
+        registration.extend_from_slice(&[0; 5 + 20 + 7]);
+        registration.push(0);
+        self.send(0x0100, &registration).await?;
+        let reply = self.receive().await?;
+        assert_eq!((reply.id, reply.body[2]), (0x8100, 0));
+
+        self.send(0x0102, &reply.body[3..]).await?;
+        let reply = self.receive().await?;
+        assert_eq!((reply.id, reply.body[4]), (0x8001, 0));
+        Ok(())
+    }
+
+    /// Answers a platform message with a 0x0001 response.
+    pub(crate) async fn respond(
+        &mut self,
+        message: &Jt808Reply,
+        result: u8,
+    ) -> std::io::Result<()> {
+        let mut body = message.serial.to_be_bytes().to_vec();
+        body.extend_from_slice(&message.id.to_be_bytes());
+        body.push(result);
+        self.send(0x0001, &body).await?;
+        Ok(())
+    }
+
+    /// Sends a message, returning its serial number.
+    pub(crate) async fn send(&mut self, id: u16, body: &[u8]) -> std::io::Result<u16> {
+        self.serial = self.serial.wrapping_add(1);
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&self.phone);
+        frame.extend_from_slice(&self.serial.to_be_bytes());
+        frame.extend_from_slice(body);
+        frame.push(frame.iter().fold(0, |check, byte| check ^ byte));
+
+        let mut out = vec![0x7E];
+        for byte in frame {
+            match byte {
+                0x7E => out.extend_from_slice(&[0x7D, 0x02]),
+                0x7D => out.extend_from_slice(&[0x7D, 0x01]),
+                byte => out.push(byte),
+            }
+        }
+        out.push(0x7E);
+        self.stream.write_all(&out).await?;
+        Ok(self.serial)
+    }
+
+    pub(crate) async fn receive(&mut self) -> std::io::Result<Jt808Reply> {
+        loop {
+            if let Some(start) = self.buffer.iter().position(|&byte| byte == 0x7E) {
+                if let Some(length) = self.buffer[start + 1..].iter().position(|&b| b == 0x7E) {
+                    let escaped: Vec<u8> = self.buffer.drain(..start + length + 2).collect();
+                    let mut frame = Vec::new();
+                    let mut bytes = escaped[start + 1..escaped.len() - 1].iter().peekable();
+                    while let Some(&byte) = bytes.next() {
+                        frame.push(match (byte, bytes.next_if(|_| byte == 0x7D)) {
+                            (0x7D, Some(0x02)) => 0x7E,
+                            (0x7D, Some(_)) => 0x7D,
+                            (byte, _) => byte,
+                        });
+                    }
+                    if frame.len() < 13 {
+                        continue;
+                    }
+                    return Ok(Jt808Reply {
+                        id: u16::from_be_bytes([frame[0], frame[1]]),
+                        serial: u16::from_be_bytes([frame[10], frame[11]]),
+                        body: frame[12..frame.len() - 1].to_vec(),
+                    });
+                }
+            }
+            let mut chunk = [0u8; 1024];
+            let read = self.stream.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
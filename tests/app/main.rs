@@ -1,113 +1,242 @@
+mod jt808_terminal;
+mod packetizer;
+mod rtmp_server;
 mod tcp_client;
 
-use jt1078_video_server::server::WebServer;
-use jt1078_video_server::{run_tcp_server, TcpServerTask};
+use futures_util::StreamExt;
+use jt1078_video_server::server::{Jt808Server, RtspServer, TcpServer, WebServer};
+use jt1078_video_server::{
+    spawn_tcp_server, RtmpPushRule, StreamRegistry, TcpServerTask, TerminalRegistry,
+};
+use jt808_terminal::Jt808Terminal;
 use once_cell::sync::Lazy;
+use packetizer::{packetize, packetize_with_g711, Codec};
+use rtmp_server::{RtmpMessage, RtmpServer};
 use std::net::SocketAddr;
-use std::sync::LazyLock;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tcp_client::TcpClient;
-use tokio::sync::{broadcast, Mutex};
+use tokio::runtime::Runtime;
+use tokio::sync::{oneshot, watch, Mutex};
 use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::APIBuilder;
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
 
-struct MyTasks {
-    client_task: Option<JoinHandle<()>>,
-    tcp_server_task: Option<TcpServerTask>,
-    web_server_task: JoinHandle<std::io::Result<()>>,
-}
-
-impl MyTasks {
-    fn new(
-        client: JoinHandle<()>,
-        tcp_server_task: TcpServerTask,
-        web_server_task: JoinHandle<std::io::Result<()>>,
-    ) -> Self {
-        Self {
-            client_task: Some(client),
-            tcp_server_task: Some(tcp_server_task),
-            web_server_task,
-        }
-    }
+struct MyTests {
+    /// Test streams, sent again once every test holding them has finished.
+    streams: Mutex<Streams>,
+    /// Messages published to the RTMP stand-in.
+    rtmp_messages: watch::Receiver<Vec<RtmpMessage>>,
+    /// Terminals signed in to the JT/T 808 server.
+    terminals: TerminalRegistry,
+    /// Streams followed by the web server.
+    registry: StreamRegistry,
+    /// Kept so that the server runs until the tests exit.
+    _tcp_server_task: TcpServerTask,
 }
 
-struct MyTests {
-    sender: broadcast::Sender<()>,
+#[derive(Default)]
+struct Streams {
+    /// Shared by the guards of the tests using the streams. The client
+    /// closes the connections once the last one is dropped.
+    users: Weak<oneshot::Sender<()>>,
+    /// Set once the streams have been sent, even for tests that start late.
+    sent: Option<watch::Receiver<bool>>,
+    /// Ends once the streams have ended and their files are removed.
+    client_task: Option<JoinHandle<()>>,
 }
 
 impl MyTests {
-    async fn increment(&self) {
-        let mut receiver = self.sender.subscribe();
-        let _ = receiver.recv().await;
-        let mut ntests = unsafe { NTESTS.lock().await };
-        *ntests += 1;
+    /// Waits for the streams to be sent, sending them first if no other
+    /// test holds them. They are kept up until the guard is released.
+    async fn streams(&self) -> StreamsGuard {
+        let mut streams = self.streams.lock().await;
+        let users = match streams.users.upgrade() {
+            Some(users) => users,
+            None => {
+                // The previous streams may still be ending if a test holding
+                // them panicked.
+                if let Some(client_task) = streams.client_task.take() {
+                    if let Err(e) = client_task.await {
+                        eprintln!("Failed to wait for client task: {}", e);
+                    }
+                }
+                let (release_tx, release_rx) = oneshot::channel();
+                let (sent_tx, sent_rx) = watch::channel(false);
+                let users = Arc::new(release_tx);
+                streams.users = Arc::downgrade(&users);
+                streams.sent = Some(sent_rx);
+                streams.client_task = Some(RUNTIME.spawn(send_streams(sent_tx, release_rx)));
+                users
+            }
+        };
+        let mut sent = streams.sent.clone().expect("Streams not started");
+        drop(streams);
+        let _ = sent.wait_for(|sent| *sent).await;
+        StreamsGuard { _users: users }
     }
+}
 
-    async fn decrement(&self) {
-        let mut ntests = unsafe { NTESTS.lock().await };
-        *ntests -= 1;
-
-        if *ntests == 0 {
-            let mut my_tasks = unsafe { TASKS.take().unwrap() };
+/// Held by each test while it uses the streams.
+struct StreamsGuard {
+    _users: Arc<oneshot::Sender<()>>,
+}
 
-            let client_task = my_tasks.client_task.take().unwrap();
-            if let Err(e) = client_task.await {
-                eprintln!("Failed to wait for client task: {}", e);
+impl StreamsGuard {
+    /// Ends the streams, and waits for their files to be removed, if no
+    /// other test holds them.
+    async fn release(self) {
+        let mut streams = TESTS.streams.lock().await;
+        drop(self);
+        if streams.users.strong_count() == 0 {
+            if let Some(client_task) = streams.client_task.take() {
+                if let Err(e) = client_task.await {
+                    eprintln!("Failed to wait for client task: {}", e);
+                }
             }
+        }
+    }
+}
 
-            let tcp_server_task = my_tasks.tcp_server_task.take().unwrap();
-            tcp_server_task.end().await;
+/// Sends the H.264 and H.265 test streams, then keeps the connections open
+/// until `release` is dropped. The streams end, and their files are
+/// removed, when the connections close.
+async fn send_streams(sent: watch::Sender<bool>, release: oneshot::Receiver<()>) {
+    let address: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+    let h264 = std::fs::read("data/test_stream.h264").expect("Failed to read H.264 fixture");
+    let h265 = std::fs::read("data/test_stream.h265").expect("Failed to read H.265 fixture");
+    let mut client = TcpClient::new(address, packetize(&h264, "353071279375", 1, Codec::H264));
+    let mut hevc_client = TcpClient::new(address, packetize(&h265, "353071279376", 1, Codec::H265));
 
-            my_tasks.web_server_task.abort();
+    client.connect().await.expect("Failed to connect to server");
+    hevc_client
+        .connect()
+        .await
+        .expect("Failed to connect to server");
+    match tokio::try_join!(client.send(), hevc_client.send()) {
+        Ok(_) => {
+            println!("Data sent successfully");
+            sent.send_replace(true);
+        }
+        Err(e) => eprintln!("Failed to send data: {}", e),
+    };
+    let _ = release.await;
+    client.close().await.expect("Failed to close connection");
+    hevc_client
+        .close()
+        .await
+        .expect("Failed to close connection");
+
+    for dir in ["353071279375/1", "353071279376/1"] {
+        let dir = std::path::Path::new(dir);
+        while dir.exists() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
         }
     }
 }
 
-static mut NTESTS: LazyLock<Mutex<usize>> = LazyLock::new(|| Mutex::new(0));
-
-static mut TASKS: Option<MyTasks> = None;
+/// Every test has its own runtime, which is shut down when the test ends, so
+/// the shared servers and clients live on a runtime of their own.
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| Runtime::new().expect("Failed to create runtime"));
 
 static TESTS: Lazy<MyTests> = Lazy::new(|| {
+    let _guard = RUNTIME.enter();
+
     let host = "127.0.0.1";
     let port = 8000;
-    let address: SocketAddr = format!("{}:{}", host, port).parse().unwrap();
-
-    let tcp_server_task = run_tcp_server(host, port);
-    let web_server = WebServer::new("127.0.0.1", 8080).expect("Failed to create web server");
-    let web_server_task = tokio::spawn(web_server.run());
-    let mut client = TcpClient::new(address, "data/test_stream.bin");
-
-    let (tx, _) = broadcast::channel(1);
-    let atx = tx.clone();
-
-    let client_task = tokio::spawn(async move {
-        client.connect().await.expect("Failed to connect to server");
-        match client.send().await {
-            Ok(_) => {
-                println!("Data sent successfully");
-                let _ = atx.send(());
-            }
-            Err(e) => eprintln!("Failed to send data: {}", e),
-        };
-        client.close().await.expect("Failed to close connection");
-    });
 
-    unsafe {
-        TASKS = Some(MyTasks::new(client_task, tcp_server_task, web_server_task));
+    let registry = StreamRegistry::new();
+    let terminals = TerminalRegistry::new();
+    let (rtmp_tx, rtmp_messages) = watch::channel(Vec::new());
+    let rtmp_server = RtmpServer::bind("127.0.0.1:19350").expect("Failed to bind RTMP stand-in");
+    tokio::spawn(rtmp_server.run(rtmp_tx));
+
+    let tcp_server = TcpServer::new(host, port)
+        .with_low_latency_hls(true)
+        .with_dash(true)
+        .with_clean_up_delay(Duration::from_secs(1))
+        .with_rtmp_push(vec![RtmpPushRule::new(
+            "rtmp://127.0.0.1:19350/live/{imei}_{channel}",
+        )
+        .with_imei("353071279375")])
+        .with_registry(registry.clone());
+    let tcp_server_task = spawn_tcp_server(tcp_server);
+    let web_server = WebServer::new("127.0.0.1", 8080)
+        .expect("Failed to create web server")
+        .with_registry(registry.clone())
+        .with_terminals(terminals.clone())
+        .with_media_address(host, port);
+    tokio::spawn(web_server.run());
+    let rtsp_server = RtspServer::new("127.0.0.1", 8554)
+        .expect("Failed to create RTSP server")
+        .with_registry(registry.clone());
+    tokio::spawn(rtsp_server.run());
+    let jt808_server = Jt808Server::new("127.0.0.1", 7611)
+        .expect("Failed to create JT/T 808 server")
+        .with_terminals(terminals.clone());
+    tokio::spawn(jt808_server.run());
+
+    MyTests {
+        streams: Mutex::default(),
+        rtmp_messages,
+        terminals,
+        registry,
+        _tcp_server_task: tcp_server_task,
     }
-    MyTests { sender: tx }
 });
 
+/// Polls the playlist until the segmenter has caught up with the stream and
+/// filled it.
 async fn get_playlist_content() -> String {
     let client = reqwest::Client::new();
-    let response = client
-        .get("http://127.0.0.1:8080/streams/353071279375/playlist.m3u8")
-        .send()
-        .await
-        .unwrap();
-    response.text().await.unwrap()
+    for _ in 0..60 {
+        let response = client
+            .get("http://127.0.0.1:8080/streams/353071279375/1/playlist.m3u8")
+            .send()
+            .await
+            .unwrap();
+        if response.status().is_success() {
+            let content = response.text().await.unwrap();
+            if content.lines().filter(|line| line.ends_with(".ts")).count() >= 10 {
+                return content;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    panic!("Playlist was not filled");
+}
+
+/// Polls the HEVC playlist until its first segment is written.
+async fn get_hevc_playlist_content() -> String {
+    let client = reqwest::Client::new();
+    for _ in 0..60 {
+        let response = client
+            .get("http://127.0.0.1:8080/streams/353071279376/1/playlist.m3u8")
+            .send()
+            .await
+            .unwrap();
+        if response.status().is_success() {
+            let content = response.text().await.unwrap();
+            if content.lines().any(|line| line.ends_with(".m4s")) {
+                return content;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    panic!("HEVC playlist has no segments");
 }
 
 #[tokio::test]
 async fn test_health_check() {
+    Lazy::force(&TESTS);
     let client = reqwest::Client::new();
     let response = client
         .get("http://127.0.0.1:8080/health_check")
@@ -120,11 +249,12 @@ async fn test_health_check() {
 
 #[tokio::test]
 async fn test_get_playlist() {
-    TESTS.increment().await;
+    let streams = TESTS.streams().await;
+    get_playlist_content().await;
 
     let client = reqwest::Client::new();
     let response = client
-        .get("http://127.0.0.1:8080/streams/353071279375/playlist.m3u8")
+        .get("http://127.0.0.1:8080/streams/353071279375/1/playlist.m3u8")
         .send()
         .await
         .unwrap();
@@ -135,12 +265,12 @@ async fn test_get_playlist() {
         "audio/x-mpegurl"
     );
 
-    TESTS.decrement().await;
+    streams.release().await;
 }
 
 #[tokio::test]
 async fn test_get_segment() {
-    TESTS.increment().await;
+    let streams = TESTS.streams().await;
 
     let content = get_playlist_content().await;
     let segment = content
@@ -149,7 +279,7 @@ async fn test_get_segment() {
         .find(|line| line.ends_with(".ts"))
         .unwrap();
 
-    let url = format!("http://127.0.0.1:8080/streams/353071279375/{segment}");
+    let url = format!("http://127.0.0.1:8080/streams/353071279375/1/{segment}");
 
     let client = reqwest::Client::new();
     let response = client.get(url).send().await.unwrap();
@@ -160,24 +290,85 @@ async fn test_get_segment() {
         "video/mp2t"
     );
 
-    TESTS.decrement().await;
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_g711_audio() {
+    Lazy::force(&TESTS);
+
+    let h264 = std::fs::read("data/test_stream.h264").unwrap();
+    let data = packetize_with_g711(&h264[..640 * 1024], "13800000007", 1);
+    let mut media = TcpClient::new("127.0.0.1:8000".parse().unwrap(), data);
+    media.connect().await.unwrap();
+    media.send().await.unwrap();
+
+    let client = reqwest::Client::new();
+    let url = "http://127.0.0.1:8080/streams/13800000007/1";
+    let mut segment = None;
+    for _ in 0..60 {
+        let response = client
+            .get(format!("{url}/playlist.m3u8"))
+            .send()
+            .await
+            .unwrap();
+        if response.status().is_success() {
+            let content = response.text().await.unwrap();
+            segment = content
+                .lines()
+                .find(|line| line.ends_with(".ts"))
+                .map(str::to_string);
+            if segment.is_some() {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    let segment = segment.expect("Playlist has no segments");
+    let response = client.get(format!("{url}/{segment}")).send().await.unwrap();
+    let body = response.bytes().await.unwrap();
+
+    // The native segmenter muxes the audio, encoded to ADTS framed AAC, on
+    // PID 0x101.
+    let packet = body
+        .chunks(188)
+        .find(|packet| packet[0] == 0x47 && packet[1] == 0x41 && packet[2] == 0x01)
+        .expect("Segment has no audio");
+    let payload = if packet[3] & 0x20 != 0 {
+        &packet[5 + packet[4] as usize..]
+    } else {
+        &packet[4..]
+    };
+    assert_eq!(payload[..4], [0x00, 0x00, 0x01, 0xC0]);
+    let adts = &payload[9 + payload[8] as usize..];
+    assert_eq!(adts[..2], [0xFF, 0xF1]);
+
+    let _ = media.close().await;
+    let dir = std::path::Path::new("13800000007/1");
+    for _ in 0..250 {
+        if !dir.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!dir.exists());
 }
 
 #[tokio::test]
 async fn test_total_segments() {
-    TESTS.increment().await;
+    let streams = TESTS.streams().await;
 
     let content = get_playlist_content().await;
     let segments = content.lines().filter(|line| line.ends_with(".ts")).count();
 
     assert_eq!(segments, 10);
 
-    TESTS.decrement().await;
+    streams.release().await;
 }
 
 #[tokio::test]
 async fn test_segment_duration() {
-    TESTS.increment().await;
+    let streams = TESTS.streams().await;
 
     let content = get_playlist_content().await;
     let duration: f64 = content
@@ -185,12 +376,729 @@ async fn test_segment_duration() {
         .find(|line| line.starts_with("#EXT-X-TARGETDURATION"))
         .unwrap()
         .split(':')
-        .last()
+        .next_back()
         .unwrap()
         .parse()
         .unwrap();
 
     assert!((6.0..7.0).contains(&duration));
 
-    TESTS.decrement().await;
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_hevc_playlist() {
+    let streams = TESTS.streams().await;
+
+    let content = get_hevc_playlist_content().await;
+    assert!(content.contains("#EXT-X-MAP:URI=\"init.mp4\""));
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:8080/streams/353071279376/1/init.mp4")
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get("content-type").unwrap(), "video/mp4");
+    let init = response.bytes().await.unwrap();
+    assert!(init.windows(4).any(|w| w == b"hvc1"));
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_get_hevc_segment() {
+    let streams = TESTS.streams().await;
+
+    let content = get_hevc_playlist_content().await;
+    let segment = content
+        .lines()
+        .rev()
+        .find(|line| line.ends_with(".m4s"))
+        .unwrap();
+
+    let url = format!("http://127.0.0.1:8080/streams/353071279376/1/{segment}");
+
+    let client = reqwest::Client::new();
+    let response = client.get(url).send().await.unwrap();
+
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "video/iso.segment"
+    );
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_dash_manifest() {
+    let streams = TESTS.streams().await;
+    get_hevc_playlist_content().await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:8080/streams/353071279376/1/manifest.mpd")
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/dash+xml"
+    );
+    let manifest = response.text().await.unwrap();
+    assert!(manifest.contains("type=\"dynamic\""));
+    assert!(manifest.contains("media=\"$Number$.m4s\""));
+    assert!(manifest.contains("codecs=\"hvc1."));
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_live_flv() {
+    let streams = TESTS.streams().await;
+    get_playlist_content().await;
+
+    let client = reqwest::Client::new();
+    let mut response = client
+        .get("http://127.0.0.1:8080/live/353071279375/1.flv")
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "video/x-flv"
+    );
+    // The body starts with the file header, then the AVC sequence header
+    // of the cached GOP.
+    let mut body = Vec::new();
+    while body.len() < 13 + 13 {
+        body.extend_from_slice(&response.chunk().await.unwrap().unwrap());
+    }
+    assert_eq!(&body[..3], b"FLV");
+    assert_eq!(body[13], 9);
+    assert_eq!(body[13 + 11..13 + 13], [0x17, 0x00]);
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_live_websocket() {
+    let streams = TESTS.streams().await;
+    get_playlist_content().await;
+
+    let url = "ws://127.0.0.1:8080/ws/353071279375/1.flv";
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let Some(Ok(Message::Binary(header))) = socket.next().await else {
+        panic!("Expected the FLV header");
+    };
+    assert_eq!(&header[..3], b"FLV");
+    let Some(Ok(Message::Binary(tag))) = socket.next().await else {
+        panic!("Expected the AVC sequence header");
+    };
+    assert_eq!(tag[0], 9);
+    assert_eq!(tag[11..13], [0x17, 0x00]);
+
+    // Raw frames start at the cached I-frame.
+    let url = "ws://127.0.0.1:8080/ws/353071279375/1.raw";
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    let Some(Ok(Message::Binary(message))) = socket.next().await else {
+        panic!("Expected a raw frame");
+    };
+    let length = u32::from_be_bytes(message[..4].try_into().unwrap()) as usize;
+    let header: serde_json::Value = serde_json::from_slice(&message[4..4 + length]).unwrap();
+    assert_eq!(header["type"], "video");
+    assert_eq!(header["codec"], "h264");
+    assert_eq!(header["keyframe"], true);
+    assert_eq!(header["rtp"]["logical_channel_number"], 1);
+    assert_eq!(message[4 + length..8 + length], [0, 0, 0, 1]);
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_whep_playback() {
+    let streams = TESTS.streams().await;
+    get_playlist_content().await;
+
+    let mut media_engine = MediaEngine::default();
+    media_engine.register_default_codecs().unwrap();
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine).unwrap();
+    let api = APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .build();
+    let peer_connection = api
+        .new_peer_connection(RTCConfiguration::default())
+        .await
+        .unwrap();
+    let init = RTCRtpTransceiverInit {
+        direction: RTCRtpTransceiverDirection::Recvonly,
+        send_encodings: Vec::new(),
+    };
+    peer_connection
+        .add_transceiver_from_kind(RTPCodecType::Video, Some(init))
+        .await
+        .unwrap();
+    let (packet_tx, mut packet_rx) = tokio::sync::mpsc::channel(1);
+    peer_connection.on_track(Box::new(move |track, _, _| {
+        let packet_tx = packet_tx.clone();
+        Box::pin(async move {
+            if let Ok((packet, _)) = track.read_rtp().await {
+                let _ = packet_tx.send((track.kind(), packet.payload)).await;
+            }
+        })
+    }));
+
+    let offer = peer_connection.create_offer(None).await.unwrap();
+    let mut gathering = peer_connection.gathering_complete_promise().await;
+    peer_connection.set_local_description(offer).await.unwrap();
+    let _ = gathering.recv().await;
+    let offer = peer_connection.local_description().await.unwrap();
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://127.0.0.1:8080/whep/353071279375/1")
+        .header("content-type", "application/sdp")
+        .body(offer.sdp)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "application/sdp"
+    );
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let answer = response.text().await.unwrap();
+    peer_connection
+        .set_remote_description(RTCSessionDescription::answer(answer).unwrap())
+        .await
+        .unwrap();
+
+    // The cached GOP is sent as soon as the connection is up.
+    let (kind, payload) = tokio::time::timeout(Duration::from_secs(10), packet_rx.recv())
+        .await
+        .expect("No RTP packet received")
+        .unwrap();
+    assert_eq!(kind, RTPCodecType::Video);
+    assert!(!payload.is_empty());
+
+    let response = client
+        .delete(format!("http://127.0.0.1:8080{location}"))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    peer_connection.close().await.unwrap();
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_rtmp_push() {
+    let streams = TESTS.streams().await;
+
+    let mut rtmp_messages = TESTS.rtmp_messages.clone();
+    let messages = tokio::time::timeout(
+        Duration::from_secs(30),
+        rtmp_messages.wait_for(|messages| messages.len() >= 2),
+    )
+    .await
+    .expect("Nothing was published over RTMP")
+    .unwrap()
+    .clone();
+
+    // Only the H.264 stream matches the rule, and its first message is the
+    // AVC sequence header of the cached GOP, then the I-frame.
+    assert!(messages
+        .iter()
+        .all(|message| message.stream == "353071279375_1"));
+    assert_eq!(messages[0].kind, 9);
+    assert_eq!(messages[0].timestamp, 0);
+    assert_eq!(messages[0].body[..2], [0x17, 0x00]);
+    assert_eq!(messages[1].body[..2], [0x17, 0x01]);
+
+    streams.release().await;
+}
+
+/// Sends an RTSP request and reads the response header and body.
+async fn rtsp_request(
+    stream: &mut tokio::io::BufReader<tokio::net::TcpStream>,
+    request: &str,
+) -> (String, String) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    stream
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .unwrap();
+    let mut header = String::new();
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        header.push_str(&line);
+    }
+    let length = header
+        .lines()
+        .find_map(|line| line.strip_prefix("Content-Length: "))
+        .map_or(0, |length| length.parse().unwrap());
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await.unwrap();
+    (header, String::from_utf8(body).unwrap())
+}
+
+#[tokio::test]
+async fn test_rtsp_playback() {
+    use tokio::io::AsyncReadExt;
+
+    let streams = TESTS.streams().await;
+    get_playlist_content().await;
+
+    let url = "rtsp://127.0.0.1:8554/353071279375/1";
+    let stream = tokio::net::TcpStream::connect("127.0.0.1:8554")
+        .await
+        .unwrap();
+    let mut stream = tokio::io::BufReader::new(stream);
+
+    let (header, sdp) = rtsp_request(
+        &mut stream,
+        &format!("DESCRIBE {url} RTSP/1.0\r\nCSeq: 1\r\nAccept: application/sdp\r\n\r\n"),
+    )
+    .await;
+    assert!(header.starts_with("RTSP/1.0 200 OK\r\n"));
+    assert!(header.contains("CSeq: 1\r\n"));
+    assert!(sdp.contains("a=rtpmap:96 H264/90000"));
+    assert!(sdp.contains(";sprop-parameter-sets="));
+
+    // The body of a request is skipped, leaving the next one intact.
+    let (header, _) = rtsp_request(
+        &mut stream,
+        &format!(
+            "GET_PARAMETER {url} RTSP/1.0\r\nCSeq: 2\r\n\
+             Content-Type: text/parameters\r\nContent-Length: 10\r\n\r\nposition\r\n"
+        ),
+    )
+    .await;
+    assert!(header.starts_with("RTSP/1.0 200 OK\r\n"));
+    assert!(header.contains("CSeq: 2\r\n"));
+
+    let (header, _) = rtsp_request(
+        &mut stream,
+        &format!(
+            "SETUP {url}/trackID=0 RTSP/1.0\r\nCSeq: 3\r\n\
+             Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n\r\n"
+        ),
+    )
+    .await;
+    assert!(header.contains("Transport: RTP/AVP/TCP;unicast;interleaved=0-1\r\n"));
+    let session = header
+        .lines()
+        .find_map(|line| line.strip_prefix("Session: "))
+        .and_then(|session| session.split(';').next())
+        .unwrap()
+        .to_string();
+
+    let (header, _) = rtsp_request(
+        &mut stream,
+        &format!("PLAY {url} RTSP/1.0\r\nCSeq: 4\r\nSession: {session}\r\n\r\n"),
+    )
+    .await;
+    assert!(header.starts_with("RTSP/1.0 200 OK\r\n"));
+    assert!(header.contains("RTP-Info: url="));
+
+    // The cached GOP starts with the parameter sets, each in a packet of
+    // its own.
+    let mut frame = [0; 4];
+    stream.read_exact(&mut frame).await.unwrap();
+    assert_eq!(frame[..2], [b'$', 0]);
+    let mut packet = vec![0; u16::from_be_bytes([frame[2], frame[3]]) as usize];
+    stream.read_exact(&mut packet).await.unwrap();
+    assert_eq!(packet[0] >> 6, 2);
+    assert_eq!(packet[1] & 0x7F, 96);
+    assert_eq!(packet[12] & 0x1F, 7);
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_blocking_playlist_reload() {
+    let streams = TESTS.streams().await;
+
+    let content = get_playlist_content().await;
+    assert!(content.contains("#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES"));
+    let hint = content
+        .lines()
+        .find_map(|line| line.strip_prefix("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\""))
+        .unwrap()
+        .trim_end_matches('"');
+    let (msn, part) = hint.trim_end_matches(".ts").split_once('.').unwrap();
+    let msn: u64 = msn.parse().unwrap();
+
+    // Parts already in the playlist are served right away.
+    let client = reqwest::Client::new();
+    let url = "http://127.0.0.1:8080/streams/353071279375/1/playlist.m3u8";
+    let response = client
+        .get(format!("{url}?_HLS_msn={msn}&_HLS_part=0"))
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    if part != "0" {
+        let content = response.text().await.unwrap();
+        assert!(content.contains(&format!("URI=\"{msn}.0.ts\"")));
+    }
+
+    let response = client
+        .get(format!("{url}?_HLS_msn={}", msn + 3))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    streams.release().await;
+}
+
+#[tokio::test]
+async fn test_jt808_signaling() {
+    Lazy::force(&TESTS);
+
+    let mut terminal = Jt808Terminal::connect("127.0.0.1:7611", "13912345678")
+        .await
+        .unwrap();
+    assert!(TESTS.terminals.get("13912345678").is_none());
+
+    // Registration: province, city, manufacturer, model, terminal ID,
+    // plate color and plate (2013 field lengths).
+    let mut body = vec![0, 44, 1, 0];
+    body.extend_from_slice(b"ACME\0");
+    body.extend_from_slice(&format!("{:\0<20}", "DVR-4").into_bytes());
+    body.extend_from_slice(b"T000001");
+    body.push(1);
+    body.extend_from_slice(b"A12345");
+    let serial = terminal.send(0x0100, &body).await.unwrap();
+    let reply = terminal.receive().await.unwrap();
+    assert_eq!(reply.id, 0x8100);
+    assert_eq!(reply.body[..3], [(serial >> 8) as u8, serial as u8, 0]);
+    let auth_code = reply.body[3..].to_vec();
+
+    // A heartbeat before signing in is refused.
+    terminal.send(0x0002, &[]).await.unwrap();
+    let reply = terminal.receive().await.unwrap();
+    assert_eq!(reply.id, 0x8001);
+    assert_eq!(reply.body[4], 1);
+
+    let serial = terminal.send(0x0102, &auth_code).await.unwrap();
+    let reply = terminal.receive().await.unwrap();
+    assert_eq!(reply.id, 0x8001);
+    assert_eq!(
+        reply.body,
+        [(serial >> 8) as u8, serial as u8, 0x01, 0x02, 0]
+    );
+    let signed_in = TESTS.terminals.get("13912345678").unwrap();
+    assert_eq!(signed_in.model, "DVR-4");
+    assert_eq!(signed_in.plate, "A12345");
+
+    terminal.send(0x0002, &[]).await.unwrap();
+    let reply = terminal.receive().await.unwrap();
+    assert_eq!(reply.id, 0x8001);
+    assert_eq!(reply.body[2..], [0x00, 0x02, 0]);
+
+    drop(terminal);
+    for _ in 0..50 {
+        if TESTS.terminals.get("13912345678").is_none() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(TESTS.terminals.get("13912345678").is_none());
+}
+
+#[tokio::test]
+async fn test_deregistration() {
+    Lazy::force(&TESTS);
+
+    let mut terminal = Jt808Terminal::connect("127.0.0.1:7611", "13800000008")
+        .await
+        .unwrap();
+    terminal.sign_in().await.unwrap();
+
+    // Another connection cannot deregister the terminal by its number.
+    let mut intruder = Jt808Terminal::connect("127.0.0.1:7611", "13800000008")
+        .await
+        .unwrap();
+    let serial = intruder.send(0x0003, &[]).await.unwrap();
+    let reply = intruder.receive().await.unwrap();
+    assert_eq!(reply.id, 0x8001);
+    assert_eq!(
+        reply.body,
+        [(serial >> 8) as u8, serial as u8, 0x00, 0x03, 1]
+    );
+    assert!(TESTS.terminals.get("13800000008").is_some());
+
+    terminal.send(0x0003, &[]).await.unwrap();
+    let reply = terminal.receive().await.unwrap();
+    assert_eq!(reply.id, 0x8001);
+    assert_eq!(reply.body[4], 0);
+    assert!(TESTS.terminals.get("13800000008").is_none());
+}
+
+#[tokio::test]
+async fn test_live_request() {
+    Lazy::force(&TESTS);
+
+    let mut terminal = Jt808Terminal::connect("127.0.0.1:7611", "13800000001")
+        .await
+        .unwrap();
+    terminal.sign_in().await.unwrap();
+
+    let request = tokio::spawn(
+        reqwest::Client::new()
+            .post("http://127.0.0.1:8080/terminals/13800000001/channels/2/live?stream_type=sub")
+            .send(),
+    );
+    let command = terminal.receive().await.unwrap();
+    assert_eq!(command.id, 0x9101);
+    let mut expected = vec![9];
+    expected.extend_from_slice(b"127.0.0.1");
+    expected.extend_from_slice(&[0x1F, 0x40, 0, 0, 2, 0, 1]);
+    assert_eq!(command.body, expected);
+    terminal.respond(&command, 0).await.unwrap();
+
+    // The terminal connects to the media server as asked.
+    let h264 = std::fs::read("data/test_stream.h264").unwrap();
+    let address = "127.0.0.1:8000".parse().unwrap();
+    let data = packetize(&h264[..64 * 1024], "13800000001", 2, Codec::H264);
+    let mut client = TcpClient::new(address, data);
+    client.connect().await.unwrap();
+    client.send().await.unwrap();
+
+    let response = request.await.unwrap().unwrap();
+    assert!(response.status().is_success());
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["playlist"], "/streams/13800000001/2/playlist.m3u8");
+    client.close().await.unwrap();
+
+    // Terminals that are not signed in cannot be asked.
+    let response = reqwest::Client::new()
+        .post("http://127.0.0.1:8080/terminals/13800000002/channels/1/live")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_live_control() {
+    Lazy::force(&TESTS);
+
+    let mut terminal = Jt808Terminal::connect("127.0.0.1:7611", "13800000003")
+        .await
+        .unwrap();
+    terminal.sign_in().await.unwrap();
+    let client = reqwest::Client::new();
+    let url = "http://127.0.0.1:8080/terminals/13800000003/channels/1/live";
+
+    let request = tokio::spawn(client.post(url).send());
+    let command = terminal.receive().await.unwrap();
+    terminal.respond(&command, 0).await.unwrap();
+    let h264 = std::fs::read("data/test_stream.h264").unwrap();
+    let data = packetize(&h264[..640 * 1024], "13800000003", 1, Codec::H264);
+    let mut media = TcpClient::new("127.0.0.1:8000".parse().unwrap(), data);
+    media.connect().await.unwrap();
+    media.send().await.unwrap();
+    assert!(request.await.unwrap().unwrap().status().is_success());
+
+    // A refused command fails without touching the stream.
+    let request = tokio::spawn(client.post(format!("{url}/pause")).send());
+    let command = terminal.receive().await.unwrap();
+    assert_eq!(
+        (command.id, command.body.as_slice()),
+        (0x9102, &[1, 2, 0, 0][..])
+    );
+    terminal.respond(&command, 1).await.unwrap();
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_GATEWAY);
+
+    let request = tokio::spawn(client.post(format!("{url}/switch?stream_type=sub")).send());
+    let command = terminal.receive().await.unwrap();
+    assert_eq!(command.body, [1, 1, 0, 1]);
+    terminal.respond(&command, 0).await.unwrap();
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+    // Closing ends the stream while the media connection is still open.
+    let dir = std::path::Path::new("13800000003/1");
+    assert!(dir.exists());
+    let request = tokio::spawn(client.post(format!("{url}/close")).send());
+    let command = terminal.receive().await.unwrap();
+    assert_eq!(command.body, [1, 0, 0, 0]);
+    terminal.respond(&command, 0).await.unwrap();
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+    // The ended playlist is served for a while before the files go.
+    let playlist = "http://127.0.0.1:8080/streams/13800000003/1/playlist.m3u8";
+    let mut ended = false;
+    for _ in 0..50 {
+        let response = client.get(playlist).send().await.unwrap();
+        if response.text().await.unwrap().ends_with("#EXT-X-ENDLIST\n") {
+            ended = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(ended);
+    for _ in 0..150 {
+        if !dir.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!dir.exists());
+    let _ = media.close().await;
+}
+
+#[tokio::test]
+async fn test_idle_stop() {
+    Lazy::force(&TESTS);
+
+    let webhook = tokio::net::TcpListener::bind("127.0.0.1:19351")
+        .await
+        .unwrap();
+    let tcp_server = TcpServer::new("127.0.0.1", 8001)
+        .with_registry(TESTS.registry.clone())
+        .with_terminals(TESTS.terminals.clone())
+        .with_idle_timeout(Duration::from_secs(2))
+        .with_clean_up_delay(Duration::from_secs(1))
+        .with_idle_webhook("http://127.0.0.1:19351/events");
+    let tcp_server_task = spawn_tcp_server(tcp_server);
+
+    let mut terminal = Jt808Terminal::connect("127.0.0.1:7611", "13800000005")
+        .await
+        .unwrap();
+    terminal.sign_in().await.unwrap();
+    let h264 = std::fs::read("data/test_stream.h264").unwrap();
+    let data = packetize(&h264[..64 * 1024], "13800000005", 1, Codec::H264);
+    let mut media = TcpClient::new("127.0.0.1:8001".parse().unwrap(), data);
+    media.connect().await.unwrap();
+    media.send().await.unwrap();
+
+    // Playlist requests keep the stream going past the idle period.
+    let client = reqwest::Client::new();
+    let playlist = "http://127.0.0.1:8080/streams/13800000005/1/playlist.m3u8";
+    let watching = async {
+        for _ in 0..8 {
+            client.get(playlist).send().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    };
+    tokio::select! {
+        _ = watching => (),
+        command = terminal.receive() => panic!("Stream stopped while watched: {command:?}"),
+    }
+
+    let command = tokio::time::timeout(Duration::from_secs(5), terminal.receive())
+        .await
+        .expect("Idle stream was not stopped")
+        .unwrap();
+    assert_eq!(
+        (command.id, command.body.as_slice()),
+        (0x9102, &[1, 0, 0, 0][..])
+    );
+    terminal.respond(&command, 0).await.unwrap();
+
+    let (mut stream, _) = webhook.accept().await.unwrap();
+    let mut request = Vec::new();
+    while !request.ends_with(b"}") {
+        let mut chunk = [0u8; 1024];
+        let read = tokio::io::AsyncReadExt::read(&mut stream, &mut chunk)
+            .await
+            .unwrap();
+        assert!(read > 0);
+        request.extend_from_slice(&chunk[..read]);
+    }
+    tokio::io::AsyncWriteExt::write_all(&mut stream, b"HTTP/1.1 204 No Content\r\n\r\n")
+        .await
+        .unwrap();
+    let request = String::from_utf8(request).unwrap();
+    assert!(request.starts_with("POST /events "));
+    let event: serde_json::Value =
+        serde_json::from_str(&request[request.find("\r\n\r\n").unwrap() + 4..]).unwrap();
+    assert_eq!(event["event"], "stream_idle_stopped");
+    assert_eq!(event["imei"], "13800000005");
+    assert_eq!(event["signaled"], true);
+
+    // The stream ends even though the terminal keeps the connection open.
+    let dir = std::path::Path::new("13800000005/1");
+    for _ in 0..150 {
+        if !dir.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!dir.exists());
+    let _ = media.close().await;
+    tcp_server_task.end().await;
+}
+
+#[tokio::test]
+async fn test_recordings() {
+    Lazy::force(&TESTS);
+
+    let mut terminal = Jt808Terminal::connect("127.0.0.1:7611", "13800000006")
+        .await
+        .unwrap();
+    terminal.sign_in().await.unwrap();
+
+    let request = tokio::spawn(
+        reqwest::Client::new()
+            .get(
+                "http://127.0.0.1:8080/terminals/13800000006/channels/1/recordings\
+                ?start=2024-05-01T08:00:00&media_type=video",
+            )
+            .send(),
+    );
+    let command = terminal.receive().await.unwrap();
+    assert_eq!(command.id, 0x9205);
+    assert_eq!(
+        command.body,
+        [1, 0x24, 0x05, 0x01, 0x08, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0]
+    );
+
+    let mut list = command.serial.to_be_bytes().to_vec();
+    list.extend_from_slice(&[0, 0, 0, 1]);
+    list.extend_from_slice(&[1, 0x24, 0x05, 0x01, 0x08, 0x00, 0x00]);
+    list.extend_from_slice(&[0x24, 0x05, 0x01, 0x08, 0x15, 0x00]);
+    list.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x04, 2, 1, 1, 0x00, 0x9C, 0x40, 0x00]);
+    terminal.send(0x1205, &list).await.unwrap();
+    let reply = terminal.receive().await.unwrap();
+    assert_eq!((reply.id, reply.body[4]), (0x8001, 0));
+
+    let response = request.await.unwrap().unwrap();
+    assert!(response.status().is_success());
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(
+        body["recordings"],
+        serde_json::json!([{
+            "channel": 1,
+            "start": "2024-05-01T08:00:00",
+            "end": "2024-05-01T08:15:00",
+            "alarm": 4,
+            "media_type": "video",
+            "stream_type": "main",
+            "storage_type": "main",
+            "size": 10240000,
+        }])
+    );
 }
@@ -0,0 +1,136 @@
+use bytes::BytesMut;
+use jt1078_video_server::{DataType, PayloadType, RtpHeader, RtpPacket, SubpacketFlag};
+
+/// Maximum data body length of a JT/T 1078 packet.
+const MAX_BODY_LENGTH: usize = 950;
+
+const FRAME_INTERVAL_MS: u64 = 40;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Codec {
+    H264,
+    H265,
+}
+
+impl Codec {
+    fn payload_type(&self) -> PayloadType {
+        match self {
+            Codec::H264 => PayloadType::H264,
+            Codec::H265 => PayloadType::H265,
+        }
+    }
+
+    /// Returns `(is_vcl, is_keyframe)` for the NAL unit.
+    fn classify(&self, nal: &[u8]) -> (bool, bool) {
+        match self {
+            Codec::H264 => {
+                let nal_type = nal[0] & 0x1F;
+                ((1..=5).contains(&nal_type), nal_type == 5)
+            }
+            Codec::H265 => {
+                let nal_type = (nal[0] >> 1) & 0x3F;
+                (nal_type < 32, (16..=23).contains(&nal_type))
+            }
+        }
+    }
+}
+
+/// Splits an Annex-B elementary stream into NAL units, start codes included.
+fn split_nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            let start = if i > 0 && data[i - 1] == 0 { i - 1 } else { i };
+            starts.push((start, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, &(start, _))| {
+            let end = starts.get(n + 1).map_or(data.len(), |&(next, _)| next);
+            &data[start..end]
+        })
+        .collect()
+}
+
+/// Packs an Annex-B elementary stream into JT/T 1078 packets, one access unit
+/// per frame, fragmenting frames larger than a single packet body.
+pub(crate) fn packetize(data: &[u8], imei: &str, channel: u8, codec: Codec) -> Vec<u8> {
+    packetize_frames(data, imei, channel, codec, false)
+}
+
+/// Like [`packetize`], with a frame interval of G.711A audio after every
+/// video frame.
+pub(crate) fn packetize_with_g711(data: &[u8], imei: &str, channel: u8) -> Vec<u8> {
+    packetize_frames(data, imei, channel, Codec::H264, true)
+}
+
+fn packetize_frames(data: &[u8], imei: &str, channel: u8, codec: Codec, audio: bool) -> Vec<u8> {
+    let mut output = BytesMut::new();
+    let mut frame = Vec::new();
+    let mut serial_number: u16 = 0;
+    let mut timestamp: u64 = 0;
+    let mut last_keyframe: u64 = 0;
+
+    for nal in split_nal_units(data) {
+        let header_len = if nal[2] == 1 { 3 } else { 4 };
+        let (is_vcl, is_keyframe) = codec.classify(&nal[header_len..]);
+        frame.extend_from_slice(nal);
+        if !is_vcl {
+            continue;
+        }
+
+        if is_keyframe {
+            last_keyframe = timestamp;
+        }
+        let data_type = if is_keyframe {
+            DataType::IFrame
+        } else {
+            DataType::PFrame
+        };
+        let chunks: Vec<&[u8]> = frame.chunks(MAX_BODY_LENGTH).collect();
+        for (n, chunk) in chunks.iter().enumerate() {
+            let subpacket_flag = match (n, chunks.len()) {
+                (_, 1) => SubpacketFlag::Atomic,
+                (0, _) => SubpacketFlag::First,
+                (n, len) if n == len - 1 => SubpacketFlag::Last,
+                _ => SubpacketFlag::Intermediate,
+            };
+            let marker = matches!(subpacket_flag, SubpacketFlag::Atomic | SubpacketFlag::Last);
+
+            let header = RtpHeader::new(codec.payload_type(), imei, channel, data_type)
+                .with_marker(marker)
+                .with_package_serial_number(serial_number)
+                .with_subpacket_processing_flag(subpacket_flag)
+                .with_timestamp(timestamp)
+                .with_frame_intervals((timestamp - last_keyframe) as u16, FRAME_INTERVAL_MS as u16);
+            RtpPacket::new(header, chunk.to_vec())
+                .encode(&mut output)
+                .unwrap();
+            serial_number = serial_number.wrapping_add(1);
+        }
+        if audio {
+            // 8 kHz, one byte per sample.
+            let samples = (0..FRAME_INTERVAL_MS * 8).map(|n| (n as u8).wrapping_mul(37));
+            let header = RtpHeader::new(PayloadType::G711A, imei, channel, DataType::AudioFrame)
+                .with_marker(true)
+                .with_package_serial_number(serial_number)
+                .with_timestamp(timestamp);
+            RtpPacket::new(header, samples.collect::<Vec<u8>>())
+                .encode(&mut output)
+                .unwrap();
+            serial_number = serial_number.wrapping_add(1);
+        }
+
+        frame.clear();
+        timestamp += FRAME_INTERVAL_MS;
+    }
+
+    output.to_vec()
+}
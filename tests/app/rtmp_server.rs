@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+
+const HANDSHAKE_SIZE: usize = 1536;
+const CHUNK_SIZE: usize = 128;
+const MAX_TIMESTAMP: u32 = 0xFF_FFFF;
+
+/// Media message received by the stand-in.
+#[derive(Clone, Debug)]
+pub(crate) struct RtmpMessage {
+    pub(crate) stream: String,
+    pub(crate) kind: u8,
+    pub(crate) timestamp: u32,
+    pub(crate) body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Number(f64),
+    String(String),
+    Other,
+}
+
+/// Stand-in for an RTMP media server: it accepts every publisher and
+/// records the audio and video messages they send.
+pub(crate) struct RtmpServer {
+    listener: std::net::TcpListener,
+}
+
+impl RtmpServer {
+    pub(crate) fn bind(address: &str) -> std::io::Result<Self> {
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener })
+    }
+
+    pub(crate) async fn run(self, messages: watch::Sender<Vec<RtmpMessage>>) {
+        let listener = TcpListener::from_std(self.listener).expect("Failed to listen");
+        while let Ok((stream, _)) = listener.accept().await {
+            let messages = messages.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, messages).await {
+                    eprintln!("RTMP stand-in connection ended: {e}");
+                }
+            });
+        }
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    messages: watch::Sender<Vec<RtmpMessage>>,
+) -> std::io::Result<()> {
+    let mut c0c1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+    stream.read_exact(&mut c0c1).await?;
+    let mut s0s1s2 = vec![3u8];
+    s0s1s2.extend_from_slice(&[0u8; HANDSHAKE_SIZE]);
+    s0s1s2.extend_from_slice(&c0c1[1..]);
+    stream.write_all(&s0s1s2).await?;
+    let mut c2 = vec![0u8; HANDSHAKE_SIZE];
+    stream.read_exact(&mut c2).await?;
+
+    let mut chunk_size = CHUNK_SIZE;
+    // Timestamp, delta, length, type and payload of each chunk stream.
+    let mut streams: HashMap<u32, (u32, u32, usize, u8, Vec<u8>)> = HashMap::new();
+    let mut name = String::new();
+    loop {
+        let first = stream.read_u8().await?;
+        let format = first >> 6;
+        let id = match first & 0x3F {
+            0 => 64 + stream.read_u8().await? as u32,
+            1 => 64 + stream.read_u16_le().await? as u32,
+            id => id as u32,
+        };
+        let state = streams.entry(id).or_default();
+        let mut timestamp = None;
+        if format < 3 {
+            let mut bytes = [0u8; 3];
+            stream.read_exact(&mut bytes).await?;
+            timestamp = Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]));
+        }
+        if format < 2 {
+            let mut bytes = [0u8; 3];
+            stream.read_exact(&mut bytes).await?;
+            state.2 = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize;
+            state.3 = stream.read_u8().await?;
+        }
+        if format == 0 {
+            stream.read_u32_le().await?;
+        }
+        if timestamp == Some(MAX_TIMESTAMP) {
+            timestamp = Some(stream.read_u32().await?);
+        }
+        if state.4.is_empty() {
+            match (format, timestamp) {
+                (0, Some(timestamp)) => (state.0, state.1) = (timestamp, 0),
+                (_, Some(delta)) => (state.0, state.1) = (state.0.wrapping_add(delta), delta),
+                _ => state.0 = state.0.wrapping_add(state.1),
+            }
+        }
+        let mut chunk = vec![0u8; (state.2 - state.4.len()).min(chunk_size)];
+        stream.read_exact(&mut chunk).await?;
+        state.4.extend_from_slice(&chunk);
+        if state.4.len() < state.2 {
+            continue;
+        }
+        let payload = std::mem::take(&mut state.4);
+
+        match state.3 {
+            1 => chunk_size = u32::from_be_bytes(payload[..4].try_into().unwrap()) as usize,
+            8 | 9 => {
+                let message = RtmpMessage {
+                    stream: name.clone(),
+                    kind: state.3,
+                    timestamp: state.0,
+                    body: payload,
+                };
+                messages.send_modify(|messages| messages.push(message));
+            }
+            20 => {
+                let values = decode(&payload);
+                let transaction = match values.get(1) {
+                    Some(Value::Number(transaction)) => *transaction,
+                    _ => 0.0,
+                };
+                let reply = match values.first() {
+                    Some(Value::String(command)) if command == "connect" => {
+                        let mut reply = encode_string("_result");
+                        reply.extend(encode_number(transaction));
+                        reply.extend(encode_object(&[("fmsVer", "FMS/3,0,1,123")]));
+                        reply.extend(encode_object(&[
+                            ("level", "status"),
+                            ("code", "NetConnection.Connect.Success"),
+                        ]));
+                        Some((0, reply))
+                    }
+                    Some(Value::String(command)) if command == "createStream" => {
+                        let mut reply = encode_string("_result");
+                        reply.extend(encode_number(transaction));
+                        reply.push(0x05);
+                        reply.extend(encode_number(1.0));
+                        Some((0, reply))
+                    }
+                    Some(Value::String(command)) if command == "publish" => {
+                        if let Some(Value::String(stream)) = values.get(3) {
+                            name = stream.clone();
+                        }
+                        let mut reply = encode_string("onStatus");
+                        reply.extend(encode_number(0.0));
+                        reply.push(0x05);
+                        reply.extend(encode_object(&[
+                            ("level", "status"),
+                            ("code", "NetStream.Publish.Start"),
+                        ]));
+                        Some((1, reply))
+                    }
+                    _ => None,
+                };
+                if let Some((stream_id, reply)) = reply {
+                    stream.write_all(&chunks(stream_id, &reply)).await?;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A command message in chunks of the default size.
+fn chunks(stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x03, 0, 0, 0];
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(20);
+    out.extend_from_slice(&stream_id.to_le_bytes());
+    for (index, chunk) in payload.chunks(CHUNK_SIZE).enumerate() {
+        if index > 0 {
+            out.push(0xC3);
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+fn encode_string(value: &str) -> Vec<u8> {
+    let mut out = vec![0x02];
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+    out
+}
+
+fn encode_number(value: f64) -> Vec<u8> {
+    let mut out = vec![0x00];
+    out.extend_from_slice(&value.to_be_bytes());
+    out
+}
+
+fn encode_object(properties: &[(&str, &str)]) -> Vec<u8> {
+    let mut out = vec![0x03];
+    for (key, value) in properties {
+        out.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        out.extend_from_slice(key.as_bytes());
+        out.extend(encode_string(value));
+    }
+    out.extend_from_slice(&[0, 0, 0x09]);
+    out
+}
+
+/// The top-level values of a command, objects left out.
+fn decode(mut data: &[u8]) -> Vec<Value> {
+    let mut values = Vec::new();
+    while let Some(value) = decode_value(&mut data) {
+        values.push(value);
+    }
+    values
+}
+
+fn decode_value(data: &mut &[u8]) -> Option<Value> {
+    let (&marker, rest) = data.split_first()?;
+    *data = rest;
+    match marker {
+        0x00 => {
+            let number = f64::from_be_bytes(data.get(..8)?.try_into().ok()?);
+            *data = &data[8..];
+            Some(Value::Number(number))
+        }
+        0x01 => {
+            *data = data.get(1..)?;
+            Some(Value::Other)
+        }
+        0x02 => Some(Value::String(decode_utf8(data)?)),
+        0x03 | 0x08 => {
+            if marker == 0x08 {
+                *data = data.get(4..)?;
+            }
+            loop {
+                let key = decode_utf8(data)?;
+                if key.is_empty() && data.first() == Some(&0x09) {
+                    *data = &data[1..];
+                    return Some(Value::Other);
+                }
+                decode_value(data)?;
+            }
+        }
+        0x05 | 0x06 => Some(Value::Other),
+        _ => None,
+    }
+}
+
+fn decode_utf8(data: &mut &[u8]) -> Option<String> {
+    let length = u16::from_be_bytes(data.get(..2)?.try_into().ok()?) as usize;
+    let string = String::from_utf8_lossy(data.get(2..2 + length)?).into_owned();
+    *data = &data[2 + length..];
+    Some(string)
+}